@@ -4,7 +4,7 @@
 //! files that would not fit in a single HTTP request, including allowing the user to resume
 //! interrupted uploads, and uploading blocks in parallel.
 
-use dropbox_toolbox::upload::{UploadResume, UploadSession, UploadOpts, ProgressHandler};
+use dropbox_toolbox::upload::{CommitOptions, UploadResume, UploadSession, UploadOpts, Progress, ProgressHandler};
 use dropbox_sdk::files;
 use dropbox_sdk::default_client::UserAuthDefaultClient;
 use std::fs::File;
@@ -158,32 +158,17 @@ fn human_number(n: u64) -> String {
     }
 }
 
-fn iso8601(t: SystemTime) -> String {
-    let timestamp: i64 = match t.duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(duration) => duration.as_secs() as i64,
-        Err(e) => -(e.duration().as_secs() as i64),
-    };
-
-    chrono::DateTime::from_timestamp(timestamp, 0 /* nsecs */)
-        .expect("invalid or out-of-range timestamp")
-        .format("%Y-%m-%dT%H:%M:%SZ").to_string()
-}
-
-struct Progress {
-    source_len: u64,
-    start_offset: u64,
-}
-
-impl ProgressHandler for Progress {
-    fn update(&self, bytes_uploaded: u64, instant_rate: f64, overall_rate: f64) {
-        let percent = (self.start_offset + bytes_uploaded) as f64
-            / self.source_len as f64 * 100.;
-
-        eprintln!("{:.01}%: {}Bytes uploaded, {}Bytes per second, {}Bytes per second average",
-            percent,
-            human_number(bytes_uploaded),
-            human_number(instant_rate as u64),
-            human_number(overall_rate as u64),
+struct ProgressPrinter;
+
+impl ProgressHandler for ProgressPrinter {
+    fn progress(&self, progress: &Progress) {
+        eprintln!("{:.01}%: {}Bytes uploaded, {}Bytes per second, {}Bytes per second average, \
+            {} seconds remaining",
+            progress.fraction().unwrap_or(0.) * 100.,
+            human_number(progress.bytes_uploaded),
+            human_number(progress.instant_rate as u64),
+            human_number(progress.overall_rate as u64),
+            progress.eta().map_or("?".to_owned(), |eta| eta.as_secs().to_string()),
             );
     }
 }
@@ -218,6 +203,9 @@ fn main() {
     let (source_mtime, source_len) = get_file_mtime_and_size(&source_file)
         .unwrap_or_else(|e| fatal!("failed to get file mtime and size: {}", e));
 
+    dropbox_toolbox::limits::check_upload_size(source_len)
+        .unwrap_or_else(|e| fatal!("Can't upload {:?}: {}", args.source_path, e));
+
     let session = if let Some(Resume(ref resume)) = args.resume {
         source_file.seek(SeekFrom::Start(resume.start_offset))
             .unwrap_or_else(|e| fatal!("Seek error: {}", e));
@@ -227,22 +215,22 @@ fn main() {
             .unwrap_or_else(|e| fatal!("failed to create upload session: {}", e))
     };
 
-    let result = session.upload(source_file, UploadOpts {
-        progress_handler: Some(Arc::new(Box::new(Progress {
-            source_len,
-            start_offset: args.resume.map(|r| r.0.start_offset).unwrap_or(0),
-        }))),
+    let bytes = session.upload(source_file, UploadOpts {
+        total_bytes: Some(source_len),
+        progress_handler: Some(Arc::new(Box::new(ProgressPrinter))),
         ..Default::default()
-    }).and_then(|bytes| {
-        eprintln!("uploaded {} bytes.", bytes);
-        session.commit(
-            files::CommitInfo::new(dest_path)
-                .with_client_modified(iso8601(source_mtime)))
-            .map_err(|e| e.boxed())
-    }).unwrap_or_else(|_| {
-        let resume = session.get_resume();
-        fatal!("Upload failed. To retry, use --resume {},{}",
-            resume.session_id, resume.start_offset);
+    }).unwrap_or_else(|e| {
+        let resume = e.resume();
+        fatal!("Upload failed: {}. To retry, use --resume {},{}",
+            e, resume.session_id, resume.start_offset);
     });
+    eprintln!("uploaded {} bytes.", bytes);
+
+    let result = session.commit(CommitOptions::new(dest_path).with_client_modified(source_mtime))
+        .unwrap_or_else(|_| {
+            let resume = session.get_resume();
+            fatal!("Commit failed. To retry, use --resume {},{}",
+                resume.session_id, resume.start_offset);
+        });
     println!("{result:#?}");
 }
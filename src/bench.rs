@@ -0,0 +1,212 @@
+//! Measuring upload throughput with different options, to choose the best ones for a network.
+//!
+//! This uploads synthetic data to upload sessions which are never committed, so it doesn't create
+//! any files, but it does use real bandwidth and count against the account's rate limits.
+
+use std::fmt::{self, Display, Formatter};
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use crate::upload::{source, Progress, ProgressHandler, UploadOpts, UploadSession};
+use crate::BLOCK_SIZE;
+
+/// Options for [`run`].
+#[derive(Clone)]
+pub struct BenchOpts {
+    /// How many bytes to upload for each combination of options.
+    pub bytes: u64,
+
+    /// The values of [`UploadOpts::parallelism`] to try.
+    pub parallelism: Vec<usize>,
+
+    /// The values of [`UploadOpts::blocks_per_request`] to try.
+    pub blocks_per_request: Vec<usize>,
+
+    /// The options to use for everything else.
+    pub upload: UploadOpts,
+}
+
+impl Default for BenchOpts {
+    fn default() -> Self {
+        Self {
+            bytes: 64 * BLOCK_SIZE as u64,
+            parallelism: vec![1, 4, 8, 16],
+            blocks_per_request: vec![1, 2, 4, 8],
+            upload: UploadOpts::default(),
+        }
+    }
+}
+
+/// The result of uploading with one combination of options.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    /// The value of [`UploadOpts::parallelism`] used.
+    pub parallelism: usize,
+
+    /// The value of [`UploadOpts::blocks_per_request`] used.
+    pub blocks_per_request: usize,
+
+    /// How many bytes were uploaded.
+    pub bytes: u64,
+
+    /// How long it took.
+    pub elapsed: Duration,
+
+    /// How many failed requests were retried.
+    pub retries: u32,
+}
+
+impl BenchResult {
+    /// The average upload rate, in bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// The given options, with the parallelism and blocks per request of this result.
+    pub fn apply(&self, opts: UploadOpts) -> UploadOpts {
+        UploadOpts {
+            parallelism: self.parallelism,
+            blocks_per_request: self.blocks_per_request,
+            ..opts
+        }
+    }
+}
+
+/// A table of results, for printing.
+pub struct Table<'a>(pub &'a [BenchResult]);
+
+impl Display for Table<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>11} {:>18} {:>10} {:>12} {:>7}",
+            "parallelism", "blocks_per_request", "seconds", "MiB/s", "retries"
+        )?;
+        for result in self.0 {
+            writeln!(
+                f,
+                "{:>11} {:>18} {:>10.2} {:>12.2} {:>7}",
+                result.parallelism,
+                result.blocks_per_request,
+                result.elapsed.as_secs_f64(),
+                result.rate() / (1024. * 1024.),
+                result.retries
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Upload [`bytes`](BenchOpts::bytes) of synthetic data with each combination of the given
+/// parallelism and blocks per request, and measure how long each takes.
+///
+/// The results are in the order tried, with blocks per request varying fastest. Print them with
+/// [`Table`], or pick the fastest with [`fastest`]. This stops at the first error.
+pub fn run<C: UserAuthClient + Send + Sync + 'static>(
+    client: Arc<C>,
+    opts: &BenchOpts,
+) -> Result<Vec<BenchResult>, BoxedError> {
+    let mut results = vec![];
+    for &parallelism in &opts.parallelism {
+        for &blocks_per_request in &opts.blocks_per_request {
+            let retries = Arc::new(AtomicU32::new(0));
+            let upload_opts = UploadOpts {
+                parallelism,
+                blocks_per_request,
+                auto_tune: false,
+                total_bytes: Some(opts.bytes),
+                progress_handler: Some(Arc::new(Box::new(CountRetries(Arc::clone(&retries))))),
+                ..opts.upload.clone()
+            };
+            let session = UploadSession::new(Arc::clone(&client)).map_err(Error::boxed)?;
+            info!(
+                "[{}] Uploading {} bytes with parallelism {parallelism} and {blocks_per_request} \
+                blocks per request",
+                session.operation_id(),
+                opts.bytes
+            );
+            let start = Instant::now();
+            let bytes = session.upload(synthetic(opts.bytes), upload_opts)?;
+            let result = BenchResult {
+                parallelism,
+                blocks_per_request,
+                bytes,
+                elapsed: start.elapsed(),
+                retries: retries.load(SeqCst),
+            };
+            info!(
+                "[{}] {:.0} bytes/sec",
+                session.operation_id(),
+                result.rate()
+            );
+            results.push(result);
+        }
+    }
+    Ok(results)
+}
+
+/// The result with the highest upload rate.
+pub fn fastest(results: &[BenchResult]) -> Option<&BenchResult> {
+    results.iter().max_by(|a, b| a.rate().total_cmp(&b.rate()))
+}
+
+/// Make a source of the given number of bytes of filler.
+fn synthetic(len: u64) -> impl std::io::Read {
+    let mut remaining = len;
+    source::from_fn(move |buf: &mut [u8]| {
+        let n = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        for (i, byte) in buf[..n].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        remaining -= n as u64;
+        Ok(n)
+    })
+}
+
+struct CountRetries(Arc<AtomicU32>);
+
+impl ProgressHandler for CountRetries {
+    fn progress(&self, progress: &Progress) {
+        self.0.fetch_max(progress.retries, SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+
+    #[test]
+    fn tries_each_combination() {
+        const SESSION: &str = r#"{"session_id": "session"}"#;
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(200, SESSION)
+                .then(200, "null")
+                .then(200, SESSION)
+                .then(200, "null"),
+        );
+        let opts = BenchOpts {
+            bytes: 10,
+            parallelism: vec![1, 2],
+            blocks_per_request: vec![1],
+            ..Default::default()
+        };
+        let results = run(client, &opts).unwrap();
+        assert_eq!(
+            vec![(1, 1, 10), (2, 1, 10)],
+            results
+                .iter()
+                .map(|r| (r.parallelism, r.blocks_per_request, r.bytes))
+                .collect::<Vec<_>>()
+        );
+        assert!(fastest(&results).is_some());
+        assert_eq!(3, Table(&results).to_string().lines().count());
+    }
+}
@@ -0,0 +1,69 @@
+//! Stopping transfers from another thread.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// A token which can be given to a transfer, and cancelled from any thread to make it stop.
+///
+/// Clones of a token share its state, so one clone can be given to the transfer and another kept
+/// to cancel it. The transfer stops the next time it checks the token: between reads of a
+/// response, and while waiting to retry a failed request.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl CancelToken {
+    /// Make a token which hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel whatever the token was given to. This can't be undone.
+    pub fn cancel(&self) {
+        *self.inner.cancelled.lock().unwrap() = true;
+        self.inner.cond.notify_all();
+    }
+
+    /// Whether the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.cancelled.lock().unwrap()
+    }
+
+    /// Sleep for the given time, or until the token is cancelled, if that's sooner.
+    pub(crate) fn sleep(&self, duration: Duration) {
+        let cancelled = self.inner.cancelled.lock().unwrap();
+        drop(
+            self.inner
+                .cond
+                .wait_timeout_while(cancelled, duration, |cancelled| !*cancelled)
+                .unwrap(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn cancel_wakes_sleep() {
+        let token = CancelToken::new();
+        let start = Instant::now();
+        thread::scope(|scope| {
+            scope.spawn(|| token.sleep(Duration::from_secs(60)));
+            thread::sleep(Duration::from_millis(10));
+            token.clone().cancel();
+        });
+        assert!(token.is_cancelled());
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+}
@@ -118,7 +118,82 @@ impl<T: AsRef<[u8]>> From<T> for ContentHash {
     }
 }
 
-fn hex(bytes: &[u8]) -> String {
+/// Calculate the SHA-256 hash of a single block of at most [`BLOCK_SIZE`] bytes.
+///
+/// The Content Hash of a file is the SHA-256 of the concatenation of these; see
+/// [`combine_block_hashes`].
+pub fn block_hash(block: &[u8]) -> [u8; OUTPUT_SIZE] {
+    assert!(block.len() <= BLOCK_SIZE, "block is larger than BLOCK_SIZE");
+    let mut out = [0u8; OUTPUT_SIZE];
+    out.copy_from_slice(ring::digest::digest(&SHA256, block).as_ref());
+    out
+}
+
+/// Combine the hashes of consecutive blocks (as calculated by [`block_hash`]) into a Content Hash.
+///
+/// This allows blocks of a file to be hashed separately (for example, in parallel) and the Content
+/// Hash of the whole file to be calculated afterwards.
+pub fn combine_block_hashes<'a>(
+    block_hashes: impl IntoIterator<Item = &'a [u8; OUTPUT_SIZE]>,
+) -> [u8; OUTPUT_SIZE] {
+    let mut ctx = HashContext::new(&SHA256);
+    for hash in block_hashes {
+        ctx.update(hash);
+    }
+    let mut out = [0u8; OUTPUT_SIZE];
+    out.copy_from_slice(ctx.finish().as_ref());
+    out
+}
+
+/// Hashes consecutive blocks of data as it arrives in pieces of any size, keeping the hash of
+/// each block, for when they need to be combined with the hashes of other parts of a file.
+#[cfg_attr(not(feature = "download"), allow(dead_code))]
+pub(crate) struct BlockHasher {
+    block_ctx: HashContext,
+    partial: usize,
+    hashes: Vec<[u8; OUTPUT_SIZE]>,
+}
+
+#[cfg_attr(not(feature = "download"), allow(dead_code))]
+impl BlockHasher {
+    pub fn new() -> Self {
+        Self {
+            block_ctx: HashContext::new(&SHA256),
+            partial: 0,
+            hashes: vec![],
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let (first, rest) = bytes.split_at(bytes.len().min(BLOCK_SIZE - self.partial));
+            self.block_ctx.update(first);
+            self.partial += first.len();
+            if self.partial == BLOCK_SIZE {
+                self.finish_block();
+            }
+            bytes = rest;
+        }
+    }
+
+    /// The hashes of the blocks, including the last one even if it is partial.
+    pub fn finish(mut self) -> Vec<[u8; OUTPUT_SIZE]> {
+        if self.partial != 0 {
+            self.finish_block();
+        }
+        self.hashes
+    }
+
+    fn finish_block(&mut self) {
+        let ctx = std::mem::replace(&mut self.block_ctx, HashContext::new(&SHA256));
+        let mut hash = [0u8; OUTPUT_SIZE];
+        hash.copy_from_slice(ctx.finish().as_ref());
+        self.hashes.push(hash);
+        self.partial = 0;
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
     bytes.iter().fold(String::new(), |mut s, byte| {
         // std::fmt::Write for String does not return errors.
         write!(&mut s, "{:02x}", byte).unwrap();
@@ -240,4 +315,25 @@ mod tests {
             &ctx.finish_hex()
         );
     }
+
+    #[test]
+    fn combined_block_hashes() {
+        let data = vec![30; 2 * BLOCK_SIZE + 5];
+        let hashes = data.chunks(BLOCK_SIZE).map(block_hash).collect::<Vec<_>>();
+        assert_eq!(
+            ContentHash::from(&data).finish(),
+            combine_block_hashes(&hashes)
+        );
+    }
+
+    #[test]
+    fn block_hasher() {
+        let data = vec![30; 2 * BLOCK_SIZE + 5];
+        let mut hasher = BlockHasher::new();
+        hasher.update(&data[..BLOCK_SIZE / 2]);
+        hasher.update(&data[BLOCK_SIZE / 2..2 * BLOCK_SIZE + 1]);
+        hasher.update(&data[2 * BLOCK_SIZE + 1..]);
+        let hashes = data.chunks(BLOCK_SIZE).map(block_hash).collect::<Vec<_>>();
+        assert_eq!(hashes, hasher.finish());
+    }
 }
@@ -0,0 +1,60 @@
+//! Functions for downloading files.
+
+mod aggregate;
+#[cfg(feature = "async")]
+mod async_reader;
+mod chunks;
+mod diff;
+#[cfg(feature = "list")]
+mod dir;
+#[cfg(feature = "unstable")]
+mod document;
+#[cfg(feature = "list")]
+mod export;
+mod links;
+mod parallel;
+mod preview;
+mod public_link;
+mod queue;
+mod range_writer;
+mod reader;
+mod remote_file;
+mod resume;
+mod revisions;
+mod save;
+mod shared_link;
+mod thumbnails;
+mod timeout;
+mod watch;
+mod work;
+mod zip;
+#[cfg(feature = "async")]
+pub use async_reader::{AsyncChunks, AsyncDownloadReader};
+pub use chunks::Chunks;
+pub use diff::{diff_blocks, BlockDiff, DiffSource};
+#[cfg(feature = "list")]
+pub use dir::{download_dir, DownloadDirEntry, DownloadDirOpts};
+#[cfg(feature = "unstable")]
+pub use document::{export_document, ExportedDocument};
+#[cfg(feature = "list")]
+pub use export::{export_folder, export_snapshot, snapshot, ExportOpts, ExportedFile, Snapshot};
+pub use links::{download_temporary_link, temporary_links, LinkOpts, TemporaryLink};
+pub use parallel::{
+    download_to_file, Cancelled, DownloadError, DownloadHashMismatch, DownloadOpts,
+    DownloadProgress, DownloadProgressHandler, FileChanged,
+};
+pub use preview::{preview, Preview};
+pub use public_link::{direct_download_url, download_public_link};
+pub use queue::{
+    DownloadSummary, Downloader, DownloaderEntry, DownloaderFileHandler, DownloaderOpts,
+};
+pub use range_writer::{FileRangeWriter, OrderedWriter, RangeTracker, RangeWriter, TrackingWriter};
+pub use reader::DownloadReader;
+pub use remote_file::{RemoteFile, RemoteFileOpts};
+pub use resume::StreamOpts;
+pub use revisions::download_rev_to_path;
+pub use save::download_to_path;
+pub use shared_link::download_shared_link;
+pub use thumbnails::{thumbnail, thumbnails, Thumbnail, ThumbnailOpts};
+pub use watch::{watch_and_fetch, WatchOpts};
+pub use zip::{download_zip, download_zip_to_writer, ZipOpts};
@@ -0,0 +1,113 @@
+//! Adding up the progress of many files downloading at once.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering::SeqCst};
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::{DownloadOpts, DownloadProgress, DownloadProgressHandler};
+
+/// The progress of all the files in a download of many of them.
+pub(super) struct TotalProgress {
+    start_time: Instant,
+    total_bytes: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    retries: AtomicU32,
+    parallelism: usize,
+    handler: Option<Arc<Box<dyn DownloadProgressHandler>>>,
+
+    /// Whether the size of each file is added to the total as it starts.
+    add_sizes: bool,
+}
+
+impl TotalProgress {
+    /// Start counting the progress of files, reporting it to the given handler. If the total size
+    /// of the files isn't known, give `None`, and it is added up as each file starts.
+    pub fn new(
+        total_bytes: Option<u64>,
+        parallelism: usize,
+        handler: Option<Arc<Box<dyn DownloadProgressHandler>>>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            start_time: Instant::now(),
+            total_bytes: AtomicU64::new(total_bytes.unwrap_or(0)),
+            bytes_downloaded: AtomicU64::new(0),
+            retries: AtomicU32::new(0),
+            parallelism,
+            handler,
+            add_sizes: total_bytes.is_none(),
+        })
+    }
+
+    /// Count a file which didn't need to be downloaded, such as because it was already there, as
+    /// if it had been.
+    #[cfg(feature = "list")]
+    pub fn skip(&self, size: u64) {
+        if self.add_sizes {
+            self.total_bytes.fetch_add(size, SeqCst);
+        }
+        self.add(size, 0, 0.);
+    }
+
+    /// Add to what has been downloaded, and report the total.
+    fn add(&self, len: u64, retries: u32, instant_rate: f64) {
+        let bytes_downloaded = self.bytes_downloaded.fetch_add(len, SeqCst) + len;
+        let retries = self.retries.fetch_add(retries, SeqCst) + retries;
+        if let Some(handler) = &self.handler {
+            handler.progress(&DownloadProgress {
+                bytes_downloaded,
+                total_bytes: self.total_bytes.load(SeqCst),
+                retries,
+                // This assumes all the files are going at about the same rate.
+                instant_rate: instant_rate * self.parallelism as f64,
+                overall_rate: bytes_downloaded as f64 / self.start_time.elapsed().as_secs_f64(),
+            });
+        }
+    }
+}
+
+/// Adds the progress of one file to the [`TotalProgress`].
+struct FileProgress {
+    total: Arc<TotalProgress>,
+    inner: Option<Arc<Box<dyn DownloadProgressHandler>>>,
+
+    /// Whether the size of the file still has to be added to the total.
+    add_size: AtomicBool,
+
+    bytes_downloaded: AtomicU64,
+    retries: AtomicU32,
+}
+
+/// Give the options for downloading one file a progress handler which adds its progress to the
+/// total, as well as reporting it to the handler the options already had, if any.
+pub(super) fn track_file(opts: &DownloadOpts, total: &Arc<TotalProgress>) -> DownloadOpts {
+    let mut opts = opts.clone();
+    opts.progress_handler = Some(Arc::new(Box::new(FileProgress {
+        total: Arc::clone(total),
+        inner: opts.progress_handler.take(),
+        add_size: AtomicBool::new(total.add_sizes),
+        bytes_downloaded: AtomicU64::new(0),
+        retries: AtomicU32::new(0),
+    })));
+    opts
+}
+
+impl DownloadProgressHandler for FileProgress {
+    fn progress(&self, progress: &DownloadProgress) {
+        if let Some(inner) = &self.inner {
+            inner.progress(progress);
+        }
+        let total = &self.total;
+        if self.add_size.swap(false, SeqCst) {
+            total.total_bytes.fetch_add(progress.total_bytes, SeqCst);
+        }
+        // Updates from the ranges of a file can arrive out of order, so only count what's new.
+        let len = progress.bytes_downloaded.saturating_sub(
+            self.bytes_downloaded
+                .fetch_max(progress.bytes_downloaded, SeqCst),
+        );
+        let retries = progress
+            .retries
+            .saturating_sub(self.retries.fetch_max(progress.retries, SeqCst));
+        total.add(len, retries, progress.instant_rate);
+    }
+}
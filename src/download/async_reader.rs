@@ -0,0 +1,473 @@
+//! Downloading a file through [`futures_io::AsyncRead`].
+
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io::{self, Read};
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use dropbox_sdk::client_trait::HttpRequestResult;
+use dropbox_sdk::files::{self, DownloadError};
+use dropbox_sdk::{BoxedError, Error, UserAuthClient};
+use futures_core::Stream;
+use futures_io::AsyncRead;
+
+use super::chunks;
+use super::resume::{Resumable, ResumingReader, StreamOpts};
+use super::timeout::ReadTimeoutClient;
+use crate::path_root;
+use crate::throttle::Throttle;
+
+/// How much the background thread reads at a time.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// How many chunks can be waiting to be read before the background thread has to wait.
+const MAX_QUEUED_CHUNKS: usize = 16;
+
+/// A file being downloaded, which can be read through [`AsyncRead`], so that async code, such as
+/// an HTTP server, can pass it on as it arrives without blocking the executor.
+///
+/// The Dropbox client is blocking, so the requests are made by a background thread, and reads
+/// return [`Poll::Pending`] while it is behind. It reads ahead a little, and then waits for the
+/// data to be read. If a response is cut off, the rest of the file is requested again, from the
+/// same revision, retrying as the options given to [`AsyncDownloadReader::open`] say. If that
+/// fails, reads fail with the error, and then with [`io::ErrorKind::BrokenPipe`].
+///
+/// Dropping the reader stops the download.
+pub struct AsyncDownloadReader {
+    metadata: files::FileMetadata,
+    shared: Arc<Shared>,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+/// State shared between the reader and the background thread.
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// The result of starting the download.
+    opened: Option<Result<files::FileMetadata, BoxedError>>,
+
+    /// Data downloaded, waiting to be read.
+    chunks: VecDeque<Vec<u8>>,
+
+    /// Whether the whole file has been downloaded.
+    done: bool,
+
+    /// The error which stopped the download, until it is returned from a read.
+    error: Option<io::Error>,
+
+    /// Whether the download failed.
+    failed: bool,
+
+    /// Whether the reader has been dropped, so the download should stop.
+    closed: bool,
+
+    /// The limit on how fast to download, if any.
+    throttle: Option<Arc<Throttle>>,
+
+    /// The task waiting for the download to start, or for data.
+    waker: Option<Waker>,
+}
+
+impl Shared {
+    /// Tell the background thread to stop.
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.cond.notify_one();
+    }
+}
+
+/// Closes the download if it is dropped before the reader is made, such as when the future
+/// returned by [`AsyncDownloadReader::open`] is dropped before it's ready.
+struct CloseGuard(Option<Arc<Shared>>);
+
+impl CloseGuard {
+    /// Hand the shared state over to the reader, which closes it itself.
+    fn into_inner(mut self) -> Arc<Shared> {
+        self.0.take().unwrap()
+    }
+}
+
+impl Drop for CloseGuard {
+    fn drop(&mut self) {
+        if let Some(shared) = &self.0 {
+            shared.close();
+        }
+    }
+}
+
+impl State {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl AsyncDownloadReader {
+    /// Start downloading the file at the given path, returning once Dropbox has responded with its
+    /// metadata. Dropping the future before then stops the download.
+    pub async fn open<C: UserAuthClient + Send + Sync + 'static>(
+        client: Arc<C>,
+        path: &str,
+        opts: &StreamOpts,
+    ) -> Result<Self, BoxedError> {
+        let shared = Arc::new(Shared::default());
+        {
+            let shared = Arc::clone(&shared);
+            let path = path.to_owned();
+            let opts = opts.clone();
+            thread::spawn(move || download(client.as_ref(), path, &opts, &shared));
+        }
+        let guard = CloseGuard(Some(Arc::clone(&shared)));
+        let metadata = poll_fn(|cx| {
+            let mut state = shared.state.lock().unwrap();
+            match state.opened.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await?;
+        drop(shared);
+        Ok(Self {
+            metadata,
+            shared: guard.into_inner(),
+            current: vec![],
+            pos: 0,
+        })
+    }
+
+    /// The metadata of the revision being downloaded, such as its size, for a `Content-Length`
+    /// header.
+    pub fn metadata(&self) -> &files::FileMetadata {
+        &self.metadata
+    }
+
+    /// The revision being downloaded, as with [`DownloadReader::rev`](super::DownloadReader::rev).
+    pub fn rev(&self) -> &str {
+        &self.metadata.rev
+    }
+
+    /// The Content Hash of the revision being downloaded, as with
+    /// [`DownloadReader::content_hash`](super::DownloadReader::content_hash).
+    pub fn content_hash(&self) -> Option<&str> {
+        self.metadata.content_hash.as_deref()
+    }
+
+    /// Limit the download to the given number of bytes per second, as with
+    /// [`max_bytes_per_sec`](super::DownloadOpts::max_bytes_per_sec), so that it can run in the
+    /// background without starving other traffic. The background thread waits before queuing
+    /// what it has read, so reads return [`Poll::Pending`] instead of blocking.
+    pub fn with_max_bytes_per_sec(self, max_bytes_per_sec: u64) -> Self {
+        self.shared.state.lock().unwrap().throttle =
+            Some(Arc::new(Throttle::new(max_bytes_per_sec)));
+        self
+    }
+
+    /// Read the rest of the file as a stream of owned chunks of the given size, such as to send to
+    /// another task.
+    pub fn chunks(self, size: usize) -> AsyncChunks {
+        AsyncChunks {
+            reader: self,
+            size,
+            chunk: vec![],
+            filled: 0,
+            done: false,
+        }
+    }
+}
+
+impl AsyncRead for AsyncDownloadReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.pos == this.current.len() {
+            let mut state = this.shared.state.lock().unwrap();
+            match state.chunks.pop_front() {
+                Some(chunk) => {
+                    this.shared.cond.notify_one();
+                    this.current = chunk;
+                    this.pos = 0;
+                }
+                None if state.failed => {
+                    let e = state.error.take().unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::BrokenPipe, "download failed")
+                    });
+                    return Poll::Ready(Err(e));
+                }
+                None if state.done => return Poll::Ready(Ok(0)),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+        let n = buf.len().min(this.current.len() - this.pos);
+        buf[..n].copy_from_slice(&this.current[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl Drop for AsyncDownloadReader {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+/// A stream of a file in chunks of a fixed size, returned by [`AsyncDownloadReader::chunks`].
+///
+/// Each chunk is the full size, except for the last one, which is whatever is left. After an
+/// error, the stream ends. A size of zero is an invalid argument, so the only item is an
+/// [`io::ErrorKind::InvalidInput`] error.
+pub struct AsyncChunks {
+    reader: AsyncDownloadReader,
+    size: usize,
+    chunk: Vec<u8>,
+    filled: usize,
+    done: bool,
+}
+
+impl Stream for AsyncChunks {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        if this.size == 0 {
+            this.done = true;
+            return Poll::Ready(Some(Err(chunks::zero_size())));
+        }
+        if this.chunk.is_empty() {
+            this.chunk = vec![0; this.size];
+        }
+        while this.filled < this.size {
+            let buf = &mut this.chunk[this.filled..];
+            match Pin::new(&mut this.reader).poll_read(cx, buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => {
+                    this.done = true;
+                    break;
+                }
+                Poll::Ready(Ok(n)) => this.filled += n,
+                Poll::Ready(Err(e)) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+        if this.filled == 0 {
+            return Poll::Ready(None);
+        }
+        let mut chunk = mem::take(&mut this.chunk);
+        chunk.truncate(this.filled);
+        this.filled = 0;
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+/// Download the file, on the background thread.
+fn download(client: &impl UserAuthClient, path: String, opts: &StreamOpts, shared: &Shared) {
+    let request = DownloadRequest {
+        client: ReadTimeoutClient::new(client, opts.watchdog()),
+        arg: files::DownloadArg::new(path),
+    };
+    let (metadata, mut reader) = match ResumingReader::open(request, &opts.retry) {
+        Ok(opened) => opened,
+        Err(e) => {
+            let mut state = shared.state.lock().unwrap();
+            state.opened = Some(Err(path_root::boxed(e)));
+            state.wake();
+            return;
+        }
+    };
+    // Continue from the same revision, if the download is cut off.
+    reader.source_mut().arg = files::DownloadArg::new(format!("rev:{}", metadata.rev));
+    {
+        let mut state = shared.state.lock().unwrap();
+        state.opened = Some(Ok(metadata));
+        state.wake();
+    }
+
+    loop {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let result = reader.read(&mut chunk);
+        if let Ok(n) = result {
+            let throttle = shared.state.lock().unwrap().throttle.clone();
+            if let Some(throttle) = throttle {
+                throttle.acquire(n as u64);
+            }
+        }
+        let mut state = shared.state.lock().unwrap();
+        match result {
+            Ok(0) => state.done = true,
+            Ok(n) => {
+                chunk.truncate(n);
+                while state.chunks.len() >= MAX_QUEUED_CHUNKS && !state.closed {
+                    state = shared.cond.wait(state).unwrap();
+                }
+                if state.closed {
+                    return;
+                }
+                state.chunks.push_back(chunk);
+            }
+            Err(e) => {
+                state.error = Some(e);
+                state.failed = true;
+            }
+        }
+        state.wake();
+        if state.done || state.failed {
+            return;
+        }
+    }
+}
+
+struct DownloadRequest<'a, C> {
+    client: ReadTimeoutClient<'a, C>,
+    arg: files::DownloadArg,
+}
+
+impl<C: UserAuthClient> Resumable for DownloadRequest<'_, C> {
+    type Result = files::FileMetadata;
+    type Error = DownloadError;
+    const WHAT: &'static str = "download";
+
+    fn request(
+        &self,
+        offset: u64,
+    ) -> Result<HttpRequestResult<files::FileMetadata>, Error<DownloadError>> {
+        files::download(
+            &self.client,
+            &self.arg,
+            (offset > 0).then_some(offset),
+            None,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::RetryOpts;
+    use crate::testing::tests::{block_on, file_json, MockClient};
+    use crate::testing::FaultInjector;
+    use std::future::Future;
+    use std::time::Duration;
+
+    async fn read_to_end(reader: &mut AsyncDownloadReader) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        let mut buf = [0; 4];
+        loop {
+            let n = poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut buf)).await?;
+            if n == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn reads_and_resumes() {
+        let file = file_json("/f", 11);
+        let client = Arc::new(
+            FaultInjector::new(
+                MockClient::new("null")
+                    .then_download(file, "hello world")
+                    // Dropbox only sends the rest.
+                    .then_download(file, "world"),
+            )
+            .truncate_body_every("files/download", 1, 6),
+        );
+        let opts = StreamOpts {
+            retry: RetryOpts {
+                initial_backoff_time: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (size, data) = block_on(async {
+            let mut reader = AsyncDownloadReader::open(client.clone(), "/f", &opts).await?;
+            let data = read_to_end(&mut reader)
+                .await
+                .map_err(|e| Error::HttpClient(e.into()))?;
+            assert_eq!("aaaaaaaaa", reader.rev());
+            Ok::<_, BoxedError>((reader.metadata().size, data))
+        })
+        .unwrap();
+        assert_eq!(11, size);
+        assert_eq!(b"hello world", data.as_slice());
+        assert_eq!(2, client.inner().urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn chunks() {
+        let file = file_json("/f", 11);
+        let client = Arc::new(MockClient::new("null").then_download(file, "hello world"));
+        let chunks = block_on(async {
+            let reader = AsyncDownloadReader::open(client, "/f", &StreamOpts::default()).await?;
+            let mut chunks = reader.chunks(4);
+            let mut all = vec![];
+            while let Some(chunk) = poll_fn(|cx| Pin::new(&mut chunks).poll_next(cx)).await {
+                all.push(chunk.map_err(|e| Error::HttpClient(e.into()))?);
+            }
+            Ok::<_, BoxedError>(all)
+        })
+        .unwrap();
+        assert_eq!(
+            vec![b"hell".to_vec(), b"o wo".to_vec(), b"rld".to_vec()],
+            chunks
+        );
+    }
+
+    #[test]
+    fn dropping_open_stops_download() {
+        // More than the background thread queues up before waiting for it to be read.
+        let body = "x".repeat(CHUNK_SIZE * (MAX_QUEUED_CHUNKS + 2)).leak();
+        let client = Arc::new(MockClient::new("null").then_download(file_json("/f", 0), body));
+        {
+            let opts = StreamOpts::default();
+            let future = AsyncDownloadReader::open(Arc::clone(&client), "/f", &opts);
+            let mut future = std::pin::pin!(future);
+            let mut cx = Context::from_waker(Waker::noop());
+            let _ = future.as_mut().poll(&mut cx);
+        }
+        // The background thread lets go of the client when it stops.
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while Arc::strong_count(&client) > 1 {
+            assert!(std::time::Instant::now() < deadline, "download didn't stop");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn open_fails() {
+        let client = Arc::new(MockClient::new("null").then(
+            409,
+            r#"{"error": {".tag": "path", "path": {".tag": "not_found"}}}"#,
+        ));
+        let result = block_on(AsyncDownloadReader::open(
+            client,
+            "/f",
+            &StreamOpts::default(),
+        ));
+        assert!(matches!(result, Err(Error::Api(_))));
+    }
+}
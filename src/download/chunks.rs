@@ -0,0 +1,103 @@
+//! Reading downloads as a series of owned chunks, to pass on to other threads or tasks.
+
+use std::io::{self, Read};
+
+/// An iterator over a download in chunks of a fixed size, returned by
+/// [`Preview::chunks`](super::Preview::chunks), and `ExportedDocument::chunks` with the `unstable`
+/// feature.
+///
+/// Each chunk is the full size, except for the last one, which is whatever is left. After an
+/// error, the iterator ends. A size of zero is an invalid argument, so the only item is an
+/// [`io::ErrorKind::InvalidInput`] error.
+pub struct Chunks<R> {
+    reader: R,
+    size: usize,
+    done: bool,
+}
+
+impl<R> Chunks<R> {
+    pub(super) fn new(reader: R, size: usize) -> Self {
+        Self {
+            reader,
+            size,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for Chunks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.size == 0 {
+            self.done = true;
+            return Some(Err(zero_size()));
+        }
+        let mut chunk = vec![0; self.size];
+        let mut filled = 0;
+        while filled < self.size {
+            match self.reader.read(&mut chunk[filled..]) {
+                Ok(0) => {
+                    self.done = true;
+                    break;
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        if filled == 0 {
+            return None;
+        }
+        chunk.truncate(filled);
+        Some(Ok(chunk))
+    }
+}
+
+/// The error for asking for chunks of no bytes.
+pub(super) fn zero_size() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "chunk size must not be zero")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reader which returns at most two bytes at a time.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(2);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn full_chunks() {
+        let chunks = Chunks::new(Trickle(b"abcdefg"), 3)
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            vec![b"abc".to_vec(), b"def".to_vec(), b"g".to_vec()],
+            chunks
+        );
+        assert_eq!(0, Chunks::new(Trickle(b""), 3).count());
+    }
+
+    #[test]
+    fn zero_size() {
+        let mut chunks = Chunks::new(Trickle(b"abc"), 0);
+        let err = chunks.next().unwrap().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(chunks.next().is_none());
+    }
+}
@@ -0,0 +1,276 @@
+//! Finding which blocks of two large files differ.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::Path;
+
+use dropbox_sdk::files::{self, DownloadError};
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use crate::content_hash::{self, OUTPUT_SIZE};
+use crate::limits::EndpointKind;
+use crate::path_root;
+use crate::retry::{Backoff, RetryOpts};
+use crate::BLOCK_SIZE;
+
+/// One of the files to compare with [`diff_blocks`].
+#[derive(Debug, Clone, Copy)]
+pub enum DiffSource<'a> {
+    /// A file in Dropbox, at the given path.
+    Remote(&'a str),
+
+    /// A local file.
+    Local(&'a Path),
+}
+
+/// The result of [`diff_blocks`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDiff {
+    /// The length of the first file.
+    pub len_a: u64,
+
+    /// The length of the second file.
+    pub len_b: u64,
+
+    /// The regions which differ between the files, in increasing order. Each is a whole number of
+    /// [`BLOCK_SIZE`] blocks, except at the end of the longer file, and adjacent differing blocks
+    /// are merged into one region. If one file is longer, the part past the end of the shorter one
+    /// is included.
+    pub differing: Vec<Range<u64>>,
+}
+
+impl BlockDiff {
+    /// Whether the files are identical.
+    pub fn is_identical(&self) -> bool {
+        self.len_a == self.len_b && self.differing.is_empty()
+    }
+}
+
+/// Compare two files block by block, and report which [`BLOCK_SIZE`] regions differ, for
+/// diagnosing how two large files have diverged.
+///
+/// First, the Content Hashes of the files are compared, which for remote files comes from their
+/// metadata; if they match, nothing needs to be downloaded. Otherwise, Dropbox doesn't provide the
+/// hashes of the individual blocks of a file, so each block of a remote file is downloaded in turn
+/// to hash it, and then discarded, so only one block is held in memory at a time and nothing is
+/// written to disk.
+///
+/// Remote files are compared at the revision they were at when this started, even if they change
+/// partway through.
+pub fn diff_blocks<C: UserAuthClient>(
+    client: &C,
+    a: DiffSource<'_>,
+    b: DiffSource<'_>,
+    retry: &RetryOpts,
+) -> Result<BlockDiff, BoxedError> {
+    let mut a = Side::open(client, a)?;
+    let mut b = Side::open(client, b)?;
+    let mut differing = Vec::<Range<u64>>::new();
+    if a.content_hash.is_some() && a.content_hash == b.content_hash {
+        debug!("Content Hashes match; files are identical");
+    } else {
+        let len = a.len.max(b.len);
+        for start in (0..len).step_by(BLOCK_SIZE) {
+            let block_a = a.block_hash(client, start, retry)?;
+            let block_b = b.block_hash(client, start, retry)?;
+            if block_a != block_b {
+                let end = (start + BLOCK_SIZE as u64).min(len);
+                match differing.last_mut() {
+                    Some(prev) if prev.end == start => prev.end = end,
+                    _ => differing.push(start..end),
+                }
+            }
+        }
+    }
+    Ok(BlockDiff {
+        len_a: a.len,
+        len_b: b.len,
+        differing,
+    })
+}
+
+/// One of the files being compared.
+struct Side<'a> {
+    len: u64,
+    content_hash: Option<String>,
+    source: SideSource<'a>,
+}
+
+enum SideSource<'a> {
+    /// The path and revision of a remote file.
+    Remote(&'a str, String),
+
+    /// The hashes of all the blocks of a local file.
+    Local(Vec<[u8; OUTPUT_SIZE]>),
+}
+
+impl<'a> Side<'a> {
+    fn open(client: &impl UserAuthClient, source: DiffSource<'a>) -> Result<Self, BoxedError> {
+        match source {
+            DiffSource::Remote(path) => {
+                let arg = files::GetMetadataArg::new(path.to_owned());
+                match files::get_metadata(client, &arg).map_err(path_root::boxed)? {
+                    files::Metadata::File(metadata) => Ok(Self {
+                        len: metadata.size,
+                        content_hash: metadata.content_hash,
+                        source: SideSource::Remote(path, metadata.rev),
+                    }),
+                    _ => Err(Error::UnexpectedResponse(format!("{path} is not a file"))),
+                }
+            }
+            DiffSource::Local(path) => {
+                let (len, hashes) = File::open(path)
+                    .and_then(local_block_hashes)
+                    .map_err(|e| Error::HttpClient(e.into()))?;
+                let content_hash = content_hash::combine_block_hashes(&hashes);
+                Ok(Self {
+                    len,
+                    content_hash: Some(content_hash::hex(&content_hash)),
+                    source: SideSource::Local(hashes),
+                })
+            }
+        }
+    }
+
+    /// Get the hash of the block starting at the given offset, or `None` if the file is shorter.
+    fn block_hash(
+        &mut self,
+        client: &impl UserAuthClient,
+        start: u64,
+        retry: &RetryOpts,
+    ) -> Result<Option<[u8; OUTPUT_SIZE]>, BoxedError> {
+        if start >= self.len {
+            return Ok(None);
+        }
+        match &self.source {
+            SideSource::Local(hashes) => {
+                Ok(hashes.get((start / BLOCK_SIZE as u64) as usize).copied())
+            }
+            SideSource::Remote(path, rev) => {
+                let end = (start + BLOCK_SIZE as u64).min(self.len);
+                trace!("downloading {path} bytes {start}..{end}");
+                let block = download_range(client, rev, start..end, retry)?;
+                Ok(Some(content_hash::block_hash(&block)))
+            }
+        }
+    }
+}
+
+/// Read a local file and hash each of its blocks.
+fn local_block_hashes(mut file: File) -> io::Result<(u64, Vec<[u8; OUTPUT_SIZE]>)> {
+    let mut buf = vec![0u8; BLOCK_SIZE];
+    let mut len = 0;
+    let mut hashes = vec![];
+    loop {
+        let mut filled = 0;
+        while filled < BLOCK_SIZE {
+            match file.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        if filled == 0 {
+            return Ok((len, hashes));
+        }
+        len += filled as u64;
+        hashes.push(content_hash::block_hash(&buf[..filled]));
+    }
+}
+
+/// Download the given range of the given revision of a file.
+fn download_range(
+    client: &impl UserAuthClient,
+    rev: &str,
+    range: Range<u64>,
+    retry: &RetryOpts,
+) -> Result<Vec<u8>, BoxedError> {
+    let arg = files::DownloadArg::new(format!("rev:{rev}"));
+    let mut backoff = Backoff::new(retry).for_endpoint(EndpointKind::Content);
+    loop {
+        backoff.wait();
+        // The end of an HTTP range is inclusive.
+        let result = files::download(client, &arg, Some(range.start), Some(range.end - 1))
+            .and_then(|response| {
+                let mut body = response.body.ok_or_else(|| {
+                    Error::UnexpectedResponse("download response has no body".to_owned())
+                })?;
+                let mut data = Vec::with_capacity((range.end - range.start) as usize);
+                body.read_to_end(&mut data)
+                    .map_err(|e| Error::<DownloadError>::HttpClient(e.into()))?;
+                Ok(data)
+            });
+        match result {
+            Ok(data) if data.len() as u64 == range.end - range.start => return Ok(data),
+            Ok(data) => {
+                let e = Error::<DownloadError>::UnexpectedResponse(format!(
+                    "requested {} bytes, but got {}",
+                    range.end - range.start,
+                    data.len()
+                ));
+                backoff
+                    .handle("downloading block", e)
+                    .map_err(Error::boxed)?;
+            }
+            Err(e @ Error::Api(_)) => return Err(path_root::boxed(e)),
+            Err(e) => backoff
+                .handle("downloading block", e)
+                .map_err(path_root::boxed)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content_hash::ContentHash;
+    use crate::testing::tests::MockClient;
+    use std::fs;
+
+    fn remote_file(size: usize, content_hash: &str) -> &'static str {
+        format!(
+            r#"{{".tag": "file", "name": "f", "id": "id:f", "size": {size},
+            "client_modified": "2024-01-01T00:00:00Z", "server_modified": "2024-01-01T00:00:00Z",
+            "rev": "aaaaaaaaa", "content_hash": "{content_hash}"}}"#
+        )
+        .leak()
+    }
+
+    #[test]
+    fn local_and_remote() {
+        let local_path = std::env::temp_dir().join(format!("diff_test_{}", std::process::id()));
+        fs::write(&local_path, "hello").unwrap();
+        let hash = ContentHash::from("hello").finish_hex();
+
+        // Matching Content Hashes: nothing to download.
+        let client = MockClient::new("null").then(200, remote_file(5, &hash));
+        let diff = diff_blocks(
+            &client,
+            DiffSource::Local(&local_path),
+            DiffSource::Remote("/f"),
+            &RetryOpts::default(),
+        )
+        .unwrap();
+        assert!(diff.is_identical());
+        assert_eq!(1, client.urls.lock().unwrap().len());
+
+        // Different contents and lengths.
+        let metadata = remote_file(6, "0000");
+        let client = MockClient::new("null")
+            .then(200, metadata)
+            .then_download(metadata, "hellox");
+        let diff = diff_blocks(
+            &client,
+            DiffSource::Local(&local_path),
+            DiffSource::Remote("/f"),
+            &RetryOpts::default(),
+        )
+        .unwrap();
+        fs::remove_file(&local_path).unwrap();
+        assert_eq!((5, 6), (diff.len_a, diff.len_b));
+        assert_eq!(vec![0..6], diff.differing);
+    }
+}
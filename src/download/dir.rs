@@ -0,0 +1,209 @@
+//! Downloading whole folders.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use dropbox_sdk::files;
+use dropbox_sdk::BoxedError;
+use dropbox_sdk::UserAuthClient;
+
+use super::aggregate::{track_file, TotalProgress};
+use super::save::{save_revision, Saved};
+use super::work::try_parallel_map;
+use super::{snapshot, DownloadError, DownloadOpts, DownloadProgressHandler};
+
+/// Options for downloading a Dropbox folder with [`download_dir`].
+#[derive(Clone)]
+pub struct DownloadDirOpts {
+    /// Options for downloading each file. Its progress handler, if any, is called for each file
+    /// separately.
+    pub file: DownloadOpts,
+
+    /// How many files to download at once. Each of them uses the
+    /// [`parallelism`](DownloadOpts::parallelism) of the file options.
+    pub parallelism: usize,
+
+    /// An optional callback to periodically receive progress updates for the whole folder.
+    pub progress_handler: Option<Arc<Box<dyn DownloadProgressHandler>>>,
+}
+
+impl Default for DownloadDirOpts {
+    fn default() -> Self {
+        Self {
+            file: DownloadOpts::default(),
+            parallelism: 4,
+            progress_handler: None,
+        }
+    }
+}
+
+/// A file downloaded by [`download_dir`].
+#[derive(Debug, Clone)]
+pub struct DownloadDirEntry {
+    /// The Dropbox path it was downloaded from.
+    pub source_path: String,
+
+    /// The local path it was written to.
+    pub dest_path: PathBuf,
+
+    /// The revision which was downloaded.
+    pub metadata: files::FileMetadata,
+
+    /// Whether the local file was already the same, so it wasn't downloaded, with
+    /// [`skip_if_identical`](DownloadOpts::skip_if_identical).
+    pub skipped: bool,
+}
+
+/// Download all the files in a Dropbox folder and its subfolders to the given local directory.
+///
+/// The folder is listed first, and then the files are downloaded,
+/// [`parallelism`](DownloadDirOpts::parallelism) at a time, each as with
+/// [`download_to_path`](super::download_to_path) and at the revision it was listed at. Folders are
+/// created even if they are empty. Progress is reported for all the files together, against the
+/// total size of the folder, and any files [skipped](DownloadOpts::skip_if_identical) count as
+/// downloaded.
+///
+/// This stops at the first error, after the files already being downloaded finish. The results
+/// are in the order the files were listed.
+pub fn download_dir<C: UserAuthClient + Sync>(
+    client: &C,
+    source_dir: &str,
+    dest_dir: &Path,
+    opts: &DownloadDirOpts,
+) -> Result<Vec<DownloadDirEntry>, DownloadError> {
+    let snapshot = snapshot(client, source_dir)?;
+    fs::create_dir_all(dest_dir).map_err(|e| BoxedError::HttpClient(e.into()))?;
+    for folder in &snapshot.folders {
+        fs::create_dir_all(dest_dir.join(folder)).map_err(|e| BoxedError::HttpClient(e.into()))?;
+    }
+
+    let progress = TotalProgress::new(
+        Some(snapshot.total_bytes()),
+        opts.parallelism.max(1),
+        opts.progress_handler.clone(),
+    );
+
+    try_parallel_map(&snapshot.files, opts.parallelism, |(relative, metadata)| {
+        let dest_path = dest_dir.join(relative);
+        let saved = download_file(client, metadata, &dest_path, opts, &progress)?;
+        Ok(DownloadDirEntry {
+            source_path: format!("{}/{relative}", source_dir.trim_end_matches('/')),
+            dest_path,
+            metadata: saved.metadata,
+            skipped: saved.skipped,
+        })
+    })
+}
+
+fn download_file(
+    client: &(impl UserAuthClient + Sync),
+    metadata: &files::FileMetadata,
+    dest_path: &Path,
+    opts: &DownloadDirOpts,
+    progress: &Arc<TotalProgress>,
+) -> Result<Saved, DownloadError> {
+    if let Some(parent) = dest_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| BoxedError::HttpClient(e.into()))?;
+    }
+    info!(
+        "Downloading {} at revision {}",
+        metadata.path_display.as_deref().unwrap_or(&metadata.name),
+        metadata.rev
+    );
+    let file_opts = track_file(&opts.file, progress);
+    let saved = save_revision(client, metadata.clone(), dest_path, &file_opts)?;
+    if saved.skipped {
+        progress.skip(metadata.size);
+    }
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content_hash::ContentHash;
+    use crate::testing::tests::{file_json, file_json_with, MockClient, ProgressRecorder};
+
+    #[test]
+    fn downloads_folder() {
+        let file_a = file_json("/Dir/a", 2);
+        let file_b = file_json_with("/Dir/sub/b", 3, "bbbbbbbbb", None);
+        let client = MockClient::new("null")
+            .then(
+                200,
+                r#"{"entries": [
+                    {".tag": "folder", "name": "Dir", "id": "id:d", "path_display": "/Dir"},
+                    {".tag": "folder", "name": "sub", "id": "id:s", "path_display": "/Dir/sub"},
+                    {".tag": "folder", "name": "empty", "id": "id:e", "path_display": "/Dir/empty"},
+                    {".tag": "file", "name": "a", "id": "id:a", "size": 2,
+                     "client_modified": "2024-01-01T00:00:00Z",
+                     "server_modified": "2024-01-01T00:00:00Z",
+                     "rev": "aaaaaaaaa", "path_display": "/Dir/a"},
+                    {".tag": "file", "name": "b", "id": "id:b", "size": 3,
+                     "client_modified": "2024-01-01T00:00:00Z",
+                     "server_modified": "2024-01-01T00:00:00Z",
+                     "rev": "bbbbbbbbb", "path_display": "/Dir/sub/b"}
+                ], "cursor": "cursor", "has_more": false}"#,
+            )
+            .then_download(file_a, "aa")
+            .then_download(file_b, "bbb");
+        let dest_dir = std::env::temp_dir().join(format!("dir_test_{}", std::process::id()));
+        let recorder = Arc::new(ProgressRecorder::default());
+        let opts = DownloadDirOpts {
+            parallelism: 1,
+            progress_handler: Some(Arc::new(Box::new(Arc::clone(&recorder)))),
+            ..Default::default()
+        };
+
+        let entries = download_dir(&client, "/Dir", &dest_dir, &opts).unwrap();
+        let a = fs::read_to_string(dest_dir.join("a")).unwrap();
+        let b = fs::read_to_string(dest_dir.join("sub/b")).unwrap();
+        let empty_exists = dest_dir.join("empty").is_dir();
+        fs::remove_dir_all(&dest_dir).unwrap();
+
+        assert_eq!(2, entries.len());
+        assert_eq!("/Dir/a", entries[0].source_path);
+        assert_eq!(dest_dir.join("sub/b"), entries[1].dest_path);
+        assert_eq!("bbbbbbbbb", entries[1].metadata.rev);
+        assert_eq!("aa", a);
+        assert_eq!("bbb", b);
+        assert!(empty_exists);
+        assert_eq!(vec![(2, 5, 0), (5, 5, 0)], recorder.updates());
+    }
+
+    #[test]
+    fn counts_skipped_files() {
+        let hash = ContentHash::from("aa").finish_hex();
+        let listing = format!(
+            r#"{{"entries": [
+                {{".tag": "folder", "name": "Dir", "id": "id:d", "path_display": "/Dir"}},
+                {{".tag": "file", "name": "a", "id": "id:a", "size": 2,
+                 "client_modified": "2024-01-01T00:00:00Z",
+                 "server_modified": "2024-01-01T00:00:00Z",
+                 "rev": "aaaaaaaaa", "path_display": "/Dir/a", "content_hash": "{hash}"}}
+            ], "cursor": "cursor", "has_more": false}}"#
+        );
+        let client = MockClient::new("null").then(200, listing.leak());
+        let dest_dir = std::env::temp_dir().join(format!("dir_skip_test_{}", std::process::id()));
+        fs::create_dir_all(&dest_dir).unwrap();
+        fs::write(dest_dir.join("a"), "aa").unwrap();
+        let recorder = Arc::new(ProgressRecorder::default());
+        let opts = DownloadDirOpts {
+            file: DownloadOpts {
+                skip_if_identical: true,
+                ..Default::default()
+            },
+            progress_handler: Some(Arc::new(Box::new(Arc::clone(&recorder)))),
+            ..Default::default()
+        };
+
+        let entries = download_dir(&client, "/Dir", &dest_dir, &opts).unwrap();
+        fs::remove_dir_all(&dest_dir).unwrap();
+
+        assert!(entries[0].skipped);
+        assert_eq!(vec![(2, 2, 0)], recorder.updates());
+        // The file isn't looked up again, only listed.
+        assert_eq!(1, client.urls.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,179 @@
+//! Exporting documents which can't be downloaded directly, such as Paper docs.
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::Arc;
+
+use dropbox_sdk::client_trait::HttpRequestResult;
+use dropbox_sdk::files::{self, ExportError};
+use dropbox_sdk::{Error, UserAuthClient};
+
+use super::chunks::Chunks;
+use super::resume::{Resumable, ResumingReader, StreamOpts, BUFFER_SIZE};
+use super::timeout::ReadTimeoutClient;
+use super::DownloadProgressHandler;
+
+/// A document exported by [`export_document`], which can be read like a file.
+///
+/// If reading it fails partway through, the rest of it is requested again, retrying as the
+/// options given to [`export_document`] say, and reading only fails if that does. It is
+/// buffered, so it can be read a line at a time with [`BufRead`].
+pub struct ExportedDocument<'a, C> {
+    /// The metadata of the document in Dropbox.
+    pub metadata: files::FileMetadata,
+
+    /// The metadata of the exported file, including its name and size.
+    pub export_metadata: files::ExportMetadata,
+
+    reader: BufReader<ResumingReader<ExportRequest<'a, C>>>,
+}
+
+/// Export a document which can't be downloaded directly, such as a Paper doc or a Google Doc, as
+/// a stream which can be read.
+///
+/// Such files have [`export_info`](files::FileMetadata::export_info) in their metadata, which
+/// gives the format they are exported as by default, and any other formats they can be exported
+/// as, which can be given here instead. Other files fail with [`ExportError::NonExportable`], and
+/// should be downloaded instead.
+///
+/// While Dropbox is still getting the exported document ready, the request is retried.
+pub fn export_document<'a, C: UserAuthClient>(
+    client: &'a C,
+    path: &str,
+    format: Option<&str>,
+    opts: &StreamOpts,
+) -> Result<ExportedDocument<'a, C>, Error<ExportError>> {
+    let arg = |path: String| {
+        let arg = files::ExportArg::new(path);
+        match format {
+            Some(format) => arg.with_export_format(format.to_owned()),
+            None => arg,
+        }
+    };
+    let request = ExportRequest {
+        client: ReadTimeoutClient::new(client, opts.watchdog()),
+        arg: arg(path.to_owned()),
+    };
+    let (result, mut reader) = ResumingReader::open(request, &opts.retry)?;
+    // Continue from the same revision, if reading is cut off.
+    reader.source_mut().arg = arg(format!("rev:{}", result.file_metadata.rev));
+    Ok(ExportedDocument {
+        metadata: result.file_metadata,
+        export_metadata: result.export_metadata,
+        reader: BufReader::with_capacity(BUFFER_SIZE, reader),
+    })
+}
+
+impl<'a, C: UserAuthClient> ExportedDocument<'a, C> {
+    /// Report the progress of reading the document to the given handler, as it is read.
+    pub fn with_progress_handler(mut self, handler: Arc<Box<dyn DownloadProgressHandler>>) -> Self {
+        self.reader.get_mut().set_progress_handler(handler);
+        self
+    }
+
+    /// Limit reading the document to the given number of bytes per second, as with
+    /// [`max_bytes_per_sec`](super::DownloadOpts::max_bytes_per_sec), so that it can be read in
+    /// the background without starving other traffic.
+    pub fn with_max_bytes_per_sec(mut self, max_bytes_per_sec: u64) -> Self {
+        self.reader
+            .get_mut()
+            .set_max_bytes_per_sec(max_bytes_per_sec);
+        self
+    }
+
+    /// Read the rest of the document in owned chunks of the given size, such as to send to another
+    /// thread.
+    pub fn chunks(self, size: usize) -> Chunks<Self> {
+        Chunks::new(self, size)
+    }
+}
+
+impl<C: UserAuthClient> Read for ExportedDocument<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<C: UserAuthClient> BufRead for ExportedDocument<'_, C> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt)
+    }
+}
+
+struct ExportRequest<'a, C> {
+    client: ReadTimeoutClient<'a, C>,
+    arg: files::ExportArg,
+}
+
+impl<C: UserAuthClient> Resumable for ExportRequest<'_, C> {
+    type Result = files::ExportResult;
+    type Error = ExportError;
+    const WHAT: &'static str = "export";
+
+    fn request(
+        &self,
+        offset: u64,
+    ) -> Result<HttpRequestResult<files::ExportResult>, Error<ExportError>> {
+        files::export(
+            &self.client,
+            &self.arg,
+            (offset > 0).then_some(offset),
+            None,
+        )
+    }
+
+    fn is_temporary(error: &ExportError) -> bool {
+        // The exported document isn't ready yet.
+        matches!(error, ExportError::RetryError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::RetryOpts;
+    use crate::testing::tests::MockClient;
+    use std::time::Duration;
+
+    const RESULT: &str = r#"{"export_metadata": {"name": "doc.md", "size": 7},
+        "file_metadata": {".tag": "file", "name": "doc.paper", "id": "id:d", "size": 0,
+            "client_modified": "2024-01-01T00:00:00Z",
+            "server_modified": "2024-01-01T00:00:00Z",
+            "rev": "aaaaaaaaa", "path_display": "/doc.paper",
+            "export_info": {"export_as": "html", "export_options": ["html", "markdown"]}}}"#;
+
+    #[test]
+    fn retries_until_ready() {
+        let client = MockClient::new("null")
+            .then(409, r#"{"error": {".tag": "retry_error"}}"#)
+            .then_download(RESULT, "# Title");
+        let opts = StreamOpts {
+            retry: RetryOpts {
+                initial_backoff_time: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut doc = export_document(&client, "/doc.paper", Some("markdown"), &opts).unwrap();
+        let mut data = String::new();
+        doc.read_to_string(&mut data).unwrap();
+        assert_eq!("id:d", doc.metadata.id);
+        assert_eq!("doc.md", doc.export_metadata.name);
+        assert_eq!("# Title", data);
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn non_exportable() {
+        let client = MockClient::new("null").then(409, r#"{"error": {".tag": "non_exportable"}}"#);
+        let result = export_document(&client, "/f.txt", None, &StreamOpts::default());
+        assert!(matches!(
+            result,
+            Err(Error::Api(ExportError::NonExportable))
+        ));
+    }
+}
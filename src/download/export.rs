@@ -0,0 +1,243 @@
+//! Exporting a Dropbox folder as a consistent snapshot.
+
+use std::fs::{self, File};
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use dropbox_sdk::files::{self, DownloadError};
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use super::watch::temp_path;
+use crate::limits::EndpointKind;
+use crate::list::visit_directory;
+use crate::path_root;
+use crate::retry::{Backoff, RetryOpts};
+use crate::scheduler::TransferScheduler;
+
+/// The contents of a Dropbox folder at one point in time, from [`snapshot`].
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The path of the folder.
+    pub path: String,
+
+    /// The paths of the subfolders, relative to the folder, parents first.
+    pub folders: Vec<String>,
+
+    /// The files, with their paths relative to the folder, and the revisions they were at.
+    pub files: Vec<(String, files::FileMetadata)>,
+}
+
+impl Snapshot {
+    /// The total size of the files.
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter().map(|(_, file)| file.size).sum()
+    }
+}
+
+/// Options for [`export_snapshot`] and [`export_folder`].
+#[derive(Debug, Clone, Default)]
+pub struct ExportOpts {
+    /// How to retry failed requests.
+    pub retry: RetryOpts,
+
+    /// A scheduler to share with other uploads and downloads, to limit how many requests they
+    /// make at once in total.
+    pub scheduler: Option<Arc<TransferScheduler>>,
+}
+
+/// A file written by [`export_snapshot`].
+#[derive(Debug, Clone)]
+pub struct ExportedFile {
+    /// Where it was written.
+    pub local_path: PathBuf,
+
+    /// The revision which was downloaded.
+    pub metadata: files::FileMetadata,
+}
+
+/// List a Dropbox folder recursively, recording the revision each file is at.
+pub fn snapshot(client: &impl UserAuthClient, path: &str) -> Result<Snapshot, BoxedError> {
+    let depth = components(path).count();
+    let mut snapshot = Snapshot {
+        path: path.to_owned(),
+        folders: vec![],
+        files: vec![],
+    };
+    visit_directory(client, path, true, |entry| {
+        let (path_display, file) = match entry {
+            files::Metadata::File(file) => (file.path_display.clone(), Some(file)),
+            files::Metadata::Folder(folder) => (folder.path_display, None),
+            files::Metadata::Deleted(_) => return ControlFlow::Continue(()),
+        };
+        let Some(path_display) = path_display else {
+            warn!("Skipping entry with no path");
+            return ControlFlow::Continue(());
+        };
+        let relative = components(&path_display)
+            .skip(depth)
+            .collect::<Vec<_>>()
+            .join("/");
+        match file {
+            Some(file) => snapshot.files.push((relative, file)),
+            // The folder itself is listed too.
+            None if relative.is_empty() => (),
+            None => snapshot.folders.push(relative),
+        }
+        ControlFlow::Continue(())
+    })?;
+    debug!(
+        "Snapshot of {path}: {} files, {} folders, {} bytes",
+        snapshot.files.len(),
+        snapshot.folders.len(),
+        snapshot.total_bytes()
+    );
+    Ok(snapshot)
+}
+
+/// Download the files in a snapshot to a local directory, each at the revision it was at when the
+/// snapshot was taken.
+///
+/// Since files are downloaded by revision, the result is consistent with the snapshot even if the
+/// folder is changed while the export is running: files changed since then are downloaded as they
+/// were, and files deleted since then are still downloaded. Folders are created even if they are
+/// empty.
+///
+/// Each file is downloaded to a temporary file next to its local path, and then renamed into
+/// place. This stops at the first error.
+pub fn export_snapshot(
+    client: &impl UserAuthClient,
+    snapshot: &Snapshot,
+    local_dir: &Path,
+    opts: &ExportOpts,
+) -> Result<Vec<ExportedFile>, BoxedError> {
+    fs::create_dir_all(local_dir).map_err(|e| Error::HttpClient(e.into()))?;
+    for folder in &snapshot.folders {
+        fs::create_dir_all(local_dir.join(folder)).map_err(|e| Error::HttpClient(e.into()))?;
+    }
+    let mut exported = vec![];
+    for (relative, metadata) in &snapshot.files {
+        let local_path = local_dir.join(relative);
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::HttpClient(e.into()))?;
+        }
+        info!(
+            "Downloading {}/{relative} at revision {}",
+            snapshot.path.trim_end_matches('/'),
+            metadata.rev
+        );
+        fetch(
+            client,
+            metadata,
+            &local_path,
+            &opts.retry,
+            opts.scheduler.as_deref(),
+        )?;
+        exported.push(ExportedFile {
+            local_path,
+            metadata: metadata.clone(),
+        });
+    }
+    Ok(exported)
+}
+
+/// Take a [`snapshot`] of a Dropbox folder, and then [export](export_snapshot) it to a local
+/// directory.
+pub fn export_folder(
+    client: &impl UserAuthClient,
+    path: &str,
+    local_dir: &Path,
+    opts: &ExportOpts,
+) -> Result<Vec<ExportedFile>, BoxedError> {
+    let snapshot = snapshot(client, path)?;
+    export_snapshot(client, &snapshot, local_dir, opts)
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|c| !c.is_empty())
+}
+
+/// Download the given revision of a file to a temporary file, and then rename it into place. If a
+/// scheduler is given, each attempt waits for a permit from it.
+fn fetch(
+    client: &impl UserAuthClient,
+    metadata: &files::FileMetadata,
+    local_path: &Path,
+    retry: &RetryOpts,
+    scheduler: Option<&TransferScheduler>,
+) -> Result<(), BoxedError> {
+    let temp_path = temp_path(local_path);
+    let arg = files::DownloadArg::new(format!("rev:{}", metadata.rev));
+    let mut backoff = Backoff::new(retry).for_endpoint(EndpointKind::Content);
+    loop {
+        backoff.wait();
+        let permit = scheduler.map(TransferScheduler::acquire);
+        let result = files::download(client, &arg, None, None).and_then(|response| {
+            let mut body = response.body.ok_or_else(|| {
+                Error::UnexpectedResponse("download response has no body".to_owned())
+            })?;
+            File::create(&temp_path)
+                .and_then(|mut file| io::copy(&mut body, &mut file))
+                .map_err(|e| Error::<DownloadError>::HttpClient(e.into()))
+        });
+        drop(permit);
+        match result {
+            Ok(len) if len == metadata.size => break,
+            Ok(len) => {
+                let e = Error::<DownloadError>::UnexpectedResponse(format!(
+                    "downloaded {len} bytes, but the file is {} bytes",
+                    metadata.size
+                ));
+                backoff.handle("downloading file", e).map_err(|e| {
+                    let _ = fs::remove_file(&temp_path);
+                    e.boxed()
+                })?;
+            }
+            Err(e @ Error::Api(_)) => return Err(path_root::boxed(e)),
+            Err(e) => backoff.handle("downloading file", e).map_err(|e| {
+                let _ = fs::remove_file(&temp_path);
+                path_root::boxed(e)
+            })?,
+        }
+    }
+    fs::rename(&temp_path, local_path).map_err(|e| Error::HttpClient(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::{file_json, MockClient};
+
+    #[test]
+    fn downloads_captured_revisions() {
+        let file = file_json("/Dir/sub/f", 2);
+        let client = MockClient::new("null")
+            .then(
+                200,
+                r#"{"entries": [
+                    {".tag": "folder", "name": "Dir", "id": "id:d", "path_display": "/Dir"},
+                    {".tag": "folder", "name": "sub", "id": "id:s", "path_display": "/Dir/sub"},
+                    {".tag": "folder", "name": "empty", "id": "id:e", "path_display": "/Dir/empty"},
+                    {".tag": "file", "name": "f", "id": "id:f", "size": 2,
+                     "client_modified": "2024-01-01T00:00:00Z",
+                     "server_modified": "2024-01-01T00:00:00Z",
+                     "rev": "aaaaaaaaa", "path_display": "/Dir/sub/f"}
+                ], "cursor": "cursor", "has_more": false}"#,
+            )
+            .then_download(file, "ab");
+        let local_dir = std::env::temp_dir().join(format!("export_test_{}", std::process::id()));
+
+        let exported = export_folder(&client, "/Dir", &local_dir, &ExportOpts::default()).unwrap();
+        let contents = fs::read_to_string(local_dir.join("sub/f")).unwrap();
+        let empty_exists = local_dir.join("empty").is_dir();
+        fs::remove_dir_all(&local_dir).unwrap();
+
+        assert_eq!(1, exported.len());
+        assert_eq!("aaaaaaaaa", exported[0].metadata.rev);
+        assert_eq!("ab", contents);
+        assert!(empty_exists);
+        assert!(client.urls.lock().unwrap()[1].ends_with("files/download"));
+    }
+}
@@ -0,0 +1,271 @@
+//! Getting temporary links to many files at once, for other systems to download them directly,
+//! and downloading files through them.
+
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use dropbox_sdk::files::{self, GetTemporaryLinkError};
+use dropbox_sdk::{Error, UserAuthClient};
+
+use super::public_link::{self, fetch_url, Response};
+use super::work::parallel_map;
+use crate::limits::{EndpointKind, TEMPORARY_LINK_LIFETIME};
+use crate::retry::{Backoff, RetryOpts};
+use crate::scheduler::TransferScheduler;
+
+/// Options for [`temporary_links`].
+#[derive(Debug, Clone)]
+pub struct LinkOpts {
+    /// How many links to request at once.
+    pub parallelism: usize,
+
+    /// How to retry failed requests.
+    pub retry: RetryOpts,
+
+    /// A scheduler to share with other uploads and downloads, to limit how many requests they
+    /// make at once in total.
+    pub scheduler: Option<Arc<TransferScheduler>>,
+}
+
+impl Default for LinkOpts {
+    fn default() -> Self {
+        Self {
+            parallelism: 8,
+            retry: RetryOpts::default(),
+            scheduler: None,
+        }
+    }
+}
+
+/// A temporary link to a file, from [`temporary_links`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemporaryLink {
+    /// The path the link was requested for.
+    pub path: String,
+
+    /// The URL the file can be downloaded from, without authentication.
+    pub url: String,
+
+    /// When the link stops working. This is estimated from when it was requested, since Dropbox
+    /// doesn't say exactly.
+    pub expires: SystemTime,
+
+    /// The metadata of the file.
+    pub metadata: files::FileMetadata,
+}
+
+/// How long before a temporary link expires to replace it with a new one.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// The HTTP status of requests for temporary links which have expired.
+const EXPIRED_STATUS: u16 = 410;
+
+impl TemporaryLink {
+    /// If the link has expired, or is about to, replace it with a new one to the same revision of
+    /// the file, so that it can be handed out again. Returns whether it was replaced.
+    pub fn refresh(
+        &mut self,
+        client: &impl UserAuthClient,
+        opts: &LinkOpts,
+    ) -> Result<bool, Error<GetTemporaryLinkError>> {
+        if SystemTime::now() + REFRESH_MARGIN < self.expires {
+            return Ok(false);
+        }
+        self.renew(client, opts)?;
+        Ok(true)
+    }
+
+    /// Replace the link with a new one to the same revision of the file.
+    fn renew(
+        &mut self,
+        client: &impl UserAuthClient,
+        opts: &LinkOpts,
+    ) -> Result<(), Error<GetTemporaryLinkError>> {
+        debug!("Getting a new temporary link to {}", self.path);
+        let link = temporary_link(client, format!("rev:{}", self.metadata.rev), opts)?;
+        self.url = link.url;
+        self.expires = link.expires;
+        Ok(())
+    }
+}
+
+/// Get temporary links to the files at the given paths, so that other systems, such as a CDN, can
+/// download them directly. The links work for
+/// [`TEMPORARY_LINK_LIFETIME`](crate::limits::TEMPORARY_LINK_LIFETIME).
+///
+/// The results are in the same order as the paths. Failing to get a link to one file, such as
+/// because it doesn't exist, doesn't stop the others.
+pub fn temporary_links<C, P>(
+    client: &C,
+    paths: impl IntoIterator<Item = P>,
+    opts: &LinkOpts,
+) -> Vec<Result<TemporaryLink, Error<GetTemporaryLinkError>>>
+where
+    C: UserAuthClient + Sync,
+    P: Into<String>,
+{
+    let paths = paths.into_iter().map(Into::into).collect::<Vec<String>>();
+    parallel_map(paths, opts.parallelism, |path| {
+        temporary_link(client, path, opts)
+    })
+}
+
+/// Download the file at the given path through a temporary link to it, writing it to the given
+/// writer, rather than through the API's content endpoint. Returns the link, with the metadata of
+/// the file.
+///
+/// Requests which fail without a response, or with a server error, are retried as set by
+/// [`retry`](LinkOpts::retry); if a response is cut off, the next request continues from where it
+/// got to. If the link expires before the file has been downloaded, a new one is used, to
+/// the same revision of the file.
+pub fn download_temporary_link<C: UserAuthClient>(
+    client: &C,
+    path: &str,
+    dest: &mut impl Write,
+    opts: &LinkOpts,
+) -> Result<TemporaryLink, Error<GetTemporaryLinkError>> {
+    let agent = public_link::agent();
+    download_link(client, path, dest, opts, |url, offset| {
+        public_link::get(&agent, url, offset)
+    })
+}
+
+/// Download through a temporary link as described for [`download_temporary_link`], with the given
+/// function, which requests the data at a URL from the given offset onwards.
+fn download_link<C: UserAuthClient>(
+    client: &C,
+    path: &str,
+    dest: &mut impl Write,
+    opts: &LinkOpts,
+    mut get: impl FnMut(&str, u64) -> Result<Response, Error<GetTemporaryLinkError>>,
+) -> Result<TemporaryLink, Error<GetTemporaryLinkError>> {
+    let mut link = temporary_link(client, path.to_owned(), opts)?;
+    fetch_url("temporary link", dest, &opts.retry, |offset| {
+        link.refresh(client, opts)?;
+        let response = get(&link.url, offset)?;
+        if response.status != EXPIRED_STATUS {
+            return Ok(response);
+        }
+        // It expired sooner than expected.
+        link.renew(client, opts)?;
+        get(&link.url, offset)
+    })?;
+    Ok(link)
+}
+
+fn temporary_link(
+    client: &impl UserAuthClient,
+    path: String,
+    opts: &LinkOpts,
+) -> Result<TemporaryLink, Error<GetTemporaryLinkError>> {
+    let arg = files::GetTemporaryLinkArg::new(path);
+    let mut backoff = Backoff::new(&opts.retry).for_endpoint(EndpointKind::Rpc);
+    loop {
+        backoff.wait();
+        let requested = SystemTime::now();
+        let result = {
+            let _permit = opts.scheduler.as_deref().map(TransferScheduler::acquire);
+            files::get_temporary_link(client, &arg)
+        };
+        match result {
+            Ok(result) => {
+                return Ok(TemporaryLink {
+                    path: arg.path,
+                    url: result.link,
+                    expires: requested + TEMPORARY_LINK_LIFETIME,
+                    metadata: result.metadata,
+                })
+            }
+            Err(e @ Error::Api(_)) => return Err(e),
+            Err(e) => backoff.handle("getting temporary link", e)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+
+    #[test]
+    fn links_in_order() {
+        let client = MockClient::new("null")
+            .then(
+                200,
+                r#"{"link": "https://example.com/a", "metadata": {"name": "a", "id": "id:a",
+                "size": 1, "client_modified": "2024-01-01T00:00:00Z",
+                "server_modified": "2024-01-01T00:00:00Z", "rev": "aaaaaaaaa"}}"#,
+            )
+            .then(
+                409,
+                r#"{"error": {".tag": "path", "path": {".tag": "not_found"}}}"#,
+            );
+        let opts = LinkOpts {
+            parallelism: 1,
+            ..Default::default()
+        };
+        let results = temporary_links(&client, ["/a", "/b"], &opts);
+        let link = results[0].as_ref().unwrap();
+        assert_eq!("/a", link.path);
+        assert_eq!("https://example.com/a", link.url);
+        assert!(link.expires > SystemTime::now());
+        assert!(matches!(
+            results[1],
+            Err(Error::Api(GetTemporaryLinkError::Path(
+                files::LookupError::NotFound
+            )))
+        ));
+    }
+
+    #[test]
+    fn renews_expired_link() {
+        let link = |url| {
+            format!(
+                r#"{{"link": "{url}", "metadata": {{"name": "a", "id": "id:a", "size": 6,
+                "client_modified": "2024-01-01T00:00:00Z",
+                "server_modified": "2024-01-01T00:00:00Z", "rev": "aaaaaaaaa"}}}}"#
+            )
+        };
+        let client = MockClient::new("null")
+            .then(200, link("https://example.com/1").leak())
+            .then(200, link("https://example.com/2").leak());
+        let mut requests = vec![];
+        let mut data = vec![];
+        let link = download_link(
+            &client,
+            "/a",
+            &mut data,
+            &LinkOpts::default(),
+            |url, offset| {
+                requests.push((url.to_owned(), offset));
+                let (status, body) = match requests.len() {
+                    1 => (206, "abc"),
+                    2 => (EXPIRED_STATUS, "gone"),
+                    _ => (206, "def"),
+                };
+                Ok(Response {
+                    status,
+                    content_length: Some(6 - offset),
+                    range_start: Some(offset),
+                    etag: None,
+                    retry_after: None,
+                    body: Box::new(body.as_bytes()),
+                })
+            },
+        )
+        .unwrap();
+        assert_eq!(b"abcdef", data.as_slice());
+        assert_eq!("/a", link.path);
+        assert_eq!("https://example.com/2", link.url);
+        assert_eq!(
+            vec![
+                ("https://example.com/1".to_owned(), 0),
+                ("https://example.com/1".to_owned(), 3),
+                ("https://example.com/2".to_owned(), 3),
+            ],
+            requests
+        );
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+}
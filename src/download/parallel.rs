@@ -0,0 +1,1000 @@
+//! Downloading a large file with several requests at once.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dropbox_sdk::files;
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use super::timeout::{ReadTimeoutClient, Watchdog};
+use super::{FileRangeWriter, RangeWriter};
+use crate::cancel::CancelToken;
+use crate::content_hash::{self, BlockHasher, ContentHash};
+use crate::limits::EndpointKind;
+use crate::path_root;
+use crate::retry::{Backoff, RetryOpts};
+use crate::scheduler::{BandwidthSchedule, TransferScheduler};
+use crate::throttle::Throttle;
+use crate::BLOCK_SIZE;
+
+/// How much of a response to read at a time.
+const READ_SIZE: usize = 256 * 1024;
+
+/// Options for [`download_to_file`].
+#[derive(Clone)]
+pub struct DownloadOpts {
+    /// How many ranges to download at once.
+    pub parallelism: usize,
+
+    /// How many bytes to request at a time. Zero is an invalid argument.
+    pub chunk_size: u64,
+
+    /// Hash the data as it is downloaded, and check that it matches the file's Content Hash,
+    /// failing with [`DownloadHashMismatch`] if it doesn't. This rounds
+    /// [`chunk_size`](Self::chunk_size) up to a multiple of [`BLOCK_SIZE`], so that each range is
+    /// made up of whole blocks.
+    pub verify: bool,
+
+    /// Don't download the file if the local file already has the same size and Content Hash as it,
+    /// which makes downloading the same files again, such as to keep a local copy up to date, only
+    /// download what has changed. This reads the whole local file to hash it.
+    pub skip_if_identical: bool,
+
+    /// Limit the download to this many bytes per second, shared by all the parallel requests for
+    /// the file, so that it can run in the background without starving other traffic. Short
+    /// bursts above the limit are allowed.
+    pub max_bytes_per_sec: Option<u64>,
+
+    /// Limit the download's bandwidth by the time of day instead of to
+    /// [`max_bytes_per_sec`](Self::max_bytes_per_sec). The same schedule can be given to many
+    /// downloads and uploads, but each is limited separately.
+    pub bandwidth_schedule: Option<Arc<BandwidthSchedule>>,
+
+    /// Give up on a response if no data arrives for this long, and request the rest of its range
+    /// again, which counts as a retry. Without this, a connection which stops sending data without
+    /// failing can stall the download forever, unless the HTTP client has a timeout of its own.
+    ///
+    /// This only covers reading responses. Connecting and waiting for a response to start are
+    /// left to the HTTP client's own timeouts.
+    pub read_timeout: Option<Duration>,
+
+    /// Give up on a response if less than this many bytes per second arrive, on average over ten
+    /// seconds, and request the rest of its range again, which counts as a retry. This catches
+    /// connections which keep sending data, but too slowly to ever finish.
+    pub min_bytes_per_sec: Option<u64>,
+
+    /// How many ranges to request ahead of the one being read from a
+    /// [`DownloadReader`](super::DownloadReader), so that the next one is ready by the time it's
+    /// needed, rather than only requesting it then.
+    /// Each range is held in memory, so this uses up to `prefetch + 1` times
+    /// [`chunk_size`](Self::chunk_size) of memory. Downloads to files use
+    /// [`parallelism`](Self::parallelism) instead.
+    pub prefetch: usize,
+
+    /// How to retry failed requests.
+    pub retry: RetryOpts,
+
+    /// A scheduler to share with other uploads and downloads, to limit how many requests they
+    /// make at once in total.
+    pub scheduler: Option<Arc<TransferScheduler>>,
+
+    /// An optional callback to periodically receive progress updates as the file downloads.
+    pub progress_handler: Option<Arc<Box<dyn DownloadProgressHandler>>>,
+
+    /// A token which stops the download when it is cancelled, failing with [`Cancelled`]. Any
+    /// read of a response in progress finishes first, but waits to retry are cut short.
+    pub cancel: Option<CancelToken>,
+}
+
+impl Default for DownloadOpts {
+    fn default() -> Self {
+        Self {
+            parallelism: 8,
+            chunk_size: 16 * 1024 * 1024,
+            verify: false,
+            skip_if_identical: false,
+            max_bytes_per_sec: None,
+            bandwidth_schedule: None,
+            read_timeout: None,
+            min_bytes_per_sec: None,
+            prefetch: 0,
+            retry: RetryOpts::default(),
+            scheduler: None,
+            progress_handler: None,
+            cancel: None,
+        }
+    }
+}
+
+impl DownloadOpts {
+    /// When to give up on responses.
+    pub(super) fn watchdog(&self) -> Watchdog {
+        Watchdog::new(self.read_timeout, self.min_bytes_per_sec)
+    }
+}
+
+/// Implement to receive periodic progress updates as a file downloads.
+///
+/// Implement either [`update`](Self::update) or, for more details, [`progress`](Self::progress).
+pub trait DownloadProgressHandler: Sync + Send {
+    /// Invoked with the following parameters:
+    /// - total bytes downloaded so far
+    /// - the rate (bytes/sec) of the most recent data read
+    /// - the overall rate (bytes/sec) of the whole download
+    fn update(&self, bytes_downloaded: u64, instant_rate: f64, overall_rate: f64) {
+        let _ = (bytes_downloaded, instant_rate, overall_rate);
+    }
+
+    /// Invoked with the details of the download's progress. By default, this calls
+    /// [`update`](Self::update).
+    fn progress(&self, progress: &DownloadProgress) {
+        self.update(
+            progress.bytes_downloaded,
+            progress.instant_rate,
+            progress.overall_rate,
+        );
+    }
+}
+
+/// The progress of a download, as given to [`DownloadProgressHandler::progress`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct DownloadProgress {
+    /// How many bytes have been downloaded so far.
+    pub bytes_downloaded: u64,
+
+    /// The size of the whole file.
+    pub total_bytes: u64,
+
+    /// How many failed requests have been retried so far, not counting rate limiting.
+    pub retries: u32,
+
+    /// The rate (bytes/sec) of the most recent data read.
+    pub instant_rate: f64,
+
+    /// The overall rate (bytes/sec) of the whole download.
+    pub overall_rate: f64,
+}
+
+impl DownloadProgress {
+    /// The fraction of the file which has been downloaded, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        if self.total_bytes == 0 {
+            1.
+        } else {
+            self.bytes_downloaded as f64 / self.total_bytes as f64
+        }
+    }
+
+    /// An estimate of how much longer the download will take, based on the overall rate so far.
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining = self.total_bytes.saturating_sub(self.bytes_downloaded);
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+        if self.overall_rate <= 0. || !self.overall_rate.is_finite() {
+            return None;
+        }
+        Duration::try_from_secs_f64(remaining as f64 / self.overall_rate).ok()
+    }
+}
+
+/// The data downloaded with [`verify`](DownloadOpts::verify) set didn't match the file's Content
+/// Hash.
+///
+/// Returned as [`DownloadError::HashMismatch`] by [`download_to_file`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadHashMismatch {
+    /// The path of the file.
+    pub path: String,
+
+    /// The Content Hash of the file, according to Dropbox.
+    pub expected: String,
+
+    /// The Content Hash of the data downloaded.
+    pub actual: String,
+}
+
+impl Display for DownloadHashMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "content hash mismatch: {} has {}, but the data downloaded has {}",
+            self.path, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for DownloadHashMismatch {}
+
+/// A response to a request for part of a file was for a different revision than the rest of the
+/// download, so its data can't be combined with the rest.
+///
+/// Returned as [`DownloadError::FileChanged`] by [`download_to_file`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChanged {
+    /// The path of the file.
+    pub path: String,
+
+    /// The revision being downloaded.
+    pub expected_rev: String,
+
+    /// The revision the response was for.
+    pub actual_rev: String,
+}
+
+impl Display for FileChanged {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} changed while downloading it: expected revision {}, but got {}",
+            self.path, self.expected_rev, self.actual_rev
+        )
+    }
+}
+
+impl std::error::Error for FileChanged {}
+
+/// The download was stopped by cancelling its [`cancel`](DownloadOpts::cancel) token.
+///
+/// Returned as [`DownloadError::Cancelled`] by [`download_to_file`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cancelled {
+    /// How many bytes had been downloaded when it stopped.
+    pub bytes_downloaded: u64,
+}
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "download cancelled after {} bytes",
+            self.bytes_downloaded
+        )
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// A download failed, from [`download_to_file`] and the other functions which download files in
+/// ranges.
+///
+/// Failures found by this crate, rather than by Dropbox, have their own variants, so that code
+/// which handles errors from the Dropbox API doesn't mistake them for one.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DownloadError {
+    /// A request failed, and wasn't retried or ran out of retries, or the local file couldn't be
+    /// read or written.
+    Request(BoxedError),
+
+    /// The data downloaded didn't match the file's Content Hash.
+    HashMismatch(DownloadHashMismatch),
+
+    /// The file changed while it was downloading.
+    FileChanged(FileChanged),
+
+    /// The download was cancelled.
+    Cancelled(Cancelled),
+}
+
+impl Display for DownloadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Request(error) => write!(f, "downloading failed: {error}"),
+            Self::HashMismatch(error) => error.fmt(f),
+            Self::FileChanged(error) => error.fmt(f),
+            Self::Cancelled(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+impl From<BoxedError> for DownloadError {
+    fn from(e: BoxedError) -> Self {
+        Self::Request(e)
+    }
+}
+
+/// Fail with [`Cancelled`] if the download's token has been cancelled.
+fn check_cancelled(opts: &DownloadOpts, bytes_downloaded: u64) -> Result<(), DownloadError> {
+    match &opts.cancel {
+        Some(token) if token.is_cancelled() => {
+            info!("Download cancelled after {bytes_downloaded} bytes");
+            Err(DownloadError::Cancelled(Cancelled { bytes_downloaded }))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// What all the ranges of a download have done so far, for reporting progress.
+pub(super) struct Counters {
+    start_time: Instant,
+    total_bytes: u64,
+    bytes_downloaded: AtomicU64,
+    retries: AtomicU32,
+    throttle: Option<Throttle>,
+}
+
+impl Counters {
+    /// Start counting a download of a file of the given size.
+    pub(super) fn new(total_bytes: u64, opts: &DownloadOpts) -> Self {
+        Self {
+            start_time: Instant::now(),
+            total_bytes,
+            bytes_downloaded: AtomicU64::new(0),
+            retries: AtomicU32::new(0),
+            throttle: Throttle::for_content(
+                opts.max_bytes_per_sec,
+                opts.bandwidth_schedule.as_ref(),
+            ),
+        }
+    }
+
+    /// Count data which was just read, taking the given time, and report the progress.
+    fn downloaded(&self, len: u64, duration: Duration, opts: &DownloadOpts) {
+        let bytes_downloaded = self.bytes_downloaded.fetch_add(len, SeqCst) + len;
+        if let Some(handler) = &opts.progress_handler {
+            handler.progress(&DownloadProgress {
+                bytes_downloaded,
+                total_bytes: self.total_bytes,
+                retries: self.retries.load(SeqCst),
+                // This assumes all the ranges are going at about the same rate.
+                instant_rate: len as f64 / duration.as_secs_f64() * opts.parallelism as f64,
+                overall_rate: bytes_downloaded as f64 / self.start_time.elapsed().as_secs_f64(),
+            });
+        }
+    }
+
+    /// Fail with [`Cancelled`] if the download has been cancelled.
+    fn check_cancelled(&self, opts: &DownloadOpts) -> Result<(), DownloadError> {
+        check_cancelled(opts, self.bytes_downloaded.load(SeqCst))
+    }
+
+    /// Count a failed request, and wait to retry it if it should be.
+    fn retry<E: std::error::Error + Send + Sync + 'static>(
+        &self,
+        backoff: &mut Backoff<'_>,
+        e: Error<E>,
+    ) -> Result<(), BoxedError> {
+        if !matches!(e, Error::RateLimited { .. }) {
+            self.retries.fetch_add(1, SeqCst);
+        }
+        backoff
+            .handle("downloading range", e)
+            .map_err(path_root::boxed)
+    }
+}
+
+/// Where the ranges of a download are requested from.
+pub(super) trait RangeSource: Sync {
+    /// The error type of the requests.
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Request the given range.
+    fn request(&self, range: Range<u64>) -> Result<RangeResponse, Error<Self::Error>>;
+}
+
+/// A response from a [`RangeSource`].
+pub(super) struct RangeResponse {
+    /// The revision of the file the response is for.
+    pub rev: String,
+
+    /// The data.
+    pub body: Box<dyn Read>,
+}
+
+/// The revision of a file a [`RangeSource`] is for.
+pub(super) struct RemoteRevision<'a> {
+    /// The path of the file, for messages and errors.
+    pub path: &'a str,
+
+    /// The revision to download.
+    pub rev: &'a str,
+
+    /// The size of the file.
+    pub size: u64,
+
+    /// The Content Hash of the file, if known, to [verify](DownloadOpts::verify) it against.
+    pub content_hash: Option<&'a str>,
+}
+
+/// A revision of a file in the user's Dropbox.
+struct FileRevision<'a, C> {
+    client: &'a C,
+    arg: files::DownloadArg,
+}
+
+impl<C: UserAuthClient + Sync> RangeSource for FileRevision<'_, C> {
+    type Error = files::DownloadError;
+
+    fn request(&self, range: Range<u64>) -> Result<RangeResponse, Error<files::DownloadError>> {
+        // The end of an HTTP range is inclusive.
+        let response = files::download(
+            self.client,
+            &self.arg,
+            Some(range.start),
+            Some(range.end - 1),
+        )?;
+        let body = response
+            .body
+            .ok_or_else(|| Error::UnexpectedResponse("download response has no body".to_owned()))?;
+        Ok(RangeResponse {
+            rev: response.result.rev,
+            body,
+        })
+    }
+}
+
+/// Download a file to the given local path, requesting [`chunk_size`](DownloadOpts::chunk_size)
+/// ranges of it in parallel and writing each at its place in the file, which is faster for large
+/// files than a single request.
+///
+/// The file's metadata is looked up first, to find its length, and every range, including any
+/// retries, is then requested from the revision it was at then, so the result is consistent even
+/// if the file is changed while it is downloading. That metadata is returned. Should a response be
+/// for any other revision anyway, this fails with [`FileChanged`] rather than mixing the two.
+///
+/// Any existing file at the local path is replaced, unless
+/// [`skip_if_identical`](DownloadOpts::skip_if_identical) is set and it is the same as the file in
+/// Dropbox. If a request is cut off, the rest of its range is requested again. This stops at the
+/// first error, leaving the local file partly written, or, if it fails
+/// [verification](DownloadOpts::verify), fully written with the wrong data.
+pub fn download_to_file<C: UserAuthClient + Sync>(
+    client: &C,
+    arg: &files::DownloadArg,
+    local_path: &Path,
+    opts: &DownloadOpts,
+) -> Result<files::FileMetadata, DownloadError> {
+    let metadata = lookup_file(client, arg, opts)?;
+    if opts.skip_if_identical && identical_local_file(local_path, &metadata)? {
+        info!(
+            "Skipping download of identical file: {}",
+            local_path.display()
+        );
+        return Ok(metadata);
+    }
+    download_revision(client, &metadata, local_path, opts)?;
+    Ok(metadata)
+}
+
+/// Look up the metadata of the file to download, at the revision given, if any.
+pub(super) fn lookup_file(
+    client: &impl UserAuthClient,
+    arg: &files::DownloadArg,
+    opts: &DownloadOpts,
+) -> Result<files::FileMetadata, DownloadError> {
+    #[allow(deprecated)]
+    let path = match &arg.rev {
+        Some(rev) => format!("rev:{rev}"),
+        None => arg.path.clone(),
+    };
+    file_metadata(client, &path, opts)
+}
+
+/// Download the revision of a file with the given metadata to the given local path, as described
+/// for [`download_to_file`].
+pub(super) fn download_revision<C: UserAuthClient + Sync>(
+    client: &C,
+    metadata: &files::FileMetadata,
+    local_path: &Path,
+    opts: &DownloadOpts,
+) -> Result<(), DownloadError> {
+    let client = ReadTimeoutClient::new(client, opts.watchdog());
+    let source = FileRevision {
+        client: &client,
+        arg: files::DownloadArg::new(format!("rev:{}", metadata.rev)),
+    };
+    download_ranges(&source, &remote_revision(metadata), local_path, opts)
+}
+
+/// Describe a file in Dropbox with the given metadata, to download it.
+fn remote_revision(metadata: &files::FileMetadata) -> RemoteRevision<'_> {
+    RemoteRevision {
+        path: metadata.path_display.as_deref().unwrap_or(&metadata.name),
+        rev: &metadata.rev,
+        size: metadata.size,
+        content_hash: metadata.content_hash.as_deref(),
+    }
+}
+
+/// Whether the local file at the given path has the same size and Content Hash as the file in
+/// Dropbox with the given metadata.
+pub(super) fn identical_local_file(
+    local_path: &Path,
+    metadata: &files::FileMetadata,
+) -> Result<bool, BoxedError> {
+    let Some(expected) = &metadata.content_hash else {
+        return Ok(false);
+    };
+    let mut file = match File::open(local_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(Error::HttpClient(e.into())),
+    };
+    let local = file.metadata().map_err(|e| Error::HttpClient(e.into()))?;
+    if !local.is_file() || local.len() != metadata.size {
+        return Ok(false);
+    }
+    let mut hash = ContentHash::new();
+    hash.read_stream(&mut file)
+        .map_err(|e| Error::HttpClient(e.into()))?;
+    Ok(hash.finish_hex() == *expected)
+}
+
+/// Download a file from the given source to the given local path, in ranges in parallel, as
+/// described for [`download_to_file`].
+pub(super) fn download_ranges(
+    source: &impl RangeSource,
+    file: &RemoteRevision<'_>,
+    local_path: &Path,
+    opts: &DownloadOpts,
+) -> Result<(), DownloadError> {
+    check_size("chunk size", opts.chunk_size)?;
+    let writer = File::create(local_path)
+        .and_then(|f| {
+            f.set_len(file.size)?;
+            Ok(FileRangeWriter::new(f))
+        })
+        .map_err(|e| BoxedError::HttpClient(e.into()))?;
+
+    let counters = Counters::new(file.size, opts);
+    let chunk_size = chunk_size(opts);
+    let next = Mutex::new((0..file.size).step_by(chunk_size as usize));
+    let block_hashes = Mutex::new(BTreeMap::new());
+    let error = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..opts.parallelism.max(1) {
+            scope.spawn(|| loop {
+                if error.lock().unwrap().is_some() {
+                    break;
+                }
+                let Some(start) = next.lock().unwrap().next() else {
+                    break;
+                };
+                let end = file.size.min(start + chunk_size);
+                let mut hasher = opts.verify.then(BlockHasher::new);
+                let range = start..end;
+                if let Err(e) = download_range(
+                    source,
+                    file,
+                    range,
+                    &writer,
+                    hasher.as_mut(),
+                    &counters,
+                    opts,
+                ) {
+                    error.lock().unwrap().get_or_insert(e);
+                    break;
+                }
+                if let Some(hasher) = hasher {
+                    block_hashes.lock().unwrap().insert(start, hasher.finish());
+                }
+            });
+        }
+    });
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e);
+    }
+    if opts.verify {
+        verify(file, block_hashes.into_inner().unwrap())?;
+    }
+    debug!(
+        "Downloaded {} bytes of {} to {}",
+        file.size,
+        file.path,
+        local_path.display()
+    );
+    Ok(())
+}
+
+/// Fail with an [`io::ErrorKind::InvalidInput`] error if the size is zero.
+pub(super) fn check_size(what: &str, size: u64) -> Result<(), BoxedError> {
+    if size == 0 {
+        return Err(Error::HttpClient(
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{what} must not be zero"),
+            )
+            .into(),
+        ));
+    }
+    Ok(())
+}
+
+/// The size of the ranges to request: [`chunk_size`](DownloadOpts::chunk_size), rounded up to a
+/// multiple of [`BLOCK_SIZE`] if the download is to be [verified](DownloadOpts::verify).
+pub(super) fn chunk_size(opts: &DownloadOpts) -> u64 {
+    if opts.verify {
+        opts.chunk_size.next_multiple_of(BLOCK_SIZE as u64)
+    } else {
+        opts.chunk_size
+    }
+}
+
+/// A range of a file downloaded into memory by [`fetch_range`].
+pub(super) struct FetchedRange {
+    /// The data of the range.
+    pub data: Vec<u8>,
+
+    /// The hashes of the blocks in the range, if the download is to be
+    /// [verified](DownloadOpts::verify).
+    pub block_hashes: Option<Vec<[u8; content_hash::OUTPUT_SIZE]>>,
+}
+
+/// Download a range of the revision of a file with the given metadata into memory.
+pub(super) fn fetch_range<C: UserAuthClient + Sync>(
+    client: &C,
+    metadata: &files::FileMetadata,
+    range: Range<u64>,
+    counters: &Counters,
+    opts: &DownloadOpts,
+) -> Result<FetchedRange, DownloadError> {
+    let client = ReadTimeoutClient::new(client, opts.watchdog());
+    let source = FileRevision {
+        client: &client,
+        arg: files::DownloadArg::new(format!("rev:{}", metadata.rev)),
+    };
+    let writer = MemoryRange {
+        start: range.start,
+        data: Mutex::new(vec![0; (range.end - range.start) as usize]),
+    };
+    let mut hasher = opts.verify.then(BlockHasher::new);
+    let file = remote_revision(metadata);
+    download_range(
+        &source,
+        &file,
+        range,
+        &writer,
+        hasher.as_mut(),
+        counters,
+        opts,
+    )?;
+    Ok(FetchedRange {
+        data: writer.data.into_inner().unwrap(),
+        block_hashes: hasher.map(BlockHasher::finish),
+    })
+}
+
+/// Turn an error from downloading a range into one which can be returned from reading, keeping
+/// any I/O error as it is. Anything else can be downcast back to a [`DownloadError`].
+pub(super) fn io_error(e: DownloadError) -> io::Error {
+    match e {
+        DownloadError::Request(Error::HttpClient(e)) => match e.downcast::<io::Error>() {
+            Ok(e) => *e,
+            Err(e) => io::Error::other(DownloadError::Request(Error::HttpClient(e))),
+        },
+        e => io::Error::other(e),
+    }
+}
+
+/// A range of a file being downloaded into memory.
+struct MemoryRange {
+    start: u64,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RangeWriter for MemoryRange {
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let start = (offset - self.start) as usize;
+        self.data.lock().unwrap()[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Check the hashes of the blocks of a file downloaded in ranges, by the offsets of the ranges,
+/// against the Content Hash in its metadata.
+pub(super) fn verify_file(
+    metadata: &files::FileMetadata,
+    block_hashes: BTreeMap<u64, Vec<[u8; content_hash::OUTPUT_SIZE]>>,
+) -> Result<(), DownloadError> {
+    verify(&remote_revision(metadata), block_hashes)
+}
+
+/// Check the hashes of the blocks downloaded, by the offsets of the ranges they are in, against
+/// the Content Hash of the file.
+fn verify(
+    file: &RemoteRevision<'_>,
+    block_hashes: BTreeMap<u64, Vec<[u8; content_hash::OUTPUT_SIZE]>>,
+) -> Result<(), DownloadError> {
+    let path = file.path;
+    let Some(expected) = file.content_hash else {
+        warn!("{path} has no Content Hash; not verifying it");
+        return Ok(());
+    };
+    let actual = content_hash::hex(&content_hash::combine_block_hashes(
+        block_hashes.values().flatten(),
+    ));
+    if actual != expected {
+        error!("Content Hash of {path} doesn't match: expected {expected}, got {actual}");
+        return Err(DownloadError::HashMismatch(DownloadHashMismatch {
+            path: path.to_owned(),
+            expected: expected.to_owned(),
+            actual,
+        }));
+    }
+    Ok(())
+}
+
+/// Look up the metadata of the file to download.
+fn file_metadata(
+    client: &impl UserAuthClient,
+    path: &str,
+    opts: &DownloadOpts,
+) -> Result<files::FileMetadata, DownloadError> {
+    let arg = files::GetMetadataArg::new(path.to_owned());
+    let mut backoff = Backoff::new(&opts.retry)
+        .for_endpoint(EndpointKind::Rpc)
+        .cancelled_by(opts.cancel.as_ref());
+    loop {
+        backoff.wait();
+        check_cancelled(opts, 0)?;
+        match files::get_metadata(client, &arg) {
+            Ok(files::Metadata::File(metadata)) => return Ok(metadata),
+            Ok(_) => {
+                return Err(BoxedError::UnexpectedResponse(format!("{path} is not a file")).into())
+            }
+            Err(e @ Error::Api(_)) => return Err(path_root::boxed(e).into()),
+            Err(e) => backoff
+                .handle("getting file metadata", e)
+                .map_err(path_root::boxed)?,
+        }
+    }
+}
+
+/// Download a range of a file and write it to the same place in the local file, hashing it if a
+/// hasher is given. If a request is cut off, the next attempt continues from where it got to.
+fn download_range<S: RangeSource>(
+    source: &S,
+    file: &RemoteRevision<'_>,
+    range: Range<u64>,
+    writer: &impl RangeWriter,
+    mut hasher: Option<&mut BlockHasher>,
+    counters: &Counters,
+    opts: &DownloadOpts,
+) -> Result<(), DownloadError> {
+    let mut backoff = Backoff::new(&opts.retry)
+        .for_endpoint(EndpointKind::Content)
+        .cancelled_by(opts.cancel.as_ref());
+    let mut buf = vec![0u8; READ_SIZE];
+    let mut offset = range.start;
+    loop {
+        backoff.wait();
+        counters.check_cancelled(opts)?;
+        let permit = opts.scheduler.as_deref().map(TransferScheduler::acquire);
+        let mut body = match source.request(offset..range.end) {
+            Ok(response) if response.rev != file.rev => {
+                error!(
+                    "{} changed while downloading it, to revision {}",
+                    file.path, response.rev
+                );
+                return Err(DownloadError::FileChanged(FileChanged {
+                    path: file.path.to_owned(),
+                    expected_rev: file.rev.to_owned(),
+                    actual_rev: response.rev,
+                }));
+            }
+            Ok(response) => response.body,
+            Err(e @ Error::Api(_)) => return Err(path_root::boxed(e).into()),
+            Err(e) => {
+                drop(permit);
+                counters.retry(&mut backoff, e)?;
+                continue;
+            }
+        };
+        let mut read_start = Instant::now();
+        let read_error = loop {
+            match body.read(&mut buf) {
+                Ok(0) => break None,
+                Ok(n) => {
+                    // Don't trust the server to stop at the end of the range.
+                    let n = n.min((range.end - offset) as usize);
+                    writer
+                        .write_at(offset, &buf[..n])
+                        .map_err(|e| BoxedError::HttpClient(e.into()))?;
+                    if let Some(hasher) = hasher.as_deref_mut() {
+                        hasher.update(&buf[..n]);
+                    }
+                    offset += n as u64;
+                    if let Some(throttle) = &counters.throttle {
+                        throttle.acquire(n as u64);
+                    }
+                    counters.downloaded(n as u64, read_start.elapsed(), opts);
+                    read_start = Instant::now();
+                    if offset == range.end {
+                        break None;
+                    }
+                    counters.check_cancelled(opts)?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => break Some(e),
+            }
+        };
+        drop(permit);
+        let e = match read_error {
+            None if offset == range.end => return Ok(()),
+            None => Error::<S::Error>::UnexpectedResponse(format!(
+                "response for {}..{} ended {} bytes early",
+                range.start,
+                range.end,
+                range.end - offset
+            )),
+            Some(e) => Error::HttpClient(e.into()),
+        };
+        counters.retry(&mut backoff, e)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content_hash::ContentHash;
+    use crate::testing::tests::{file_json, file_json_with, MockClient, ProgressRecorder};
+    use std::fs;
+
+    #[test]
+    fn ranges_and_cut_off_responses() {
+        let file = file_json("/f", 6);
+        let client = MockClient::new("null")
+            .then(200, file)
+            .then_download(file, "a")
+            .then_download(file, "bc")
+            .then_download(file, "def");
+        let local_path = std::env::temp_dir().join(format!("parallel_test_{}", std::process::id()));
+        let recorder = Arc::new(ProgressRecorder::default());
+        let opts = DownloadOpts {
+            parallelism: 1,
+            chunk_size: 3,
+            retry: RetryOpts {
+                initial_backoff_time: Duration::ZERO,
+                ..Default::default()
+            },
+            progress_handler: Some(Arc::new(Box::new(Arc::clone(&recorder)))),
+            ..Default::default()
+        };
+
+        let arg = files::DownloadArg::new("/f".to_owned());
+        let metadata = download_to_file(&client, &arg, &local_path, &opts).unwrap();
+        let contents = fs::read_to_string(&local_path).unwrap();
+        fs::remove_file(&local_path).unwrap();
+
+        assert_eq!("aaaaaaaaa", metadata.rev);
+        // The first response was cut off after one byte, so the rest of its range was requested
+        // again.
+        assert_eq!("abcdef", contents);
+        assert_eq!(4, client.urls.lock().unwrap().len());
+        assert_eq!(vec![(1, 6, 0), (3, 6, 1), (6, 6, 1)], recorder.updates());
+    }
+
+    #[test]
+    fn verify() {
+        let download = |content_hash: &str| {
+            let metadata = file_json_with("/f", 6, "aaaaaaaaa", Some(content_hash));
+            let client = MockClient::new("null")
+                .then(200, metadata)
+                .then_download(metadata, "abcdef");
+            let local_path = std::env::temp_dir().join(format!(
+                "parallel_verify_test_{}_{}",
+                std::process::id(),
+                &content_hash[..4]
+            ));
+            let opts = DownloadOpts {
+                // Rounded up to a whole block.
+                chunk_size: 3,
+                verify: true,
+                ..Default::default()
+            };
+            let arg = files::DownloadArg::new("/f".to_owned());
+            let result = download_to_file(&client, &arg, &local_path, &opts);
+            fs::remove_file(&local_path).unwrap();
+            assert_eq!(2, client.urls.lock().unwrap().len());
+            result
+        };
+
+        let hash = ContentHash::from("abcdef").finish_hex();
+        assert!(download(&hash).is_ok());
+
+        let Err(DownloadError::HashMismatch(e)) = download("0000") else {
+            panic!("wrong result");
+        };
+        assert_eq!(
+            DownloadHashMismatch {
+                path: "/f".to_owned(),
+                expected: "0000".to_owned(),
+                actual: hash,
+            },
+            e
+        );
+    }
+
+    #[test]
+    fn file_changed() {
+        let file = file_json("/f", 6);
+        let changed = file_json_with("/f", 6, "bbbbbbbbb", None);
+        let client = MockClient::new("null")
+            .then(200, file)
+            .then_download(file, "a")
+            .then_download(changed, "xyzdef");
+        let local_path =
+            std::env::temp_dir().join(format!("parallel_changed_test_{}", std::process::id()));
+        let opts = DownloadOpts {
+            parallelism: 1,
+            retry: RetryOpts {
+                initial_backoff_time: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let arg = files::DownloadArg::new("/f".to_owned());
+        let result = download_to_file(&client, &arg, &local_path, &opts);
+        let _ = fs::remove_file(&local_path);
+
+        // The response to the retry after the cut off one was for another revision, which isn't
+        // retried.
+        assert_eq!(3, client.urls.lock().unwrap().len());
+        let Err(DownloadError::FileChanged(e)) = result else {
+            panic!("wrong result");
+        };
+        assert_eq!(
+            FileChanged {
+                path: "/f".to_owned(),
+                expected_rev: "aaaaaaaaa".to_owned(),
+                actual_rev: "bbbbbbbbb".to_owned(),
+            },
+            e
+        );
+    }
+
+    struct CancelOnProgress(CancelToken);
+
+    impl DownloadProgressHandler for CancelOnProgress {
+        fn progress(&self, _progress: &DownloadProgress) {
+            self.0.cancel();
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let file = file_json("/f", 6);
+        let client = MockClient::new("null")
+            .then(200, file)
+            .then_download(file, "abc");
+        let local_path =
+            std::env::temp_dir().join(format!("parallel_cancel_test_{}", std::process::id()));
+        let token = CancelToken::new();
+        let opts = DownloadOpts {
+            parallelism: 1,
+            chunk_size: 3,
+            progress_handler: Some(Arc::new(Box::new(CancelOnProgress(token.clone())))),
+            cancel: Some(token),
+            ..Default::default()
+        };
+
+        let arg = files::DownloadArg::new("/f".to_owned());
+        let result = download_to_file(&client, &arg, &local_path, &opts);
+        let _ = fs::remove_file(&local_path);
+
+        // The first range finished, but the second one wasn't requested.
+        assert_eq!(2, client.urls.lock().unwrap().len());
+        let Err(DownloadError::Cancelled(e)) = result else {
+            panic!("wrong result");
+        };
+        assert_eq!(
+            Cancelled {
+                bytes_downloaded: 3
+            },
+            e
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Previewing documents without downloading them.
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::Arc;
+
+use dropbox_sdk::client_trait::HttpRequestResult;
+use dropbox_sdk::files::{self, PreviewError};
+use dropbox_sdk::{Error, UserAuthClient};
+
+use super::chunks::Chunks;
+use super::resume::{Resumable, ResumingReader, StreamOpts, BUFFER_SIZE};
+use super::timeout::ReadTimeoutClient;
+use super::DownloadProgressHandler;
+
+/// A preview of a document, from [`preview`], which can be read like the document itself.
+///
+/// Previews of documents, such as `.docx` and `.pptx` files, are PDFs, and previews of
+/// spreadsheets, such as `.xlsx` and `.csv` files, are HTML.
+///
+/// If reading the preview fails partway through, the rest of it is requested again, retrying as
+/// the options given to [`preview`] say, and reading only fails if that does. It is buffered, so
+/// it can be read a line at a time with [`BufRead`].
+pub struct Preview<'a, C> {
+    /// The metadata of the file being previewed.
+    pub metadata: files::FileMetadata,
+
+    reader: BufReader<ResumingReader<PreviewRequest<'a, C>>>,
+}
+
+/// Get a preview of the document at the given path, as a stream which can be read.
+///
+/// Dropbox makes previews of documents when they are first requested, so while it is still working
+/// on one, the request is retried.
+pub fn preview<'a, C: UserAuthClient>(
+    client: &'a C,
+    path: &str,
+    opts: &StreamOpts,
+) -> Result<Preview<'a, C>, Error<PreviewError>> {
+    let request = PreviewRequest {
+        client: ReadTimeoutClient::new(client, opts.watchdog()),
+        arg: files::PreviewArg::new(path.to_owned()),
+    };
+    let (metadata, mut reader) = ResumingReader::open(request, &opts.retry)?;
+    // Continue from the same revision, if reading is cut off.
+    reader.source_mut().arg = files::PreviewArg::new(format!("rev:{}", metadata.rev));
+    Ok(Preview {
+        metadata,
+        reader: BufReader::with_capacity(BUFFER_SIZE, reader),
+    })
+}
+
+impl<'a, C: UserAuthClient> Preview<'a, C> {
+    /// Report the progress of reading the preview to the given handler, as it is read.
+    pub fn with_progress_handler(mut self, handler: Arc<Box<dyn DownloadProgressHandler>>) -> Self {
+        self.reader.get_mut().set_progress_handler(handler);
+        self
+    }
+
+    /// Limit reading the preview to the given number of bytes per second, as with
+    /// [`max_bytes_per_sec`](super::DownloadOpts::max_bytes_per_sec), so that it can be read in
+    /// the background without starving other traffic.
+    pub fn with_max_bytes_per_sec(mut self, max_bytes_per_sec: u64) -> Self {
+        self.reader
+            .get_mut()
+            .set_max_bytes_per_sec(max_bytes_per_sec);
+        self
+    }
+
+    /// Read the rest of the preview in owned chunks of the given size, such as to send to another
+    /// thread.
+    pub fn chunks(self, size: usize) -> Chunks<Self> {
+        Chunks::new(self, size)
+    }
+}
+
+impl<C: UserAuthClient> Read for Preview<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<C: UserAuthClient> BufRead for Preview<'_, C> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt)
+    }
+}
+
+struct PreviewRequest<'a, C> {
+    client: ReadTimeoutClient<'a, C>,
+    arg: files::PreviewArg,
+}
+
+impl<C: UserAuthClient> Resumable for PreviewRequest<'_, C> {
+    type Result = files::FileMetadata;
+    type Error = PreviewError;
+    const WHAT: &'static str = "preview";
+
+    fn request(
+        &self,
+        offset: u64,
+    ) -> Result<HttpRequestResult<files::FileMetadata>, Error<PreviewError>> {
+        files::get_preview(
+            &self.client,
+            &self.arg,
+            (offset > 0).then_some(offset),
+            None,
+        )
+    }
+
+    fn is_temporary(error: &PreviewError) -> bool {
+        // Dropbox is still making the preview.
+        matches!(error, PreviewError::InProgress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::RetryOpts;
+    use crate::testing::tests::{file_json, MockClient, ProgressRecorder};
+    use crate::testing::FaultInjector;
+    use std::time::Duration;
+
+    #[test]
+    fn waits_and_resumes() {
+        let file = file_json("/f.docx", 100);
+        let client = FaultInjector::new(
+            MockClient::new("null")
+                .then(409, r#"{"error": {".tag": "in_progress"}}"#)
+                .then_download(file, "%PDF-1.7")
+                // Dropbox only sends the rest.
+                .then_download(file, "F-1.7"),
+        )
+        .truncate_body_every("files/get_preview", 2, 3);
+        let opts = StreamOpts {
+            retry: RetryOpts {
+                initial_backoff_time: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut preview = preview(&client, "/f.docx", &opts).unwrap();
+        let mut data = String::new();
+        preview.read_to_string(&mut data).unwrap();
+        assert_eq!("id:f.docx", preview.metadata.id);
+        assert_eq!("%PDF-1.7", data);
+        assert_eq!(3, client.inner().urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn reports_progress() {
+        let file = file_json("/f.docx", 100);
+        let client = FaultInjector::new(
+            MockClient::new("null")
+                .then_download(file, "%PDF-1.7")
+                .then_download(file, "1.7"),
+        )
+        .truncate_body_every("files/get_preview", 1, 5);
+        let opts = StreamOpts {
+            retry: RetryOpts {
+                initial_backoff_time: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let recorder = Arc::new(ProgressRecorder::default());
+
+        let mut preview = preview(&client, "/f.docx", &opts)
+            .unwrap()
+            .with_progress_handler(Arc::new(Box::new(recorder.clone())));
+        io::copy(&mut preview, &mut io::sink()).unwrap();
+        assert_eq!(vec![(5, 8, 0), (8, 8, 1)], recorder.updates());
+    }
+
+    #[test]
+    fn throttles_reads() {
+        let file = file_json("/f.docx", 100);
+        let client = MockClient::new("null").then_download(file, "%PDF-1.7 0123456");
+        let mut preview = preview(&client, "/f.docx", &StreamOpts::default())
+            .unwrap()
+            .with_max_bytes_per_sec(10);
+        let start = std::time::Instant::now();
+        io::copy(&mut preview, &mut io::sink()).unwrap();
+        // The first 10 bytes are allowed at once, and the rest take over half a second.
+        assert!(start.elapsed() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn reads_lines() {
+        let file = file_json("/f.docx", 100);
+        let client = MockClient::new("null").then_download(file, "<table>\n<tr>\n</table>\n");
+        let preview = preview(&client, "/f.csv", &StreamOpts::default()).unwrap();
+        let lines = preview.lines().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(vec!["<table>", "<tr>", "</table>"], lines);
+    }
+
+    #[test]
+    fn unsupported() {
+        let client =
+            MockClient::new("null").then(409, r#"{"error": {".tag": "unsupported_extension"}}"#);
+        let result = preview(&client, "/f.txt", &StreamOpts::default());
+        assert!(matches!(
+            result,
+            Err(Error::Api(PreviewError::UnsupportedExtension))
+        ));
+    }
+}
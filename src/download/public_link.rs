@@ -0,0 +1,376 @@
+//! Downloading files from public shared links, without any Dropbox credentials.
+
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+
+use dropbox_sdk::auth::RateLimitReason;
+use dropbox_sdk::Error;
+
+use crate::limits::EndpointKind;
+use crate::retry::{Backoff, RetryOpts};
+
+/// How much of a response to read at a time.
+const READ_SIZE: usize = 256 * 1024;
+
+/// How long to wait after being rate-limited by a response without a `Retry-After` header.
+const DEFAULT_RETRY_AFTER_SECONDS: u32 = 5;
+
+/// Convert a public shared link, such as `https://www.dropbox.com/s/...` or
+/// `https://www.dropbox.com/scl/fi/...`, to the URL its file can be downloaded from directly,
+/// rather than the web page for it.
+///
+/// Returns `None` if the URL isn't a shared link to a file.
+pub fn direct_download_url(url: &str) -> Option<String> {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))?;
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (host, rest) = rest.split_once('/')?;
+    if !matches!(host, "www.dropbox.com" | "dropbox.com" | "dl.dropbox.com") {
+        return None;
+    }
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+    if !(path.starts_with("s/") || path.starts_with("scl/")) {
+        return None;
+    }
+    let mut params = query
+        .split('&')
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or_default();
+            !param.is_empty() && name != "dl" && name != "raw"
+        })
+        .collect::<Vec<_>>();
+    params.push("dl=1");
+    Some(format!(
+        "https://www.dropbox.com/{path}?{}",
+        params.join("&")
+    ))
+}
+
+/// Download the file a public shared link is to, writing it to the given writer, without any
+/// Dropbox credentials. Returns how many bytes were written.
+///
+/// The link can be either the link as shared, which is converted with [`direct_download_url`], or
+/// a direct download URL already.
+///
+/// Requests which fail without a response, or with a server error, are retried with the given
+/// options, and rate limiting is waited out; if a response is cut off, the next request continues
+/// from where it got to. If the file changes in the meantime, according to its `ETag`, this fails
+/// with [`Error::UnexpectedResponse`] rather than mixing the two. Other errors, such as the link
+/// not existing or needing a password, are returned as [`Error::UnexpectedHttpError`].
+pub fn download_public_link(
+    url: &str,
+    dest: &mut impl Write,
+    retry: &RetryOpts,
+) -> Result<u64, Error> {
+    let url = direct_download_url(url).unwrap_or_else(|| url.to_owned());
+    let agent = agent();
+    fetch(dest, retry, |offset| get(&agent, &url, offset))
+}
+
+/// Make an HTTP client for downloading from URLs which don't need Dropbox credentials.
+pub(super) fn agent() -> ureq::Agent {
+    ureq::Agent::new_with_config(
+        ureq::Agent::config_builder()
+            .https_only(true)
+            .http_status_as_error(false)
+            .build(),
+    )
+}
+
+/// Request the data at the given URL from the given offset onwards.
+pub(super) fn get<E>(agent: &ureq::Agent, url: &str, offset: u64) -> Result<Response, Error<E>> {
+    let mut request = agent.get(url);
+    if offset > 0 {
+        request = request.header("Range", format!("bytes={offset}-"));
+    }
+    let response = request.call().map_err(|e| Error::HttpClient(Box::new(e)))?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let range_start = header("Content-Range").and_then(parse_range_start);
+    let etag = header("ETag").map(str::to_owned);
+    let retry_after = header("Retry-After").and_then(|value| value.trim().parse().ok());
+    let content_length = response.body().content_length();
+    Ok(Response {
+        status: response.status().as_u16(),
+        content_length,
+        range_start,
+        etag,
+        retry_after,
+        body: Box::new(response.into_body().into_reader()),
+    })
+}
+
+/// Get where the range a response is for starts from its `Content-Range` header, such as
+/// `bytes 100-199/1000`.
+fn parse_range_start(content_range: &str) -> Option<u64> {
+    let range = content_range.trim().strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.parse().ok()
+}
+
+/// A response to a request for a URL.
+pub(super) struct Response {
+    pub status: u16,
+    pub content_length: Option<u64>,
+
+    /// Where the range the response is for starts, for a partial response.
+    pub range_start: Option<u64>,
+
+    /// Identifies the version of the file the response is for.
+    pub etag: Option<String>,
+
+    /// How many seconds to wait before trying again, when rate-limited.
+    pub retry_after: Option<u32>,
+
+    pub body: Box<dyn Read>,
+}
+
+/// Download with the given function, which makes a request for the data from the given offset
+/// onwards, retrying until it has all been written.
+fn fetch(
+    dest: &mut impl Write,
+    retry: &RetryOpts,
+    request: impl FnMut(u64) -> Result<Response, Error>,
+) -> Result<u64, Error> {
+    fetch_url("public link", dest, retry, request)
+}
+
+/// Download what is described by `what` as with [`fetch`], with a function which can also fail
+/// with an API error. Such errors aren't retried.
+pub(super) fn fetch_url<E: Display>(
+    what: &str,
+    dest: &mut impl Write,
+    retry: &RetryOpts,
+    mut request: impl FnMut(u64) -> Result<Response, Error<E>>,
+) -> Result<u64, Error<E>> {
+    let what = format!("downloading {what}");
+    let mut backoff = Backoff::new(retry).for_endpoint(EndpointKind::Content);
+    let mut buf = vec![0u8; READ_SIZE];
+    let mut offset = 0;
+    let mut etag = None;
+    loop {
+        backoff.wait();
+        let mut response = match request(offset).and_then(check_status) {
+            Ok(response) => response,
+            Err(e @ (Error::UnexpectedHttpError { .. } | Error::Api(_))) => {
+                error!("Error {what}: {e}, failing.");
+                return Err(e);
+            }
+            Err(e) => {
+                backoff.handle(&what, e)?;
+                continue;
+            }
+        };
+        match (&etag, response.etag.take()) {
+            (Some(expected), Some(actual)) if *expected != actual => {
+                error!("Error {what}: the file changed from ETag {expected} to {actual}, failing.");
+                return Err(Error::UnexpectedResponse(format!(
+                    "the file changed while downloading it: expected ETag {expected}, got {actual}"
+                )));
+            }
+            (None, actual) => etag = actual,
+            _ => (),
+        }
+        if response.status == 206 && response.range_start != Some(offset) {
+            let e = Error::UnexpectedResponse(format!(
+                "partial response starts at {:?}, not at {offset}",
+                response.range_start
+            ));
+            backoff.handle(&what, e)?;
+            continue;
+        }
+        if offset > 0 && response.status == 200 {
+            // The range was ignored, so skip what has already been written.
+            debug!("Download restarted from the beginning; skipping {offset} bytes");
+            if let Err(e) = io::copy(&mut (&mut response.body).take(offset), &mut io::sink()) {
+                backoff.handle(&what, Error::HttpClient(Box::new(e)))?;
+                continue;
+            }
+            response.content_length = response
+                .content_length
+                .map(|len| len.saturating_sub(offset));
+        }
+        let end = response.content_length.map(|len| offset + len);
+        let read_error = loop {
+            match response.body.read(&mut buf) {
+                Ok(0) => break None,
+                Ok(n) => {
+                    dest.write_all(&buf[..n])
+                        .map_err(|e| Error::HttpClient(Box::new(e)))?;
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => break Some(e),
+            }
+        };
+        let e = match (read_error, end) {
+            (None, None) => return Ok(offset),
+            (None, Some(end)) if offset >= end => return Ok(offset),
+            (None, Some(end)) => {
+                Error::UnexpectedResponse(format!("response ended {} bytes early", end - offset))
+            }
+            (Some(e), _) => Error::HttpClient(Box::new(e)),
+        };
+        backoff.handle(&what, e)?;
+    }
+}
+
+/// Turn a response into an error if it wasn't successful.
+fn check_status<E>(response: Response) -> Result<Response, Error<E>> {
+    let status = response.status;
+    if status == 200 || status == 206 {
+        return Ok(response);
+    }
+    if status == 429 {
+        return Err(Error::RateLimited {
+            reason: RateLimitReason::TooManyRequests,
+            retry_after_seconds: response.retry_after.unwrap_or(DEFAULT_RETRY_AFTER_SECONDS),
+        });
+    }
+    let mut body = String::new();
+    if let Err(e) = response.body.take(64 * 1024).read_to_string(&mut body) {
+        return Err(Error::HttpClient(Box::new(e)));
+    }
+    if status >= 500 {
+        // These are worth retrying.
+        Err(Error::ServerError(format!("HTTP {status}: {body}")))
+    } else {
+        Err(Error::UnexpectedHttpError {
+            code: status,
+            response: body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn response(status: u16, content_length: Option<u64>, body: &'static str) -> Response {
+        Response {
+            status,
+            content_length,
+            range_start: None,
+            etag: None,
+            retry_after: None,
+            body: Box::new(body.as_bytes()),
+        }
+    }
+
+    fn partial(start: u64, content_length: u64, body: &'static str) -> Response {
+        Response {
+            range_start: Some(start),
+            ..response(206, Some(content_length), body)
+        }
+    }
+
+    fn retry() -> RetryOpts {
+        RetryOpts {
+            initial_backoff_time: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn direct_urls() {
+        assert_eq!(
+            Some("https://www.dropbox.com/s/abc/f.txt?dl=1".to_owned()),
+            direct_download_url("https://www.dropbox.com/s/abc/f.txt?dl=0")
+        );
+        assert_eq!(
+            Some("https://www.dropbox.com/scl/fi/abc/f.txt?rlkey=xyz&st=1&dl=1".to_owned()),
+            direct_download_url("https://www.dropbox.com/scl/fi/abc/f.txt?rlkey=xyz&raw=1&st=1#x")
+        );
+        assert_eq!(
+            Some("https://www.dropbox.com/s/abc/f.txt?dl=1".to_owned()),
+            direct_download_url("http://dropbox.com/s/abc/f.txt")
+        );
+        assert_eq!(
+            None,
+            direct_download_url("https://www.dropbox.com/home/f.txt")
+        );
+        assert_eq!(None, direct_download_url("https://example.com/s/abc/f.txt"));
+    }
+
+    #[test]
+    fn resumes_cut_off_responses() {
+        let mut responses = vec![
+            response(200, Some(6), "ab"),
+            response(503, None, "unavailable"),
+            Response {
+                retry_after: Some(0),
+                ..response(429, None, "")
+            },
+            partial(0, 6, "abcdef"),
+            partial(2, 4, "cdef"),
+        ]
+        .into_iter();
+        let mut offsets = vec![];
+        let mut data = vec![];
+        let retry = RetryOpts {
+            retry_count: 4,
+            ..retry()
+        };
+        let len = fetch(&mut data, &retry, |offset| {
+            offsets.push(offset);
+            Ok(responses.next().unwrap())
+        })
+        .unwrap();
+        assert_eq!(6, len);
+        assert_eq!(b"abcdef", data.as_slice());
+        // The rate limit is waited out, and the response for the wrong range is retried.
+        assert_eq!(vec![0, 2, 2, 2, 2], offsets);
+    }
+
+    #[test]
+    fn fails_if_file_changes() {
+        let mut responses = vec![
+            Response {
+                etag: Some("1".to_owned()),
+                ..response(200, Some(6), "ab")
+            },
+            Response {
+                etag: Some("2".to_owned()),
+                ..partial(2, 4, "xyzw")
+            },
+        ]
+        .into_iter();
+        let mut data = vec![];
+        let result = fetch(&mut data, &retry(), |_| Ok(responses.next().unwrap()));
+        assert!(matches!(result, Err(Error::UnexpectedResponse(_))));
+        assert_eq!(b"ab", data.as_slice());
+        assert_eq!(Some(6), parse_range_start("bytes 6-9/10"));
+    }
+
+    #[test]
+    fn skips_data_when_range_is_ignored() {
+        let mut responses = vec![
+            response(200, Some(6), "abc"),
+            response(200, Some(6), "abcdef"),
+        ]
+        .into_iter();
+        let mut data = vec![];
+        fetch(&mut data, &retry(), |_| Ok(responses.next().unwrap())).unwrap();
+        assert_eq!(b"abcdef", data.as_slice());
+    }
+
+    #[test]
+    fn missing_link_fails() {
+        let mut requests = 0;
+        let result = fetch(&mut vec![], &retry(), |_| {
+            requests += 1;
+            Ok(response(404, None, "not found"))
+        });
+        assert!(matches!(
+            result,
+            Err(Error::UnexpectedHttpError { code: 404, .. })
+        ));
+        assert_eq!(1, requests);
+    }
+}
@@ -0,0 +1,281 @@
+//! Downloading many files at once, such as to restore them.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use dropbox_sdk::files;
+use dropbox_sdk::BoxedError;
+use dropbox_sdk::UserAuthClient;
+
+use super::aggregate::{track_file, TotalProgress};
+use super::save::{save, Saved};
+use super::work::parallel_map;
+use super::{DownloadError, DownloadOpts, DownloadProgress, DownloadProgressHandler};
+
+/// Options for a [`Downloader`].
+#[derive(Clone)]
+pub struct DownloaderOpts {
+    /// Options for downloading each file. Its progress handler, if any, is called for each file
+    /// separately.
+    pub file: DownloadOpts,
+
+    /// How many files to download at once. Each of them uses the
+    /// [`parallelism`](DownloadOpts::parallelism) of the file options.
+    pub parallelism: usize,
+
+    /// An optional callback to periodically receive progress updates for all the files together.
+    /// Since the size of each file is only known once it starts, the total grows as they do.
+    pub progress_handler: Option<Arc<Box<dyn DownloadProgressHandler>>>,
+
+    /// An optional callback to receive the progress of each file, by its path.
+    pub file_handler: Option<Arc<Box<dyn DownloaderFileHandler>>>,
+}
+
+impl Default for DownloaderOpts {
+    fn default() -> Self {
+        Self {
+            file: DownloadOpts::default(),
+            parallelism: 4,
+            progress_handler: None,
+            file_handler: None,
+        }
+    }
+}
+
+/// Implement to receive the progress of each file downloaded by a [`Downloader`].
+pub trait DownloaderFileHandler: Sync + Send {
+    /// Invoked periodically while the file from the given Dropbox path downloads.
+    fn progress(&self, source_path: &str, progress: &DownloadProgress) {
+        let _ = (source_path, progress);
+    }
+
+    /// Invoked when the file from the given Dropbox path has finished downloading, or failed.
+    fn finished(&self, entry: &DownloaderEntry) {
+        let _ = entry;
+    }
+}
+
+/// A queue of files to download, from Dropbox paths to local paths.
+///
+/// The files are downloaded [`parallelism`](DownloaderOpts::parallelism) at a time, each as with
+/// [`download_to_path`](super::download_to_path). Unlike `download_dir`, one file failing doesn't
+/// stop the others; the [summary](DownloadSummary) says which ones failed.
+pub struct Downloader {
+    opts: DownloaderOpts,
+    files: Vec<(String, PathBuf)>,
+}
+
+impl Downloader {
+    /// Make an empty queue.
+    pub fn new(opts: DownloaderOpts) -> Self {
+        Self {
+            opts,
+            files: vec![],
+        }
+    }
+
+    /// Add a file to the queue, to be downloaded from the given Dropbox path (or `id:` or `rev:`)
+    /// to the given local path. The local directory it is in is created if it doesn't exist.
+    pub fn add(&mut self, source_path: impl Into<String>, dest_path: impl Into<PathBuf>) {
+        self.files.push((source_path.into(), dest_path.into()));
+    }
+
+    /// How many files are in the queue.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Whether the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Download all the files in the queue.
+    pub fn run<C: UserAuthClient + Sync>(&self, client: &C) -> DownloadSummary {
+        let opts = &self.opts;
+        let progress =
+            TotalProgress::new(None, opts.parallelism.max(1), opts.progress_handler.clone());
+        let entries = parallel_map(&self.files, opts.parallelism, |(source_path, dest_path)| {
+            let result = self.download_file(client, source_path, dest_path, &progress);
+            if let Err(e) = &result {
+                error!("Failed to download {source_path}: {e}");
+            }
+            let skipped = matches!(result, Ok(Saved { skipped: true, .. }));
+            let entry = DownloaderEntry {
+                source_path: source_path.clone(),
+                dest_path: dest_path.clone(),
+                result: result.map(|saved| saved.metadata),
+                skipped,
+            };
+            if let Some(handler) = &opts.file_handler {
+                handler.finished(&entry);
+            }
+            entry
+        });
+        DownloadSummary { entries }
+    }
+
+    fn download_file(
+        &self,
+        client: &(impl UserAuthClient + Sync),
+        source_path: &str,
+        dest_path: &Path,
+        progress: &Arc<TotalProgress>,
+    ) -> Result<Saved, DownloadError> {
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| BoxedError::HttpClient(e.into()))?;
+        }
+        info!("Downloading {source_path} to {}", dest_path.display());
+        let mut file_opts = self.opts.file.clone();
+        if let Some(handler) = &self.opts.file_handler {
+            file_opts.progress_handler = Some(Arc::new(Box::new(PathProgress {
+                source_path: source_path.to_owned(),
+                handler: Arc::clone(handler),
+                inner: file_opts.progress_handler.take(),
+            })));
+        }
+        let file_opts = track_file(&file_opts, progress);
+        let arg = files::DownloadArg::new(source_path.to_owned());
+        save(client, &arg, dest_path, &file_opts)
+    }
+}
+
+/// Reports the progress of a file to a [`DownloaderFileHandler`], with its path.
+struct PathProgress {
+    source_path: String,
+    handler: Arc<Box<dyn DownloaderFileHandler>>,
+    inner: Option<Arc<Box<dyn DownloadProgressHandler>>>,
+}
+
+impl DownloadProgressHandler for PathProgress {
+    fn progress(&self, progress: &DownloadProgress) {
+        if let Some(inner) = &self.inner {
+            inner.progress(progress);
+        }
+        self.handler.progress(&self.source_path, progress);
+    }
+}
+
+/// A file downloaded, or not, by a [`Downloader`].
+#[derive(Debug)]
+pub struct DownloaderEntry {
+    /// The Dropbox path it was downloaded from.
+    pub source_path: String,
+
+    /// The local path it was written to.
+    pub dest_path: PathBuf,
+
+    /// The revision which was downloaded, or why it couldn't be.
+    pub result: Result<files::FileMetadata, DownloadError>,
+
+    /// Whether the local file was already the same, so it wasn't downloaded, with
+    /// [`skip_if_identical`](DownloadOpts::skip_if_identical).
+    pub skipped: bool,
+}
+
+/// What happened to each of the files in a [`Downloader`], in the order they were added.
+#[derive(Debug)]
+pub struct DownloadSummary {
+    /// The files, in the order they were added.
+    pub entries: Vec<DownloaderEntry>,
+}
+
+impl DownloadSummary {
+    /// The files which were downloaded, or skipped because they were already there.
+    pub fn succeeded(&self) -> impl Iterator<Item = &DownloaderEntry> {
+        self.entries.iter().filter(|entry| entry.result.is_ok())
+    }
+
+    /// The files which were skipped because they were already there.
+    pub fn skipped(&self) -> impl Iterator<Item = &DownloaderEntry> {
+        self.entries.iter().filter(|entry| entry.skipped)
+    }
+
+    /// The files which couldn't be downloaded.
+    pub fn failed(&self) -> impl Iterator<Item = &DownloaderEntry> {
+        self.entries.iter().filter(|entry| entry.result.is_err())
+    }
+
+    /// Whether all the files were downloaded.
+    pub fn is_success(&self) -> bool {
+        self.failed().next().is_none()
+    }
+
+    /// The total size of the files which were downloaded, not counting any skipped.
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.entries
+            .iter()
+            .filter(|entry| !entry.skipped)
+            .filter_map(|entry| entry.result.as_ref().ok())
+            .map(|metadata| metadata.size)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::{file_json, file_json_with, MockClient, ProgressRecorder};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Finished(Mutex<Vec<(String, bool)>>);
+
+    impl DownloaderFileHandler for Arc<Finished> {
+        fn finished(&self, entry: &DownloaderEntry) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((entry.source_path.clone(), entry.result.is_ok()));
+        }
+    }
+
+    #[test]
+    fn continues_after_failure() {
+        let file_a = file_json("/a", 2);
+        let file_c = file_json_with("/c", 3, "ccccccccc", None);
+        let client = MockClient::new("null")
+            .then(200, file_a)
+            .then_download(file_a, "aa")
+            .then(
+                409,
+                r#"{"error": {".tag": "path", "path": {".tag": "not_found"}}}"#,
+            )
+            .then(200, file_c)
+            .then_download(file_c, "ccc");
+        let dest_dir = std::env::temp_dir().join(format!("queue_test_{}", std::process::id()));
+        let recorder = Arc::new(ProgressRecorder::default());
+        let finished = Arc::new(Finished::default());
+        let mut downloader = Downloader::new(DownloaderOpts {
+            parallelism: 1,
+            progress_handler: Some(Arc::new(Box::new(Arc::clone(&recorder)))),
+            file_handler: Some(Arc::new(Box::new(Arc::clone(&finished)))),
+            ..Default::default()
+        });
+        downloader.add("/a", dest_dir.join("a"));
+        downloader.add("/b", dest_dir.join("b"));
+        downloader.add("/c", dest_dir.join("sub/c"));
+
+        let summary = downloader.run(&client);
+        let a = fs::read_to_string(dest_dir.join("a")).unwrap();
+        let c = fs::read_to_string(dest_dir.join("sub/c")).unwrap();
+        fs::remove_dir_all(&dest_dir).unwrap();
+
+        assert!(!summary.is_success());
+        assert_eq!(5, summary.bytes_downloaded());
+        let failed = summary.failed().map(|e| &e.source_path).collect::<Vec<_>>();
+        assert_eq!(vec!["/b"], failed);
+        assert_eq!("aa", a);
+        assert_eq!("ccc", c);
+        assert_eq!(vec![(2, 2, 0), (5, 5, 0)], recorder.updates());
+        assert_eq!(
+            vec![
+                ("/a".to_owned(), true),
+                ("/b".to_owned(), false),
+                ("/c".to_owned(), true)
+            ],
+            *finished.0.lock().unwrap()
+        );
+    }
+}
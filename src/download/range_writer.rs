@@ -0,0 +1,292 @@
+//! Writing ranges of a file which are downloaded in parallel, and so can arrive in any order.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// A destination for ranges of a download, which can be written in any order and from multiple
+/// threads at once.
+pub trait RangeWriter: Sync + Send {
+    /// Write the given data at the given offset from the start of the file.
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()>;
+}
+
+/// Writes ranges directly to their positions in a file.
+pub struct FileRangeWriter {
+    file: File,
+}
+
+impl FileRangeWriter {
+    /// Write to the given file, which must be open for writing.
+    pub fn new(file: File) -> Self {
+        Self { file }
+    }
+
+    /// Get back the file.
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+}
+
+impl RangeWriter for FileRangeWriter {
+    #[cfg(unix)]
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.file.write_all_at(data, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&self, mut offset: u64, mut data: &[u8]) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !data.is_empty() {
+            match self.file.seek_write(data, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    data = &data[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes ranges to a stream in order, for consumers which can't seek, such as a pipe or a
+/// channel.
+///
+/// Ranges which arrive before the ones preceding them are held in memory until they can be
+/// written, so the amount buffered depends on how far out of order they arrive.
+pub struct OrderedWriter<W> {
+    state: Mutex<OrderedState<W>>,
+}
+
+struct OrderedState<W> {
+    inner: W,
+    next_offset: u64,
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<W: Write + Send> OrderedWriter<W> {
+    /// Write to the given stream, starting at the given offset of the file.
+    pub fn new(inner: W, start_offset: u64) -> Self {
+        Self {
+            state: Mutex::new(OrderedState {
+                inner,
+                next_offset: start_offset,
+                pending: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// The offset up to which data has been written to the stream.
+    pub fn written_up_to(&self) -> u64 {
+        self.state.lock().unwrap().next_offset
+    }
+
+    /// Get back the stream. Any ranges still waiting for earlier ones are discarded.
+    pub fn into_inner(self) -> W {
+        self.state.into_inner().unwrap().inner
+    }
+}
+
+impl<W: Write + Send> RangeWriter for OrderedWriter<W> {
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if offset != state.next_offset {
+            if offset < state.next_offset {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("range at {offset} was already written"),
+                ));
+            }
+            state.pending.insert(offset, data.to_vec());
+            return Ok(());
+        }
+        state.inner.write_all(data)?;
+        state.next_offset += data.len() as u64;
+        while let Some(data) = {
+            let next = state.next_offset;
+            state.pending.remove(&next)
+        } {
+            state.inner.write_all(&data)?;
+            state.next_offset += data.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps track of which ranges of a file have been written, so that a partial download can be
+/// completed later by only fetching the rest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RangeTracker {
+    len: u64,
+    done: BTreeMap<u64, u64>, // start -> end, with no overlapping or adjacent ranges
+}
+
+impl RangeTracker {
+    /// Track a file of the given length, with nothing written yet.
+    pub fn new(len: u64) -> Self {
+        Self {
+            len,
+            done: BTreeMap::new(),
+        }
+    }
+
+    /// Track a file of the given length, of which the given ranges have already been written,
+    /// such as from [`RangeTracker::done`] of a previous attempt.
+    pub fn resume(len: u64, done: impl IntoIterator<Item = Range<u64>>) -> Self {
+        let mut tracker = Self::new(len);
+        for range in done {
+            tracker.mark_done(range);
+        }
+        tracker
+    }
+
+    /// The length of the file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Record that the given range has been written.
+    pub fn mark_done(&mut self, range: Range<u64>) {
+        let Range { mut start, mut end } = range;
+        if start >= end {
+            return;
+        }
+        // Absorb any ranges which overlap or touch this one.
+        if let Some((&prev_start, &prev_end)) = self.done.range(..=start).next_back() {
+            if prev_end >= start {
+                start = prev_start;
+                end = end.max(prev_end);
+            }
+        }
+        while let Some((&next_start, &next_end)) = self.done.range(start..).next() {
+            if next_start > end {
+                break;
+            }
+            end = end.max(next_end);
+            self.done.remove(&next_start);
+        }
+        self.done.insert(start, end);
+    }
+
+    /// The ranges which have been written, in increasing order.
+    pub fn done(&self) -> Vec<Range<u64>> {
+        self.done.iter().map(|(&start, &end)| start..end).collect()
+    }
+
+    /// The ranges which still need to be written, in increasing order.
+    pub fn missing(&self) -> Vec<Range<u64>> {
+        let mut missing = vec![];
+        let mut pos = 0;
+        for (&start, &end) in &self.done {
+            if start > pos {
+                missing.push(pos..start.min(self.len));
+            }
+            pos = end;
+        }
+        if pos < self.len {
+            missing.push(pos..self.len);
+        }
+        missing.retain(|range| !range.is_empty());
+        missing
+    }
+
+    /// Whether the whole file has been written.
+    pub fn is_complete(&self) -> bool {
+        self.missing().is_empty()
+    }
+}
+
+/// A [`RangeWriter`] which keeps track of the ranges successfully written to another one.
+pub struct TrackingWriter<W> {
+    inner: W,
+    tracker: Mutex<RangeTracker>,
+}
+
+impl<W: RangeWriter> TrackingWriter<W> {
+    /// Write to the given writer, starting with the given state of the file.
+    pub fn new(inner: W, tracker: RangeTracker) -> Self {
+        Self {
+            inner,
+            tracker: Mutex::new(tracker),
+        }
+    }
+
+    /// The current state of the file.
+    pub fn tracker(&self) -> RangeTracker {
+        self.tracker.lock().unwrap().clone()
+    }
+
+    /// Get back the writer and the state of the file.
+    pub fn into_inner(self) -> (W, RangeTracker) {
+        (self.inner, self.tracker.into_inner().unwrap())
+    }
+}
+
+impl<W: RangeWriter> RangeWriter for TrackingWriter<W> {
+    fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.inner.write_at(offset, data)?;
+        self.tracker
+            .lock()
+            .unwrap()
+            .mark_done(offset..offset + data.len() as u64);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordered_writer() {
+        let writer = OrderedWriter::new(vec![], 0);
+        writer.write_at(3, b"def").unwrap();
+        writer.write_at(6, b"gh").unwrap();
+        assert_eq!(0, writer.written_up_to());
+        writer.write_at(0, b"abc").unwrap();
+        assert_eq!(8, writer.written_up_to());
+        assert!(writer.write_at(0, b"abc").is_err());
+        assert_eq!(b"abcdefgh", &writer.into_inner()[..]);
+    }
+
+    #[test]
+    fn range_tracker() {
+        let mut tracker = RangeTracker::new(100);
+        assert_eq!(vec![0..100], tracker.missing());
+        tracker.mark_done(10..20);
+        tracker.mark_done(30..40);
+        tracker.mark_done(20..25);
+        assert_eq!(vec![10..25, 30..40], tracker.done());
+        assert_eq!(vec![0..10, 25..30, 40..100], tracker.missing());
+        tracker.mark_done(5..35);
+        assert_eq!(vec![5..40], tracker.done());
+
+        let mut tracker = RangeTracker::resume(100, tracker.done());
+        tracker.mark_done(0..5);
+        tracker.mark_done(40..100);
+        assert!(tracker.is_complete());
+        assert_eq!(vec![0..100], tracker.done());
+    }
+
+    #[test]
+    fn tracking_writer() {
+        let writer = TrackingWriter::new(OrderedWriter::new(vec![], 0), RangeTracker::new(6));
+        writer.write_at(3, b"def").unwrap();
+        assert_eq!(vec![0..3], writer.tracker().missing());
+        writer.write_at(0, b"abc").unwrap();
+        let (inner, tracker) = writer.into_inner();
+        assert!(tracker.is_complete());
+        assert_eq!(b"abcdef", &inner.into_inner()[..]);
+    }
+}
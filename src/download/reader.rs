@@ -0,0 +1,321 @@
+//! Reading a file as it downloads in ranges, requesting the next ones ahead of time.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use dropbox_sdk::files;
+use dropbox_sdk::{BoxedError, UserAuthClient};
+
+use super::parallel::{
+    check_size, chunk_size, fetch_range, io_error, lookup_file, verify_file, Counters,
+    DownloadError, FetchedRange,
+};
+use super::DownloadOpts;
+use crate::content_hash;
+use crate::throttle::Throttle;
+
+/// A file being downloaded in ranges of [`chunk_size`](DownloadOpts::chunk_size), which can be
+/// read in order, like a single download.
+///
+/// While one range is being read, the next [`prefetch`](DownloadOpts::prefetch) ranges are
+/// requested by background threads, which hides the time taken by each request from readers which
+/// read in bursts. Every range is requested from the revision the file was at when it was opened,
+/// and retried and [verified](DownloadOpts::verify) as with
+/// [`download_to_file`](super::download_to_file). Any bandwidth limit applies to reading, so
+/// ranges are requested ahead of time at full speed, but no more than `prefetch` of them.
+///
+/// If a range fails, reading fails with its error, which can be downcast to a [`DownloadError`]
+/// unless it is an I/O error, and then with [`io::ErrorKind::BrokenPipe`]. Ranges already
+/// requested when the reader is dropped are still downloaded, and then discarded.
+pub struct DownloadReader<C> {
+    client: Arc<C>,
+    metadata: Arc<files::FileMetadata>,
+    opts: Arc<DownloadOpts>,
+    counters: Arc<Counters>,
+    throttle: Option<Throttle>,
+    chunk_size: u64,
+
+    /// Where the next range to request starts.
+    next_start: u64,
+
+    /// The ranges requested and not read yet, in order.
+    pending: VecDeque<(u64, JoinHandle<Result<FetchedRange, DownloadError>>)>,
+
+    /// The range being read.
+    current: Vec<u8>,
+    pos: usize,
+
+    block_hashes: BTreeMap<u64, Vec<[u8; content_hash::OUTPUT_SIZE]>>,
+    done: bool,
+    failed: bool,
+}
+
+impl<C: UserAuthClient + Send + Sync + 'static> DownloadReader<C> {
+    /// Look up the file to download, and start requesting its first ranges.
+    pub fn open(
+        client: Arc<C>,
+        arg: &files::DownloadArg,
+        opts: &DownloadOpts,
+    ) -> Result<Self, DownloadError> {
+        check_size("chunk size", opts.chunk_size)?;
+        let metadata = lookup_file(client.as_ref(), arg, opts)?;
+        let unthrottled = DownloadOpts {
+            max_bytes_per_sec: None,
+            bandwidth_schedule: None,
+            ..opts.clone()
+        };
+        let mut reader = Self {
+            client,
+            counters: Arc::new(Counters::new(metadata.size, &unthrottled)),
+            throttle: Throttle::for_content(
+                opts.max_bytes_per_sec,
+                opts.bandwidth_schedule.as_ref(),
+            ),
+            metadata: Arc::new(metadata),
+            opts: Arc::new(opts.clone()),
+            chunk_size: chunk_size(opts),
+            next_start: 0,
+            pending: VecDeque::new(),
+            current: vec![],
+            pos: 0,
+            block_hashes: BTreeMap::new(),
+            done: false,
+            failed: false,
+        };
+        reader.request_more();
+        Ok(reader)
+    }
+
+    /// The metadata of the revision being downloaded.
+    pub fn metadata(&self) -> &files::FileMetadata {
+        &self.metadata
+    }
+
+    /// The revision being downloaded, which can be remembered to tell later whether the file has
+    /// changed since, or downloaded again with a `rev:` path.
+    pub fn rev(&self) -> &str {
+        &self.metadata.rev
+    }
+
+    /// The [Content Hash](crate::content_hash) of the revision being downloaded, if Dropbox gave
+    /// one, which can be compared with that of a local copy.
+    pub fn content_hash(&self) -> Option<&str> {
+        self.metadata.content_hash.as_deref()
+    }
+
+    /// Request ranges until the one being read and `prefetch` more are requested.
+    fn request_more(&mut self) {
+        while self.pending.len() <= self.opts.prefetch && self.next_start < self.metadata.size {
+            let start = self.next_start;
+            let range = start..self.metadata.size.min(start + self.chunk_size);
+            self.next_start = range.end;
+            let client = Arc::clone(&self.client);
+            let metadata = Arc::clone(&self.metadata);
+            let counters = Arc::clone(&self.counters);
+            let opts = Arc::clone(&self.opts);
+            let handle = thread::spawn(move || {
+                fetch_range(client.as_ref(), &metadata, range, &counters, &opts)
+            });
+            self.pending.push_back((start, handle));
+        }
+    }
+
+    /// Move on to the next range, returning false at the end of the file.
+    fn next_range(&mut self) -> Result<bool, DownloadError> {
+        let Some((start, handle)) = self.pending.pop_front() else {
+            if self.opts.verify {
+                verify_file(&self.metadata, std::mem::take(&mut self.block_hashes))?;
+            }
+            return Ok(false);
+        };
+        let fetched = handle.join().map_err(|_| {
+            BoxedError::HttpClient(Box::new(io::Error::other("range download panicked")))
+        })??;
+        if let Some(hashes) = fetched.block_hashes {
+            self.block_hashes.insert(start, hashes);
+        }
+        self.current = fetched.data;
+        self.pos = 0;
+        self.request_more();
+        Ok(true)
+    }
+}
+
+impl<C: UserAuthClient + Send + Sync + 'static> Read for DownloadReader<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "download failed"));
+        }
+        while self.pos == self.current.len() {
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+            match self.next_range() {
+                Ok(true) => (),
+                Ok(false) => self.done = true,
+                Err(e) => {
+                    self.failed = true;
+                    return Err(io_error(e));
+                }
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        if let Some(throttle) = &self.throttle {
+            throttle.acquire(n as u64);
+        }
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::{file_json, file_json_with, MockClient};
+    use dropbox_sdk::client_trait::{HttpClient, HttpRequest, HttpRequestResultRaw};
+    use dropbox_sdk::Error;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    /// A client which responds to requests for ranges of a file with those ranges, whatever order
+    /// they are requested in, and records them. Requests without a range get the file's metadata.
+    struct RangeClient {
+        file: &'static str,
+        data: &'static [u8],
+        ranges: Mutex<Vec<String>>,
+    }
+
+    struct RangeRequest(Option<String>);
+
+    impl HttpRequest for RangeRequest {
+        fn set_header(self, name: &str, value: &str) -> Self {
+            match name {
+                "Range" => Self(Some(value.to_owned())),
+                _ => self,
+            }
+        }
+    }
+
+    impl HttpClient for RangeClient {
+        type Request = RangeRequest;
+
+        fn execute(
+            &self,
+            request: Self::Request,
+            _body: &[u8],
+        ) -> Result<HttpRequestResultRaw, Error> {
+            let Some(range) = request.0 else {
+                return Ok(HttpRequestResultRaw {
+                    status: 200,
+                    result_header: None,
+                    content_length: Some(self.file.len() as u64),
+                    body: Box::new(self.file.as_bytes()),
+                });
+            };
+            let (start, end) = range
+                .strip_prefix("bytes=")
+                .and_then(|range| range.split_once('-'))
+                .unwrap();
+            let data = &self.data[start.parse().unwrap()..=end.parse().unwrap()];
+            self.ranges.lock().unwrap().push(range);
+            Ok(HttpRequestResultRaw {
+                status: 200,
+                result_header: Some(self.file.to_owned()),
+                content_length: Some(data.len() as u64),
+                body: Box::new(data),
+            })
+        }
+
+        fn new_request(&self, _url: &str) -> Self::Request {
+            RangeRequest(None)
+        }
+
+        fn token(&self) -> Option<Arc<String>> {
+            Some(Arc::new("token".to_owned()))
+        }
+    }
+
+    impl UserAuthClient for RangeClient {}
+
+    #[test]
+    fn prefetches_ranges() {
+        let client = Arc::new(RangeClient {
+            file: file_json("/f", 6),
+            data: b"abcdef",
+            ranges: Mutex::new(vec![]),
+        });
+        let opts = DownloadOpts {
+            chunk_size: 2,
+            prefetch: 2,
+            ..Default::default()
+        };
+        let arg = files::DownloadArg::new("/f".to_owned());
+        let mut reader = DownloadReader::open(Arc::clone(&client), &arg, &opts).unwrap();
+        let mut first = [0; 1];
+        reader.read_exact(&mut first).unwrap();
+        // All three ranges are requested before the first is finished with.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while client.ranges.lock().unwrap().len() < 3 {
+            assert!(Instant::now() < deadline, "ranges weren't prefetched");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut ranges = client.ranges.lock().unwrap().clone();
+        ranges.sort();
+        assert_eq!(vec!["bytes=0-1", "bytes=2-3", "bytes=4-5"], ranges);
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!("abcdef", format!("{}{rest}", first[0] as char));
+        assert_eq!(6, reader.metadata().size);
+        assert_eq!("aaaaaaaaa", reader.rev());
+        assert_eq!(None, reader.content_hash());
+    }
+
+    #[test]
+    fn throttles_reads() {
+        let file = file_json("/f", 15);
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(200, file)
+                .then_download(file, "abcdefghijklmno"),
+        );
+        let opts = DownloadOpts {
+            max_bytes_per_sec: Some(10),
+            ..Default::default()
+        };
+        let arg = files::DownloadArg::new("/f".to_owned());
+        let mut reader = DownloadReader::open(client, &arg, &opts).unwrap();
+        let start = Instant::now();
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        // The first 10 bytes are allowed at once, and the rest take half a second.
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(b"abcdefghijklmno", data.as_slice());
+    }
+
+    #[test]
+    fn verifies() {
+        let hash = "0".repeat(64);
+        let file = file_json_with("/f", 6, "aaaaaaaaa", Some(&hash));
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(200, file)
+                .then_download(file, "abcdef"),
+        );
+        let opts = DownloadOpts {
+            verify: true,
+            ..Default::default()
+        };
+        let arg = files::DownloadArg::new("/f".to_owned());
+        let mut reader = DownloadReader::open(client, &arg, &opts).unwrap();
+        let e = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert!(e.to_string().contains("hash"), "{e}");
+        assert_eq!(
+            io::ErrorKind::BrokenPipe,
+            reader.read(&mut [0; 1]).unwrap_err().kind()
+        );
+    }
+}
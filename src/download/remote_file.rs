@@ -0,0 +1,184 @@
+//! Reading parts of a file in Dropbox as they are needed, without downloading all of it.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use dropbox_sdk::files;
+use dropbox_sdk::UserAuthClient;
+
+use super::parallel::{check_size, fetch_range, io_error, lookup_file, Counters, DownloadError};
+use super::DownloadOpts;
+
+/// Options for a [`RemoteFile`].
+#[derive(Clone)]
+pub struct RemoteFileOpts {
+    /// How many bytes to request at a time. Each request is for a whole block, even if less of it
+    /// is read. Zero is an invalid argument.
+    pub block_size: u64,
+
+    /// How many of the blocks most recently read to keep in memory, so that reading them again
+    /// doesn't need another request.
+    pub cache_blocks: usize,
+
+    /// Options for the requests, such as how to retry them. Since only parts of the file are
+    /// downloaded, it can't be [verified](DownloadOpts::verify), and the options for dividing it
+    /// into ranges aren't used.
+    pub download: DownloadOpts,
+}
+
+impl Default for RemoteFileOpts {
+    fn default() -> Self {
+        Self {
+            block_size: 256 * 1024,
+            cache_blocks: 16,
+            download: DownloadOpts::default(),
+        }
+    }
+}
+
+/// A file in Dropbox which can be read from anywhere, like a local file, by downloading blocks of
+/// it as they are read. This lets libraries which read only parts of large files, such as archive
+/// readers, read them from Dropbox without downloading all of them.
+///
+/// Every block is requested from the revision the file was at when it was opened. If a request
+/// fails, reading fails with its error, which can be downcast to a
+/// [`DownloadError`](super::DownloadError) unless it is an I/O error, and can be tried again.
+pub struct RemoteFile<'a, C> {
+    client: &'a C,
+    metadata: files::FileMetadata,
+    opts: RemoteFileOpts,
+    counters: Counters,
+    pos: u64,
+
+    /// The blocks read most recently, by their index, with the most recent last.
+    cache: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl<'a, C: UserAuthClient + Sync> RemoteFile<'a, C> {
+    /// Look up the file to read.
+    pub fn open(
+        client: &'a C,
+        arg: &files::DownloadArg,
+        opts: &RemoteFileOpts,
+    ) -> Result<Self, DownloadError> {
+        check_size("block size", opts.block_size)?;
+        let mut opts = opts.clone();
+        opts.download.verify = false;
+        let metadata = lookup_file(client, arg, &opts.download)?;
+        Ok(Self {
+            client,
+            counters: Counters::new(metadata.size, &opts.download),
+            metadata,
+            opts,
+            pos: 0,
+            cache: VecDeque::new(),
+        })
+    }
+
+    /// The metadata of the revision being read.
+    pub fn metadata(&self) -> &files::FileMetadata {
+        &self.metadata
+    }
+
+    /// Get the block with the given index, from the cache or by downloading it.
+    fn block(&mut self, index: u64) -> Result<&[u8], DownloadError> {
+        if let Some(i) = self.cache.iter().position(|(cached, _)| *cached == index) {
+            let block = self.cache.remove(i).unwrap();
+            self.cache.push_back(block);
+        } else {
+            let start = index * self.opts.block_size;
+            let range = start..self.metadata.size.min(start + self.opts.block_size);
+            let fetched = fetch_range(
+                self.client,
+                &self.metadata,
+                range,
+                &self.counters,
+                &self.opts.download,
+            )?;
+            if self.cache.len() >= self.opts.cache_blocks.max(1) {
+                self.cache.pop_front();
+            }
+            self.cache.push_back((index, fetched.data));
+        }
+        Ok(&self.cache.back().unwrap().1)
+    }
+}
+
+impl<C: UserAuthClient + Sync> Read for RemoteFile<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.metadata.size {
+            return Ok(0);
+        }
+        let block_size = self.opts.block_size;
+        let offset = (self.pos % block_size) as usize;
+        let block = self.block(self.pos / block_size).map_err(io_error)?;
+        let n = buf.len().min(block.len() - offset);
+        buf[..n].copy_from_slice(&block[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<C> Seek for RemoteFile<'_, C> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.metadata.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::{file_json, MockClient};
+
+    #[test]
+    fn seeks_and_caches_blocks() {
+        let file = file_json("/f", 10);
+        let client = MockClient::new("null")
+            .then(200, file)
+            .then_download(file, "ij")
+            .then_download(file, "abcd")
+            .then_download(file, "efgh");
+        let opts = RemoteFileOpts {
+            block_size: 4,
+            cache_blocks: 2,
+            ..Default::default()
+        };
+        let arg = files::DownloadArg::new("/f".to_owned());
+        let mut file = RemoteFile::open(&client, &arg, &opts).unwrap();
+        let mut buf = [0; 3];
+
+        assert_eq!(8, file.seek(SeekFrom::End(-2)).unwrap());
+        assert_eq!(2, file.read(&mut buf).unwrap());
+        assert_eq!(b"ij", &buf[..2]);
+        assert_eq!(0, file.read(&mut buf).unwrap());
+
+        file.rewind().unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(b"abc", &buf);
+
+        // Both blocks are still cached.
+        file.seek(SeekFrom::Current(6)).unwrap();
+        file.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(b'j', buf[0]);
+        file.seek(SeekFrom::Start(1)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(b"bcd", &buf);
+
+        // Reading across the end of a block needs the next one.
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(b"efg", &buf);
+        assert_eq!(4, client.urls.lock().unwrap().len());
+        assert!(file.seek(SeekFrom::Current(-10)).is_err());
+    }
+}
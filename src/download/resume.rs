@@ -0,0 +1,204 @@
+//! Reading a download as a stream, which continues where it got to if it is cut off.
+
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dropbox_sdk::client_trait::HttpRequestResult;
+use dropbox_sdk::Error;
+
+use super::timeout::Watchdog;
+use super::{DownloadProgress, DownloadProgressHandler};
+use crate::limits::EndpointKind;
+use crate::retry::{Backoff, RetryOpts};
+use crate::throttle::Throttle;
+
+/// How much of a [`ResumingReader`] to buffer, for readers which implement [`io::BufRead`], so
+/// small reads, such as of lines, don't each read from the response.
+pub(super) const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Options for [`preview`](super::preview), `export_document` and `AsyncDownloadReader::open`,
+/// which read a single response as a stream.
+#[derive(Debug, Clone, Default)]
+pub struct StreamOpts {
+    /// How to retry failed requests, including requesting the rest of the stream if reading it is
+    /// cut off.
+    pub retry: RetryOpts,
+
+    /// Give up on the response if no data arrives for this long, and request the rest of it
+    /// again, as with [`DownloadOpts::read_timeout`](super::DownloadOpts::read_timeout).
+    pub read_timeout: Option<Duration>,
+
+    /// Give up on the response if it is slower than this, and request the rest of it again, as
+    /// with [`DownloadOpts::min_bytes_per_sec`](super::DownloadOpts::min_bytes_per_sec).
+    pub min_bytes_per_sec: Option<u64>,
+}
+
+impl StreamOpts {
+    /// When to give up on responses.
+    pub(super) fn watchdog(&self) -> Watchdog {
+        Watchdog::new(self.read_timeout, self.min_bytes_per_sec)
+    }
+}
+
+/// A download which can be requested from any offset.
+pub(super) trait Resumable {
+    /// The result of the request, besides the data.
+    type Result;
+
+    /// The error type of the request.
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// What is being downloaded, for log messages.
+    const WHAT: &'static str;
+
+    /// Request the data from the given offset onwards.
+    fn request(&self, offset: u64) -> Result<HttpRequestResult<Self::Result>, Error<Self::Error>>;
+
+    /// Whether an error from Dropbox is only temporary, so the request should be retried.
+    fn is_temporary(error: &Self::Error) -> bool {
+        let _ = error;
+        false
+    }
+}
+
+/// The data of a [`Resumable`] download. If reading it fails partway through, the rest of it is
+/// requested again, and reading only fails if that does.
+pub(super) struct ResumingReader<R> {
+    source: R,
+    retry: RetryOpts,
+    body: Box<dyn Read>,
+    offset: u64,
+    end: Option<u64>,
+    progress: Option<Progress>,
+    throttle: Option<Throttle>,
+}
+
+/// What's needed to report the progress of a [`ResumingReader`].
+struct Progress {
+    handler: Arc<Box<dyn DownloadProgressHandler>>,
+    start_time: Instant,
+    retries: u32,
+}
+
+impl<R: Resumable> ResumingReader<R> {
+    /// Make the first request, returning its result and a reader of its data.
+    pub fn open(source: R, retry: &RetryOpts) -> Result<(R::Result, Self), Error<R::Error>> {
+        let response = request(&source, 0, retry)?;
+        let reader = Self {
+            source,
+            retry: retry.clone(),
+            body: response.body,
+            offset: 0,
+            end: response.content_length,
+            progress: None,
+            throttle: None,
+        };
+        Ok((response.result, reader))
+    }
+
+    /// The source, to change how any further requests are made.
+    pub fn source_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    /// Report the progress of reading to the given handler. If the length of the response isn't
+    /// known, the total is what has been read so far.
+    pub fn set_progress_handler(&mut self, handler: Arc<Box<dyn DownloadProgressHandler>>) {
+        self.progress = Some(Progress {
+            handler,
+            start_time: Instant::now(),
+            retries: 0,
+        });
+    }
+
+    /// Limit reading to the given number of bytes per second.
+    pub fn set_max_bytes_per_sec(&mut self, max_bytes_per_sec: u64) {
+        self.throttle = Some(Throttle::new(max_bytes_per_sec));
+    }
+}
+
+impl<R: Resumable> Read for ResumingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.end == Some(self.offset) {
+            return Ok(0);
+        }
+        let mut backoff = Backoff::new(&self.retry).for_endpoint(EndpointKind::Content);
+        loop {
+            let read_start = Instant::now();
+            let e = match self.body.read(buf) {
+                Ok(0) if self.end.is_none() => return Ok(0),
+                Ok(0) => Error::<R::Error>::UnexpectedResponse(format!(
+                    "{} response ended {} bytes early",
+                    R::WHAT,
+                    self.end.unwrap_or_default() - self.offset
+                )),
+                Ok(n) => {
+                    self.offset += n as u64;
+                    if let Some(throttle) = &self.throttle {
+                        throttle.acquire(n as u64);
+                    }
+                    if let Some(progress) = &self.progress {
+                        progress.handler.progress(&DownloadProgress {
+                            bytes_downloaded: self.offset,
+                            total_bytes: self.end.unwrap_or(self.offset),
+                            retries: progress.retries,
+                            instant_rate: n as f64 / read_start.elapsed().as_secs_f64(),
+                            overall_rate: self.offset as f64
+                                / progress.start_time.elapsed().as_secs_f64(),
+                        });
+                    }
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Error::HttpClient(e.into()),
+            };
+            backoff
+                .handle(&format!("reading {}", R::WHAT), e)
+                .map_err(io::Error::other)?;
+            if let Some(progress) = &mut self.progress {
+                progress.retries += 1;
+            }
+            let response =
+                request(&self.source, self.offset, &self.retry).map_err(io::Error::other)?;
+            self.body = response.body;
+            self.end = response.content_length.map(|len| self.offset + len);
+        }
+    }
+}
+
+/// A response to a [`Resumable`] request.
+struct Response<T> {
+    result: T,
+    content_length: Option<u64>,
+    body: Box<dyn Read>,
+}
+
+/// Make a request, from the given offset onwards, retrying it if it fails.
+fn request<R: Resumable>(
+    source: &R,
+    offset: u64,
+    retry: &RetryOpts,
+) -> Result<Response<R::Result>, Error<R::Error>> {
+    let what = format!("getting {}", R::WHAT);
+    let mut backoff = Backoff::new(retry).for_endpoint(EndpointKind::Content);
+    loop {
+        backoff.wait();
+        let result = source.request(offset).and_then(|response| {
+            let body = response.body.ok_or_else(|| {
+                Error::UnexpectedResponse(format!("{} response has no body", R::WHAT))
+            })?;
+            Ok(Response {
+                result: response.result,
+                content_length: response.content_length,
+                body,
+            })
+        });
+        match result {
+            Ok(response) => return Ok(response),
+            Err(Error::Api(e)) if R::is_temporary(&e) => backoff.handle(&what, Error::Api(e))?,
+            Err(e @ Error::Api(_)) => return Err(e),
+            Err(e) => backoff.handle(&what, e)?,
+        }
+    }
+}
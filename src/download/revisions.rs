@@ -0,0 +1,22 @@
+//! Downloading earlier revisions of files, such as to restore them.
+
+use std::path::Path;
+
+use dropbox_sdk::files;
+use dropbox_sdk::UserAuthClient;
+
+use super::save::download_to_path;
+use super::{DownloadError, DownloadOpts};
+
+/// Download the given revision of a file to the given local path, as with [`download_to_path`],
+/// such as one found with `list::revisions` or `list::revision_at`. Returns the metadata of the
+/// revision.
+pub fn download_rev_to_path<C: UserAuthClient + Sync>(
+    client: &C,
+    rev: &str,
+    local_path: &Path,
+    opts: &DownloadOpts,
+) -> Result<files::FileMetadata, DownloadError> {
+    let arg = files::DownloadArg::new(format!("rev:{rev}"));
+    download_to_path(client, &arg, local_path, opts)
+}
@@ -0,0 +1,192 @@
+//! Downloading a file into place safely.
+
+use std::fs::{self, File};
+use std::path::Path;
+
+use dropbox_sdk::files;
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use super::parallel::{download_revision, identical_local_file, lookup_file, DownloadError};
+use super::watch::temp_path;
+use super::DownloadOpts;
+use crate::timestamp;
+
+/// Download a file to the given local path, so that the file there is only ever either what was
+/// there before or the whole download, and give it the file's modification time from Dropbox.
+///
+/// The file is downloaded as with [`download_to_file`](super::download_to_file) to a temporary
+/// file in the same directory, which is checked to be the length Dropbox says the file is, has its
+/// modification time set from the file's [`client_modified`](files::FileMetadata::client_modified)
+/// time, and is then renamed into place, replacing any existing file. If anything fails, the
+/// temporary file is removed.
+///
+/// With [`skip_if_identical`](DownloadOpts::skip_if_identical), an existing file which is the same
+/// as the one in Dropbox is left as it is.
+///
+/// Returns the metadata of the revision which was downloaded.
+pub fn download_to_path<C: UserAuthClient + Sync>(
+    client: &C,
+    arg: &files::DownloadArg,
+    local_path: &Path,
+    opts: &DownloadOpts,
+) -> Result<files::FileMetadata, DownloadError> {
+    save(client, arg, local_path, opts).map(|saved| saved.metadata)
+}
+
+/// A file saved by [`save`].
+pub(super) struct Saved {
+    /// The metadata of the revision which was downloaded, or which was already there.
+    pub metadata: files::FileMetadata,
+
+    /// Whether the file was already there, so it wasn't downloaded.
+    pub skipped: bool,
+}
+
+/// Download a file as described for [`download_to_path`], saying whether it was skipped.
+pub(super) fn save<C: UserAuthClient + Sync>(
+    client: &C,
+    arg: &files::DownloadArg,
+    local_path: &Path,
+    opts: &DownloadOpts,
+) -> Result<Saved, DownloadError> {
+    let metadata = lookup_file(client, arg, opts)?;
+    save_revision(client, metadata, local_path, opts)
+}
+
+/// Download the revision of a file with the given metadata, which has already been looked up, as
+/// for [`save`].
+pub(super) fn save_revision<C: UserAuthClient + Sync>(
+    client: &C,
+    metadata: files::FileMetadata,
+    local_path: &Path,
+    opts: &DownloadOpts,
+) -> Result<Saved, DownloadError> {
+    if opts.skip_if_identical && identical_local_file(local_path, &metadata)? {
+        info!(
+            "Skipping download of identical file: {}",
+            local_path.display()
+        );
+        return Ok(Saved {
+            metadata,
+            skipped: true,
+        });
+    }
+    let temp_path = temp_path(local_path);
+    let result = download_revision(client, &metadata, &temp_path, opts)
+        .and_then(|()| finish(&temp_path, local_path, &metadata).map_err(DownloadError::from));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.map(|()| Saved {
+        metadata,
+        skipped: false,
+    })
+}
+
+/// Check the downloaded file, set its modification time, and rename it into place.
+fn finish(
+    temp_path: &Path,
+    local_path: &Path,
+    metadata: &files::FileMetadata,
+) -> Result<(), BoxedError> {
+    let file = File::options()
+        .write(true)
+        .open(temp_path)
+        .map_err(|e| Error::HttpClient(e.into()))?;
+    let len = file
+        .metadata()
+        .map_err(|e| Error::HttpClient(e.into()))?
+        .len();
+    if len != metadata.size {
+        return Err(Error::UnexpectedResponse(format!(
+            "downloaded {len} bytes, but the file is {} bytes",
+            metadata.size
+        )));
+    }
+    match timestamp::parse(&metadata.client_modified) {
+        Some(mtime) => file
+            .set_modified(mtime)
+            .map_err(|e| Error::HttpClient(e.into()))?,
+        None => warn!(
+            "Not setting the modification time of {}: can't parse {:?}",
+            local_path.display(),
+            metadata.client_modified
+        ),
+    }
+    drop(file);
+    fs::rename(temp_path, local_path).map_err(|e| Error::HttpClient(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content_hash::ContentHash;
+    use crate::testing::tests::{file_json_with, MockClient};
+    use std::time::{Duration, SystemTime};
+
+    // Modified on a different day on the server, to check which time is used.
+    const FILE: &str = r#"{".tag": "file", "name": "f", "id": "id:f", "size": 2,
+        "client_modified": "2024-01-01T00:00:00Z", "server_modified": "2024-01-02T00:00:00Z",
+        "rev": "aaaaaaaaa", "path_display": "/f"}"#;
+
+    #[test]
+    fn renames_and_sets_mtime() {
+        let client = MockClient::new("null")
+            .then(200, FILE)
+            .then_download(FILE, "ab");
+        let local_path = std::env::temp_dir().join(format!("save_test_{}", std::process::id()));
+        fs::write(&local_path, "old contents").unwrap();
+
+        let arg = files::DownloadArg::new("/f".to_owned());
+        download_to_path(&client, &arg, &local_path, &DownloadOpts::default()).unwrap();
+        let contents = fs::read_to_string(&local_path).unwrap();
+        let mtime = fs::metadata(&local_path).unwrap().modified().unwrap();
+        let temp_exists = temp_path(&local_path).exists();
+        fs::remove_file(&local_path).unwrap();
+
+        assert_eq!("ab", contents);
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_067_200),
+            mtime
+        );
+        assert!(!temp_exists);
+    }
+
+    #[test]
+    fn skips_identical_file() {
+        let hash = ContentHash::from("ab").finish_hex();
+        let client =
+            MockClient::new("null").then(200, file_json_with("/f", 2, "aaaaaaaaa", Some(&hash)));
+        let local_path =
+            std::env::temp_dir().join(format!("save_skip_test_{}", std::process::id()));
+        fs::write(&local_path, "ab").unwrap();
+
+        let arg = files::DownloadArg::new("/f".to_owned());
+        let opts = DownloadOpts {
+            skip_if_identical: true,
+            ..Default::default()
+        };
+        let saved = save(&client, &arg, &local_path, &opts).unwrap();
+        fs::remove_file(&local_path).unwrap();
+
+        assert!(saved.skipped);
+        assert_eq!("aaaaaaaaa", saved.metadata.rev);
+        assert_eq!(1, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn removes_temp_file_on_error() {
+        let client = MockClient::new("null")
+            .then(200, FILE)
+            .then(409, r#"{"error": {".tag": "unsupported_file"}}"#);
+        let local_path =
+            std::env::temp_dir().join(format!("save_error_test_{}", std::process::id()));
+
+        let arg = files::DownloadArg::new("/f".to_owned());
+        let result = download_to_path(&client, &arg, &local_path, &DownloadOpts::default());
+        assert!(matches!(result, Err(DownloadError::Request(Error::Api(_)))));
+        assert!(!temp_path(&local_path).exists());
+        assert!(!local_path.exists());
+    }
+}
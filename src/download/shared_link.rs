@@ -0,0 +1,179 @@
+//! Downloading files from shared links.
+
+use std::ops::Range;
+use std::path::Path;
+
+use dropbox_sdk::sharing::{self, GetSharedLinkFileError};
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use super::parallel::{download_ranges, DownloadError, RangeResponse, RangeSource, RemoteRevision};
+use super::timeout::ReadTimeoutClient;
+use super::DownloadOpts;
+use crate::limits::EndpointKind;
+use crate::path_root;
+use crate::retry::Backoff;
+
+/// Download the file a shared link is to, or a file inside a shared folder link, to the given
+/// local path, requesting ranges of it in parallel like
+/// [`download_to_file`](super::download_to_file).
+///
+/// Set [`path`](sharing::GetSharedLinkFileArg::path) to download a file inside a shared folder
+/// link, and [`link_password`](sharing::GetSharedLinkFileArg::link_password) if the link needs
+/// one. This is useful when all that's known of a file is a link to it, rather than its path in
+/// the user's Dropbox.
+///
+/// Since a file can't be downloaded from a link by revision, if it is changed while it is
+/// downloading, this fails with [`FileChanged`](super::FileChanged). Dropbox doesn't give the
+/// Content Hash of files in shared links, so they can't be [verified](DownloadOpts::verify).
+///
+/// Returns the metadata of the file.
+pub fn download_shared_link<C: UserAuthClient + Sync>(
+    client: &C,
+    arg: &sharing::GetSharedLinkFileArg,
+    local_path: &Path,
+    opts: &DownloadOpts,
+) -> Result<sharing::FileLinkMetadata, DownloadError> {
+    let metadata = link_metadata(client, arg, opts)?;
+    let client = ReadTimeoutClient::new(client, opts.watchdog());
+    let source = SharedLink {
+        client: &client,
+        arg,
+    };
+    let file = RemoteRevision {
+        path: metadata.path_lower.as_deref().unwrap_or(&metadata.name),
+        rev: &metadata.rev,
+        size: metadata.size,
+        content_hash: None,
+    };
+    download_ranges(&source, &file, local_path, opts)?;
+    Ok(metadata)
+}
+
+/// Look up the metadata of the file a link is to.
+fn link_metadata(
+    client: &impl UserAuthClient,
+    arg: &sharing::GetSharedLinkFileArg,
+    opts: &DownloadOpts,
+) -> Result<sharing::FileLinkMetadata, BoxedError> {
+    let mut backoff = Backoff::new(&opts.retry).for_endpoint(EndpointKind::Rpc);
+    loop {
+        backoff.wait();
+        match sharing::get_shared_link_metadata(client, arg) {
+            Ok(sharing::SharedLinkMetadata::File(metadata)) => return Ok(metadata),
+            Ok(sharing::SharedLinkMetadata::Folder(_)) => {
+                return Err(Error::Api(Box::new(
+                    GetSharedLinkFileError::SharedLinkIsDirectory,
+                )))
+            }
+            Ok(_) => {
+                return Err(Error::UnexpectedResponse(format!(
+                    "{} is not a link to a file",
+                    arg.url
+                )))
+            }
+            Err(e @ Error::Api(_)) => return Err(path_root::boxed(e)),
+            Err(e) => backoff
+                .handle("getting shared link metadata", e)
+                .map_err(path_root::boxed)?,
+        }
+    }
+}
+
+/// A file in a shared link.
+struct SharedLink<'a, C> {
+    client: &'a C,
+    arg: &'a sharing::GetSharedLinkFileArg,
+}
+
+impl<C: UserAuthClient + Sync> RangeSource for SharedLink<'_, C> {
+    type Error = GetSharedLinkFileError;
+
+    fn request(&self, range: Range<u64>) -> Result<RangeResponse, Error<GetSharedLinkFileError>> {
+        // The end of an HTTP range is inclusive.
+        let response = sharing::get_shared_link_file(
+            self.client,
+            self.arg,
+            Some(range.start),
+            Some(range.end - 1),
+        )?;
+        let sharing::SharedLinkMetadata::File(metadata) = response.result else {
+            return Err(Error::UnexpectedResponse(
+                "shared link download is not of a file".to_owned(),
+            ));
+        };
+        let body = response
+            .body
+            .ok_or_else(|| Error::UnexpectedResponse("download response has no body".to_owned()))?;
+        Ok(RangeResponse {
+            rev: metadata.rev,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+    use std::fs;
+
+    const FILE: &str = r#"{".tag": "file", "url": "https://www.dropbox.com/s/x/f", "name": "f",
+        "link_permissions": {"can_revoke": false, "visibility_policies": [],
+            "can_set_expiry": false, "can_remove_expiry": false, "allow_download": true,
+            "can_allow_download": false, "can_disallow_download": false,
+            "allow_comments": false, "team_restricts_comments": false},
+        "client_modified": "2024-01-01T00:00:00Z", "server_modified": "2024-01-01T00:00:00Z",
+        "rev": "aaaaaaaaa", "size": 6}"#;
+
+    #[test]
+    fn downloads_ranges() {
+        let client = MockClient::new("null")
+            .then(200, FILE)
+            .then_download(FILE, "abc")
+            .then_download(FILE, "def");
+        let local_path =
+            std::env::temp_dir().join(format!("shared_link_test_{}", std::process::id()));
+        let opts = DownloadOpts {
+            parallelism: 1,
+            chunk_size: 3,
+            ..Default::default()
+        };
+
+        let arg = sharing::GetSharedLinkFileArg::new("https://www.dropbox.com/s/x/f".to_owned());
+        let metadata = download_shared_link(&client, &arg, &local_path, &opts).unwrap();
+        let contents = fs::read_to_string(&local_path).unwrap();
+        fs::remove_file(&local_path).unwrap();
+
+        assert_eq!("aaaaaaaaa", metadata.rev);
+        assert_eq!("abcdef", contents);
+        let urls = client.urls.lock().unwrap();
+        assert!(urls[0].ends_with("sharing/get_shared_link_metadata"));
+        assert!(urls[1].ends_with("sharing/get_shared_link_file"));
+    }
+
+    #[test]
+    fn folder_link() {
+        let client = MockClient::new("null").then(
+            200,
+            r#"{".tag": "folder", "url": "https://www.dropbox.com/sh/x", "name": "d",
+            "link_permissions": {"can_revoke": false, "visibility_policies": [],
+                "can_set_expiry": false, "can_remove_expiry": false, "allow_download": true,
+                "can_allow_download": false, "can_disallow_download": false,
+                "allow_comments": false, "team_restricts_comments": false}}"#,
+        );
+        let local_path =
+            std::env::temp_dir().join(format!("shared_link_folder_test_{}", std::process::id()));
+
+        let arg = sharing::GetSharedLinkFileArg::new("https://www.dropbox.com/sh/x".to_owned());
+        let result = download_shared_link(&client, &arg, &local_path, &DownloadOpts::default());
+        let Err(DownloadError::Request(Error::Api(e))) = result else {
+            panic!("wrong result");
+        };
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(GetSharedLinkFileError::SharedLinkIsDirectory)
+        ));
+        assert!(!local_path.exists());
+    }
+}
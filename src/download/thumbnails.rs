@@ -0,0 +1,256 @@
+//! Getting thumbnails of images, one at a time or many at once.
+
+use std::io::Read;
+use std::sync::Arc;
+
+use dropbox_sdk::files::{
+    self, GetThumbnailBatchError, ThumbnailError, ThumbnailFormat, ThumbnailMode, ThumbnailSize,
+    ThumbnailV2Error,
+};
+use dropbox_sdk::{Error, UserAuthClient};
+
+use super::work::try_parallel_map;
+use crate::limits::{EndpointKind, MAX_THUMBNAIL_BATCH_ENTRIES};
+use crate::retry::{Backoff, RetryOpts};
+use crate::scheduler::TransferScheduler;
+
+/// Options for [`thumbnail`] and [`thumbnails`].
+#[derive(Debug, Clone)]
+pub struct ThumbnailOpts {
+    /// The size of the thumbnails.
+    pub size: ThumbnailSize,
+
+    /// The image format of the thumbnails: JPEG is better for photos, and PNG for screenshots and
+    /// drawings.
+    pub format: ThumbnailFormat,
+
+    /// How to resize and crop the images to the size.
+    pub mode: ThumbnailMode,
+
+    /// How many batches of [`MAX_THUMBNAIL_BATCH_ENTRIES`] thumbnails to request at once.
+    pub parallelism: usize,
+
+    /// How to retry failed requests.
+    pub retry: RetryOpts,
+
+    /// A scheduler to share with other uploads and downloads, to limit how many requests they
+    /// make at once in total.
+    pub scheduler: Option<Arc<TransferScheduler>>,
+}
+
+impl Default for ThumbnailOpts {
+    fn default() -> Self {
+        Self {
+            size: ThumbnailSize::W64h64,
+            format: ThumbnailFormat::Jpeg,
+            mode: ThumbnailMode::Strict,
+            parallelism: 4,
+            retry: RetryOpts::default(),
+            scheduler: None,
+        }
+    }
+}
+
+/// A thumbnail of an image, from [`thumbnail`] or [`thumbnails`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    /// The metadata of the image.
+    pub metadata: files::FileMetadata,
+
+    /// The thumbnail, in the [format](ThumbnailOpts::format) requested.
+    pub data: Vec<u8>,
+}
+
+/// Get a thumbnail of the image at the given path.
+///
+/// Images larger than [`MAX_THUMBNAIL_SOURCE_SIZE`](crate::limits::MAX_THUMBNAIL_SOURCE_SIZE)
+/// can't be made into thumbnails.
+pub fn thumbnail(
+    client: &impl UserAuthClient,
+    path: &str,
+    opts: &ThumbnailOpts,
+) -> Result<Thumbnail, Error<ThumbnailV2Error>> {
+    let arg = files::ThumbnailV2Arg::new(files::PathOrLink::Path(path.to_owned()))
+        .with_size(opts.size.clone())
+        .with_format(opts.format.clone())
+        .with_mode(opts.mode.clone());
+    let mut backoff = Backoff::new(&opts.retry).for_endpoint(EndpointKind::Content);
+    loop {
+        backoff.wait();
+        let permit = opts.scheduler.as_deref().map(TransferScheduler::acquire);
+        let result = files::get_thumbnail_v2(client, &arg, None, None).and_then(|response| {
+            let mut body = response.body.ok_or_else(|| {
+                Error::UnexpectedResponse("thumbnail response has no body".to_owned())
+            })?;
+            let mut data = vec![];
+            body.read_to_end(&mut data)
+                .map_err(|e| Error::HttpClient(e.into()))?;
+            Ok((response.result.file_metadata, data))
+        });
+        drop(permit);
+        match result {
+            Ok((Some(metadata), data)) => return Ok(Thumbnail { metadata, data }),
+            Ok((None, _)) => {
+                return Err(Error::UnexpectedResponse(format!(
+                    "thumbnail of {path} has no file metadata"
+                )))
+            }
+            Err(e @ Error::Api(_)) => return Err(e),
+            Err(e) => backoff.handle("getting thumbnail", e)?,
+        }
+    }
+}
+
+/// Get thumbnails of the images at the given paths, requesting them in batches of
+/// [`MAX_THUMBNAIL_BATCH_ENTRIES`], [`parallelism`](ThumbnailOpts::parallelism) batches at once.
+///
+/// The results are in the same order as the paths. Failing to get a thumbnail of one image, such
+/// as because it isn't an image, doesn't stop the others, but if a whole batch fails, this fails.
+pub fn thumbnails<C, P>(
+    client: &C,
+    paths: impl IntoIterator<Item = P>,
+    opts: &ThumbnailOpts,
+) -> Result<Vec<Result<Thumbnail, Error<ThumbnailError>>>, Error<GetThumbnailBatchError>>
+where
+    C: UserAuthClient + Sync,
+    P: Into<String>,
+{
+    let args = paths
+        .into_iter()
+        .map(|path| {
+            files::ThumbnailArg::new(path.into())
+                .with_size(opts.size.clone())
+                .with_format(opts.format.clone())
+                .with_mode(opts.mode.clone())
+        })
+        .collect::<Vec<_>>();
+    let batches = try_parallel_map(
+        args.chunks(MAX_THUMBNAIL_BATCH_ENTRIES),
+        opts.parallelism,
+        |batch| thumbnail_batch(client, batch, opts),
+    )?;
+    Ok(batches.into_iter().flatten().collect())
+}
+
+fn thumbnail_batch(
+    client: &impl UserAuthClient,
+    entries: &[files::ThumbnailArg],
+    opts: &ThumbnailOpts,
+) -> Result<Vec<Result<Thumbnail, Error<ThumbnailError>>>, Error<GetThumbnailBatchError>> {
+    let arg = files::GetThumbnailBatchArg::new(entries.to_vec());
+    let mut backoff = Backoff::new(&opts.retry).for_endpoint(EndpointKind::Content);
+    let result = loop {
+        backoff.wait();
+        let result = {
+            let _permit = opts.scheduler.as_deref().map(TransferScheduler::acquire);
+            files::get_thumbnail_batch(client, &arg)
+        };
+        match result {
+            Ok(result) => break result,
+            Err(e @ Error::Api(_)) => return Err(e),
+            Err(e) => backoff.handle("getting thumbnails", e)?,
+        }
+    };
+    if result.entries.len() != entries.len() {
+        return Err(Error::UnexpectedResponse(format!(
+            "requested {} thumbnails, but got {}",
+            entries.len(),
+            result.entries.len()
+        )));
+    }
+    Ok(result
+        .entries
+        .into_iter()
+        .map(|entry| match entry {
+            files::GetThumbnailBatchResultEntry::Success(result) => {
+                let data = decode_base64(&result.thumbnail).ok_or_else(|| {
+                    Error::UnexpectedResponse("thumbnail is not valid base64".to_owned())
+                })?;
+                Ok(Thumbnail {
+                    metadata: result.metadata,
+                    data,
+                })
+            }
+            files::GetThumbnailBatchResultEntry::Failure(e) => Err(Error::Api(e)),
+            _ => Err(Error::UnexpectedResponse(
+                "unknown thumbnail batch result".to_owned(),
+            )),
+        })
+        .collect())
+}
+
+/// Decode standard base64, which is how batches of thumbnails are returned.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(s.len() / 4 * 3);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        acc = (acc << 6 | u32::from(value)) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((acc >> bits) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::{file_json, MockClient};
+
+    #[test]
+    fn single() {
+        let file = file_json("/a.jpg", 1);
+        let client = MockClient::new("null").then_download(
+            format!(r#"{{"file_metadata": {file}}}"#).leak(),
+            "jpeg data",
+        );
+        let thumbnail = thumbnail(&client, "/a.jpg", &ThumbnailOpts::default()).unwrap();
+        assert_eq!("id:a.jpg", thumbnail.metadata.id);
+        assert_eq!(b"jpeg data", thumbnail.data.as_slice());
+    }
+
+    #[test]
+    fn batch() {
+        let file = file_json("/a.jpg", 1);
+        let client = MockClient::new("null").then(
+            200,
+            format!(
+                r#"{{"entries": [
+                    {{".tag": "success", "metadata": {file}, "thumbnail": "anBlZyBkYXRh"}},
+                    {{".tag": "failure", "failure": {{".tag": "unsupported_extension"}}}}
+                ]}}"#
+            )
+            .leak(),
+        );
+        let results = thumbnails(&client, ["/a.jpg", "/b.txt"], &ThumbnailOpts::default()).unwrap();
+        assert_eq!(2, results.len());
+        assert_eq!(b"jpeg data", results[0].as_ref().unwrap().data.as_slice());
+        assert!(matches!(
+            results[1],
+            Err(Error::Api(ThumbnailError::UnsupportedExtension))
+        ));
+        assert_eq!(1, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(Some(b"".to_vec()), decode_base64(""));
+        assert_eq!(Some(b"f".to_vec()), decode_base64("Zg=="));
+        assert_eq!(Some(b"fo".to_vec()), decode_base64("Zm8="));
+        assert_eq!(Some(b"foo".to_vec()), decode_base64("Zm9v"));
+        assert_eq!(Some(vec![0xfb, 0xff]), decode_base64("+/8="));
+        assert_eq!(None, decode_base64("Zm9v!"));
+    }
+}
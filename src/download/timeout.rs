@@ -0,0 +1,325 @@
+//! Giving up on responses which stop sending data, rather than waiting for them forever.
+
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dropbox_sdk::client_trait::{HttpClient, HttpRequestResultRaw, TeamSelect, UserAuthClient};
+use dropbox_sdk::Error;
+
+/// How much of a response to read at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+/// How long a response's rate is averaged over, to compare with its minimum.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+/// The most threads which can be reading responses for [`TimeoutBody`] at once, across all
+/// downloads. A thread whose read timed out stays blocked until the connection fails, so this
+/// bounds how many of them can pile up. Beyond it, responses are read without a watchdog.
+const MAX_READER_THREADS: usize = 64;
+
+/// How many threads are reading responses for [`TimeoutBody`].
+static READER_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// When to give up on a response.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Watchdog {
+    /// Give up if no data arrives for this long.
+    pub timeout: Option<Duration>,
+
+    /// Give up if less than this many bytes per second arrive, on average over a window.
+    pub min_bytes_per_sec: Option<u64>,
+
+    window: Duration,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Option<Duration>, min_bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            timeout,
+            min_bytes_per_sec,
+            window: THROUGHPUT_WINDOW,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.timeout.is_some() || self.min_bytes_per_sec.is_some()
+    }
+}
+
+/// A client which passes requests through to another one, but makes reading their responses fail
+/// with [`io::ErrorKind::TimedOut`] if they stall or are too slow, as the [`Watchdog`] says. With
+/// no limits, responses are passed through as they are.
+///
+/// Waiting for a response to start can't be given up on, since the request is made by the other
+/// client on the calling thread, so that relies on the other client's own timeouts.
+pub(super) struct ReadTimeoutClient<'a, C> {
+    inner: &'a C,
+    watchdog: Watchdog,
+}
+
+impl<'a, C> ReadTimeoutClient<'a, C> {
+    pub fn new(inner: &'a C, watchdog: Watchdog) -> Self {
+        Self { inner, watchdog }
+    }
+}
+
+impl<C: HttpClient> HttpClient for ReadTimeoutClient<'_, C> {
+    type Request = C::Request;
+
+    fn execute(&self, request: Self::Request, body: &[u8]) -> Result<HttpRequestResultRaw, Error> {
+        let mut result = self.inner.execute(request, body)?;
+        if self.watchdog.is_enabled() {
+            result.body = match TimeoutBody::new(result.body, self.watchdog) {
+                Ok(body) => Box::new(body),
+                Err(body) => {
+                    warn!("too many responses being watched; reading this one without a watchdog");
+                    body
+                }
+            };
+        }
+        Ok(result)
+    }
+
+    fn new_request(&self, url: &str) -> Self::Request {
+        self.inner.new_request(url)
+    }
+
+    fn update_token(&self, old_token: Arc<String>) -> Result<bool, Error> {
+        self.inner.update_token(old_token)
+    }
+
+    fn token(&self) -> Option<Arc<String>> {
+        self.inner.token()
+    }
+
+    fn path_root(&self) -> Option<&str> {
+        self.inner.path_root()
+    }
+
+    fn team_select(&self) -> Option<&TeamSelect> {
+        self.inner.team_select()
+    }
+}
+
+impl<C: UserAuthClient> UserAuthClient for ReadTimeoutClient<'_, C> {}
+
+/// One of the [`MAX_READER_THREADS`], given back when dropped.
+struct ReaderThread;
+
+impl ReaderThread {
+    fn reserve() -> Option<Self> {
+        READER_THREADS
+            .fetch_update(SeqCst, SeqCst, |n| {
+                (n < MAX_READER_THREADS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self)
+    }
+}
+
+impl Drop for ReaderThread {
+    fn drop(&mut self) {
+        READER_THREADS.fetch_sub(1, SeqCst);
+    }
+}
+
+/// A response body which is read by a background thread, so that reading it can give up waiting.
+///
+/// A blocking read can't be interrupted, so after a timeout, the thread is left waiting for the
+/// read to finish, or for the connection to fail, and then exits. If the response is too slow,
+/// the thread stops reading it and drops it.
+struct TimeoutBody {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    timeout: Option<Duration>,
+    current: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl TimeoutBody {
+    /// Start reading the body, or give it back if there are too many threads reading already.
+    fn new(
+        mut body: Box<dyn Read + Send>,
+        watchdog: Watchdog,
+    ) -> Result<Self, Box<dyn Read + Send>> {
+        let Some(slot) = ReaderThread::reserve() else {
+            return Err(body);
+        };
+        let (tx, rx) = mpsc::sync_channel(1);
+        thread::spawn(move || {
+            let _slot = slot;
+            // The time spent reading, and how much was read, since the window started.
+            let mut window = (Duration::ZERO, 0);
+            loop {
+                let mut buf = vec![0; READ_SIZE];
+                let read_start = Instant::now();
+                let result = match body.read(&mut buf) {
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Ok(buf)
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let last = !matches!(&result, Ok(buf) if !buf.is_empty());
+                if let Ok(buf) = &result {
+                    window.0 += read_start.elapsed();
+                    window.1 += buf.len() as u64;
+                }
+                // Stop if the body has been dropped.
+                if tx.send(result).is_err() || last {
+                    break;
+                }
+                let Some(min_bytes_per_sec) = watchdog.min_bytes_per_sec else {
+                    continue;
+                };
+                if window.0 >= watchdog.window {
+                    let rate = window.1 as f64 / window.0.as_secs_f64();
+                    if rate < min_bytes_per_sec as f64 {
+                        warn!("response is too slow, at {rate:.0} bytes/sec; giving up on it");
+                        let _ = tx.send(Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("response is slower than {min_bytes_per_sec} bytes/sec"),
+                        )));
+                        break;
+                    }
+                    window = (Duration::ZERO, 0);
+                }
+            }
+        });
+        Ok(Self {
+            rx,
+            timeout: watchdog.timeout,
+            current: vec![],
+            pos: 0,
+            done: false,
+        })
+    }
+
+    /// Wait for the next data from the background thread.
+    fn recv(&self) -> io::Result<Vec<u8>> {
+        let result = match self.timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout),
+            None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match result {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => {
+                warn!(
+                    "no data received for {:?}; giving up on response",
+                    self.timeout.unwrap_or_default()
+                );
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "no data received for {:?}",
+                        self.timeout.unwrap_or_default()
+                    ),
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "response reader stopped",
+            )),
+        }
+    }
+}
+
+impl Read for TimeoutBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.current.len() {
+            if self.done {
+                return Ok(0);
+            }
+            let data = self.recv()?;
+            self.done = data.is_empty();
+            self.current = data;
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reader which returns some data, and then blocks.
+    struct Stalls(&'static [u8]);
+
+    impl Read for Stalls {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                thread::sleep(Duration::from_secs(1));
+                return Ok(0);
+            }
+            let n = buf.len().min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn times_out() {
+        let watchdog = Watchdog::new(Some(Duration::from_millis(50)), None);
+        let mut body = TimeoutBody::new(Box::new(Stalls(b"abc")), watchdog)
+            .ok()
+            .unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(3, body.read(&mut buf).unwrap());
+        assert_eq!(b"abc", &buf[..3]);
+        let e = body.read(&mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, e.kind());
+    }
+
+    #[test]
+    fn passes_data_through() {
+        let watchdog = Watchdog::new(Some(Duration::from_secs(10)), Some(1));
+        let mut body = TimeoutBody::new(Box::new(&b"hello"[..]), watchdog)
+            .ok()
+            .unwrap();
+        let mut data = String::new();
+        body.read_to_string(&mut data).unwrap();
+        assert_eq!("hello", data);
+    }
+
+    /// A reader which returns a byte at a time, slowly.
+    struct Slow(&'static [u8]);
+
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(20));
+            let n = buf.len().min(self.0.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn too_slow() {
+        let watchdog = Watchdog {
+            window: Duration::from_millis(50),
+            ..Watchdog::new(None, Some(1000))
+        };
+        let mut body = TimeoutBody::new(Box::new(Slow(b"abcdefghij")), watchdog)
+            .ok()
+            .unwrap();
+        let mut data = vec![];
+        let e = body.read_to_end(&mut data).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, e.kind());
+        // What arrived before the window ended is still read.
+        assert!(!data.is_empty() && data.len() < 10, "{data:?}");
+    }
+}
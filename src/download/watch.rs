@@ -0,0 +1,191 @@
+//! Keeping a local copy of a Dropbox file up to date.
+
+use std::ffi::OsString;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+
+use dropbox_sdk::files::{self, GetMetadataError, LookupError};
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use super::save::save_revision;
+use super::{DownloadError, DownloadOpts};
+use crate::limits::EndpointKind;
+use crate::path_root;
+use crate::retry::{Backoff, RetryOpts};
+
+/// Options for [`watch_and_fetch`].
+#[derive(Debug, Clone)]
+pub struct WatchOpts {
+    /// How often to check the file for changes.
+    pub poll_interval: Duration,
+
+    /// How long the file has to go without changing before a new revision is downloaded. This
+    /// avoids downloading every revision of a file which is being updated repeatedly.
+    pub debounce: Duration,
+
+    /// How to retry failed requests.
+    pub retry: RetryOpts,
+}
+
+impl Default for WatchOpts {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(30),
+            debounce: Duration::from_secs(5),
+            retry: RetryOpts::default(),
+        }
+    }
+}
+
+/// Download a file from Dropbox to the given local path, and then download it again each time it
+/// changes.
+///
+/// After each download, `on_update` is called with the local path and the metadata of the
+/// revision that was downloaded. This continues until it returns [`ControlFlow::Break`], or an
+/// error occurs. If the file is deleted, this waits for it to come back.
+///
+/// Each revision is saved as with [`download_to_path`](super::download_to_path): downloaded to a
+/// temporary file next to the local path, checked to be the right length, given the file's
+/// modification time, and then renamed into place, so readers of the local file never see a
+/// partial download.
+pub fn watch_and_fetch<C: UserAuthClient + Sync>(
+    client: &C,
+    path: &str,
+    local_path: &Path,
+    opts: &WatchOpts,
+    mut on_update: impl FnMut(&Path, &files::FileMetadata) -> ControlFlow<()>,
+) -> Result<(), DownloadError> {
+    let download_opts = DownloadOpts {
+        retry: opts.retry.clone(),
+        ..Default::default()
+    };
+    let mut current_rev = None;
+    loop {
+        if let Some(mut latest) = get_file_metadata(client, path, opts)? {
+            if current_rev.as_ref() != Some(&latest.rev) {
+                if current_rev.is_some() {
+                    // Wait for it to settle down.
+                    match settle(client, path, latest, opts)? {
+                        Some(settled) => latest = settled,
+                        None => continue,
+                    }
+                }
+                info!("Downloading {path} at revision {}", latest.rev);
+                latest = save_revision(client, latest, local_path, &download_opts)?.metadata;
+                current_rev = Some(latest.rev.clone());
+                if on_update(local_path, &latest).is_break() {
+                    return Ok(());
+                }
+            }
+        }
+        sleep(opts.poll_interval);
+    }
+}
+
+/// Get the metadata of the file at the given path, or `None` if there isn't one.
+fn get_file_metadata(
+    client: &impl UserAuthClient,
+    path: &str,
+    opts: &WatchOpts,
+) -> Result<Option<files::FileMetadata>, BoxedError> {
+    let arg = files::GetMetadataArg::new(path.to_owned());
+    let mut backoff = Backoff::new(&opts.retry).for_endpoint(EndpointKind::Rpc);
+    loop {
+        backoff.wait();
+        match files::get_metadata(client, &arg) {
+            Ok(files::Metadata::File(metadata)) => return Ok(Some(metadata)),
+            Ok(files::Metadata::Folder(_)) => {
+                return Err(Error::UnexpectedResponse(format!("{path} is a folder")));
+            }
+            Ok(files::Metadata::Deleted(_))
+            | Err(Error::Api(GetMetadataError::Path(LookupError::NotFound))) => return Ok(None),
+            Err(e @ Error::Api(_)) => return Err(path_root::boxed(e)),
+            Err(e) => backoff
+                .handle("getting file metadata", e)
+                .map_err(path_root::boxed)?,
+        }
+    }
+}
+
+/// Wait until the file stops changing, and return its metadata then, or `None` if it was deleted.
+fn settle(
+    client: &impl UserAuthClient,
+    path: &str,
+    mut latest: files::FileMetadata,
+    opts: &WatchOpts,
+) -> Result<Option<files::FileMetadata>, BoxedError> {
+    loop {
+        sleep(opts.debounce);
+        match get_file_metadata(client, path, opts)? {
+            Some(metadata) if metadata.rev == latest.rev => return Ok(Some(metadata)),
+            Some(metadata) => {
+                debug!("{path} changed again; waiting");
+                latest = metadata;
+            }
+            None => return Ok(None),
+        }
+    }
+}
+
+/// A temporary path in the same directory as the given one, so it can be renamed into place.
+pub(super) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".download");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+    use std::fs;
+
+    const REV_A: &str = r#"{".tag": "file", "name": "f", "id": "id:f", "size": 1,
+        "client_modified": "2024-01-01T00:00:00Z", "server_modified": "2024-01-01T00:00:00Z",
+        "rev": "aaaaaaaaa"}"#;
+    const REV_B: &str = r#"{".tag": "file", "name": "f", "id": "id:f", "size": 2,
+        "client_modified": "2024-01-01T00:00:00Z", "server_modified": "2024-01-01T00:00:00Z",
+        "rev": "bbbbbbbbb"}"#;
+
+    #[test]
+    fn fetches_new_revisions() {
+        let client = MockClient::new("null")
+            .then(200, REV_A)
+            .then_download(REV_A, "a")
+            .then(200, REV_A)
+            .then(200, REV_B)
+            .then(200, REV_B)
+            .then_download(REV_B, "bb");
+        let local_path = std::env::temp_dir().join(format!("watch_test_{}", std::process::id()));
+        let opts = WatchOpts {
+            poll_interval: Duration::ZERO,
+            debounce: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut contents = vec![];
+        watch_and_fetch(&client, "/f", &local_path, &opts, |path, metadata| {
+            contents.push((metadata.rev.clone(), fs::read_to_string(path).unwrap()));
+            if contents.len() == 2 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+        let mtime = fs::metadata(&local_path).unwrap().modified().unwrap();
+        fs::remove_file(&local_path).unwrap();
+        assert_eq!(crate::timestamp::parse("2024-01-01T00:00:00Z"), Some(mtime));
+        assert_eq!(
+            vec![
+                ("aaaaaaaaa".to_owned(), "a".to_owned()),
+                ("bbbbbbbbb".to_owned(), "bb".to_owned()),
+            ],
+            contents
+        );
+        assert_eq!(6, client.urls.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,98 @@
+//! Working through many items at once, such as files to download.
+
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::thread;
+
+/// Call `f` with each of the items, on up to `parallelism` threads at once, and return the results
+/// in the same order as the items.
+pub(super) fn parallel_map<I, R>(
+    items: I,
+    parallelism: usize,
+    f: impl Fn(I::Item) -> R + Sync,
+) -> Vec<R>
+where
+    I: IntoIterator,
+    I::IntoIter: Send,
+    R: Send,
+{
+    match try_parallel_map(items, parallelism, |item| Ok::<_, Infallible>(f(item))) {
+        Ok(results) => results,
+        Err(never) => match never {},
+    }
+}
+
+/// Like [`parallel_map`], but stop once `f` fails, after the calls already in progress finish, and
+/// return the first error.
+pub(super) fn try_parallel_map<I, R, E>(
+    items: I,
+    parallelism: usize,
+    f: impl Fn(I::Item) -> Result<R, E> + Sync,
+) -> Result<Vec<R>, E>
+where
+    I: IntoIterator,
+    I::IntoIter: Send,
+    R: Send,
+    E: Send,
+{
+    let next = Mutex::new(items.into_iter().enumerate());
+    let results = Mutex::new(vec![]);
+    let error = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..parallelism.max(1) {
+            scope.spawn(|| loop {
+                if error.lock().unwrap().is_some() {
+                    break;
+                }
+                let Some((i, item)) = next.lock().unwrap().next() else {
+                    break;
+                };
+                match f(item) {
+                    Ok(result) => results.lock().unwrap().push((i, result)),
+                    Err(e) => {
+                        error.lock().unwrap().get_or_insert(e);
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(e);
+    }
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use std::time::Duration;
+
+    #[test]
+    fn keeps_order() {
+        let results = parallel_map(0..20u64, 4, |i| {
+            // Later items finish first.
+            thread::sleep(Duration::from_millis(20 - i));
+            i * 2
+        });
+        assert_eq!((0..20).map(|i| i * 2).collect::<Vec<_>>(), results);
+    }
+
+    #[test]
+    fn stops_on_error() {
+        let calls = AtomicUsize::new(0);
+        let result = try_parallel_map(0..100, 1, |i| {
+            calls.fetch_add(1, SeqCst);
+            if i == 3 {
+                Err(i)
+            } else {
+                Ok(i)
+            }
+        });
+        assert_eq!(Err(3), result);
+        assert_eq!(4, calls.load(SeqCst));
+    }
+}
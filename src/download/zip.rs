@@ -0,0 +1,238 @@
+//! Downloading a whole folder as a zip file.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use dropbox_sdk::files::{self, DownloadZipError};
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use super::watch::temp_path;
+use crate::limits::EndpointKind;
+use crate::path_root;
+use crate::retry::{Backoff, RetryOpts};
+use crate::scheduler::TransferScheduler;
+
+/// Options for [`download_zip`].
+#[derive(Debug, Clone, Default)]
+pub struct ZipOpts {
+    /// How to retry failed requests.
+    pub retry: RetryOpts,
+
+    /// A scheduler to share with other uploads and downloads, to limit how many requests they
+    /// make at once in total.
+    pub scheduler: Option<Arc<TransferScheduler>>,
+}
+
+/// Download a Dropbox folder and everything in it as a single zip file, written to the given local
+/// path.
+///
+/// This is much faster than downloading each file separately for folders of many small files, but
+/// Dropbox refuses to zip folders which are too large or have too many files in them, failing
+/// with [`DownloadZipError::TooLarge`] or [`DownloadZipError::TooManyFiles`].
+///
+/// The zip file is streamed to a temporary file next to the local path, and then renamed into
+/// place, replacing any existing file. Since the zip file is generated on the fly, a request which
+/// is cut off can't be resumed, so it is retried from the beginning. Returns the metadata of the
+/// folder.
+pub fn download_zip(
+    client: &impl UserAuthClient,
+    path: &str,
+    local_path: &Path,
+    opts: &ZipOpts,
+) -> Result<files::FolderMetadata, BoxedError> {
+    let temp_path = temp_path(local_path);
+    let result = request_zip(client, path, opts, |body| {
+        File::create(&temp_path)
+            .and_then(|mut file| io::copy(body, &mut file))
+            .map_err(|error| CopyError {
+                error,
+                restartable: true,
+            })
+    });
+    let metadata = match result {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+    };
+    fs::rename(&temp_path, local_path).map_err(|e| Error::HttpClient(e.into()))?;
+    Ok(metadata)
+}
+
+/// Download a Dropbox folder as a zip file, as with [`download_zip`], but write it to the given
+/// writer as it arrives, such as to pass it on without storing it first.
+///
+/// Since what has been written can't be taken back, a request which is cut off is only retried if
+/// none of the zip file has been written yet; otherwise this fails with the error from reading or
+/// writing it. Returns the metadata of the folder.
+pub fn download_zip_to_writer(
+    client: &impl UserAuthClient,
+    path: &str,
+    writer: &mut impl Write,
+    opts: &ZipOpts,
+) -> Result<files::FolderMetadata, BoxedError> {
+    let mut writer = CountingWriter {
+        inner: writer,
+        written: 0,
+    };
+    request_zip(client, path, opts, |body| {
+        io::copy(body, &mut writer).map_err(|error| CopyError {
+            error,
+            restartable: writer.written == 0,
+        })
+    })
+}
+
+/// Copying a zip file from a response failed.
+struct CopyError {
+    error: io::Error,
+
+    /// Whether the zip file can be requested again and copied from the beginning.
+    restartable: bool,
+}
+
+/// Request a folder as a zip file, and give the response to `copy`, retrying failed requests and
+/// failed copies which can be started again.
+fn request_zip(
+    client: &impl UserAuthClient,
+    path: &str,
+    opts: &ZipOpts,
+    mut copy: impl FnMut(&mut dyn Read) -> Result<u64, CopyError>,
+) -> Result<files::FolderMetadata, BoxedError> {
+    let arg = files::DownloadZipArg::new(path.to_owned());
+    let mut backoff = Backoff::new(&opts.retry).for_endpoint(EndpointKind::Content);
+    loop {
+        backoff.wait();
+        let permit = opts.scheduler.as_deref().map(TransferScheduler::acquire);
+        let result = files::download_zip(client, &arg, None, None).and_then(|response| {
+            response
+                .body
+                .map(|body| (response.result.metadata, body))
+                .ok_or_else(|| {
+                    Error::UnexpectedResponse("download response has no body".to_owned())
+                })
+        });
+        let e: Error<DownloadZipError> = match result {
+            Ok((metadata, mut body)) => match copy(&mut body) {
+                Ok(len) => {
+                    debug!("Downloaded {path} as a {len} byte zip file");
+                    return Ok(metadata);
+                }
+                Err(CopyError {
+                    error,
+                    restartable: false,
+                }) => {
+                    error!("Error downloading zip of {path} after writing some of it: {error}");
+                    return Err(Error::HttpClient(error.into()));
+                }
+                Err(CopyError { error, .. }) => Error::HttpClient(error.into()),
+            },
+            Err(e @ Error::Api(_)) => return Err(path_root::boxed(e)),
+            Err(e) => e,
+        };
+        drop(permit);
+        backoff
+            .handle("downloading zip", e)
+            .map_err(path_root::boxed)?;
+    }
+}
+
+/// Counts the bytes written through it.
+struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+
+    #[test]
+    fn downloads_zip() {
+        let client = MockClient::new("null")
+            .then(409, r#"{"error": {".tag": "too_many_files"}}"#)
+            .then_download(
+                r#"{"metadata": {"name": "Dir", "id": "id:d", "path_display": "/Dir"}}"#,
+                "PK",
+            );
+        let local_path = std::env::temp_dir().join(format!("zip_test_{}", std::process::id()));
+
+        let result = download_zip(&client, "/Dir", &local_path, &ZipOpts::default());
+        let Err(Error::Api(e)) = result else {
+            panic!("wrong result");
+        };
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(DownloadZipError::TooManyFiles)
+        ));
+        assert!(!local_path.exists());
+
+        let metadata = download_zip(&client, "/Dir", &local_path, &ZipOpts::default()).unwrap();
+        let contents = fs::read_to_string(&local_path).unwrap();
+        fs::remove_file(&local_path).unwrap();
+        assert_eq!("id:d", metadata.id);
+        assert_eq!("PK", contents);
+        assert!(!temp_path(&local_path).exists());
+    }
+
+    /// Takes one byte, and then fails.
+    struct OneByte(Vec<u8>);
+
+    impl Write for OneByte {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.0.is_empty() {
+                return Err(io::Error::other("full"));
+            }
+            self.0.push(buf[0]);
+            Ok(1)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn downloads_zip_to_writer() {
+        let folder = r#"{"metadata": {"name": "Dir", "id": "id:d", "path_display": "/Dir"}}"#;
+        let client = MockClient::new("null")
+            .then_download(folder, "PK")
+            .then_download(folder, "PK");
+        let opts = ZipOpts {
+            retry: RetryOpts {
+                initial_backoff_time: std::time::Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut zip = vec![];
+        let metadata = download_zip_to_writer(&client, "/Dir", &mut zip, &opts).unwrap();
+        assert_eq!("id:d", metadata.id);
+        assert_eq!(b"PK", zip.as_slice());
+
+        // Part of the zip file was written, so it isn't requested again.
+        let mut writer = OneByte(vec![]);
+        let result = download_zip_to_writer(&client, "/Dir", &mut writer, &opts);
+        assert!(matches!(result, Err(Error::HttpClient(_))));
+        assert_eq!(b"P", writer.0.as_slice());
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,128 @@
+//! Operations on whole files in Dropbox which the API doesn't provide directly.
+
+use std::io::Read;
+use std::sync::Arc;
+
+use dropbox_sdk::files;
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use crate::path_root;
+use crate::upload::{CommitOptions, UploadOpts, UploadSession};
+
+/// Add data to the end of a file in Dropbox, creating it if it doesn't exist.
+///
+/// Dropbox files can't be modified, only replaced, and there is no way to start an upload session
+/// with the contents of an existing file, so this downloads the whole file and uploads it again
+/// with the data added. The cost of each call is therefore proportional to the size of the file,
+/// not of the data, which makes this suitable for small files, such as logs which are rotated
+/// regularly, but not for adding to large ones.
+///
+/// The new version is only committed if the file hasn't been changed since it was downloaded; if
+/// it has, nothing is changed and an [`UpdateConflict`](crate::upload::UpdateConflict) error is
+/// returned, and the append can be tried again. Likewise, if the file didn't exist but is created
+/// in the meantime, the commit fails with a [`WriteError::Conflict`](files::WriteError::Conflict).
+///
+/// This blocks the current thread until the new version has been committed, or an error occurs.
+pub fn append<C: UserAuthClient + Send + Sync + 'static>(
+    client: Arc<C>,
+    path: &str,
+    data: &[u8],
+    opts: &UploadOpts,
+) -> Result<files::FileMetadata, BoxedError> {
+    let existing = match files::get_metadata(
+        client.as_ref(),
+        &files::GetMetadataArg::new(path.to_owned()),
+    ) {
+        Ok(files::Metadata::File(file)) => Some(file),
+        // Committing will fail with a conflict.
+        Ok(_) => None,
+        Err(Error::Api(files::GetMetadataError::Path(files::LookupError::NotFound))) => None,
+        Err(e) => return Err(path_root::boxed(e)),
+    };
+
+    let session = UploadSession::new(client.clone()).map_err(Error::boxed)?;
+    let Some(existing) = existing else {
+        debug!(
+            "[{}] {path} doesn't exist; creating it",
+            session.operation_id()
+        );
+        session.upload(data, opts.clone())?;
+        return session
+            .commit(CommitOptions::new(path).with_mode(files::WriteMode::Add))
+            .map_err(Error::boxed);
+    };
+
+    debug!(
+        "[{}] Appending {} bytes to {path} at revision {} by uploading its {} bytes again",
+        session.operation_id(),
+        data.len(),
+        existing.rev,
+        existing.size
+    );
+    let arg = files::DownloadArg::new(format!("rev:{}", existing.rev));
+    let body = files::download(client.as_ref(), &arg, None, None)
+        .map_err(path_root::boxed)?
+        .body
+        .ok_or_else(|| Error::UnexpectedResponse("download response has no body".to_owned()))?;
+    let mut opts = opts.clone();
+    opts.total_bytes = Some(existing.size + data.len() as u64);
+    let len = session.upload(body.chain(data), opts)?;
+    if len != existing.size + data.len() as u64 {
+        return Err(Error::UnexpectedResponse(format!(
+            "downloaded {} bytes, but the file is {} bytes",
+            len - data.len() as u64,
+            existing.size
+        )));
+    }
+    session.commit_update(CommitOptions::new(path), existing.rev)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+
+    const METADATA: &str = r#"{
+        ".tag": "file",
+        "name": "log",
+        "id": "id:log",
+        "client_modified": "2024-01-01T00:00:00Z",
+        "server_modified": "2024-01-01T00:00:00Z",
+        "rev": "0123456789abcdef",
+        "size": 6,
+        "path_display": "/log"
+    }"#;
+
+    #[test]
+    fn appends_to_existing_file() {
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(200, METADATA)
+                .then(200, r#"{"session_id": "session"}"#)
+                .then_download(METADATA, "hello ")
+                .then(200, "null")
+                .then(200, METADATA),
+        );
+        append(client.clone(), "/log", b"world", &UploadOpts::default()).unwrap();
+        let urls = client.urls.lock().unwrap();
+        assert_eq!(5, urls.len());
+        assert!(urls[2].ends_with("files/download"));
+        assert!(urls[4].ends_with("upload_session/finish"));
+    }
+
+    #[test]
+    fn short_download_is_not_committed() {
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(200, METADATA)
+                .then(200, r#"{"session_id": "session"}"#)
+                .then_download(METADATA, "hi")
+                .then(200, "null"),
+        );
+        let result = append(client.clone(), "/log", b"world", &UploadOpts::default());
+        assert!(matches!(result, Err(Error::UnexpectedResponse(_))));
+        // No finish.
+        assert_eq!(4, client.urls.lock().unwrap().len());
+    }
+}
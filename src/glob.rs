@@ -0,0 +1,86 @@
+//! Matching paths against glob patterns, for ignore files and filtering listings.
+
+/// Split a path or pattern into its components, leaving out empty ones.
+pub(crate) fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty()).collect()
+}
+
+/// Whether the given path components match the pattern's. `*` and `?` in a component match
+/// anything and any one character, and a `**` component matches any number of components.
+pub(crate) fn match_path(pattern: &[String], path: &[&str]) -> bool {
+    match_wildcard(pattern, path, |p| p == "**", |p, name| match_name(p, name))
+}
+
+fn match_name(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    match_wildcard(&pattern, &name, |&p| p == '*', |&p, &c| p == '?' || p == c)
+}
+
+/// Whether `items` match `pattern`, where an item of the pattern for which `is_star` is true
+/// matches any number of items, and any other matches one item for which `matches` is true.
+///
+/// This backtracks only to the last star, which is enough because a later star can match
+/// anything an earlier one could, so it takes at most `pattern.len() * items.len()` steps.
+fn match_wildcard<P, I>(
+    pattern: &[P],
+    items: &[I],
+    is_star: impl Fn(&P) -> bool,
+    matches: impl Fn(&P, &I) -> bool,
+) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Where the last star in the pattern was, and the item it was tried up to.
+    let mut star = None;
+    while i < items.len() {
+        if p < pattern.len() && is_star(&pattern[p]) {
+            star = Some((p, i));
+            p += 1;
+        } else if p < pattern.len() && matches(&pattern[p], &items[i]) {
+            p += 1;
+            i += 1;
+        } else if let Some((star_p, star_i)) = star {
+            // Let the star match one more item, and try again after it.
+            star = Some((star_p, star_i + 1));
+            p = star_p + 1;
+            i = star_i + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(is_star)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        let pattern = components(pattern)
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        match_path(&pattern, &components(path))
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(matches("a/*.txt", "a/b.txt"));
+        assert!(!matches("a/*.txt", "a/b/c.txt"));
+        assert!(matches("a/**/c.txt", "a/c.txt"));
+        assert!(matches("a/**/c.txt", "a/b/b/c.txt"));
+        assert!(matches("**/*.t?t", "a/b/c.txt"));
+        assert!(!matches("**/*.t?t", "a/b/c.tt"));
+        // `?` matches a whole character, not a byte of one.
+        assert!(matches("caf?", "café"));
+        assert!(!matches("caf??", "café"));
+    }
+
+    #[test]
+    fn doesnt_backtrack_exponentially() {
+        let name = "a".repeat(100);
+        let pattern = format!("{}b", "*a".repeat(20));
+        assert!(!matches(&pattern, &name));
+        let pattern = format!("{}/b", "**/".repeat(20));
+        assert!(!matches(&pattern, &"a/".repeat(100)));
+    }
+}
@@ -10,8 +10,34 @@
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "bench")]
+pub mod bench;
+#[cfg(any(feature = "download", feature = "list", feature = "upload"))]
+pub mod cancel;
 pub mod content_hash;
+#[cfg(feature = "download")]
+pub mod download;
+#[cfg(feature = "upload")]
+pub mod fileops;
+#[cfg(any(feature = "list", feature = "upload"))]
+mod glob;
+pub mod limits;
+#[cfg(feature = "list")]
 pub mod list;
+#[cfg(any(feature = "download", feature = "list", feature = "upload"))]
+pub mod path_root;
+pub mod read_only;
+pub mod retry;
+pub mod scheduler;
+#[cfg(feature = "status")]
+pub mod status;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(any(feature = "download", feature = "upload"))]
+mod throttle;
+#[cfg(any(feature = "download", feature = "list", feature = "upload"))]
+mod timestamp;
+#[cfg(feature = "upload")]
 pub mod upload;
 
 /// The size of a block. This is a Dropbox constant, not adjustable.
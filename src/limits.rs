@@ -0,0 +1,119 @@
+//! Operational limits of the Dropbox API.
+//!
+//! These are enforced by the Dropbox servers, but checking them up front means that a long-running
+//! operation can fail right away with a clear error, instead of partway through.
+
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+/// The largest file that can be uploaded using an upload session: 350 GiB.
+pub const MAX_UPLOAD_SESSION_SIZE: u64 = 350 * 1024 * 1024 * 1024;
+
+/// The largest amount of data that can be sent in a single upload request: 150 MiB.
+pub const MAX_REQUEST_SIZE: u64 = 150 * 1024 * 1024;
+
+/// The longest path, in characters, that Dropbox accepts for a file or folder.
+pub const MAX_PATH_LENGTH: usize = 4096;
+
+/// The largest number of entries that can be given to a batch operation, such as
+/// [`upload_session_finish_batch_v2`](dropbox_sdk::files::upload_session_finish_batch_v2) or
+/// [`copy_batch_v2`](dropbox_sdk::files::copy_batch_v2).
+pub const MAX_BATCH_ENTRIES: usize = 1000;
+
+/// The largest number of files that can be given to a single
+/// [`get_thumbnail_batch`](dropbox_sdk::files::get_thumbnail_batch) call.
+pub const MAX_THUMBNAIL_BATCH_ENTRIES: usize = 25;
+
+/// Files larger than this (20 MiB) will not be converted to thumbnails.
+pub const MAX_THUMBNAIL_SOURCE_SIZE: u64 = 20 * 1024 * 1024;
+
+/// How long a temporary link to a file works for: 4 hours.
+pub const TEMPORARY_LINK_LIFETIME: Duration = Duration::from_secs(4 * 60 * 60);
+
+/// The shortest time a temporary upload link can be requested to work for: 1 minute.
+pub const MIN_UPLOAD_LINK_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The longest time a temporary upload link can be requested to work for: 4 hours.
+pub const MAX_UPLOAD_LINK_LIFETIME: Duration = Duration::from_secs(4 * 60 * 60);
+
+/// The two kinds of Dropbox API endpoints, which have different characteristics.
+///
+/// Operations which use both kinds can limit them separately, such as with the
+/// `max_bytes_per_sec` and `max_rpc_per_sec` upload options, so that metadata-heavy work doesn't
+/// starve data transfers, or the other way around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EndpointKind {
+    /// Endpoints which take and return JSON, such as getting metadata, listing folders, and
+    /// copying files. These are small and quick, so the number of requests is what matters, and
+    /// they are the ones most likely to be rate-limited.
+    ///
+    /// The toolbox uses these for listing, watching for changes, and checking for and copying
+    /// existing files when uploading.
+    Rpc,
+
+    /// Endpoints which transfer file contents in the request or response body, up to
+    /// [`MAX_REQUEST_SIZE`] bytes per upload request, such as uploading and downloading. These can
+    /// take a long time, so the number of bytes transferred is what matters.
+    ///
+    /// The toolbox uses these for the upload session calls and for downloading.
+    Content,
+}
+
+impl EndpointKind {
+    /// The most data that can be sent in one request to an endpoint of this kind, if it's limited
+    /// separately from the request's metadata.
+    pub fn max_request_size(self) -> Option<u64> {
+        match self {
+            Self::Rpc => None,
+            Self::Content => Some(MAX_REQUEST_SIZE),
+        }
+    }
+}
+
+/// A value was larger than Dropbox allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitExceeded {
+    /// A description of what was too large.
+    pub what: &'static str,
+
+    /// The value given.
+    pub value: u64,
+
+    /// The largest value allowed.
+    pub max: u64,
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is {}, which exceeds the Dropbox limit of {}",
+            self.what, self.value, self.max
+        )
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Check that a value is no larger than the given limit.
+pub fn check(what: &'static str, value: u64, max: u64) -> Result<(), LimitExceeded> {
+    if value > max {
+        Err(LimitExceeded { what, value, max })
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that a file of the given size can be uploaded.
+pub fn check_upload_size(len: u64) -> Result<(), LimitExceeded> {
+    check("file size", len, MAX_UPLOAD_SESSION_SIZE)
+}
+
+/// Check that a path isn't too long for Dropbox.
+pub fn check_path_length(path: &str) -> Result<(), LimitExceeded> {
+    check(
+        "path length",
+        path.chars().count() as u64,
+        MAX_PATH_LENGTH as u64,
+    )
+}
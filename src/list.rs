@@ -1,12 +1,35 @@
 //! Functions for listing directories.
 
+mod parallel;
+mod revisions;
+mod search;
+mod stats;
+#[cfg(feature = "async")]
+mod stream;
+pub use parallel::{list_directory_parallel, ParallelDirectoryIterator};
+pub use revisions::{revision_at, revisions, Revision, RevisionsOpts};
+pub use search::{search, SearchIterator, SearchOpts};
+pub use stats::{child_stats, folder_stats, FolderStats};
+#[cfg(feature = "async")]
+pub use stream::{list_directory_async, DirectoryStream};
+
 use std::collections::VecDeque;
-use std::thread::sleep;
-use std::time::Duration;
+use std::convert::Infallible;
+use std::fmt::{self, Display};
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 
-use dropbox_sdk::Error;
-use dropbox_sdk::files::{ListFolderError, ListFolderContinueError};
+use dropbox_sdk::files::{ListFolderContinueError, ListFolderError};
 use dropbox_sdk::{files, UserAuthClient};
+use dropbox_sdk::{BoxedError, Error};
+
+use crate::glob;
+use crate::limits::EndpointKind;
+use crate::path_root;
+use crate::retry::{Backoff, RetryOpts};
+use crate::timestamp;
 
 /// Make an iterator that yields directory entries under a given path, optionally recursively.
 pub fn list_directory<'a, T: UserAuthClient>(
@@ -14,109 +37,889 @@ pub fn list_directory<'a, T: UserAuthClient>(
     path: &str,
     recursive: bool,
 ) -> Result<DirectoryIterator<'a, T>, Error<ListFolderError>> {
-    assert!(
-        path.starts_with('/'),
-        "path needs to be absolute (start with a '/')"
-    );
-    let requested_path = if path == "/" {
-        // Root folder should be requested as empty string.
-        String::new()
-    } else {
-        path.to_owned()
+    let opts = ListOpts {
+        recursive,
+        ..Default::default()
     };
+    list_directory_with_opts(client, path, &opts)
+}
+
+/// Options for [`list_directory_with_opts`].
+#[derive(Debug, Clone)]
+pub struct ListOpts {
+    /// List everything under the path, not just what's directly in it.
+    pub recursive: bool,
+
+    /// Include entries for files and folders which used to exist but were deleted, as
+    /// [`files::Metadata::Deleted`].
+    pub include_deleted: bool,
+
+    /// Include the media info of photos and videos. Dropbox no longer fills this in.
+    pub include_media_info: bool,
+
+    /// Include what's in mounted folders, such as shared folders, team folders, and app folders.
+    pub include_mounted_folders: bool,
+
+    /// Include files which can't be downloaded directly, such as Google Docs.
+    pub include_non_downloadable_files: bool,
+
+    /// About how many entries to get per request, rather than as many as Dropbox chooses. The
+    /// iterator still returns all of them.
+    pub limit: Option<u32>,
+
+    /// How to retry failed requests, for the first page of entries and the rest.
+    pub retry: RetryOpts,
+}
+
+impl Default for ListOpts {
+    fn default() -> Self {
+        Self {
+            recursive: false,
+            include_deleted: false,
+            include_media_info: false,
+            include_mounted_folders: true,
+            include_non_downloadable_files: true,
+            limit: None,
+            retry: RetryOpts::default(),
+        }
+    }
+}
+
+impl ListOpts {
+    fn arg(&self, path: String) -> files::ListFolderArg {
+        let mut arg = files::ListFolderArg::new(path)
+            .with_recursive(self.recursive)
+            .with_include_deleted(self.include_deleted)
+            .with_include_media_info(self.include_media_info)
+            .with_include_mounted_folders(self.include_mounted_folders)
+            .with_include_non_downloadable_files(self.include_non_downloadable_files);
+        arg.limit = self.limit;
+        arg
+    }
+}
+
+/// Like [`list_directory`], with more options for what to list.
+pub fn list_directory_with_opts<'a, T: UserAuthClient>(
+    client: &'a T,
+    path: &str,
+    opts: &ListOpts,
+) -> Result<DirectoryIterator<'a, T>, Error<ListFolderError>> {
     let result = list_folder_internal(
         client,
         files::list_folder,
-        &files::ListFolderArg::new(requested_path).with_recursive(recursive),
+        &opts.arg(requested_path(path)),
+        &opts.retry,
     )?;
-    let cursor = if result.has_more {
-        Some(result.cursor)
-    } else {
-        None
-    };
     Ok(DirectoryIterator {
         client,
-        cursor,
         buffer: result.entries.into(),
+        cursor: result.cursor,
+        has_more: result.has_more,
+        retry: opts.retry.clone(),
     })
 }
 
+/// Call `visit` with each directory entry under a given path, optionally recursively, until it
+/// returns [`ControlFlow::Break`].
+///
+/// Only one page of entries is held in memory at a time, and each entry is dropped after it is
+/// visited, so this is suitable for folding over very large trees.
+pub fn visit_directory<T: UserAuthClient>(
+    client: &T,
+    path: &str,
+    recursive: bool,
+    mut visit: impl FnMut(files::Metadata) -> ControlFlow<()>,
+) -> Result<(), BoxedError> {
+    for entry in list_directory(client, path, recursive).map_err(Error::boxed)? {
+        if visit(entry.map_err(Error::boxed)?.into()).is_break() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Where to start a listing with [`visit_directory_timeboxed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListFrom {
+    /// Start listing the given path.
+    Path(String),
+
+    /// Continue a listing from a cursor returned by a previous call.
+    Cursor(String),
+}
+
+/// Like [`visit_directory`], but stop once the given amount of time has passed, so that a large
+/// listing can be done in pieces, such as by a scheduled job with a fixed amount of time to run.
+///
+/// If the listing was finished, this returns `None`. Otherwise, it returns a cursor which can be
+/// passed back in [`ListFrom::Cursor`] to continue where it left off. The time is checked between
+/// pages of results, so it can be exceeded by the time it takes to get and visit one page.
+///
+/// If `visit` returns [`ControlFlow::Break`], this stops and returns `None`, like when the listing
+/// is finished.
+///
+/// The options say what to list when starting from a path, and how to retry failed requests. A
+/// listing continued from a cursor lists the same things as the one the cursor came from.
+pub fn visit_directory_timeboxed<T: UserAuthClient>(
+    client: &T,
+    from: ListFrom,
+    opts: &ListOpts,
+    budget: Duration,
+    mut visit: impl FnMut(files::Metadata) -> ControlFlow<()>,
+) -> Result<Option<String>, BoxedError> {
+    let deadline = Instant::now() + budget;
+    let mut result = match from {
+        ListFrom::Path(path) => list_folder_internal(
+            client,
+            files::list_folder,
+            &opts.arg(requested_path(&path)),
+            &opts.retry,
+        )
+        .map_err(Error::boxed)?,
+        ListFrom::Cursor(cursor) => list_folder_internal(
+            client,
+            files::list_folder_continue,
+            &files::ListFolderContinueArg::new(cursor),
+            &opts.retry,
+        )
+        .map_err(Error::boxed)?,
+    };
+    loop {
+        for entry in result.entries {
+            if visit(entry).is_break() {
+                return Ok(None);
+            }
+        }
+        if !result.has_more {
+            return Ok(None);
+        }
+        if Instant::now() >= deadline {
+            debug!("listing ran out of time");
+            return Ok(Some(result.cursor));
+        }
+        result = list_folder_internal(
+            client,
+            files::list_folder_continue,
+            &files::ListFolderContinueArg::new(result.cursor),
+            &opts.retry,
+        )
+        .map_err(Error::boxed)?;
+    }
+}
+
+/// A point in a listing which it can be continued from, such as by a later run of a program which
+/// saved it to disk.
+///
+/// This is the cursor Dropbox gives for the listing. It can be saved as a string with
+/// [`to_string`](ToString::to_string), and parsed back with [`parse`](str::parse). A cursor from
+/// the end of a listing can be continued from too, to list only what has changed since.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ListCursor(String);
+
+impl ListCursor {
+    /// The cursor as Dropbox gave it.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for ListCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for ListCursor {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl From<String> for ListCursor {
+    fn from(cursor: String) -> Self {
+        Self(cursor)
+    }
+}
+
+impl From<ListCursor> for String {
+    fn from(cursor: ListCursor) -> Self {
+        cursor.0
+    }
+}
+
+/// Get a cursor for the current state of the given path, without listing it, so that what changes
+/// under it from now on can be listed later with [`changes_since`].
+///
+/// Deleted files and folders are included in the changes, as [`files::Metadata::Deleted`].
+pub fn latest_cursor(
+    client: &impl UserAuthClient,
+    path: &str,
+    recursive: bool,
+) -> Result<ListCursor, Error<ListFolderError>> {
+    let opts = ListOpts {
+        recursive,
+        include_deleted: true,
+        ..Default::default()
+    };
+    let result = list_folder_internal(
+        client,
+        files::list_folder_get_latest_cursor,
+        &opts.arg(requested_path(path)),
+        &opts.retry,
+    )?;
+    Ok(ListCursor(result.cursor))
+}
+
+/// Make an iterator that yields what has changed since the given cursor was got, such as from
+/// [`latest_cursor`]. Once it is finished, its [`cursor`](DirectoryIterator::cursor) can be saved
+/// to list the changes after that next time.
+///
+/// This is the same as [`DirectoryIterator::continue_from`] with the default retry options.
+pub fn changes_since<T: UserAuthClient>(
+    client: &T,
+    cursor: ListCursor,
+) -> DirectoryIterator<'_, T> {
+    DirectoryIterator::continue_from(client, cursor, RetryOpts::default())
+}
+
+/// A directory entry yielded by a [`DirectoryIterator`]: the metadata Dropbox gave for it, with
+/// accessors for what most callers need from any kind of entry.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)] // Laid out like files::Metadata, so converting is cheap.
+pub enum Entry {
+    /// A file.
+    File(files::FileMetadata),
+
+    /// A folder.
+    Folder(files::FolderMetadata),
+
+    /// A file or folder which used to exist but was deleted.
+    Deleted(files::DeletedMetadata),
+}
+
+impl Entry {
+    /// The full path of the entry, with the case it was given when it was created. Dropbox gives
+    /// this for everything in a listing; it's empty if not.
+    pub fn path(&self) -> &str {
+        let (path_display, path_lower) = match self {
+            Self::File(f) => (&f.path_display, &f.path_lower),
+            Self::Folder(f) => (&f.path_display, &f.path_lower),
+            Self::Deleted(f) => (&f.path_display, &f.path_lower),
+        };
+        path_display
+            .as_deref()
+            .or(path_lower.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// The lowercased path of the entry, which Dropbox matches paths by.
+    fn path_lower(&self) -> String {
+        let path_lower = match self {
+            Self::File(f) => &f.path_lower,
+            Self::Folder(f) => &f.path_lower,
+            Self::Deleted(f) => &f.path_lower,
+        };
+        match path_lower {
+            Some(path) => path.clone(),
+            None => self.path().to_lowercase(),
+        }
+    }
+
+    /// The last part of the entry's path.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::File(f) => &f.name,
+            Self::Folder(f) => &f.name,
+            Self::Deleted(f) => &f.name,
+        }
+    }
+
+    /// The size of a file, in bytes.
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            Self::File(f) => Some(f.size),
+            _ => None,
+        }
+    }
+
+    /// The [Content Hash](crate::content_hash) of a file, if Dropbox gave one.
+    pub fn content_hash(&self) -> Option<&str> {
+        match self {
+            Self::File(f) => f.content_hash.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// When a file was last modified, as given by whatever uploaded it
+    /// ([`client_modified`](files::FileMetadata::client_modified)).
+    pub fn modified(&self) -> Option<SystemTime> {
+        match self {
+            Self::File(f) => timestamp::parse(&f.client_modified),
+            _ => None,
+        }
+    }
+
+    /// Whether the entry is a file.
+    pub fn is_file(&self) -> bool {
+        matches!(self, Self::File(_))
+    }
+
+    /// Whether the entry is a folder.
+    pub fn is_folder(&self) -> bool {
+        matches!(self, Self::Folder(_))
+    }
+
+    /// Whether the entry is a file or folder which was deleted.
+    pub fn is_deleted(&self) -> bool {
+        matches!(self, Self::Deleted(_))
+    }
+}
+
+impl From<files::Metadata> for Entry {
+    fn from(metadata: files::Metadata) -> Self {
+        match metadata {
+            files::Metadata::File(f) => Self::File(f),
+            files::Metadata::Folder(f) => Self::Folder(f),
+            files::Metadata::Deleted(f) => Self::Deleted(f),
+        }
+    }
+}
+
+impl From<Entry> for files::Metadata {
+    fn from(entry: Entry) -> Self {
+        match entry {
+            Entry::File(f) => Self::File(f),
+            Entry::Folder(f) => Self::Folder(f),
+            Entry::Deleted(f) => Self::Deleted(f),
+        }
+    }
+}
+
 /// An iterator over directory entries which pages though the Dropbox API as necessary.
 pub struct DirectoryIterator<'a, T: UserAuthClient> {
     client: &'a T,
     buffer: VecDeque<files::Metadata>,
-    cursor: Option<String>,
+    cursor: String,
+    has_more: bool,
+    retry: RetryOpts,
+}
+
+impl<'a, T: UserAuthClient> DirectoryIterator<'a, T> {
+    /// Continue a listing from a cursor given by [`cursor`](Self::cursor). What is listed is the
+    /// same as when the listing was started, but failed requests are retried as given here.
+    /// Nothing is requested until the first entry is.
+    pub fn continue_from(client: &'a T, cursor: ListCursor, retry: RetryOpts) -> Self {
+        Self {
+            client,
+            buffer: VecDeque::new(),
+            cursor: cursor.0,
+            has_more: true,
+            retry,
+        }
+    }
+
+    /// The cursor to continue this listing from with [`continue_from`](Self::continue_from), if
+    /// all the entries received so far have been yielded. Since entries are received a page at a
+    /// time, this is `None` until the last entry of each page has been yielded.
+    ///
+    /// Once the iterator is finished, the cursor can be used later to list what has changed since.
+    pub fn cursor(&self) -> Option<ListCursor> {
+        if self.buffer.is_empty() {
+            Some(ListCursor(self.cursor.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// Only yield the files, skipping folders and deleted entries.
+    pub fn files(
+        self,
+    ) -> impl Iterator<Item = Result<files::FileMetadata, Error<ListFolderContinueError>>> + 'a
+    {
+        self.filter_map(|entry| match entry {
+            Ok(Entry::File(f)) => Some(Ok(f)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Only yield the folders, skipping files and deleted entries.
+    pub fn folders(
+        self,
+    ) -> impl Iterator<Item = Result<files::FolderMetadata, Error<ListFolderContinueError>>> + 'a
+    {
+        self.filter_map(|entry| match entry {
+            Ok(Entry::Folder(f)) => Some(Ok(f)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Only yield entries whose paths match any of the given glob patterns, such as `**/*.jpg` or
+    /// `/photos/*/*.jpg`.
+    ///
+    /// Each pattern is matched against the whole path of an entry, ignoring case: `*` matches
+    /// anything except `/`, `?` matches any one character except `/`, and `**` as a whole path
+    /// component matches any number of components, including none.
+    pub fn matching<P: AsRef<str>>(
+        self,
+        patterns: impl IntoIterator<Item = P>,
+    ) -> impl Iterator<Item = Result<Entry, Error<ListFolderContinueError>>> + 'a {
+        let patterns = patterns
+            .into_iter()
+            .map(|pattern| {
+                glob::components(&pattern.as_ref().to_lowercase())
+                    .into_iter()
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        self.filter(move |entry| {
+            let Ok(entry) = entry else {
+                return true;
+            };
+            let path = entry.path_lower();
+            let components = glob::components(&path);
+            patterns
+                .iter()
+                .any(|pattern| glob::match_path(pattern, &components))
+        })
+    }
+
+    /// Get the next page of entries.
+    fn fetch(&mut self) -> Result<(), Error<ListFolderContinueError>> {
+        let result = list_folder_internal(
+            self.client,
+            files::list_folder_continue,
+            &files::ListFolderContinueArg::new(self.cursor.clone()),
+            &self.retry,
+        )?;
+        self.buffer.extend(result.entries);
+        self.cursor = result.cursor;
+        self.has_more = result.has_more;
+        Ok(())
+    }
 }
 
 impl<T: UserAuthClient> Iterator for DirectoryIterator<'_, T> {
-    type Item = Result<files::Metadata, Error<ListFolderContinueError>>;
+    type Item = Result<Entry, Error<ListFolderContinueError>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(entry) = self.buffer.pop_front() {
-            Some(Ok(entry))
-        } else if let Some(cursor) = self.cursor.take() {
-            let result = match list_folder_internal(
-                self.client,
-                files::list_folder_continue,
-                &files::ListFolderContinueArg::new(cursor),
-            ) {
-                Ok(r) => r,
-                Err(e) => return Some(Err(e)),
-            };
-            self.buffer.extend(result.entries);
-            if result.has_more {
-                self.cursor = Some(result.cursor);
+        while self.buffer.is_empty() && self.has_more {
+            if let Err(e) = self.fetch() {
+                // Stop here, but leave the cursor, so the listing can be continued later.
+                self.has_more = false;
+                return Some(Err(e));
             }
-            self.buffer.pop_front().map(Ok)
-        } else {
-            None
         }
+        self.buffer.pop_front().map(|entry| Ok(entry.into()))
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
         (
             self.buffer.len(),
-            if self.cursor.is_none() {
-                Some(self.buffer.len())
-            } else {
+            if self.has_more {
                 None
+            } else {
+                Some(self.buffer.len())
             },
         )
     }
 }
 
-fn list_folder_internal<T, A, E>(
+/// Write a recursive listing of the given path to `out` as newline-delimited JSON, one entry per
+/// line, as it is received. Returns the number of entries written.
+///
+/// Each line is an object with `type` (`"file"`, `"folder"`, or `"deleted"`), `path`, `size`,
+/// `content_hash`, and `modified` (the file's `client_modified` timestamp) fields. Fields which
+/// don't apply to an entry are `null`.
+pub fn write_ndjson<T: UserAuthClient>(
+    client: &T,
+    path: &str,
+    mut out: impl Write,
+) -> Result<u64, BoxedError> {
+    let mut count = 0;
+    for entry in list_directory(client, path, true).map_err(Error::boxed)? {
+        let line = match entry.map_err(Error::boxed)? {
+            Entry::File(f) => serde_json::json!({
+                "type": "file",
+                "path": f.path_display,
+                "size": f.size,
+                "content_hash": f.content_hash,
+                "modified": f.client_modified,
+            }),
+            Entry::Folder(f) => serde_json::json!({
+                "type": "folder",
+                "path": f.path_display,
+                "size": null,
+                "content_hash": null,
+                "modified": null,
+            }),
+            Entry::Deleted(f) => serde_json::json!({
+                "type": "deleted",
+                "path": f.path_display,
+                "size": null,
+                "content_hash": null,
+                "modified": null,
+            }),
+        };
+        serde_json::to_writer(&mut out, &line)?;
+        out.write_all(b"\n")
+            .map_err(|e| Error::HttpClient(e.into()))?;
+        count += 1;
+    }
+    out.flush().map_err(|e| Error::HttpClient(e.into()))?;
+    Ok(count)
+}
+
+/// The path to request for the given absolute path.
+fn requested_path(path: &str) -> String {
+    assert!(
+        path.starts_with('/'),
+        "path needs to be absolute (start with a '/')"
+    );
+    if path == "/" {
+        // Root folder should be requested as empty string.
+        String::new()
+    } else {
+        path.to_owned()
+    }
+}
+
+fn list_folder_internal<T, A, R, E>(
     client: &T,
-    f: impl Fn(&T, &A) -> Result<files::ListFolderResult, Error<E>>,
+    f: impl Fn(&T, &A) -> Result<R, Error<E>>,
     arg: &A,
-) -> Result<files::ListFolderResult, Error<E>>
+    retry: &RetryOpts,
+) -> Result<R, Error<E>>
 where
     T: UserAuthClient,
     A: Clone,
     E: std::error::Error + Send + Sync + 'static,
 {
-    let mut errors = 0;
+    let mut backoff = Backoff::new(retry).for_endpoint(EndpointKind::Rpc);
     loop {
+        backoff.wait();
         match f(client, arg) {
             Ok(r) => break Ok(r),
-            Err(Error::RateLimited {
-                reason,
-                retry_after_seconds,
-            }) => {
-                warn!("rate-limited ({reason}), waiting {retry_after_seconds} seconds");
-                if retry_after_seconds > 0 {
-                    sleep(Duration::from_secs(u64::from(retry_after_seconds)));
-                }
-            }
-            Err(e) => {
-                errors += 1;
-                if errors == 3 {
-                    warn!("Error calling list_folder_continue: {e}, failing");
-                    return Err(e);
-                } else {
-                    warn!("Error calling list_folder_continue: {e}, retrying.");
-                }
+            Err(e @ Error::Api(_)) => return Err(e),
+            Err(e) if path_root::classify(&e).is_some() => {
+                // This won't go away by retrying. Use `path_root::classify` on the error to find
+                // out what to do instead.
+                warn!("Error listing folder: {e}, failing");
+                return Err(e);
             }
+            Err(e) => backoff.handle("listing folder", e)?,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+
+    #[test]
+    fn ndjson() {
+        let client = MockClient::new(
+            r#"{"entries": [
+                {".tag": "folder", "name": "d", "id": "id:1", "path_display": "/d"},
+                {".tag": "file", "name": "f", "id": "id:2", "path_display": "/d/f",
+                 "client_modified": "2020-01-01T00:00:00Z", "server_modified": "2020-01-02T00:00:00Z",
+                 "rev": "0123456789abc", "size": 5, "content_hash": "abc"}
+            ], "cursor": "cursor", "has_more": false}"#,
+        );
+        let mut out = vec![];
+        assert_eq!(2, write_ndjson(&client, "/d", &mut out).unwrap());
+        assert_eq!(
+            concat!(
+                r#"{"content_hash":null,"modified":null,"path":"/d","size":null,"type":"folder"}"#,
+                "\n",
+                r#"{"content_hash":"abc","modified":"2020-01-01T00:00:00Z","path":"/d/f","size":5,"type":"file"}"#,
+                "\n",
+            ),
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn entries() {
+        let client = MockClient::new(
+            r#"{"entries": [
+                {".tag": "folder", "name": "d", "id": "id:1", "path_display": "/D"},
+                {".tag": "file", "name": "f", "id": "id:2", "path_lower": "/d/f",
+                 "client_modified": "2020-01-01T00:00:00Z", "server_modified": "2020-01-02T00:00:00Z",
+                 "rev": "0123456789abc", "size": 5, "content_hash": "abc"}
+            ], "cursor": "cursor", "has_more": false}"#,
+        );
+        let entries = list_directory(&client, "/d", false)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let [folder, file] = &entries[..] else {
+            panic!("expected two entries");
+        };
+        assert!(folder.is_folder());
+        assert_eq!(
+            ("/D", "d", None, None),
+            (
+                folder.path(),
+                folder.name(),
+                folder.size(),
+                folder.modified()
+            )
+        );
+        assert!(file.is_file());
+        assert_eq!(
+            ("/d/f", "f", Some(5)),
+            (file.path(), file.name(), file.size())
+        );
+        assert_eq!(Some("abc"), file.content_hash());
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_577_836_800)),
+            file.modified()
+        );
+        assert!(matches!(
+            files::Metadata::from(file.clone()),
+            files::Metadata::File(_)
+        ));
+    }
+
+    #[test]
+    fn files_and_folders() {
+        let page = r#"{"entries": [
+            {".tag": "folder", "name": "d", "id": "id:1", "path_display": "/d"},
+            {".tag": "file", "name": "f", "id": "id:2", "path_display": "/d/f",
+             "client_modified": "2020-01-01T00:00:00Z", "server_modified": "2020-01-02T00:00:00Z",
+             "rev": "0123456789abc", "size": 5},
+            {".tag": "deleted", "name": "g", "path_display": "/d/g"}
+        ], "cursor": "cursor", "has_more": false}"#;
+        let client = MockClient::new(page);
+        let files = list_directory(&client, "/", true)
+            .unwrap()
+            .files()
+            .map(|f| f.unwrap().name)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["f"], files);
+        let folders = list_directory(&client, "/", true)
+            .unwrap()
+            .folders()
+            .map(|f| f.unwrap().name)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["d"], folders);
+    }
+
+    #[test]
+    fn matching() {
+        let client = MockClient::new(
+            r#"{"entries": [
+                {".tag": "folder", "name": "Photos", "id": "id:1", "path_lower": "/photos",
+                 "path_display": "/Photos"},
+                {".tag": "deleted", "name": "a.JPG", "path_lower": "/photos/a.jpg",
+                 "path_display": "/Photos/a.JPG"},
+                {".tag": "deleted", "name": "b.png", "path_lower": "/photos/x/b.png"},
+                {".tag": "deleted", "name": "c.txt", "path_lower": "/c.txt"}
+            ], "cursor": "cursor", "has_more": false}"#,
+        );
+        let paths = |patterns: &[&str]| {
+            list_directory(&client, "/", true)
+                .unwrap()
+                .matching(patterns)
+                .map(|entry| entry.unwrap().path().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["/Photos/a.JPG"], paths(&["**/*.jpg"]));
+        assert_eq!(vec!["/photos/x/b.png"], paths(&["/photos/*/*"]));
+        assert_eq!(vec!["/Photos", "/c.txt"], paths(&["*", "**/*.txt"]));
+        assert!(paths(&[]).is_empty());
+    }
+
+    #[test]
+    fn list_opts() {
+        let opts = ListOpts {
+            include_deleted: true,
+            include_mounted_folders: false,
+            limit: Some(10),
+            ..Default::default()
+        };
+        let arg = opts.arg("/d".to_owned());
+        assert!(!arg.recursive);
+        assert!(arg.include_deleted);
+        assert!(!arg.include_mounted_folders);
+        assert!(arg.include_non_downloadable_files);
+        assert_eq!(Some(10), arg.limit);
+
+        let client = MockClient::new(
+            r#"{"entries": [
+                {".tag": "deleted", "name": "f", "path_display": "/d/f"}
+            ], "cursor": "cursor", "has_more": false}"#,
+        );
+        let entries = list_directory_with_opts(&client, "/d", &opts)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(matches!(entries[..], [Entry::Deleted(_)]));
+    }
+
+    #[test]
+    fn retries() {
+        let client = MockClient::new("null").then(500, "oops").then(
+            200,
+            r#"{"entries": [
+                    {".tag": "folder", "name": "a", "id": "id:a", "path_display": "/a"}
+                ], "cursor": "cursor", "has_more": false}"#,
+        );
+        let opts = ListOpts {
+            retry: RetryOpts {
+                initial_backoff_time: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let entries = list_directory_with_opts(&client, "/", &opts)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(2, client.urls.lock().unwrap().len());
+
+        let client = MockClient::new("null").then(500, "oops");
+        let opts = ListOpts {
+            retry: RetryOpts {
+                retry_count: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(list_directory_with_opts(&client, "/", &opts).is_err());
+        assert_eq!(1, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn continue_from_cursor() {
+        let client = MockClient::new("null")
+            .then(
+                200,
+                r#"{"entries": [
+                    {".tag": "folder", "name": "a", "id": "id:a", "path_display": "/a"}
+                ], "cursor": "one", "has_more": true}"#,
+            )
+            .then(
+                200,
+                r#"{"entries": [
+                    {".tag": "folder", "name": "b", "id": "id:b", "path_display": "/b"},
+                    {".tag": "folder", "name": "c", "id": "id:c", "path_display": "/c"}
+                ], "cursor": "two", "has_more": false}"#,
+            );
+        let mut iter = list_directory(&client, "/", false).unwrap();
+        assert_eq!(None, iter.cursor());
+        iter.next().unwrap().unwrap();
+        let saved = iter.cursor().unwrap().to_string();
+        assert_eq!("one", saved);
+
+        let cursor = saved.parse().unwrap();
+        let mut iter = DirectoryIterator::continue_from(&client, cursor, RetryOpts::default());
+        assert_eq!(1, client.urls.lock().unwrap().len());
+        iter.next().unwrap().unwrap();
+        assert_eq!(None, iter.cursor());
+        iter.next().unwrap().unwrap();
+        assert!(iter.next().is_none());
+        assert_eq!("two", iter.cursor().unwrap().as_str());
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn changes() {
+        let client = MockClient::new("null")
+            .then(200, r#"{"cursor": "latest"}"#)
+            .then(
+                200,
+                r#"{"entries": [
+                    {".tag": "deleted", "name": "f", "path_display": "/d/f"}
+                ], "cursor": "after", "has_more": false}"#,
+            );
+        let cursor = latest_cursor(&client, "/d", true).unwrap();
+        assert_eq!("latest", cursor.as_str());
+
+        let mut changes = changes_since(&client, cursor);
+        assert!(matches!(changes.next(), Some(Ok(Entry::Deleted(_)))));
+        assert!(changes.next().is_none());
+        assert_eq!("after", changes.cursor().unwrap().as_str());
+    }
+
+    #[test]
+    fn visit_pages() {
+        let client = MockClient::new("null")
+            .then(
+                200,
+                r#"{"entries": [
+                    {".tag": "folder", "name": "a", "id": "id:a", "path_display": "/a"},
+                    {".tag": "folder", "name": "b", "id": "id:b", "path_display": "/b"}
+                ], "cursor": "cursor", "has_more": true}"#,
+            )
+            .then(
+                200,
+                r#"{"entries": [
+                    {".tag": "folder", "name": "c", "id": "id:c", "path_display": "/c"},
+                    {".tag": "folder", "name": "d", "id": "id:d", "path_display": "/d"}
+                ], "cursor": "cursor", "has_more": true}"#,
+            );
+        let mut names = vec![];
+        visit_directory(&client, "/", true, |entry| {
+            let files::Metadata::Folder(folder) = entry else {
+                panic!("expected a folder");
+            };
+            names.push(folder.name);
+            if names.len() == 3 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+        assert_eq!(vec!["a", "b", "c"], names);
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn timeboxed() {
+        let page = r#"{"entries": [
+            {".tag": "folder", "name": "a", "id": "id:a", "path_display": "/a"}
+        ], "cursor": "next", "has_more": true}"#;
+        let client = MockClient::new("null").then(200, page);
+        let mut count = 0;
+        let opts = ListOpts {
+            recursive: true,
+            ..Default::default()
+        };
+        let cursor = visit_directory_timeboxed(
+            &client,
+            ListFrom::Path("/".to_owned()),
+            &opts,
+            Duration::ZERO,
+            |_| {
+                count += 1;
+                ControlFlow::Continue(())
+            },
+        )
+        .unwrap();
+        assert_eq!(Some("next".to_owned()), cursor);
+        assert_eq!(1, count);
+
+        let client = MockClient::new("null").then(
+            200,
+            r#"{"entries": [], "cursor": "done", "has_more": false}"#,
+        );
+        let cursor = visit_directory_timeboxed(
+            &client,
+            ListFrom::Cursor("next".to_owned()),
+            &ListOpts::default(),
+            Duration::ZERO,
+            |_| ControlFlow::Continue(()),
+        )
+        .unwrap();
+        assert_eq!(None, cursor);
+    }
+}
@@ -0,0 +1,200 @@
+//! Listing the revisions of a file.
+
+use std::time::SystemTime;
+
+use dropbox_sdk::files::{self, ListRevisionsError};
+use dropbox_sdk::Error;
+use dropbox_sdk::UserAuthClient;
+
+use crate::limits::EndpointKind;
+use crate::retry::{Backoff, RetryOpts};
+use crate::timestamp;
+
+/// The most revisions Dropbox lists at once. It doesn't list any more than this, even in pages.
+const MAX_REVISIONS: u64 = 100;
+
+/// Options for [`revisions`].
+#[derive(Debug, Clone)]
+pub struct RevisionsOpts {
+    /// How many of the most recent revisions to list, up to 100.
+    pub limit: u64,
+
+    /// Follow the file by its ID rather than by its path, so that revisions from before it was
+    /// moved or renamed are included, and revisions of other files which were at its path aren't.
+    pub by_id: bool,
+
+    /// How to retry the request.
+    pub retry: RetryOpts,
+}
+
+impl Default for RevisionsOpts {
+    fn default() -> Self {
+        Self {
+            limit: 10,
+            by_id: false,
+            retry: RetryOpts::default(),
+        }
+    }
+}
+
+/// A revision of a file, listed by [`revisions`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Revision {
+    metadata: files::FileMetadata,
+}
+
+impl Revision {
+    /// The revision's ID, which can be downloaded with a `rev:` path, such as with
+    /// `download::download_rev_to_path`, or restored with [`files::restore`].
+    pub fn rev(&self) -> &str {
+        &self.metadata.rev
+    }
+
+    /// The size of the file at this revision, in bytes.
+    pub fn size(&self) -> u64 {
+        self.metadata.size
+    }
+
+    /// The [Content Hash](crate::content_hash) of the file at this revision, if Dropbox gave one.
+    pub fn content_hash(&self) -> Option<&str> {
+        self.metadata.content_hash.as_deref()
+    }
+
+    /// When the file was modified, as given by whatever uploaded this revision.
+    pub fn modified(&self) -> Option<SystemTime> {
+        timestamp::parse(&self.metadata.client_modified)
+    }
+
+    /// When Dropbox received this revision.
+    pub fn server_modified(&self) -> Option<SystemTime> {
+        timestamp::parse(&self.metadata.server_modified)
+    }
+
+    /// The metadata Dropbox gave for the revision.
+    pub fn metadata(&self) -> &files::FileMetadata {
+        &self.metadata
+    }
+
+    /// Take the metadata Dropbox gave for the revision.
+    pub fn into_metadata(self) -> files::FileMetadata {
+        self.metadata
+    }
+}
+
+/// List the most recent revisions of the file at the given path, or with the given ID, newest
+/// first. Deleted revisions aren't included.
+pub fn revisions(
+    client: &impl UserAuthClient,
+    path: &str,
+    opts: &RevisionsOpts,
+) -> Result<Vec<Revision>, Error<ListRevisionsError>> {
+    let mode = if opts.by_id {
+        files::ListRevisionsMode::Id
+    } else {
+        files::ListRevisionsMode::Path
+    };
+    let arg = files::ListRevisionsArg::new(path.to_owned())
+        .with_mode(mode)
+        .with_limit(opts.limit.clamp(1, MAX_REVISIONS));
+    let mut backoff = Backoff::new(&opts.retry).for_endpoint(EndpointKind::Rpc);
+    loop {
+        backoff.wait();
+        match files::list_revisions(client, &arg) {
+            Ok(result) => {
+                return Ok(result
+                    .entries
+                    .into_iter()
+                    .map(|metadata| Revision { metadata })
+                    .collect())
+            }
+            Err(e @ Error::Api(_)) => return Err(e),
+            Err(e) => backoff.handle("listing revisions", e)?,
+        }
+    }
+}
+
+/// Find the revision the file at the given path, or with the given ID, was at the given time: the
+/// newest one Dropbox received before then. The [`limit`](RevisionsOpts::limit) is ignored, and
+/// the 100 most recent revisions are looked at, so this returns `None` if they are all newer, as
+/// well as if the file didn't exist yet.
+pub fn revision_at(
+    client: &impl UserAuthClient,
+    path: &str,
+    time: SystemTime,
+    opts: &RevisionsOpts,
+) -> Result<Option<Revision>, Error<ListRevisionsError>> {
+    let opts = RevisionsOpts {
+        limit: MAX_REVISIONS,
+        ..opts.clone()
+    };
+    let revisions = revisions(client, path, &opts)?;
+    Ok(revisions.into_iter().find(|revision| {
+        revision
+            .server_modified()
+            .is_some_and(|modified| modified <= time)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+    use std::time::Duration;
+
+    #[test]
+    fn lists_revisions() {
+        let client = MockClient::new("null").then(500, "oops").then(
+            200,
+            r#"{"is_deleted": false, "entries": [
+                {"name": "f", "id": "id:f", "size": 2, "rev": "bbbbbbbbb",
+                "client_modified": "2024-01-01T00:00:00Z",
+                "server_modified": "2024-01-02T00:00:00Z"},
+                {"name": "f", "id": "id:f", "size": 1, "rev": "aaaaaaaaa",
+                "client_modified": "2024-01-01T00:00:00Z",
+                "server_modified": "2024-01-01T00:00:00Z"}]}"#,
+        );
+        let opts = RevisionsOpts {
+            retry: RetryOpts {
+                initial_backoff_time: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let revisions = revisions(&client, "/f", &opts).unwrap();
+        assert_eq!(
+            vec![("bbbbbbbbb", 2), ("aaaaaaaaa", 1)],
+            revisions
+                .iter()
+                .map(|r| (r.rev(), r.size()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_153_600)),
+            revisions[0].server_modified()
+        );
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn finds_revision_at_time() {
+        let client = MockClient::new("null").then(
+            200,
+            r#"{"is_deleted": false, "entries": [
+                {"name": "f", "id": "id:f", "size": 1, "rev": "ccccccccc",
+                "client_modified": "2024-01-03T00:00:00Z",
+                "server_modified": "2024-01-03T00:00:00Z"},
+                {"name": "f", "id": "id:f", "size": 1, "rev": "bbbbbbbbb",
+                "client_modified": "2024-01-02T00:00:00Z",
+                "server_modified": "2024-01-02T00:00:00Z"},
+                {"name": "f", "id": "id:f", "size": 1, "rev": "aaaaaaaaa",
+                "client_modified": "2024-01-01T00:00:00Z",
+                "server_modified": "2024-01-01T00:00:00Z"}]}"#,
+        );
+        // 2024-01-02T12:00:00Z
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_196_800);
+        let revision = revision_at(&client, "/f", time, &RevisionsOpts::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!("bbbbbbbbb", revision.rev());
+    }
+}
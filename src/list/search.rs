@@ -0,0 +1,171 @@
+//! Searching for files and folders by name and contents.
+
+use std::collections::VecDeque;
+
+use dropbox_sdk::files::{self, SearchError};
+use dropbox_sdk::Error;
+use dropbox_sdk::UserAuthClient;
+
+use super::Entry;
+use crate::limits::EndpointKind;
+use crate::retry::{Backoff, RetryOpts};
+
+/// Options for [`search`].
+#[derive(Debug, Clone, Default)]
+pub struct SearchOpts {
+    /// Only search under this path, rather than everywhere.
+    pub path: Option<String>,
+
+    /// Only match the query against names, not the contents of files.
+    pub filename_only: bool,
+
+    /// Only find files with these extensions, such as `"jpg"`.
+    pub file_extensions: Option<Vec<String>>,
+
+    /// Find deleted files and folders instead of ones which still exist.
+    pub deleted: bool,
+
+    /// How many results to get per request, up to 1000, rather than 100. The iterator still
+    /// returns all of them.
+    pub page_size: Option<u64>,
+
+    /// How to retry failed requests, for the first page of results and the rest.
+    pub retry: RetryOpts,
+}
+
+impl SearchOpts {
+    fn arg(&self, query: &str) -> files::SearchV2Arg {
+        let mut options = files::SearchOptions::default()
+            .with_filename_only(self.filename_only)
+            .with_file_status(if self.deleted {
+                files::FileStatus::Deleted
+            } else {
+                files::FileStatus::Active
+            });
+        options.path = self.path.clone();
+        options.file_extensions = self.file_extensions.clone();
+        if let Some(page_size) = self.page_size {
+            options.max_results = page_size;
+        }
+        files::SearchV2Arg::new(query.to_owned()).with_options(options)
+    }
+}
+
+/// Make an iterator that yields the files and folders matching a search query, most relevant
+/// first, getting more pages of results as necessary.
+///
+/// Dropbox indexes files for search some time after they change, so recent changes might not be
+/// found yet.
+pub fn search<'a, T: UserAuthClient>(
+    client: &'a T,
+    query: &str,
+    opts: &SearchOpts,
+) -> Result<SearchIterator<'a, T>, Error<SearchError>> {
+    let result = search_internal(client, files::search_v2, &opts.arg(query), &opts.retry)?;
+    let mut iter = SearchIterator {
+        client,
+        buffer: VecDeque::new(),
+        cursor: None,
+        retry: opts.retry.clone(),
+    };
+    iter.add(result);
+    Ok(iter)
+}
+
+/// An iterator over search results which pages through the Dropbox API as necessary.
+pub struct SearchIterator<'a, T: UserAuthClient> {
+    client: &'a T,
+    buffer: VecDeque<files::Metadata>,
+    cursor: Option<String>,
+    retry: RetryOpts,
+}
+
+impl<T: UserAuthClient> SearchIterator<'_, T> {
+    fn add(&mut self, result: files::SearchV2Result) {
+        self.buffer
+            .extend(result.matches.into_iter().filter_map(|m| match m.metadata {
+                files::MetadataV2::Metadata(metadata) => Some(metadata),
+                // Something newer than this version of the SDK knows about.
+                _ => None,
+            }));
+        self.cursor = result.cursor.filter(|_| result.has_more);
+    }
+}
+
+impl<T: UserAuthClient> Iterator for SearchIterator<'_, T> {
+    type Item = Result<Entry, Error<SearchError>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.is_empty() {
+            let cursor = self.cursor.take()?;
+            match search_internal(
+                self.client,
+                files::search_continue_v2,
+                &files::SearchV2ContinueArg::new(cursor),
+                &self.retry,
+            ) {
+                Ok(result) => self.add(result),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.buffer.pop_front().map(|entry| Ok(entry.into()))
+    }
+}
+
+fn search_internal<T: UserAuthClient, A>(
+    client: &T,
+    f: impl Fn(&T, &A) -> Result<files::SearchV2Result, Error<SearchError>>,
+    arg: &A,
+    retry: &RetryOpts,
+) -> Result<files::SearchV2Result, Error<SearchError>> {
+    let mut backoff = Backoff::new(retry).for_endpoint(EndpointKind::Rpc);
+    loop {
+        backoff.wait();
+        match f(client, arg) {
+            Ok(result) => return Ok(result),
+            Err(e @ Error::Api(_)) => return Err(e),
+            Err(e) => backoff.handle("searching", e)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+
+    #[test]
+    fn pages() {
+        let client = MockClient::new("null")
+            .then(
+                200,
+                r#"{"matches": [
+                    {"metadata": {".tag": "metadata", "metadata":
+                        {".tag": "folder", "name": "a", "id": "id:a", "path_display": "/a"}}}
+                ], "has_more": true, "cursor": "next"}"#,
+            )
+            .then(
+                200,
+                r#"{"matches": [
+                    {"metadata": {".tag": "metadata", "metadata":
+                        {".tag": "folder", "name": "b", "id": "id:b", "path_display": "/b"}}}
+                ], "has_more": false}"#,
+            );
+        let opts = SearchOpts {
+            deleted: true,
+            page_size: Some(1),
+            ..Default::default()
+        };
+        let arg = opts.arg("q");
+        let options = arg.options.as_ref().unwrap();
+        assert_eq!(files::FileStatus::Deleted, options.file_status);
+        assert_eq!(1, options.max_results);
+
+        let names = search(&client, "q", &opts)
+            .unwrap()
+            .map(|entry| entry.unwrap().name().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["a", "b"], names);
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+}
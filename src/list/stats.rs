@@ -0,0 +1,136 @@
+//! Adding up how much is in a folder.
+
+use std::collections::BTreeMap;
+
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use super::{list_directory, Entry};
+use crate::glob;
+
+/// How much is in a folder, counted by [`folder_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FolderStats {
+    /// The total size of the files, in bytes.
+    pub bytes: u64,
+
+    /// How many files there are.
+    pub files: u64,
+
+    /// How many folders there are, not counting the folder itself.
+    pub folders: u64,
+}
+
+impl FolderStats {
+    fn add(&mut self, entry: &Entry) {
+        match entry {
+            Entry::File(f) => {
+                self.bytes += f.size;
+                self.files += 1;
+            }
+            Entry::Folder(_) => self.folders += 1,
+            Entry::Deleted(_) => (),
+        }
+    }
+}
+
+/// Count the files and folders under the given path, and add up the sizes of the files.
+pub fn folder_stats(client: &impl UserAuthClient, path: &str) -> Result<FolderStats, BoxedError> {
+    stats(client, path, |_, _| ())
+}
+
+/// Like [`folder_stats`], but count what's under each file and folder directly in the given path
+/// separately. They're keyed by their names in lowercase, since Dropbox doesn't always give the
+/// case of the folders a path is in. A file's stats count just the file, and a folder's count the
+/// folder as well as what's in it.
+pub fn child_stats(
+    client: &impl UserAuthClient,
+    path: &str,
+) -> Result<BTreeMap<String, FolderStats>, BoxedError> {
+    let depth = glob::components(path).len();
+    let mut children = BTreeMap::<String, FolderStats>::new();
+    stats(client, path, |path_lower, entry| {
+        if let Some(child) = glob::components(path_lower).get(depth) {
+            children.entry((*child).to_owned()).or_default().add(entry);
+        }
+    })?;
+    Ok(children)
+}
+
+/// List everything under the path, adding it up, and calling `each` with the lowercased path of
+/// every entry but the folder itself.
+fn stats(
+    client: &impl UserAuthClient,
+    path: &str,
+    mut each: impl FnMut(&str, &Entry),
+) -> Result<FolderStats, BoxedError> {
+    let path = match path.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    };
+    // A recursive listing of a folder includes the folder itself, except for the root.
+    let folder = path.to_lowercase();
+    let mut stats = FolderStats::default();
+    for entry in list_directory(client, path, true).map_err(Error::boxed)? {
+        let entry = entry.map_err(Error::boxed)?;
+        let path_lower = entry.path_lower();
+        if path_lower == folder {
+            continue;
+        }
+        stats.add(&entry);
+        each(&path_lower, &entry);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+
+    #[test]
+    fn counts_children() {
+        let client = MockClient::new(
+            r#"{"entries": [
+                {".tag": "folder", "name": "D", "id": "id:1", "path_lower": "/d"},
+                {".tag": "folder", "name": "E", "id": "id:2", "path_lower": "/d/e"},
+                {".tag": "file", "name": "f", "id": "id:3", "path_lower": "/d/e/f",
+                 "client_modified": "2020-01-01T00:00:00Z", "server_modified": "2020-01-02T00:00:00Z",
+                 "rev": "0123456789abc", "size": 5},
+                {".tag": "file", "name": "G", "id": "id:4", "path_lower": "/d/g",
+                 "client_modified": "2020-01-01T00:00:00Z", "server_modified": "2020-01-02T00:00:00Z",
+                 "rev": "0123456789abc", "size": 2}
+            ], "cursor": "cursor", "has_more": false}"#,
+        );
+        assert_eq!(
+            FolderStats {
+                bytes: 7,
+                files: 2,
+                folders: 1,
+            },
+            folder_stats(&client, "/D/").unwrap()
+        );
+        let children = child_stats(&client, "/D").unwrap();
+        assert_eq!(
+            vec![
+                (
+                    "e".to_owned(),
+                    FolderStats {
+                        bytes: 5,
+                        files: 1,
+                        folders: 1,
+                    }
+                ),
+                (
+                    "g".to_owned(),
+                    FolderStats {
+                        bytes: 2,
+                        files: 1,
+                        folders: 0,
+                    }
+                ),
+            ],
+            children.into_iter().collect::<Vec<_>>()
+        );
+    }
+}
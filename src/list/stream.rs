@@ -0,0 +1,206 @@
+//! Listing a folder through [`futures_core::Stream`].
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use dropbox_sdk::{BoxedError, Error, UserAuthClient};
+use futures_core::Stream;
+
+use super::{list_directory_with_opts, Entry, ListOpts};
+
+/// How many entries can be waiting to be yielded before the background thread has to wait.
+const MAX_QUEUED_ENTRIES: usize = 10_000;
+
+/// Make a stream that yields directory entries under a given path, like the iterator made by
+/// [`list_directory_with_opts`], so that async code can list folders without blocking the
+/// executor.
+///
+/// The Dropbox client is blocking, so the requests are made by a background thread, and the
+/// stream is [`Poll::Pending`] while it is behind. It gets pages ahead of time, until ten thousand
+/// entries are waiting. If a request fails, including the first one, the error is yielded, and
+/// then the stream ends. A path which isn't absolute fails the same way, without being requested.
+///
+/// Dropping the stream stops the listing.
+pub fn list_directory_async<C: UserAuthClient + Send + Sync + 'static>(
+    client: Arc<C>,
+    path: &str,
+    opts: ListOpts,
+) -> DirectoryStream {
+    let shared = Arc::new(Shared::default());
+    if !path.starts_with('/') {
+        let mut state = shared.state.lock().unwrap();
+        state.error = Some(Error::HttpClient(Box::new(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("path {path:?} needs to be absolute (start with a '/')"),
+        ))));
+        state.done = true;
+    } else {
+        let shared = Arc::clone(&shared);
+        let path = path.to_owned();
+        thread::spawn(move || list(client.as_ref(), &path, &opts, &shared));
+    }
+    DirectoryStream { shared }
+}
+
+/// A stream of directory entries, made by [`list_directory_async`].
+pub struct DirectoryStream {
+    shared: Arc<Shared>,
+}
+
+/// State shared between the stream and the background thread.
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// Entries received, waiting to be yielded.
+    entries: VecDeque<Entry>,
+
+    /// Whether the listing is finished, or failed.
+    done: bool,
+
+    /// The error which stopped the listing, until it is yielded.
+    error: Option<BoxedError>,
+
+    /// Whether the stream has been dropped, so the listing should stop.
+    closed: bool,
+
+    /// The task waiting for entries.
+    waker: Option<Waker>,
+}
+
+impl State {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Stream for DirectoryStream {
+    type Item = Result<Entry, BoxedError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(entry) = state.entries.pop_front() {
+            self.shared.cond.notify_one();
+            Poll::Ready(Some(Ok(entry)))
+        } else if let Some(e) = state.error.take() {
+            Poll::Ready(Some(Err(e)))
+        } else if state.done {
+            Poll::Ready(None)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for DirectoryStream {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        self.shared.cond.notify_one();
+    }
+}
+
+/// List the folder, on the background thread.
+fn list(client: &impl UserAuthClient, path: &str, opts: &ListOpts, shared: &Shared) {
+    let iter = match list_directory_with_opts(client, path, opts) {
+        Ok(iter) => iter,
+        Err(e) => {
+            let mut state = shared.state.lock().unwrap();
+            state.error = Some(Error::boxed(e));
+            state.done = true;
+            state.wake();
+            return;
+        }
+    };
+    for entry in iter {
+        let mut state = shared.state.lock().unwrap();
+        match entry {
+            Ok(entry) => {
+                while state.entries.len() >= MAX_QUEUED_ENTRIES && !state.closed {
+                    state = shared.cond.wait(state).unwrap();
+                }
+                if state.closed {
+                    return;
+                }
+                state.entries.push_back(entry);
+            }
+            Err(e) => {
+                state.error = Some(Error::boxed(e));
+                state.done = true;
+            }
+        }
+        state.wake();
+        if state.done {
+            return;
+        }
+    }
+    let mut state = shared.state.lock().unwrap();
+    state.done = true;
+    state.wake();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::{block_on, MockClient};
+    use std::future::poll_fn;
+
+    async fn collect(mut stream: DirectoryStream) -> Vec<Result<Entry, BoxedError>> {
+        let mut items = vec![];
+        while let Some(item) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn streams_pages() {
+        let client = MockClient::new("null")
+            .then(
+                200,
+                r#"{"entries": [
+                    {".tag": "folder", "name": "a", "id": "id:a", "path_display": "/a"}
+                ], "cursor": "cursor", "has_more": true}"#,
+            )
+            .then(
+                200,
+                r#"{"entries": [
+                    {".tag": "folder", "name": "b", "id": "id:b", "path_display": "/b"}
+                ], "cursor": "cursor", "has_more": false}"#,
+            );
+        let stream = list_directory_async(Arc::new(client), "/", ListOpts::default());
+        let names = block_on(collect(stream))
+            .into_iter()
+            .map(|entry| entry.unwrap().name().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["a", "b"], names);
+    }
+
+    #[test]
+    fn first_request_fails() {
+        let client = MockClient::new("null").then(409, r#"{"error": {".tag": "other"}}"#);
+        let stream = list_directory_async(Arc::new(client), "/", ListOpts::default());
+        let items = block_on(collect(stream));
+        assert!(matches!(items[..], [Err(Error::Api(_))]));
+    }
+
+    #[test]
+    fn relative_path_fails() {
+        let client = Arc::new(MockClient::new("null"));
+        let stream = list_directory_async(Arc::clone(&client), "a", ListOpts::default());
+        let items = block_on(collect(stream));
+        assert!(matches!(items[..], [Err(Error::HttpClient(_))]));
+        assert!(client.urls.lock().unwrap().is_empty());
+    }
+}
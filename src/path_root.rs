@@ -0,0 +1,137 @@
+//! Recognizing errors from trying to reach content which isn't accessible from the path root in
+//! use, such as a team's shared space, and saying what to do about them.
+//!
+//! Members of teams with a team space have a root namespace which is different from their home
+//! namespace, and paths are relative to the home namespace unless the client is set to use a
+//! different [`PathRoot`](common::PathRoot), such as with
+//! [`UserAuthDefaultClient::set_path_root`](dropbox_sdk::default_client::UserAuthDefaultClient::set_path_root).
+//! The toolbox can't change the path root of a client it is given, so instead, errors which can be
+//! fixed by doing so are returned as a [`Restricted`] error saying which path root to use, and the
+//! operation can be tried again with it.
+
+use std::fmt::{self, Display, Formatter};
+
+use dropbox_sdk::{common, files};
+use dropbox_sdk::{BoxedError, Error};
+
+/// The HTTP status of responses to requests with an invalid path root.
+pub(crate) const PATH_ROOT_ERROR_STATUS: u16 = 422;
+
+/// Content couldn't be reached, because of where it is rather than because it doesn't exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Restricted {
+    /// The path root the client used isn't valid for the user. To reach their whole root
+    /// namespace, including any team space, use the one given by [`Restricted::path_root`], with
+    /// this namespace ID.
+    NeedsPathRoot(String),
+
+    /// The user doesn't have permission to use the namespace the client gave as its path root.
+    NoPermission,
+
+    /// The file can't be transferred because its content is restricted, such as for legal
+    /// reasons. Changing the path root won't help.
+    RestrictedContent,
+}
+
+impl Restricted {
+    /// The path root to set on the client before trying again, if that would help.
+    pub fn path_root(&self) -> Option<common::PathRoot> {
+        match self {
+            Self::NeedsPathRoot(namespace_id) => Some(common::PathRoot::Root(namespace_id.clone())),
+            Self::NoPermission | Self::RestrictedContent => None,
+        }
+    }
+}
+
+impl Display for Restricted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NeedsPathRoot(namespace_id) => write!(
+                f,
+                "invalid path root; set the client's path root to root namespace {namespace_id}"
+            ),
+            Self::NoPermission => f.write_str("no permission to use the client's path root"),
+            Self::RestrictedContent => f.write_str("the content is restricted"),
+        }
+    }
+}
+
+impl std::error::Error for Restricted {}
+
+/// Recognize an error which means content couldn't be reached because of where it is.
+pub fn classify<E: std::error::Error + 'static>(error: &Error<E>) -> Option<Restricted> {
+    match error {
+        Error::UnexpectedHttpError { code, response } if *code == PATH_ROOT_ERROR_STATUS => {
+            let json = serde_json::from_str::<serde_json::Value>(response).ok()?;
+            let error = &json["error"];
+            match error[".tag"].as_str()? {
+                "invalid_root" => error["invalid_root"]["root_namespace_id"]
+                    .as_str()
+                    .map(|id| Restricted::NeedsPathRoot(id.to_owned())),
+                "no_permission" => Some(Restricted::NoPermission),
+                _ => None,
+            }
+        }
+        Error::Api(_) => match error.downcast_ref_inner::<files::LookupError>() {
+            Some(files::LookupError::RestrictedContent) => Some(Restricted::RestrictedContent),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Box an error like [`Error::boxed`], replacing it with a [`Restricted`] error if it is one.
+pub fn boxed<E: std::error::Error + Send + Sync + 'static>(error: Error<E>) -> BoxedError {
+    match classify(&error) {
+        Some(restricted) => {
+            warn!("{restricted}: {error}");
+            Error::Api(Box::new(restricted))
+        }
+        None => error.boxed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_root() {
+        let error = Error::<files::GetMetadataError>::UnexpectedHttpError {
+            code: 422,
+            response: r#"{
+                "error_summary": "invalid_root/..",
+                "error": {
+                    ".tag": "invalid_root",
+                    "invalid_root": {
+                        ".tag": "team",
+                        "root_namespace_id": "123",
+                        "home_namespace_id": "456",
+                        "home_path": "/Someone"
+                    }
+                }
+            }"#
+            .to_owned(),
+        };
+        let restricted = classify(&error).unwrap();
+        assert_eq!(Restricted::NeedsPathRoot("123".to_owned()), restricted);
+        assert_eq!(
+            Some(common::PathRoot::Root("123".to_owned())),
+            restricted.path_root()
+        );
+    }
+
+    #[test]
+    fn restricted_content() {
+        let error = Error::Api(files::GetMetadataError::Path(
+            files::LookupError::RestrictedContent,
+        ));
+        let Error::Api(e) = boxed(error) else {
+            panic!("wrong result");
+        };
+        assert_eq!(Some(&Restricted::RestrictedContent), e.downcast_ref());
+
+        let error = Error::Api(files::GetMetadataError::Path(files::LookupError::NotFound));
+        assert_eq!(None, classify(&error));
+    }
+}
@@ -0,0 +1,170 @@
+//! A guard against accidentally modifying a Dropbox account.
+//!
+//! Wrap a client in a [`ReadOnlyClient`] and use it in place of the original, and any request that
+//! could modify the account fails with a [`ReadOnlyMode`] error without being sent. This is useful
+//! for backup and audit tools, which need to be sure they never change anything.
+
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+
+use dropbox_sdk::client_trait::{
+    HttpClient, HttpRequest, HttpRequestResultRaw, NoauthClient, TeamSelect, UserAuthClient,
+};
+use dropbox_sdk::Error;
+
+/// Routes which are known not to modify anything. Requests to any other route are refused.
+const READ_ONLY_ROUTES: &[&str] = &[
+    "check/user",
+    "files/download",
+    "files/download_zip",
+    "files/export",
+    "files/get_file_lock_batch",
+    "files/get_metadata",
+    "files/get_preview",
+    "files/get_temporary_link",
+    "files/get_thumbnail",
+    "files/get_thumbnail_batch",
+    "files/get_thumbnail_v2",
+    "files/list_folder",
+    "files/list_folder/continue",
+    "files/list_folder/get_latest_cursor",
+    "files/list_folder/longpoll",
+    "files/list_revisions",
+    "files/search/continue_v2",
+    "files/search_v2",
+    "sharing/get_shared_link_file",
+    "sharing/get_shared_link_metadata",
+    "sharing/list_shared_links",
+    "users/get_current_account",
+    "users/get_space_usage",
+];
+
+/// A request was refused because it could modify the account.
+///
+/// This is returned as [`Error::HttpClient`], and can be found using
+/// [`Error::downcast_ref_inner`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadOnlyMode {
+    /// The API route that was refused, such as `files/upload_session/start`.
+    pub route: String,
+}
+
+impl Display for ReadOnlyMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "refusing to call {} in read-only mode", self.route)
+    }
+}
+
+impl std::error::Error for ReadOnlyMode {}
+
+/// A client which passes read-only requests through to another client, and refuses all others.
+pub struct ReadOnlyClient<C> {
+    inner: C,
+}
+
+impl<C: HttpClient> ReadOnlyClient<C> {
+    /// Wrap the given client.
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+
+    /// The wrapped client.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+/// A request made by a [`ReadOnlyClient`].
+pub struct ReadOnlyRequest<R> {
+    inner: R,
+    route: Option<String>,
+}
+
+impl<R: HttpRequest> HttpRequest for ReadOnlyRequest<R> {
+    fn set_header(self, name: &str, value: &str) -> Self {
+        Self {
+            inner: self.inner.set_header(name, value),
+            route: self.route,
+        }
+    }
+}
+
+impl<C: HttpClient> HttpClient for ReadOnlyClient<C> {
+    type Request = ReadOnlyRequest<C::Request>;
+
+    fn execute(&self, request: Self::Request, body: &[u8]) -> Result<HttpRequestResultRaw, Error> {
+        if let Some(route) = request.route {
+            error!("refusing to call {route} in read-only mode");
+            return Err(Error::HttpClient(Box::new(ReadOnlyMode { route })));
+        }
+        self.inner.execute(request.inner, body)
+    }
+
+    fn new_request(&self, url: &str) -> Self::Request {
+        // URLs are like "https://api.dropboxapi.com/2/files/list_folder".
+        let route = url.split_once("/2/").map_or(url, |(_, route)| route);
+        ReadOnlyRequest {
+            inner: self.inner.new_request(url),
+            route: if READ_ONLY_ROUTES.contains(&route) {
+                None
+            } else {
+                Some(route.to_owned())
+            },
+        }
+    }
+
+    fn update_token(&self, old_token: Arc<String>) -> Result<bool, Error> {
+        self.inner.update_token(old_token)
+    }
+
+    fn token(&self) -> Option<Arc<String>> {
+        self.inner.token()
+    }
+
+    fn path_root(&self) -> Option<&str> {
+        self.inner.path_root()
+    }
+
+    fn team_select(&self) -> Option<&TeamSelect> {
+        self.inner.team_select()
+    }
+}
+
+impl<C: UserAuthClient> UserAuthClient for ReadOnlyClient<C> {}
+impl<C: NoauthClient> NoauthClient for ReadOnlyClient<C> {}
+
+#[cfg(all(test, any(feature = "list", feature = "upload")))]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+
+    #[cfg(feature = "upload")]
+    #[test]
+    fn refuses_upload() {
+        use crate::upload::UploadSession;
+        let client = Arc::new(ReadOnlyClient::new(MockClient::new(
+            r#"{"session_id": "session"}"#,
+        )));
+        let err = UploadSession::new(client).err().unwrap();
+        assert_eq!(
+            Some(&ReadOnlyMode {
+                route: "files/upload_session/start".to_owned()
+            }),
+            err.downcast_ref_inner()
+        );
+    }
+
+    #[cfg(feature = "list")]
+    #[test]
+    fn allows_listing() {
+        let client = ReadOnlyClient::new(MockClient::new(
+            r#"{"entries": [], "cursor": "cursor", "has_more": false}"#,
+        ));
+        assert_eq!(
+            0,
+            crate::list::list_directory(&client, "/", false)
+                .unwrap()
+                .count()
+        );
+    }
+}
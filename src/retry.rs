@@ -0,0 +1,386 @@
+//! Retrying failed requests.
+
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(any(feature = "download", feature = "list", feature = "upload"))]
+use {dropbox_sdk::Error, std::fmt::Display};
+
+#[cfg(any(feature = "download", feature = "list", feature = "upload"))]
+use crate::cancel::CancelToken;
+use crate::limits::EndpointKind;
+
+/// Options for how to retry failed requests.
+#[derive(Debug, Clone)]
+pub struct RetryOpts {
+    /// How many consecutive errors until retries are abandoned and the operation is failed?
+    pub retry_count: u32,
+
+    /// Errors are handled with retry and exponential backoff with jitter. The first backoff will
+    /// be this long, and subsequent backoffs will each be doubled in length (up to
+    /// [`max_backoff_time`](Self::max_backoff_time)), until [`retry_count`](Self::retry_count)
+    /// retries have been attempted, or the request succeeds.
+    ///
+    /// Rate-limiting errors are not counted as errors; instead, the request is retried after
+    /// waiting as long as Dropbox asks.
+    pub initial_backoff_time: Duration,
+
+    /// Exponential backoff duration won't increase past this time.
+    pub max_backoff_time: Duration,
+
+    /// Randomly vary each backoff time by up to a quarter, so that many requests which fail at
+    /// once don't all retry at once too. Turn this off to make retries happen at predictable
+    /// times, such as in tests.
+    pub jitter: bool,
+
+    /// Where the random numbers for [`jitter`](Self::jitter) come from. This is [`SystemRng`] by
+    /// default; a [`SeededRng`] can be used to make the jitter the same each time.
+    pub rng: Arc<dyn Rng>,
+}
+
+impl Default for RetryOpts {
+    fn default() -> Self {
+        Self {
+            retry_count: 3,
+            initial_backoff_time: Duration::from_millis(500), // 0.5 + 1 + 2 = 3.5 secs max (+/- jitter)
+            max_backoff_time: Duration::from_secs(2),
+            jitter: true,
+            rng: Arc::new(SystemRng),
+        }
+    }
+}
+
+/// A source of random numbers, for [jittering](RetryOpts::jitter) backoff times.
+pub trait Rng: Debug + Send + Sync {
+    /// Return a random number, uniformly distributed over all `u32` values.
+    fn next_u32(&self) -> u32;
+}
+
+/// Random numbers from the operating system, using [`ring`'s](ring::rand::SystemRandom).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemRng;
+
+impl Rng for SystemRng {
+    fn next_u32(&self) -> u32 {
+        use ring::rand::{generate, SystemRandom};
+        let bytes: [u8; 4] = generate(&SystemRandom::new()).unwrap().expose();
+        u32::from_ne_bytes(bytes)
+    }
+}
+
+/// Pseudo-random numbers which are the same each time for a given seed, for reproducible tests.
+/// These are not suitable for anything needing unpredictability.
+#[derive(Debug)]
+pub struct SeededRng {
+    state: AtomicU64,
+}
+
+impl SeededRng {
+    /// Make one starting from the given seed.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: AtomicU64::new(seed),
+        }
+    }
+}
+
+impl Rng for SeededRng {
+    fn next_u32(&self) -> u32 {
+        // SplitMix64.
+        const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut z = self.state.fetch_add(GAMMA, SeqCst).wrapping_add(GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        ((z ^ (z >> 31)) >> 32) as u32
+    }
+}
+
+/// Remembers when Dropbox last asked for each kind of endpoint to be left alone, so that requests
+/// started after being rate-limited, even by other operations, wait until then instead of being
+/// rate-limited again right away.
+///
+/// The toolbox uses the [global](RateLimits::global) one. It can be [saved](RateLimits::save) to
+/// a file and [loaded](RateLimits::load) again, so that the next run of a program which was just
+/// rate-limited waits too.
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    until: Mutex<[Option<SystemTime>; 2]>, // indexed by `index`
+}
+
+static GLOBAL_RATE_LIMITS: RateLimits = RateLimits::new();
+
+impl RateLimits {
+    /// Make a new one, with no rate limits in effect.
+    pub const fn new() -> Self {
+        Self {
+            until: Mutex::new([None; 2]),
+        }
+    }
+
+    /// The one shared by everything in this process.
+    pub fn global() -> &'static Self {
+        &GLOBAL_RATE_LIMITS
+    }
+
+    fn index(kind: EndpointKind) -> usize {
+        match kind {
+            EndpointKind::Rpc => 0,
+            EndpointKind::Content => 1,
+        }
+    }
+
+    /// When requests to the given kind of endpoint may be made again, if that's in the future.
+    pub fn until(&self, kind: EndpointKind) -> Option<SystemTime> {
+        self.until.lock().unwrap()[Self::index(kind)].filter(|&until| until > SystemTime::now())
+    }
+
+    /// Record that Dropbox asked for requests to the given kind of endpoint to wait for the given
+    /// amount of time.
+    pub fn record(&self, kind: EndpointKind, retry_after: Duration) {
+        self.extend(kind, SystemTime::now() + retry_after);
+    }
+
+    fn extend(&self, kind: EndpointKind, until: SystemTime) {
+        let mut all = self.until.lock().unwrap();
+        let entry = &mut all[Self::index(kind)];
+        if entry.is_none_or(|prev| prev < until) {
+            *entry = Some(until);
+        }
+    }
+
+    /// Wait until requests to the given kind of endpoint may be made.
+    pub fn wait(&self, kind: EndpointKind) {
+        if let Some(wait) = self.remaining(kind) {
+            debug!("waiting {wait:?} for an earlier rate limit on {kind:?} endpoints");
+            sleep(wait);
+        }
+    }
+
+    /// How long requests to the given kind of endpoint still have to wait, if at all.
+    fn remaining(&self, kind: EndpointKind) -> Option<Duration> {
+        self.until(kind)
+            .and_then(|until| until.duration_since(SystemTime::now()).ok())
+    }
+
+    /// Save the rate limits in effect to the given file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = vec![];
+        for kind in [EndpointKind::Rpc, EndpointKind::Content] {
+            if let Some(until) = self.until(kind) {
+                let secs = until.duration_since(UNIX_EPOCH).unwrap_or_default();
+                writeln!(out, "{} {}", Self::index(kind), secs.as_secs_f64())?;
+            }
+        }
+        fs::write(path, out)
+    }
+
+    /// Load rate limits saved by [`RateLimits::save`], keeping any later ones already in effect. A
+    /// missing file is treated as having none.
+    pub fn load(&self, path: &Path) -> io::Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for line in contents.lines() {
+            let invalid =
+                || io::Error::new(io::ErrorKind::InvalidData, format!("bad line {line:?}"));
+            let (index, secs) = line.split_once(' ').ok_or_else(invalid)?;
+            let kind = match index {
+                "0" => EndpointKind::Rpc,
+                "1" => EndpointKind::Content,
+                _ => return Err(invalid()),
+            };
+            let secs = secs
+                .parse::<f64>()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(invalid)?;
+            self.extend(kind, UNIX_EPOCH + secs);
+        }
+        Ok(())
+    }
+}
+
+/// Keeps track of errors and backoff time for retrying a request.
+#[cfg(any(feature = "download", feature = "list", feature = "upload"))]
+pub(crate) struct Backoff<'a> {
+    opts: &'a RetryOpts,
+    errors: u32,
+    next: Duration,
+    operation_id: Option<&'a str>,
+    endpoint: Option<EndpointKind>,
+    cancel: Option<&'a CancelToken>,
+}
+
+#[cfg(any(feature = "download", feature = "list", feature = "upload"))]
+impl<'a> Backoff<'a> {
+    pub fn new(opts: &'a RetryOpts) -> Self {
+        Self {
+            opts,
+            errors: 0,
+            next: opts.initial_backoff_time,
+            operation_id: None,
+            endpoint: None,
+            cancel: None,
+        }
+    }
+
+    /// Record rate limits for the given kind of endpoint in the [global](RateLimits::global)
+    /// [`RateLimits`], so that other requests wait for them too.
+    pub fn for_endpoint(mut self, kind: EndpointKind) -> Self {
+        self.endpoint = Some(kind);
+        self
+    }
+
+    /// Stop waiting, whether for a rate limit or to retry, if the given token is cancelled. The
+    /// caller still has to check it before making the next request.
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    pub fn cancelled_by(mut self, token: Option<&'a CancelToken>) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Wait for any rate limit in effect for the endpoint, before making a request.
+    pub fn wait(&self) {
+        let Some(kind) = self.endpoint else {
+            return;
+        };
+        if let Some(wait) = RateLimits::global().remaining(kind) {
+            debug!("waiting {wait:?} for an earlier rate limit on {kind:?} endpoints");
+            self.sleep(wait);
+        }
+    }
+
+    /// Sleep for the given time, or until cancelled.
+    fn sleep(&self, duration: Duration) {
+        match self.cancel {
+            Some(token) => token.sleep(duration),
+            None => sleep(duration),
+        }
+    }
+
+    /// Include the given operation ID in log messages.
+    #[cfg_attr(not(feature = "upload"), allow(dead_code))]
+    pub fn with_operation_id(mut self, operation_id: &'a str) -> Self {
+        self.operation_id = Some(operation_id);
+        self
+    }
+
+    /// Handle an error from a request described by `what`. If it should be retried, this sleeps
+    /// as appropriate and returns `Ok`; otherwise it returns the error.
+    pub fn handle<E: Display>(&mut self, what: &str, error: Error<E>) -> Result<(), Error<E>> {
+        let id = match self.operation_id {
+            Some(id) => format!("[{id}] "),
+            None => String::new(),
+        };
+        match error {
+            Error::RateLimited {
+                reason,
+                retry_after_seconds,
+            } => {
+                warn!("{id}rate-limited ({reason}), waiting {retry_after_seconds} seconds");
+                let retry_after = Duration::from_secs(u64::from(retry_after_seconds));
+                if let Some(kind) = self.endpoint {
+                    RateLimits::global().record(kind, retry_after);
+                }
+                if !retry_after.is_zero() {
+                    self.sleep(retry_after);
+                }
+                Ok(())
+            }
+            e @ Error::UnexpectedHttpError {
+                code: crate::path_root::PATH_ROOT_ERROR_STATUS,
+                ..
+            } => {
+                // This won't go away by retrying.
+                error!("{id}Error {what}: {e}, failing.");
+                Err(e)
+            }
+            e => {
+                self.errors += 1;
+                if self.errors >= self.opts.retry_count {
+                    error!("{id}Error {what}: {e}, failing.");
+                    return Err(e);
+                }
+                warn!("{id}Error {what}: {e}, retrying.");
+                self.sleep(if self.opts.jitter {
+                    jitter(self.next, self.opts.rng.as_ref())
+                } else {
+                    self.next
+                });
+                if self.next < self.opts.max_backoff_time {
+                    self.next *= 2;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Add a random duration in the range [-duration/4, duration/4].
+#[cfg(any(feature = "download", feature = "list", feature = "upload"))]
+fn jitter(duration: Duration, rng: &dyn Rng) -> Duration {
+    let u = rng.next_u32();
+    let max = f64::from(u32::MAX);
+    let f = f64::from(u) / max / 4.;
+    if u % 2 == 0 {
+        duration + duration.mul_f64(f)
+    } else {
+        duration - duration.mul_f64(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(feature = "download", feature = "list", feature = "upload"))]
+    fn seeded_jitter() {
+        let a = SeededRng::new(42);
+        let b = SeededRng::new(42);
+        let second = Duration::from_secs(1);
+        for _ in 0..100 {
+            let d = jitter(second, &a);
+            assert_eq!(d, jitter(second, &b));
+            assert!(d >= second * 3 / 4 && d <= second * 5 / 4, "{d:?}");
+        }
+        assert_ne!(SeededRng::new(1).next_u32(), SeededRng::new(2).next_u32());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let limits = RateLimits::new();
+        assert_eq!(None, limits.until(EndpointKind::Rpc));
+        limits.record(EndpointKind::Rpc, Duration::from_secs(60));
+        limits.record(EndpointKind::Rpc, Duration::from_secs(30));
+        let until = limits.until(EndpointKind::Rpc).unwrap();
+        assert!(until > SystemTime::now() + Duration::from_secs(50));
+        assert_eq!(None, limits.until(EndpointKind::Content));
+
+        let path = std::env::temp_dir().join(format!("rate_limits_{}", std::process::id()));
+        limits.save(&path).unwrap();
+        let loaded = RateLimits::new();
+        loaded.load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let loaded_until = loaded.until(EndpointKind::Rpc).unwrap();
+        // Saved with sub-second precision.
+        assert!(
+            loaded_until
+                .max(until)
+                .duration_since(loaded_until.min(until))
+                .unwrap()
+                < Duration::from_millis(1)
+        );
+        assert_eq!(None, loaded.until(EndpointKind::Content));
+
+        // A missing file has none.
+        RateLimits::new().load(&path).unwrap();
+    }
+}
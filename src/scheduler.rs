@@ -0,0 +1,237 @@
+//! Limiting how many requests are made to Dropbox at once, across everything that shares a
+//! scheduler.
+//!
+//! Each upload has its own [`parallelism`](crate::upload::UploadOpts::parallelism), so several
+//! uploads running at once multiply into many more concurrent requests than any of them asked
+//! for, which mostly results in rate limiting. Giving them all the same [`TransferScheduler`]
+//! caps the total instead, while each upload still uses as many threads as it is configured to.
+//!
+//! A [`BandwidthSchedule`] similarly limits the bandwidth of the transfers given it, with limits
+//! which change with the time of day.
+
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A limit on the number of requests which can be in progress at once, shared by uploads and
+/// downloads which are given it in their options.
+///
+/// Only the requests themselves are counted: a request's slot is given up while it waits to be
+/// retried, so one which keeps failing doesn't hold up the others.
+#[derive(Debug)]
+pub struct TransferScheduler {
+    max_concurrent: usize,
+    active: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl TransferScheduler {
+    /// Make a scheduler which allows at most the given number of requests at once.
+    ///
+    /// Panics if it is zero.
+    pub fn new(max_concurrent: usize) -> Self {
+        assert!(max_concurrent > 0, "non-zero limit required");
+        Self {
+            max_concurrent,
+            active: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// A scheduler shared by everything in this process which uses it, allowing 20 requests at
+    /// once.
+    pub fn global() -> Arc<Self> {
+        static GLOBAL: OnceLock<Arc<TransferScheduler>> = OnceLock::new();
+        Arc::clone(GLOBAL.get_or_init(|| Arc::new(Self::new(20))))
+    }
+
+    /// The most requests allowed at once.
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// How many requests are in progress now.
+    pub fn active(&self) -> usize {
+        *self.active.lock().unwrap()
+    }
+
+    /// Wait until a request can be made, and return a permit which allows it until it is dropped.
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut active = self
+            .freed
+            .wait_while(self.active.lock().unwrap(), |active| {
+                *active >= self.max_concurrent
+            })
+            .unwrap();
+        *active += 1;
+        Permit { scheduler: self }
+    }
+
+    /// Like [`acquire`](Self::acquire), but return `None` instead of waiting.
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut active = self.active.lock().unwrap();
+        if *active >= self.max_concurrent {
+            return None;
+        }
+        *active += 1;
+        Some(Permit { scheduler: self })
+    }
+}
+
+/// Permission from a [`TransferScheduler`] to make a request. Drop it when the request is done.
+#[derive(Debug)]
+#[must_use = "the request is only allowed while the permit is held"]
+pub struct Permit<'a> {
+    scheduler: &'a TransferScheduler,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.scheduler.active.lock().unwrap() -= 1;
+        self.scheduler.freed.notify_one();
+    }
+}
+
+/// Bandwidth limits which change with the time of day, such as to limit a long-running backup
+/// during working hours but not at night. Give it to the `bandwidth_schedule` option of uploads
+/// or downloads, where it takes the place of their fixed `max_bytes_per_sec` limit.
+///
+/// Times of day are in UTC, unless an offset is given with [`utc_offset`](Self::utc_offset).
+#[derive(Debug, Clone)]
+pub struct BandwidthSchedule {
+    default: Option<u64>,
+    windows: Vec<BandwidthWindow>,
+    utc_offset: i64,
+}
+
+/// A time of day when a [`BandwidthSchedule`] has a different limit.
+#[derive(Debug, Clone)]
+struct BandwidthWindow {
+    start: Duration,
+    end: Duration,
+    max_bytes_per_sec: Option<u64>,
+}
+
+impl BandwidthWindow {
+    fn contains(&self, time_of_day: Duration) -> bool {
+        if self.start <= self.end {
+            self.start <= time_of_day && time_of_day < self.end
+        } else {
+            // It goes past midnight.
+            self.start <= time_of_day || time_of_day < self.end
+        }
+    }
+}
+
+/// How long a day is.
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+impl BandwidthSchedule {
+    /// Make a schedule with the given limit in bytes per second, or none, outside of any windows
+    /// added to it.
+    ///
+    /// Panics if the limit is zero.
+    pub fn new(default_max_bytes_per_sec: Option<u64>) -> Self {
+        assert_ne!(Some(0), default_max_bytes_per_sec, "non-zero rate required");
+        Self {
+            default: default_max_bytes_per_sec,
+            windows: vec![],
+            utc_offset: 0,
+        }
+    }
+
+    /// Use times of day which are the given number of seconds ahead of UTC, such as those of the
+    /// local time zone.
+    pub fn utc_offset(mut self, seconds: i32) -> Self {
+        self.utc_offset = i64::from(seconds);
+        self
+    }
+
+    /// Limit transfers to the given number of bytes per second, or not at all, from the start time
+    /// of day until the end time of day, given as times since midnight. If the end is before the
+    /// start, the window goes past midnight. Where windows overlap, the one added first applies.
+    ///
+    /// Panics if the limit is zero, or a time isn't within a day.
+    pub fn window(
+        mut self,
+        start: Duration,
+        end: Duration,
+        max_bytes_per_sec: Option<u64>,
+    ) -> Self {
+        assert_ne!(Some(0), max_bytes_per_sec, "non-zero rate required");
+        assert!(start < DAY && end <= DAY, "times must be within a day");
+        self.windows.push(BandwidthWindow {
+            start,
+            end,
+            max_bytes_per_sec,
+        });
+        self
+    }
+
+    /// The limit in bytes per second at the given time, if any.
+    pub fn max_bytes_per_sec_at(&self, time: SystemTime) -> Option<u64> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs =
+            (since_epoch.as_secs() as i64 + self.utc_offset).rem_euclid(DAY.as_secs() as i64);
+        let time_of_day = Duration::new(secs as u64, since_epoch.subsec_nanos());
+        self.windows
+            .iter()
+            .find(|window| window.contains(time_of_day))
+            .map_or(self.default, |window| window.max_bytes_per_sec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_concurrency() {
+        let scheduler = TransferScheduler::new(2);
+        let peak = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let _permit = scheduler.acquire();
+                    peak.fetch_max(scheduler.active(), SeqCst);
+                    thread::sleep(Duration::from_millis(5));
+                });
+            }
+        });
+        assert!(peak.load(SeqCst) <= 2);
+        assert_eq!(0, scheduler.active());
+    }
+
+    #[test]
+    fn try_acquire() {
+        let scheduler = TransferScheduler::new(1);
+        let permit = scheduler.try_acquire().unwrap();
+        assert!(scheduler.try_acquire().is_none());
+        drop(permit);
+        assert!(scheduler.try_acquire().is_some());
+    }
+
+    #[test]
+    fn bandwidth_schedule() {
+        let hour = Duration::from_secs(60 * 60);
+        let schedule = BandwidthSchedule::new(Some(1000))
+            .utc_offset(-5 * 60 * 60)
+            .window(22 * hour, 6 * hour, None)
+            .window(9 * hour, 17 * hour, Some(10));
+        // 2024-01-01T00:00:00Z, which is 19:00 at the offset.
+        let midnight = UNIX_EPOCH + Duration::from_secs(1_704_067_200);
+        assert_eq!(Some(1000), schedule.max_bytes_per_sec_at(midnight));
+        assert_eq!(None, schedule.max_bytes_per_sec_at(midnight + 3 * hour));
+        assert_eq!(None, schedule.max_bytes_per_sec_at(midnight + 10 * hour));
+        assert_eq!(
+            Some(1000),
+            schedule.max_bytes_per_sec_at(midnight + 11 * hour)
+        );
+        assert_eq!(
+            Some(10),
+            schedule.max_bytes_per_sec_at(midnight + 14 * hour)
+        );
+    }
+}
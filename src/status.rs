@@ -0,0 +1,154 @@
+//! A JSON view of the progress of uploads, for reporting status remotely.
+//!
+//! Add a [`Job`] to a [`StatusBoard`] for each upload, and pass its
+//! [`progress_handler`](Job::progress_handler) in the upload's options. Then
+//! [`StatusBoard::snapshot`] gives the status of all the jobs as JSON, which can be served from an
+//! HTTP status endpoint or written to a file. No server is included.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+use serde_json::json;
+
+use crate::upload::ProgressHandler;
+
+/// A collection of jobs whose status can be reported together.
+#[derive(Default)]
+pub struct StatusBoard {
+    jobs: Mutex<BTreeMap<String, Job>>,
+}
+
+impl StatusBoard {
+    /// Make a new empty board.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a job with the given name, and the total number of bytes it will transfer, if known. If
+    /// there is already a job with the same name, it is replaced.
+    pub fn add_job(&self, name: impl Into<String>, total_bytes: Option<u64>) -> Job {
+        let job = Job {
+            state: Arc::new(Mutex::new(JobState {
+                total_bytes,
+                ..Default::default()
+            })),
+        };
+        self.jobs.lock().unwrap().insert(name.into(), job.clone());
+        job
+    }
+
+    /// Remove all the jobs which have finished, successfully or not.
+    pub fn remove_finished(&self) {
+        self.jobs
+            .lock()
+            .unwrap()
+            .retain(|_, job| job.state.lock().unwrap().result.is_none());
+    }
+
+    /// Get the status of all the jobs, in order of name, like:
+    ///
+    /// ```json
+    /// {"jobs": [{"name": "backup.tar", "state": "running", "bytes_transferred": 8388608,
+    ///   "total_bytes": 104857600, "instant_rate": 1048576.0, "overall_rate": 943718.4,
+    ///   "error": null}]}
+    /// ```
+    ///
+    /// `state` is one of `running`, `done`, or `failed`, and `error` is only set if it failed.
+    pub fn snapshot(&self) -> serde_json::Value {
+        let jobs = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, job)| {
+                let state = job.state.lock().unwrap();
+                let (status, error) = match &state.result {
+                    None => ("running", None),
+                    Some(Ok(())) => ("done", None),
+                    Some(Err(e)) => ("failed", Some(e.as_str())),
+                };
+                json!({
+                    "name": name,
+                    "state": status,
+                    "bytes_transferred": state.bytes_transferred,
+                    "total_bytes": state.total_bytes,
+                    "instant_rate": state.instant_rate,
+                    "overall_rate": state.overall_rate,
+                    "error": error,
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "jobs": jobs })
+    }
+}
+
+/// A handle to update the status of one job on a [`StatusBoard`].
+#[derive(Clone)]
+pub struct Job {
+    state: Arc<Mutex<JobState>>,
+}
+
+#[derive(Default)]
+struct JobState {
+    bytes_transferred: u64,
+    total_bytes: Option<u64>,
+    instant_rate: f64,
+    overall_rate: f64,
+    result: Option<Result<(), String>>,
+}
+
+impl Job {
+    /// A progress handler which updates this job, for use in
+    /// [`UploadOpts`](crate::upload::UploadOpts).
+    pub fn progress_handler(&self) -> Arc<Box<dyn ProgressHandler>> {
+        Arc::new(Box::new(self.clone()))
+    }
+
+    /// Mark the job as finished successfully.
+    pub fn finished(&self) {
+        self.state.lock().unwrap().result = Some(Ok(()));
+    }
+
+    /// Mark the job as failed with the given error.
+    pub fn failed(&self, error: impl Display) {
+        self.state.lock().unwrap().result = Some(Err(error.to_string()));
+    }
+}
+
+impl ProgressHandler for Job {
+    fn update(&self, bytes_uploaded: u64, instant_rate: f64, overall_rate: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.bytes_transferred = bytes_uploaded;
+        state.instant_rate = instant_rate;
+        state.overall_rate = overall_rate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot() {
+        let board = StatusBoard::new();
+        let a = board.add_job("a", Some(100));
+        let b = board.add_job("b", None);
+        a.progress_handler().update(50, 10., 5.);
+        b.failed("oops");
+
+        assert_eq!(
+            json!({"jobs": [
+                {"name": "a", "state": "running", "bytes_transferred": 50, "total_bytes": 100,
+                    "instant_rate": 10.0, "overall_rate": 5.0, "error": null},
+                {"name": "b", "state": "failed", "bytes_transferred": 0, "total_bytes": null,
+                    "instant_rate": 0.0, "overall_rate": 0.0, "error": "oops"},
+            ]}),
+            board.snapshot()
+        );
+
+        a.finished();
+        board.remove_finished();
+        assert_eq!(json!({"jobs": []}), board.snapshot());
+    }
+}
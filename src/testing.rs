@@ -0,0 +1,449 @@
+//! Fault injection for testing how code using this crate handles errors.
+//!
+//! Wrap a client in a [`FaultInjector`] and configure it to make some of the requests it sends
+//! fail, then pass it to the functions in this crate as usual. This allows testing resume and
+//! retry handling against realistic failure patterns without needing an unreliable network.
+//!
+//! This module is only available with the `testing` feature enabled.
+
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::sync::Arc;
+
+use dropbox_sdk::auth::RateLimitReason;
+use dropbox_sdk::client_trait::{
+    HttpClient, HttpRequest, HttpRequestResultRaw, NoauthClient, TeamSelect, UserAuthClient,
+};
+use dropbox_sdk::Error;
+
+/// The route used to upload data to an upload session.
+pub const APPEND_ROUTE: &str = "files/upload_session/append_v2";
+
+/// The route used to download a file.
+pub const DOWNLOAD_ROUTE: &str = "files/download";
+
+/// A kind of failure to inject.
+#[derive(Debug, Clone)]
+pub enum Fault {
+    /// Fail the request as if the connection was reset.
+    Fail,
+
+    /// Fail the request as if Dropbox had rate-limited it.
+    RateLimited {
+        /// How long Dropbox says to wait before retrying.
+        retry_after_seconds: u32,
+    },
+
+    /// Let the request succeed, but fail reading the response body after this many bytes.
+    TruncateBody {
+        /// How many bytes of the body can be read before the error.
+        after_bytes: u64,
+    },
+}
+
+struct Rule {
+    route: String,
+    every: u64,
+    fault: Fault,
+    count: AtomicU64,
+}
+
+/// A client which passes requests through to another client, except for ones that the configured
+/// rules say should fail.
+pub struct FaultInjector<C> {
+    inner: C,
+    rules: Vec<Rule>,
+}
+
+impl<C: HttpClient> FaultInjector<C> {
+    /// Wrap the given client. Until rules are added, all requests are passed through.
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            rules: vec![],
+        }
+    }
+
+    /// Inject the given fault into every `n`th request to the given route (such as
+    /// [`APPEND_ROUTE`]).
+    ///
+    /// Every rule for a route counts all the requests to it. If more than one is due to inject a
+    /// fault into the same request, the one added first is used.
+    pub fn with_fault(mut self, route: impl Into<String>, n: u64, fault: Fault) -> Self {
+        assert!(n > 0, "n must be at least 1");
+        self.rules.push(Rule {
+            route: route.into(),
+            every: n,
+            fault,
+            count: AtomicU64::new(0),
+        });
+        self
+    }
+
+    /// Fail every `n`th request to the given route.
+    pub fn fail_every(self, route: impl Into<String>, n: u64) -> Self {
+        self.with_fault(route, n, Fault::Fail)
+    }
+
+    /// Rate-limit every `n`th request to the given route.
+    pub fn rate_limit_every(
+        self,
+        route: impl Into<String>,
+        n: u64,
+        retry_after_seconds: u32,
+    ) -> Self {
+        self.with_fault(
+            route,
+            n,
+            Fault::RateLimited {
+                retry_after_seconds,
+            },
+        )
+    }
+
+    /// Fail reading the body of every `n`th response from the given route after the given number
+    /// of bytes.
+    pub fn truncate_body_every(self, route: impl Into<String>, n: u64, after_bytes: u64) -> Self {
+        self.with_fault(route, n, Fault::TruncateBody { after_bytes })
+    }
+
+    /// The wrapped client.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    fn fault_for(&self, url: &str) -> Option<&Fault> {
+        // Count the request against every rule for the route, not just up to the first one due,
+        // so that each rule's schedule doesn't depend on the others.
+        let mut fault = None;
+        for rule in self.rules.iter().filter(|rule| url.ends_with(&rule.route)) {
+            if (rule.count.fetch_add(1, SeqCst) + 1) % rule.every == 0 {
+                fault = fault.or(Some(&rule.fault));
+            }
+        }
+        fault
+    }
+}
+
+/// A request made by a [`FaultInjector`].
+pub struct FaultRequest<R> {
+    inner: R,
+    url: String,
+}
+
+impl<R: HttpRequest> HttpRequest for FaultRequest<R> {
+    fn set_header(self, name: &str, value: &str) -> Self {
+        Self {
+            inner: self.inner.set_header(name, value),
+            url: self.url,
+        }
+    }
+}
+
+impl<C: HttpClient> HttpClient for FaultInjector<C> {
+    type Request = FaultRequest<C::Request>;
+
+    fn execute(&self, request: Self::Request, body: &[u8]) -> Result<HttpRequestResultRaw, Error> {
+        let fault = self.fault_for(&request.url);
+        if let Some(fault) = fault {
+            warn!(
+                "injecting fault into request to {}: {:?}",
+                request.url, fault
+            );
+        }
+        match fault {
+            None => self.inner.execute(request.inner, body),
+            Some(Fault::Fail) => Err(Error::HttpClient(Box::new(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "injected fault",
+            )))),
+            Some(Fault::RateLimited {
+                retry_after_seconds,
+            }) => Err(Error::RateLimited {
+                reason: RateLimitReason::TooManyRequests,
+                retry_after_seconds: *retry_after_seconds,
+            }),
+            Some(Fault::TruncateBody { after_bytes }) => {
+                let mut result = self.inner.execute(request.inner, body)?;
+                result.body = Box::new(TruncatedBody {
+                    inner: result.body,
+                    remaining: *after_bytes,
+                });
+                Ok(result)
+            }
+        }
+    }
+
+    fn new_request(&self, url: &str) -> Self::Request {
+        FaultRequest {
+            inner: self.inner.new_request(url),
+            url: url.to_owned(),
+        }
+    }
+
+    fn update_token(&self, old_token: Arc<String>) -> Result<bool, Error> {
+        self.inner.update_token(old_token)
+    }
+
+    fn token(&self) -> Option<Arc<String>> {
+        self.inner.token()
+    }
+
+    fn path_root(&self) -> Option<&str> {
+        self.inner.path_root()
+    }
+
+    fn team_select(&self) -> Option<&TeamSelect> {
+        self.inner.team_select()
+    }
+}
+
+impl<C: UserAuthClient> UserAuthClient for FaultInjector<C> {}
+impl<C: NoauthClient> NoauthClient for FaultInjector<C> {}
+
+struct TruncatedBody {
+    inner: Box<dyn Read + Send>,
+    remaining: u64,
+}
+
+impl Read for TruncatedBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "injected fault",
+            ));
+        }
+        let len = buf.len().min(self.remaining as usize);
+        let nread = self.inner.read(&mut buf[..len])?;
+        self.remaining -= nread as u64;
+        Ok(nread)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use dropbox_sdk::files;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// A client that responds to requests with a scripted series of responses, and records the
+    /// URLs requested.
+    pub(crate) struct MockClient {
+        /// Responses (HTTP status, result header, and body) to give, in order.
+        responses: Mutex<VecDeque<(u16, Option<&'static str>, &'static str)>>,
+
+        /// The response to give once the scripted ones run out.
+        default: (u16, Option<&'static str>, &'static str),
+
+        /// The URLs requested so far.
+        pub(crate) urls: Mutex<Vec<String>>,
+    }
+
+    impl MockClient {
+        /// Respond to every request with HTTP 200 and the given body.
+        pub(crate) fn new(default_body: &'static str) -> Self {
+            Self {
+                responses: Mutex::new(VecDeque::new()),
+                default: (200, None, default_body),
+                urls: Mutex::new(vec![]),
+            }
+        }
+
+        /// Respond to the next request (after any already scripted) with the given status and
+        /// body.
+        #[cfg_attr(not(any(feature = "list", feature = "upload")), allow(dead_code))]
+        pub(crate) fn then(self, status: u16, body: &'static str) -> Self {
+            self.responses
+                .lock()
+                .unwrap()
+                .push_back((status, None, body));
+            self
+        }
+
+        /// Respond to the next request (after any already scripted) like a download endpoint,
+        /// with the given result in a header, and the given body.
+        #[cfg_attr(not(feature = "download"), allow(dead_code))]
+        pub(crate) fn then_download(self, result: &'static str, body: &'static str) -> Self {
+            self.responses
+                .lock()
+                .unwrap()
+                .push_back((200, Some(result), body));
+            self
+        }
+    }
+
+    pub(crate) struct MockRequest;
+
+    impl HttpRequest for MockRequest {
+        fn set_header(self, _name: &str, _value: &str) -> Self {
+            self
+        }
+    }
+
+    impl HttpClient for MockClient {
+        type Request = MockRequest;
+
+        fn execute(
+            &self,
+            _request: Self::Request,
+            _body: &[u8],
+        ) -> Result<HttpRequestResultRaw, Error> {
+            let (status, result_header, body) = self
+                .responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or(self.default);
+            Ok(HttpRequestResultRaw {
+                status,
+                result_header: result_header.map(str::to_owned),
+                content_length: Some(body.len() as u64),
+                body: Box::new(body.as_bytes()),
+            })
+        }
+
+        fn new_request(&self, url: &str) -> Self::Request {
+            self.urls.lock().unwrap().push(url.to_owned());
+            MockRequest
+        }
+
+        fn token(&self) -> Option<Arc<String>> {
+            Some(Arc::new("token".to_owned()))
+        }
+    }
+
+    impl UserAuthClient for MockClient {}
+
+    /// Metadata for a file at the given path, as Dropbox gives it in responses, with its ID made
+    /// from its name, and `rev` `aaaaaaaaa`.
+    #[cfg(feature = "download")]
+    pub(crate) fn file_json(path: &str, size: u64) -> &'static str {
+        file_json_with(path, size, "aaaaaaaaa", None)
+    }
+
+    /// Like [`file_json`], but with the given rev and Content Hash.
+    #[cfg(feature = "download")]
+    pub(crate) fn file_json_with(
+        path: &str,
+        size: u64,
+        rev: &str,
+        content_hash: Option<&str>,
+    ) -> &'static str {
+        let name = path.rsplit('/').next().unwrap();
+        let content_hash = content_hash
+            .map(|hash| format!(r#", "content_hash": "{hash}""#))
+            .unwrap_or_default();
+        // Leaked so it can be given to the MockClient, which only takes static strings.
+        format!(
+            r#"{{".tag": "file", "name": "{name}", "id": "id:{name}", "size": {size},
+            "client_modified": "2024-01-01T00:00:00Z", "server_modified": "2024-01-01T00:00:00Z",
+            "rev": "{rev}", "path_display": "{path}"{content_hash}}}"#
+        )
+        .leak()
+    }
+
+    /// A download progress handler which records the bytes downloaded, total bytes, and retries
+    /// of each update.
+    #[cfg(feature = "download")]
+    #[derive(Default)]
+    pub(crate) struct ProgressRecorder(Mutex<Vec<(u64, u64, u32)>>);
+
+    #[cfg(feature = "download")]
+    impl ProgressRecorder {
+        /// The updates recorded so far.
+        pub(crate) fn updates(&self) -> Vec<(u64, u64, u32)> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[cfg(feature = "download")]
+    impl crate::download::DownloadProgressHandler for Arc<ProgressRecorder> {
+        fn progress(&self, progress: &crate::download::DownloadProgress) {
+            self.0.lock().unwrap().push((
+                progress.bytes_downloaded,
+                progress.total_bytes,
+                progress.retries,
+            ));
+        }
+    }
+
+    #[cfg(feature = "async")]
+    struct Unpark(std::thread::Thread);
+
+    #[cfg(feature = "async")]
+    impl std::task::Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Run a future to completion on the current thread, parking it while the future is pending.
+    #[cfg(feature = "async")]
+    pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        use std::task::{Context, Poll, Waker};
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    fn append(client: &impl UserAuthClient) -> Result<(), Error<files::UploadSessionAppendError>> {
+        files::upload_session_append_v2(
+            client,
+            &files::UploadSessionAppendArg::new(files::UploadSessionCursor::new(
+                "session".to_owned(),
+                0,
+            )),
+            &[],
+        )
+    }
+
+    #[test]
+    fn fail_every_nth() {
+        let client = FaultInjector::new(MockClient::new("null")).fail_every(APPEND_ROUTE, 2);
+        assert!(append(&client).is_ok());
+        assert!(matches!(append(&client), Err(Error::HttpClient(_))));
+        assert!(append(&client).is_ok());
+        assert!(matches!(append(&client), Err(Error::HttpClient(_))));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let client =
+            FaultInjector::new(MockClient::new("null")).rate_limit_every(APPEND_ROUTE, 1, 0);
+        assert!(matches!(
+            append(&client),
+            Err(Error::RateLimited {
+                retry_after_seconds: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rules_count_independently() {
+        let client = FaultInjector::new(MockClient::new("null"))
+            .fail_every(APPEND_ROUTE, 2)
+            .rate_limit_every(APPEND_ROUTE, 3, 0);
+        assert!(append(&client).is_ok());
+        assert!(matches!(append(&client), Err(Error::HttpClient(_))));
+        assert!(matches!(append(&client), Err(Error::RateLimited { .. })));
+        assert!(matches!(append(&client), Err(Error::HttpClient(_))));
+        assert!(append(&client).is_ok());
+        // Both are due, and the first rule wins.
+        assert!(matches!(append(&client), Err(Error::HttpClient(_))));
+    }
+
+    #[test]
+    fn other_routes_unaffected() {
+        let client = FaultInjector::new(MockClient::new("null")).fail_every(DOWNLOAD_ROUTE, 1);
+        assert!(append(&client).is_ok());
+    }
+}
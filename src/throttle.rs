@@ -0,0 +1,180 @@
+//! Limiting the bandwidth and request rate used by uploads and downloads.
+
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::limits::EndpointKind;
+use crate::retry::RateLimits;
+use crate::scheduler::BandwidthSchedule;
+
+/// Separate throttles for each kind of endpoint, so that one kind of request doesn't use up the
+/// allowance of the other.
+#[derive(Debug, Default)]
+#[cfg_attr(not(feature = "upload"), allow(dead_code))]
+pub struct Throttles {
+    content: Option<Throttle>, // in bytes
+    rpc: Option<Throttle>,     // in requests
+}
+
+#[cfg_attr(not(feature = "upload"), allow(dead_code))]
+impl Throttles {
+    pub fn new(max_bytes_per_sec: Option<u64>, max_rpc_per_sec: Option<u64>) -> Self {
+        Self {
+            content: max_bytes_per_sec.map(Throttle::new),
+            rpc: max_rpc_per_sec.map(Throttle::new),
+        }
+    }
+
+    /// Limit the bandwidth by the given schedule instead, if there is one.
+    pub fn with_bandwidth_schedule(mut self, schedule: Option<&Arc<BandwidthSchedule>>) -> Self {
+        if let Some(schedule) = schedule {
+            self.content = Some(Throttle::scheduled(Arc::clone(schedule)));
+        }
+        self
+    }
+
+    /// Wait until a request to the given kind of endpoint, sending or receiving the given number of
+    /// bytes, can be made. This includes waiting for any rate limit recently imposed on that kind
+    /// of endpoint.
+    pub fn acquire(&self, kind: EndpointKind, bytes: u64) {
+        RateLimits::global().wait(kind);
+        match kind {
+            EndpointKind::Content => {
+                if let Some(throttle) = &self.content {
+                    throttle.acquire(bytes);
+                }
+            }
+            EndpointKind::Rpc => {
+                if let Some(throttle) = &self.rpc {
+                    throttle.acquire(1);
+                }
+            }
+        }
+    }
+}
+
+/// A token bucket shared by all the threads of an upload or download.
+///
+/// Tokens are bytes (or requests), and accumulate at the given rate up to one second's worth.
+/// Taking more than are available puts the bucket into debt, and the caller waits until it would
+/// have been paid off, so requests larger than the bucket are still allowed, just delayed.
+///
+/// With a [`BandwidthSchedule`], the rate is whatever the schedule says at the time, and nothing is
+/// limited while it says there is no limit.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: f64,
+    schedule: Option<Arc<BandwidthSchedule>>,
+    state: Mutex<(f64, Instant)>, // available bytes, and when it was last updated
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "non-zero rate required");
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            schedule: None,
+            state: Mutex::new((bytes_per_sec as f64, Instant::now())),
+        }
+    }
+
+    /// Make a throttle whose rate follows the given schedule.
+    pub fn scheduled(schedule: Arc<BandwidthSchedule>) -> Self {
+        Self {
+            bytes_per_sec: 0.,
+            schedule: Some(schedule),
+            // A full bucket to start with, whatever the rate is then.
+            state: Mutex::new((f64::INFINITY, Instant::now())),
+        }
+    }
+
+    /// Make the throttle for data transfers with the given options: following the schedule, if
+    /// there is one, or else limited to the given rate, if any.
+    #[cfg_attr(not(feature = "download"), allow(dead_code))]
+    pub fn for_content(
+        max_bytes_per_sec: Option<u64>,
+        schedule: Option<&Arc<BandwidthSchedule>>,
+    ) -> Option<Self> {
+        match schedule {
+            Some(schedule) => Some(Self::scheduled(Arc::clone(schedule))),
+            None => max_bytes_per_sec.map(Self::new),
+        }
+    }
+
+    /// Wait until the given number of bytes can be sent.
+    pub fn acquire(&self, bytes: u64) {
+        let bytes_per_sec = match &self.schedule {
+            Some(schedule) => match schedule.max_bytes_per_sec_at(SystemTime::now()) {
+                Some(bytes_per_sec) => bytes_per_sec as f64,
+                None => return,
+            },
+            None => self.bytes_per_sec,
+        };
+        let wait = self.reserve(bytes, Instant::now(), bytes_per_sec);
+        if !wait.is_zero() {
+            trace!("throttling for {wait:?}");
+            sleep(wait);
+        }
+    }
+
+    /// Take the given number of bytes from the bucket at the given time, filling it at the given
+    /// rate, and return how long to wait before sending them.
+    fn reserve(&self, bytes: u64, now: Instant, bytes_per_sec: f64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (available, last) = &mut *state;
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *available = (*available + elapsed * bytes_per_sec).min(bytes_per_sec);
+        *last = now.max(*last);
+        *available -= bytes as f64;
+        if *available < 0. {
+            Duration::from_secs_f64(-*available / bytes_per_sec)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let throttle = Throttle::new(1000);
+        let start = Instant::now();
+        // A full bucket to start with.
+        assert_eq!(Duration::ZERO, throttle.reserve(1000, start, 1000.));
+        // Now in debt, so these have to wait in turn.
+        assert_eq!(
+            Duration::from_millis(500),
+            throttle.reserve(500, start, 1000.)
+        );
+        assert_eq!(
+            Duration::from_millis(1500),
+            throttle.reserve(1000, start, 1000.)
+        );
+        // After the debt is paid off, the bucket refills, but only up to one second's worth.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(Duration::ZERO, throttle.reserve(1000, later, 1000.));
+        assert_eq!(
+            Duration::from_millis(100),
+            throttle.reserve(100, later, 1000.)
+        );
+    }
+
+    #[test]
+    fn separate_kinds() {
+        let throttles = Throttles::new(Some(1000), Some(10));
+        // Each of these takes a full bucket's worth of its own kind, or less.
+        throttles.acquire(EndpointKind::Content, 1000);
+        throttles.acquire(EndpointKind::Rpc, 1_000_000);
+        let now = Instant::now();
+        // Only one request was counted, not the bytes...
+        let rpc = throttles.rpc.as_ref().unwrap();
+        assert_eq!(Duration::ZERO, rpc.reserve(9, now, 10.));
+        // ...and the bytes of the RPC request weren't taken from the content bucket.
+        let content = throttles.content.as_ref().unwrap();
+        assert!(content.reserve(1000, now, 1000.) <= Duration::from_secs(1));
+    }
+}
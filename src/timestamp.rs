@@ -0,0 +1,129 @@
+//! Converting [`SystemTime`] to and from the timestamp strings used by the Dropbox API.
+
+use std::time::{Duration, SystemTime};
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Format a time as `%Y-%m-%dT%H:%M:%SZ`, discarding fractional seconds.
+#[cfg_attr(not(feature = "upload"), allow(dead_code))]
+pub fn format(t: SystemTime) -> String {
+    let secs = match t.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs_f64().ceil() as i64),
+    };
+    let days = secs.div_euclid(SECS_PER_DAY);
+    let rem = secs.rem_euclid(SECS_PER_DAY);
+    let (y, m, d) = civil_from_days(days);
+    format!(
+        "{y:04}-{m:02}-{d:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// Parse a time in the `%Y-%m-%dT%H:%M:%SZ` format, or return `None` if it isn't in that format.
+#[cfg_attr(not(feature = "download"), allow(dead_code))]
+pub fn parse(s: &str) -> Option<SystemTime> {
+    let b = s.as_bytes();
+    if b.len() != 20
+        || (b[4], b[7], b[10], b[13], b[16], b[19]) != (b'-', b'-', b'T', b':', b':', b'Z')
+    {
+        return None;
+    }
+    let field = |start: usize, end: usize| {
+        let digits = &s[start..end];
+        if digits.bytes().all(|c| c.is_ascii_digit()) {
+            digits.parse::<i64>().ok()
+        } else {
+            None
+        }
+    };
+    let (y, m, d) = (field(0, 4)?, field(5, 7)?, field(8, 10)?);
+    let (hh, mm, ss) = (field(11, 13)?, field(14, 16)?, field(17, 19)?);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) || hh > 23 || mm > 59 || ss > 59 {
+        return None;
+    }
+    let secs = days_from_civil(y, m, d) * SECS_PER_DAY + hh * 3600 + mm * 60 + ss;
+    Some(if secs >= 0 {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    })
+}
+
+// This is from Howard Hinnant's date algorithms:
+// https://howardhinnant.github.io/date_algorithms.html
+
+#[cfg_attr(not(feature = "upload"), allow(dead_code))]
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+#[cfg_attr(not(feature = "download"), allow(dead_code))]
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if m > 2 { m - 3 } else { m + 9 };
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chrono_format(secs: i64) -> String {
+        chrono::DateTime::from_timestamp(secs, 0)
+            .unwrap()
+            .format("%Y-%m-%dT%H:%M:%SZ")
+            .to_string()
+    }
+
+    #[test]
+    fn matches_chrono() {
+        for secs in [
+            0i64,
+            1,
+            951_782_400,   // 2000-02-29
+            1_700_000_000, // 2023-11-14
+            4_102_444_800, // 2100-01-01
+            -1,
+            -86_400 * 365 * 100,
+        ] {
+            let t = if secs >= 0 {
+                SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64)
+            } else {
+                SystemTime::UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+            };
+            assert_eq!(chrono_format(secs), format(t));
+            assert_eq!(Some(t), parse(&chrono_format(secs)));
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for s in [
+            "",
+            "2024-01-01",
+            "2024-01-01T00:00:00",
+            "2024-01-01 00:00:00Z",
+            "2024-13-01T00:00:00Z",
+            "2024-01-01T24:00:00Z",
+            "2024-+1-01T00:00:00Z",
+        ] {
+            assert_eq!(None, parse(s), "{s}");
+        }
+    }
+}
@@ -1,20 +1,58 @@
 //! Functions for uploading files.
 
-use std::collections::HashMap;
-use std::io::Read;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
 use std::sync::atomic::AtomicBool;
-use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
-use std::sync::{Arc, Mutex};
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering::SeqCst};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::content_hash::ContentHash;
+use crate::content_hash::{self, ContentHash, OUTPUT_SIZE};
+use crate::limits::{self, EndpointKind};
+use crate::path_root;
+use crate::retry::{Backoff, RetryOpts};
+use crate::scheduler::{BandwidthSchedule, TransferScheduler};
+use crate::throttle::Throttles;
 use crate::BLOCK_SIZE;
-use dropbox_sdk::{BoxedError, Error};
 use dropbox_sdk::files::{self, UploadSessionAppendError, UploadSessionFinishError};
-use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{users, UserAuthClient};
+use dropbox_sdk::{BoxedError, Error};
+
+#[cfg(feature = "async")]
+mod async_writer;
+mod builder;
+mod command;
+mod dir;
+mod ignore;
+mod link;
+#[cfg(all(unix, feature = "mmap"))]
+mod mmap;
+mod pipeline;
+pub mod source;
+mod tune;
+mod writer;
+#[cfg(feature = "async")]
+pub use async_writer::AsyncUploadWriter;
+pub use builder::{InvalidUploadOpts, UploadOptsBuilder};
+pub use command::{upload_child, upload_command, CommandFailed};
+#[cfg(feature = "list")]
+pub use dir::{plan_upload_dir, PlanAction, PlannedFile, UploadPlan};
+pub use dir::{upload_dir, UploadDirEntry, UploadDirOpts};
+pub use ignore::IgnorePatterns;
+pub use link::{temporary_upload_link, upload_to_link, UploadLink, UploadLinkOpts};
+pub use pipeline::BufferPool;
+use tune::BlockTuner;
+pub use writer::UploadWriter;
 
 /// Options for how to perform uploads.
+///
+/// These can be built with [`UploadOpts::builder`] to check them for invalid combinations up
+/// front.
 #[derive(Clone)]
 pub struct UploadOpts {
     /// How many blocks to upload in parallel.
@@ -22,25 +60,126 @@ pub struct UploadOpts {
 
     /// How many blocks (of [`BLOCK_SIZE`] bytes each) are uploaded in each request.
     ///
+    /// Requests can be at most [`MAX_REQUEST_SIZE`](limits::MAX_REQUEST_SIZE) bytes, so this can
+    /// be at most 37.
+    ///
     /// Uploading multiple blocks per request reduces the number of requests needed to complete the
     /// upload and can reduce overhead and help avoid running into rate limits, at the cost of
-    /// increasing the cost of a request that has to be retried in the event of an error.
+    /// increasing the cost of a request that has to be retried in the event of an error. If a
+    /// request of several blocks is rejected for being too large, or keeps failing, such as by
+    /// timing out, its blocks are retried one per request instead of failing the upload.
+    ///
+    /// If [`auto_tune`](Self::auto_tune) is set, this is the maximum instead.
     pub blocks_per_request: usize,
 
-    /// How many consecutive errors until retries are abandoned and the upload is failed?
-    pub retry_count: u32,
+    /// Choose the number of blocks per request automatically, based on how long requests take.
+    ///
+    /// The upload starts with 1 block per request, and increases it while doing so makes requests
+    /// transfer data faster, which is the case when most of the time of a request is overhead
+    /// rather than transferring data. It is decreased again if requests fail or time out.
+    pub auto_tune: bool,
 
-    /// Errors when uploading are handled with retry and exponential backoff with jitter. The first
-    /// backoff will be this long, and subsequent backoffs will each be doubled in length (up to
-    /// [`max_backoff_time`](Self::max_backoff_time)), until [`retry_count`](Self::retry_count)
-    /// retries have been attempted, or the upload request succeeds.
-    pub initial_backoff_time: Duration,
+    /// How many times to start the whole upload over in a new session if the upload session is
+    /// lost, such as by expiring, before failing.
+    ///
+    /// This requires rewinding the source, so it is only used by functions which take a seekable
+    /// source, such as [`upload_seekable`] and [`upload_file`].
+    pub restart_on_session_loss: u32,
+
+    /// How many times to send a request again if Dropbox rejects it because the data it received
+    /// doesn't match the Content Hash sent with it, which means it was corrupted on the way. If it
+    /// keeps happening, the upload fails with a [`BlockHashMismatch`] error.
+    pub hash_mismatch_retries: u32,
+
+    /// Limit the upload to this many bytes per second, shared by all the parallel requests.
+    ///
+    /// This is a finer-grained way to avoid saturating the network than reducing
+    /// [`parallelism`](Self::parallelism). Short bursts above the limit are allowed.
+    ///
+    /// This only applies to requests to [content endpoints](EndpointKind::Content), which carry
+    /// the data.
+    pub max_bytes_per_sec: Option<u64>,
 
-    /// Exponential backoff duration won't increase past this time.
-    pub max_backoff_time: Duration,
+    /// Limit the requests to [RPC endpoints](EndpointKind::Rpc) made by the upload, such as for
+    /// getting metadata or copying files, to this many per second.
+    ///
+    /// This is separate from [`max_bytes_per_sec`](Self::max_bytes_per_sec), so that
+    /// metadata-heavy work, such as checking many files with [`upload_dir`], doesn't take up the
+    /// allowance for data transfers.
+    pub max_rpc_per_sec: Option<u64>,
+
+    /// Limit the upload's bandwidth by the time of day instead of to
+    /// [`max_bytes_per_sec`](Self::max_bytes_per_sec), such as for a backup which runs all day.
+    /// The same schedule can be given to many uploads and downloads, but each is limited
+    /// separately.
+    pub bandwidth_schedule: Option<Arc<BandwidthSchedule>>,
+
+    /// Limit the memory used for buffering data read from the source and waiting to be uploaded, in
+    /// bytes. Reading stops while this much is buffered.
+    ///
+    /// Otherwise, about twice [`parallelism`](Self::parallelism) requests' worth of data can be
+    /// buffered at once. The limit is rounded down to a whole number of requests, but at least one
+    /// request's worth of data is always buffered.
+    pub max_buffered_bytes: Option<usize>,
+
+    /// A pool to take buffers for reading the source from, so that they can be reused by multiple
+    /// uploads, such as the files uploaded by [`upload_dir`]. If not set, each upload uses its own
+    /// pool, so buffers are only reused within the upload.
+    pub buffer_pool: Option<Arc<BufferPool>>,
+
+    /// How to retry failed requests. These are also used by [`UploadSession::commit`].
+    pub retry: RetryOpts,
+
+    /// The size of the whole file being uploaded, including any part uploaded before the session
+    /// was resumed, if known. This is used for progress reporting, and to fail right away if it's
+    /// larger than Dropbox allows.
+    ///
+    /// [`upload_file`], [`upload_seekable`], and `UploadSession::upload_mmap` fill this in
+    /// automatically if it isn't set.
+    pub total_bytes: Option<u64>,
 
     /// An optional callback to periodically receive progress updates as the file uploads.
     pub progress_handler: Option<Arc<Box<dyn ProgressHandler>>>,
+
+    /// An optional callback to receive the resume point whenever it advances, so that it can be
+    /// persisted as the upload goes.
+    pub checkpoint_handler: Option<Arc<Box<dyn CheckpointHandler>>>,
+
+    /// Leave the session open at the end of the source, so that more data can be added to it by
+    /// calling [`UploadSession::upload`] again, such as when shipping logs as they are written.
+    /// Call [`UploadSession::close`] after the last one, before committing.
+    ///
+    /// Except in [sequential](UploadSession::new_sequential) sessions, only the last request of a
+    /// session can be less than a whole number of blocks, so if a source ends partway through a
+    /// block, that part is held back and sent at the start of the next upload, or by
+    /// [`UploadSession::close`].
+    pub keep_open: bool,
+
+    /// Make the same requests in the same order each time the same source is uploaded, so that
+    /// tests against a mock client are reproducible.
+    ///
+    /// This uploads one request at a time, in order, each of
+    /// [`blocks_per_request`](Self::blocks_per_request) blocks, ignoring
+    /// [`parallelism`](Self::parallelism) and [`auto_tune`](Self::auto_tune), and retries without
+    /// [jitter](RetryOpts::jitter).
+    pub deterministic: bool,
+
+    /// A scheduler to share with other uploads and downloads, to limit how many requests they
+    /// make at once in total. Each request also waits for a permit from it, on top of
+    /// [`parallelism`](Self::parallelism).
+    pub scheduler: Option<Arc<TransferScheduler>>,
+}
+
+impl UploadOpts {
+    /// Apply [`deterministic`](Self::deterministic) to the other options.
+    fn resolve(mut self) -> Self {
+        if self.deterministic {
+            self.parallelism = 1;
+            self.auto_tune = false;
+            self.retry.jitter = false;
+        }
+        self
+    }
 }
 
 impl Default for UploadOpts {
@@ -48,21 +187,348 @@ impl Default for UploadOpts {
         Self {
             parallelism: 20,
             blocks_per_request: 2,
-            retry_count: 3,
-            initial_backoff_time: Duration::from_millis(500), // 0.5 + 1 + 2 = 3.5 secs max (+/- jitter)
-            max_backoff_time: Duration::from_secs(2),
+            auto_tune: false,
+            restart_on_session_loss: 0,
+            hash_mismatch_retries: 3,
+            max_bytes_per_sec: None,
+            max_rpc_per_sec: None,
+            bandwidth_schedule: None,
+            max_buffered_bytes: None,
+            buffer_pool: None,
+            retry: RetryOpts::default(),
+            total_bytes: None,
             progress_handler: None,
+            checkpoint_handler: None,
+            keep_open: false,
+            deterministic: false,
+            scheduler: None,
         }
     }
 }
 
 /// Implement to receive periodic progress updates as a file uploads.
+///
+/// Implement either [`update`](Self::update) or, for more details, [`progress`](Self::progress).
 pub trait ProgressHandler: Sync + Send {
     /// Invoked with the following parameters:
     /// - total bytes uploaded so far
     /// - the rate (bytes/sec) of the most recent chunk uploaded
     /// - the overall rate (bytes/sec) of the whole upload
-    fn update(&self, bytes_uploaded: u64, instant_rate: f64, overall_rate: f64);
+    fn update(&self, bytes_uploaded: u64, instant_rate: f64, overall_rate: f64) {
+        let _ = (bytes_uploaded, instant_rate, overall_rate);
+    }
+
+    /// Invoked with the details of the upload's progress. By default, this calls
+    /// [`update`](Self::update).
+    fn progress(&self, progress: &Progress) {
+        self.update(
+            progress.bytes_uploaded,
+            progress.instant_rate,
+            progress.overall_rate,
+        );
+    }
+}
+
+/// The progress of an upload, as given to [`ProgressHandler::progress`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Progress {
+    /// The ID of the upload, as used in log messages: the session's
+    /// [`operation_id`](UploadSession::operation_id), for an upload through a session.
+    pub operation_id: String,
+
+    /// How many bytes had been uploaded before the session was resumed, if it was.
+    pub start_offset: u64,
+
+    /// How many bytes have been uploaded so far, not including `start_offset`.
+    pub bytes_uploaded: u64,
+
+    /// The size of the whole file, including `start_offset`, if known from
+    /// [`UploadOpts::total_bytes`].
+    pub total_bytes: Option<u64>,
+
+    /// How many blocks of the file have been uploaded so far, including any before the session was
+    /// resumed.
+    pub blocks_completed: u64,
+
+    /// How many blocks are in the whole file, if its size is known.
+    pub total_blocks: Option<u64>,
+
+    /// How many failed requests have been retried so far, not counting rate limiting.
+    pub retries: u32,
+
+    /// The rate (bytes/sec) of the most recent chunk uploaded.
+    pub instant_rate: f64,
+
+    /// The overall rate (bytes/sec) of the whole upload.
+    pub overall_rate: f64,
+}
+
+impl Progress {
+    /// The fraction of the file which has been uploaded, from 0 to 1, if its size is known.
+    pub fn fraction(&self) -> Option<f64> {
+        self.total_bytes.map(|total| {
+            if total == 0 {
+                1.
+            } else {
+                (self.start_offset + self.bytes_uploaded) as f64 / total as f64
+            }
+        })
+    }
+
+    /// An estimate of how much longer the upload will take, based on the overall rate so far, if
+    /// the size of the file is known.
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining = self
+            .total_bytes?
+            .saturating_sub(self.start_offset + self.bytes_uploaded);
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+        if self.overall_rate <= 0. || !self.overall_rate.is_finite() {
+            return None;
+        }
+        Duration::try_from_secs_f64(remaining as f64 / self.overall_rate).ok()
+    }
+}
+
+/// Implement to be notified each time the upload's resume point advances.
+///
+/// Because blocks are uploaded in parallel and can finish out of order, this is not called for
+/// every block, only when all the data up to a new offset has been uploaded.
+pub trait CheckpointHandler: Sync + Send {
+    /// Invoked with the parameters needed to resume the upload from the furthest point that is
+    /// known to be completely uploaded.
+    ///
+    /// This is called while holding a lock that other upload threads need, so that checkpoints
+    /// are always delivered in increasing order. Implementations should return quickly.
+    fn checkpoint(&self, resume: &UploadResume);
+}
+
+/// Options for committing an upload, for use with [`UploadSession::commit`].
+///
+/// This is a more convenient way of making a [`CommitInfo`](files::CommitInfo).
+#[derive(Debug, Clone)]
+pub struct CommitOptions {
+    info: files::CommitInfo,
+}
+
+impl CommitOptions {
+    /// Commit to the given path, with default options: if a file already exists there, the commit
+    /// fails.
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            info: files::CommitInfo::new(path.into()),
+        }
+    }
+
+    /// What to do if a file already exists at the path.
+    pub fn with_mode(mut self, mode: files::WriteMode) -> Self {
+        self.info.mode = mode;
+        self
+    }
+
+    /// If there's a conflict, as determined by the mode, have Dropbox try to rename the file to
+    /// avoid it.
+    pub fn with_autorename(mut self, autorename: bool) -> Self {
+        self.info.autorename = autorename;
+        self
+    }
+
+    /// The modification time to record for the file, such as from the local file's metadata.
+    pub fn with_client_modified(mut self, time: SystemTime) -> Self {
+        self.info.client_modified = Some(crate::timestamp::format(time));
+        self
+    }
+
+    /// Don't notify the user of this modification in their Dropbox clients.
+    pub fn with_mute(mut self, mute: bool) -> Self {
+        self.info.mute = mute;
+        self
+    }
+
+    /// Be more strict about detecting conflicts: for example, fail a
+    /// [`WriteMode::Update`](files::WriteMode::Update) if the file has been deleted, and fail even
+    /// if the existing file has the same contents.
+    pub fn with_strict_conflict(mut self, strict_conflict: bool) -> Self {
+        self.info.strict_conflict = strict_conflict;
+        self
+    }
+
+    /// Custom properties to add to the file.
+    pub fn with_property_groups(
+        mut self,
+        property_groups: Vec<dropbox_sdk::file_properties::PropertyGroup>,
+    ) -> Self {
+        self.info.property_groups = Some(property_groups);
+        self
+    }
+}
+
+impl From<CommitOptions> for files::CommitInfo {
+    fn from(opts: CommitOptions) -> Self {
+        opts.info
+    }
+}
+
+/// Options for uploading a local file with [`upload_file`].
+#[derive(Clone, Default)]
+pub struct UploadFileOpts {
+    /// Options for the upload session.
+    pub upload: UploadOpts,
+
+    /// What to do if a file already exists at the destination path.
+    pub if_exists: IfExists,
+
+    /// An optional policy for adjusting the local file's modification time before it is recorded
+    /// in Dropbox. If not set, the modification time is recorded as-is.
+    pub client_modified_policy: Option<Arc<Box<dyn ClientModifiedPolicy>>>,
+
+    /// Before uploading, check that the account has enough free space for the file, and fail with
+    /// an [`InsufficientSpace`] error if it doesn't, instead of only finding out when committing
+    /// it.
+    ///
+    /// This doesn't account for the space freed by overwriting an existing file, and the space can
+    /// be used up by something else during the upload, so it's only a best-effort check.
+    pub check_space: bool,
+}
+
+/// Implement to adjust the modification times of local files before they are recorded in
+/// Dropbox, such as to correct for broken clocks.
+pub trait ClientModifiedPolicy: Sync + Send {
+    /// Given the path of a local file and its modification time, return the time to record in
+    /// Dropbox, or `None` to not record one, in which case Dropbox uses the upload time.
+    fn client_modified(&self, path: &Path, mtime: SystemTime) -> Option<SystemTime>;
+}
+
+/// A [`ClientModifiedPolicy`] which logs a warning about modification times that are in the future
+/// or implausibly far in the past, and optionally clamps them to a plausible range.
+///
+/// Files from devices with broken clocks, such as cameras whose clock battery has run out, often
+/// have such modification times.
+#[derive(Debug, Clone)]
+pub struct ClockSkewPolicy {
+    /// How far in the future a modification time can be before it is considered wrong.
+    pub max_future: Duration,
+
+    /// The earliest plausible modification time.
+    pub earliest: SystemTime,
+
+    /// Whether to replace wrong modification times with the nearest plausible one (the current
+    /// time, or `earliest`), rather than just warning about them.
+    pub clamp: bool,
+}
+
+impl Default for ClockSkewPolicy {
+    fn default() -> Self {
+        Self {
+            max_future: Duration::from_secs(24 * 60 * 60),
+            earliest: SystemTime::UNIX_EPOCH + Duration::from_secs(631_152_000), // 1990-01-01
+            clamp: true,
+        }
+    }
+}
+
+impl ClientModifiedPolicy for ClockSkewPolicy {
+    fn client_modified(&self, path: &Path, mtime: SystemTime) -> Option<SystemTime> {
+        let now = SystemTime::now();
+        let (fixed, problem) = if mtime > now + self.max_future {
+            (now, "in the future")
+        } else if mtime < self.earliest {
+            (self.earliest, "implausibly old")
+        } else {
+            return Some(mtime);
+        };
+        warn!(
+            "modification time of {path:?} ({}) is {problem}",
+            crate::timestamp::format(mtime)
+        );
+        Some(if self.clamp { fixed } else { mtime })
+    }
+}
+
+/// What to do when uploading a file to a path where a file already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IfExists {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+
+    /// Don't upload anything if the existing file has the same size and Content Hash as the local
+    /// file; otherwise replace it.
+    SkipIfIdentical,
+}
+
+/// What was done to get a file to its destination, as part of an [`UploadOutcome`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadAction {
+    /// The file was uploaded.
+    Uploaded,
+
+    /// The file was not uploaded because an identical file was already present.
+    Skipped,
+
+    /// The file was not uploaded because a file with identical contents was uploaded already, and
+    /// it was copied from that one instead.
+    Copied,
+}
+
+/// The result of an upload, such as from [`upload_file`] or [`upload_seekable`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct UploadOutcome {
+    /// What was done.
+    pub action: UploadAction,
+
+    /// The metadata of the file in Dropbox: the new file if it was uploaded or copied, or the
+    /// existing one if it was skipped.
+    pub metadata: files::FileMetadata,
+
+    /// The Content Hash of the data, as calculated locally, if it is known. It isn't known for
+    /// uploads which were resumed, since the data before the resume point wasn't read.
+    pub content_hash: Option<String>,
+
+    /// How many bytes were sent, not counting those sent before the session was resumed or data
+    /// which had to be sent again.
+    pub bytes_uploaded: u64,
+
+    /// How many bytes had been uploaded before the session was resumed, if it was.
+    pub resumed_bytes: u64,
+
+    /// How long it took.
+    pub elapsed: Duration,
+
+    /// How many failed requests were retried, not counting rate limiting.
+    pub retries: u32,
+}
+
+impl UploadOutcome {
+    /// An outcome where nothing was uploaded.
+    fn without_upload(
+        action: UploadAction,
+        metadata: files::FileMetadata,
+        content_hash: Option<String>,
+        elapsed: Duration,
+    ) -> Self {
+        Self {
+            action,
+            metadata,
+            content_hash,
+            bytes_uploaded: 0,
+            resumed_bytes: 0,
+            elapsed,
+            retries: 0,
+        }
+    }
+
+    /// The average upload rate, in bytes per second, or zero if no time elapsed, such as when
+    /// nothing was uploaded.
+    pub fn rate(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.bytes_uploaded as f64 / self.elapsed.as_secs_f64()
+    }
 }
 
 /// Parameters to resume an incomplete upload.
@@ -75,6 +541,392 @@ pub struct UploadResume {
     pub start_offset: u64,
 }
 
+/// The Content Hash of a committed file didn't match the hash of the data that was uploaded.
+///
+/// Returned (inside a [`BoxedError`]) by [`UploadSession::commit_verified`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashMismatch {
+    /// The Content Hash of the data uploaded, as a hexadecimal string.
+    pub expected: String,
+
+    /// The Content Hash returned by Dropbox for the committed file, if any.
+    pub actual: Option<String>,
+}
+
+impl Display for HashMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "content hash mismatch: uploaded {}, but Dropbox has {}",
+            self.expected,
+            self.actual.as_deref().unwrap_or("none"),
+        )
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+/// The Content Hash of an upload can't be checked, because the session was resumed partway
+/// through and the hash of the data uploaded before that isn't known.
+///
+/// Returned (inside a [`BoxedError`]) by [`UploadSession::commit_verified`], before committing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnverifiableUpload {
+    /// The offset the session was resumed from.
+    pub start_offset: u64,
+}
+
+impl Display for UnverifiableUpload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "can't verify the content hash of an upload resumed at offset {}",
+            self.start_offset
+        )
+    }
+}
+
+impl std::error::Error for UnverifiableUpload {}
+
+/// Dropbox kept receiving data for part of an upload which didn't match its Content Hash, even
+/// after sending it [`hash_mismatch_retries`](UploadOpts::hash_mismatch_retries) more times.
+///
+/// Returned (inside a [`BoxedError`]) by [`UploadSession::upload`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHashMismatch {
+    /// The offset in the file of the data.
+    pub offset: u64,
+
+    /// The length of the data.
+    pub len: u64,
+
+    /// How many times it was sent.
+    pub attempts: u32,
+}
+
+impl Display for BlockHashMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "data sent for {} bytes at offset {} was corrupted {} times in a row",
+            self.len, self.offset, self.attempts
+        )
+    }
+}
+
+impl std::error::Error for BlockHashMismatch {}
+
+/// The file being updated by [`UploadSession::commit_update`] has changed since the given
+/// revision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateConflict {
+    /// The path of the file.
+    pub path: String,
+
+    /// The revision the file was expected to be at.
+    pub rev: String,
+}
+
+impl Display for UpdateConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} has changed since revision {}, not overwriting it",
+            self.path, self.rev
+        )
+    }
+}
+
+impl std::error::Error for UpdateConflict {}
+
+/// There isn't enough free space in the account for an upload.
+///
+/// Returned (inside a [`BoxedError`]) when [`check_space`](UploadFileOpts::check_space) is set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsufficientSpace {
+    /// The number of bytes that were going to be uploaded.
+    pub needed: u64,
+
+    /// The number of bytes of free space.
+    pub available: u64,
+}
+
+impl Display for InsufficientSpace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "not enough space: {} bytes needed, but only {} bytes are available",
+            self.needed, self.available
+        )
+    }
+}
+
+impl std::error::Error for InsufficientSpace {}
+
+/// Committing an upload with [`UploadSession::commit_or_conflict`] failed because of something
+/// already at the path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    /// The path the upload was being committed to.
+    pub path: String,
+
+    /// What kind of thing is in the way.
+    pub reason: files::WriteConflictError,
+
+    /// The metadata of what is at the path now, if it could be found. This can be `None` if it was
+    /// removed after the commit failed.
+    pub existing: Option<files::Metadata>,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "conflict committing to {}: {}", self.path, self.reason)
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+/// The upload was stopped by a [`ShutdownHandle`] before all the data was sent.
+///
+/// This is [`UploadError::Stopped`] when returned by [`UploadSession::upload`], and is returned
+/// inside a [`BoxedError`] by functions which upload through it. Get the parameters to resume it
+/// from [`ShutdownHandle::shutdown`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadStopped;
+
+impl Display for UploadStopped {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("upload was stopped before it was finished")
+    }
+}
+
+impl std::error::Error for UploadStopped {}
+
+/// An upload to an [`UploadSession`] failed, from [`UploadSession::upload`] and the similar
+/// methods.
+///
+/// Each kind of failure includes the parameters to resume the upload, as of when it stopped, so
+/// that they can be saved without calling [`UploadSession::get_resume`] separately, and the
+/// session's operation ID, to find its log messages.
+///
+/// This converts into a [`BoxedError`] holding the error it wraps, so it can be passed on with
+/// `?` by functions which return one.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum UploadError {
+    /// Reading the source failed, or it can't be uploaded from.
+    Read {
+        /// The error from reading.
+        error: io::Error,
+
+        /// Where to resume the upload from.
+        resume: UploadResume,
+
+        /// The [`operation_id`](UploadSession::operation_id) of the session.
+        operation_id: String,
+    },
+
+    /// A request to append data to the session failed, and wasn't retried or ran out of retries.
+    Append {
+        /// The error from the last attempt.
+        error: BoxedError,
+
+        /// Where to resume the upload from.
+        resume: UploadResume,
+
+        /// The [`operation_id`](UploadSession::operation_id) of the session.
+        operation_id: String,
+    },
+
+    /// Data kept being corrupted on the way to Dropbox.
+    Integrity {
+        /// Which data it was.
+        error: BlockHashMismatch,
+
+        /// Where to resume the upload from.
+        resume: UploadResume,
+
+        /// The [`operation_id`](UploadSession::operation_id) of the session.
+        operation_id: String,
+    },
+
+    /// The upload, or the request size, would be larger than Dropbox allows.
+    LimitExceeded {
+        /// Which limit it was.
+        error: limits::LimitExceeded,
+
+        /// Where to resume the upload from, such as to commit what has been uploaded so far.
+        resume: UploadResume,
+
+        /// The [`operation_id`](UploadSession::operation_id) of the session.
+        operation_id: String,
+    },
+
+    /// The upload was stopped by a [`ShutdownHandle`].
+    Stopped {
+        /// Where to resume the upload from.
+        resume: UploadResume,
+
+        /// The [`operation_id`](UploadSession::operation_id) of the session.
+        operation_id: String,
+    },
+}
+
+impl UploadError {
+    /// Sort out a failure from the upload pipeline.
+    fn new(error: pipeline::Error<BoxedError>, resume: UploadResume, operation_id: String) -> Self {
+        let error = match error {
+            pipeline::Error::Read(error) => {
+                return Self::Read {
+                    error,
+                    resume,
+                    operation_id,
+                }
+            }
+            pipeline::Error::Process(error) => error,
+        };
+        let e = match error {
+            Error::Api(e) => e,
+            error => {
+                return Self::Append {
+                    error,
+                    resume,
+                    operation_id,
+                }
+            }
+        };
+        let e = match e.downcast::<BlockHashMismatch>() {
+            Ok(error) => {
+                return Self::Integrity {
+                    error: *error,
+                    resume,
+                    operation_id,
+                }
+            }
+            Err(e) => e,
+        };
+        let e = match e.downcast::<limits::LimitExceeded>() {
+            Ok(error) => {
+                return Self::LimitExceeded {
+                    error: *error,
+                    resume,
+                    operation_id,
+                }
+            }
+            Err(e) => e,
+        };
+        if e.is::<UploadStopped>() {
+            return Self::Stopped {
+                resume,
+                operation_id,
+            };
+        }
+        Self::Append {
+            error: Error::Api(e),
+            resume,
+            operation_id,
+        }
+    }
+
+    /// The parameters to resume the upload from where it got to.
+    pub fn resume(&self) -> &UploadResume {
+        match self {
+            Self::Read { resume, .. }
+            | Self::Append { resume, .. }
+            | Self::Integrity { resume, .. }
+            | Self::LimitExceeded { resume, .. }
+            | Self::Stopped { resume, .. } => resume,
+        }
+    }
+
+    /// The [`operation_id`](UploadSession::operation_id) of the session, as used in its log
+    /// messages.
+    pub fn operation_id(&self) -> &str {
+        match self {
+            Self::Read { operation_id, .. }
+            | Self::Append { operation_id, .. }
+            | Self::Integrity { operation_id, .. }
+            | Self::LimitExceeded { operation_id, .. }
+            | Self::Stopped { operation_id, .. } => operation_id,
+        }
+    }
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read { error, .. } => write!(f, "reading the upload source failed: {error}"),
+            Self::Append { error, .. } => write!(f, "uploading failed: {error}"),
+            Self::Integrity { error, .. } => error.fmt(f),
+            Self::LimitExceeded { error, .. } => error.fmt(f),
+            Self::Stopped { .. } => UploadStopped.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl From<UploadError> for BoxedError {
+    fn from(e: UploadError) -> Self {
+        match e {
+            UploadError::Read { error, .. } => Error::HttpClient(error.into()),
+            UploadError::Append { error, .. } => error,
+            UploadError::Integrity { error, .. } => Error::Api(Box::new(error)),
+            UploadError::LimitExceeded { error, .. } => Error::Api(Box::new(error)),
+            UploadError::Stopped { .. } => Error::Api(Box::new(UploadStopped)),
+        }
+    }
+}
+
+/// Something that happened during an upload, as received from [`UploadSession::events`].
+///
+/// Offsets are from the start of the file, including any part uploaded before the session was
+/// resumed.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum UploadEvent {
+    /// A request to upload a block (or several consecutive blocks) is starting.
+    BlockStarted {
+        /// The offset of the data.
+        offset: u64,
+        /// The length of the data.
+        len: u64,
+    },
+
+    /// A request to upload a block failed, and is being retried.
+    BlockRetried {
+        /// The offset of the data.
+        offset: u64,
+        /// A description of the error.
+        error: String,
+    },
+
+    /// A request was rate-limited by Dropbox, and is being retried after a wait.
+    RateLimited {
+        /// The offset of the data.
+        offset: u64,
+        /// How long Dropbox asked to wait.
+        retry_after_seconds: u32,
+    },
+
+    /// A block was uploaded successfully.
+    BlockCompleted {
+        /// The offset of the data.
+        offset: u64,
+        /// The length of the data.
+        len: u64,
+    },
+
+    /// The session was closed, so no more data can be added to it.
+    SessionClosed {
+        /// The total length of the data in the session.
+        len: u64,
+    },
+
+    /// The upload was committed to a file.
+    Committed(Box<files::FileMetadata>),
+}
+
 /// An upload session for a file.
 pub struct UploadSession<C: UserAuthClient + Send + Sync + 'static> {
     client: Arc<C>,
@@ -83,18 +935,51 @@ pub struct UploadSession<C: UserAuthClient + Send + Sync + 'static> {
 
 struct SessionInner {
     session_id: String,
+    operation_id: String,
+    sequential: bool,
     start_offset: u64,
+    created: Instant,
     bytes_transferred: AtomicU64,
+    blocks_transferred: AtomicU64,
+    retries: AtomicU32,
     completion: Mutex<CompletionTracker>,
+    block_hashes: Mutex<BTreeMap<u64, [u8; OUTPUT_SIZE]>>,
+    retry: Mutex<RetryOpts>,
+    tuner: Mutex<Option<BlockTuner>>,
+    throttles: Mutex<Arc<Throttles>>,
+    scheduler: Mutex<Option<Arc<TransferScheduler>>>,
+    events: Mutex<Vec<mpsc::Sender<UploadEvent>>>,
+    tail: Mutex<Vec<u8>>,
+    closed: AtomicBool,
+    shutdown: ShutdownState,
 }
 
 impl<C: UserAuthClient + Send + Sync + 'static> UploadSession<C> {
     /// Make a new upload session.
     pub fn new(client: Arc<C>) -> Result<Self, Error<files::UploadSessionStartError>> {
+        Self::start(client, files::UploadSessionType::Concurrent)
+    }
+
+    /// Make a new sequential upload session, for callers which need the data to be sent strictly
+    /// in order, such as when it is generated on the fly.
+    ///
+    /// Data is appended to a sequential session one request at a time, in order, regardless of
+    /// [`parallelism`](UploadOpts::parallelism), and unlike with a concurrent session, Dropbox
+    /// doesn't require the requests to be a multiple of [`BLOCK_SIZE`].
+    ///
+    /// To resume a sequential session, use [`UploadSession::resume_sequential`].
+    pub fn new_sequential(client: Arc<C>) -> Result<Self, Error<files::UploadSessionStartError>> {
+        Self::start(client, files::UploadSessionType::Sequential)
+    }
+
+    fn start(
+        client: Arc<C>,
+        session_type: files::UploadSessionType,
+    ) -> Result<Self, Error<files::UploadSessionStartError>> {
+        let sequential = session_type == files::UploadSessionType::Sequential;
         let session_id = files::upload_session_start(
             client.as_ref(),
-            &files::UploadSessionStartArg::default()
-                .with_session_type(files::UploadSessionType::Concurrent),
+            &files::UploadSessionStartArg::default().with_session_type(session_type),
             &[],
         )?
         .session_id;
@@ -102,142 +987,618 @@ impl<C: UserAuthClient + Send + Sync + 'static> UploadSession<C> {
         Ok(Self {
             client,
             inner: Arc::new(SessionInner {
+                operation_id: new_operation_id(),
                 session_id,
+                sequential,
                 start_offset: 0,
+                created: Instant::now(),
                 bytes_transferred: AtomicU64::new(0),
+                blocks_transferred: AtomicU64::new(0),
+                retries: AtomicU32::new(0),
                 completion: Mutex::new(CompletionTracker::default()),
+                block_hashes: Mutex::new(BTreeMap::new()),
+                retry: Mutex::new(RetryOpts::default()),
+                tuner: Mutex::new(None),
+                throttles: Mutex::new(Arc::default()),
+                scheduler: Mutex::new(None),
+                events: Mutex::new(vec![]),
+                tail: Mutex::new(vec![]),
+                closed: AtomicBool::new(false),
+                shutdown: ShutdownState::default(),
             }),
         })
     }
 
     /// Resume a pre-existing (i.e. interrupted) upload session.
     pub fn resume(client: Arc<C>, resume: UploadResume) -> Self {
+        Self::resume_internal(client, resume, false)
+    }
+
+    /// Resume a pre-existing (i.e. interrupted) upload session which was made with
+    /// [`UploadSession::new_sequential`].
+    pub fn resume_sequential(client: Arc<C>, resume: UploadResume) -> Self {
+        Self::resume_internal(client, resume, true)
+    }
+
+    fn resume_internal(client: Arc<C>, resume: UploadResume, sequential: bool) -> Self {
         Self {
             client,
             inner: Arc::new(SessionInner {
+                operation_id: new_operation_id(),
                 session_id: resume.session_id,
+                sequential,
                 start_offset: resume.start_offset,
+                created: Instant::now(),
                 bytes_transferred: AtomicU64::new(0),
+                blocks_transferred: AtomicU64::new(0),
+                retries: AtomicU32::new(0),
                 completion: Mutex::new(CompletionTracker::resume_from(resume.start_offset)),
+                block_hashes: Mutex::new(BTreeMap::new()),
+                retry: Mutex::new(RetryOpts::default()),
+                tuner: Mutex::new(None),
+                throttles: Mutex::new(Arc::default()),
+                scheduler: Mutex::new(None),
+                events: Mutex::new(vec![]),
+                tail: Mutex::new(vec![]),
+                closed: AtomicBool::new(false),
+                shutdown: ShutdownState::default(),
             }),
         }
     }
 
     /// Upload the given stream to the upload session, using the given
     /// [upload parameters](UploadOpts). This may only be called once for a given
-    /// [`UploadSession`].
+    /// [`UploadSession`], unless [`keep_open`](UploadOpts::keep_open) is set, in which case each
+    /// call appends to the data from the previous ones.
+    ///
+    /// To upload data which is generated on the fly, see the adapters in [`source`].
     ///
     /// This blocks the current thread until the entire source has been transferred, or an error
     /// occurs.
     ///
     /// The return value is the number of bytes uploaded, or an error.
     ///
-    /// If the upload fails, call [`UploadSession::get_resume`] to get the resume parameters which
-    /// can be passed to [`UploadSession::resume`] to make a new [`UploadSession`] which can be
-    /// used to retry the upload without re-uploading all the data.
-    pub fn upload(&self, mut source: impl Read, opts: UploadOpts) -> Result<u64, BoxedError> {
-        let closed = Arc::new(AtomicBool::new(false));
+    /// Unless [`keep_open`](UploadOpts::keep_open) is set, the session is closed at the end of the
+    /// source so that it can be committed: by the last request, if the source doesn't end on a
+    /// [`BLOCK_SIZE`] boundary, or else by an extra empty request. If that extra request fails,
+    /// the error is only logged, since committing may still work, such as when the session was
+    /// already closed before it was resumed; call [`UploadSession::close`] to be sure.
+    ///
+    /// If the options would make requests larger than Dropbox allows, or
+    /// [`total_bytes`](UploadOpts::total_bytes) is larger than
+    /// [`MAX_UPLOAD_SESSION_SIZE`](limits::MAX_UPLOAD_SESSION_SIZE), this returns an
+    /// [`UploadError::LimitExceeded`] error before uploading anything. If the source turns out to
+    /// be larger than that, the upload fails with the same error without sending the data past
+    /// that point.
+    ///
+    /// If the upload fails, the error includes the resume parameters, which can be passed to
+    /// [`UploadSession::resume`] to make a new [`UploadSession`] which can be used to retry the
+    /// upload without re-uploading all the data.
+    pub fn upload(&self, source: impl Read, opts: UploadOpts) -> Result<u64, UploadError> {
+        let opts = opts.resolve();
+        self.start_upload(&opts)?;
+        // Continue after the data from any previous calls, starting with any part of a block that
+        // was held back from the last one.
+        let base = self.inner.complete_up_to() - self.inner.start_offset;
+        let tail = std::mem::take(&mut *self.inner.tail.lock().unwrap());
+        let mut source = io::Cursor::new(tail).chain(source);
+        let pool = opts
+            .buffer_pool
+            .clone()
+            .unwrap_or_else(|| Arc::new(BufferPool::new(self.inner.parallelism(&opts) * 2 + 1)));
+
+        let closed = AtomicBool::new(false);
         let start_time = Instant::now();
-        let result = {
-            let client = self.client.clone();
-            let inner = self.inner.clone();
-            let opts = opts.clone();
-            let closed = closed.clone();
-            parallel_reader::read_stream_and_process_chunks_in_parallel(
-                &mut source,
-                BLOCK_SIZE * opts.blocks_per_request,
-                opts.parallelism,
-                Arc::new(
-                    move |block_offset,
-                          data: &[u8]|
-                          -> Result<(), Error<UploadSessionAppendError>> {
-                        let mut append_arg = inner
-                            .append_arg(block_offset)
-                            .with_content_hash(ContentHash::from(data).finish_hex());
-                        if data.len() != BLOCK_SIZE * opts.blocks_per_request {
-                            // This must be the last block. Only the last one is allowed to be not 4 MiB
-                            // exactly.
-                            append_arg.close = true;
-                            closed.store(true, SeqCst);
-                        }
-                        let result = Self::upload_block_with_retry(
-                            client.as_ref(),
-                            inner.as_ref(),
-                            &append_arg,
-                            data,
-                            start_time,
-                            &opts,
-                        );
-                        if result.is_ok() {
-                            inner.mark_block_uploaded(block_offset, data.len() as u64);
+        pipeline::read_and_process(
+            &mut source,
+            || BLOCK_SIZE * self.inner.blocks_per_request(&opts),
+            self.inner.parallelism(&opts),
+            opts.max_buffered_bytes,
+            &pool,
+            |block_offset, data: &[u8]| {
+                Self::upload_chunk(
+                    self.client.as_ref(),
+                    self.inner.as_ref(),
+                    base + block_offset,
+                    data,
+                    &closed,
+                    start_time,
+                    &opts,
+                )
+            },
+        )
+        .map_err(|e| self.upload_error(e))?;
+
+        Ok(self.finish_upload(&closed, start_time, &opts))
+    }
+
+    /// Like [`UploadSession::upload`], but upload a local file by mapping it into memory, which
+    /// avoids copying the data into buffers first.
+    ///
+    /// The file is uploaded starting from the session's resume offset, after anything already
+    /// uploaded to it, so unlike with [`UploadSession::upload`], there is no need to seek to it
+    /// first. It can't follow an upload with [`keep_open`](UploadOpts::keep_open) which held back
+    /// part of a block.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be changed, by this process or any other, until this returns. The mapped
+    /// data is read as a `&[u8]`, so changing it is undefined behavior, and truncating the file
+    /// makes reading past the new end crash the process.
+    #[cfg(all(unix, feature = "mmap"))]
+    pub unsafe fn upload_mmap(
+        &self,
+        source_path: &Path,
+        opts: UploadOpts,
+    ) -> Result<u64, UploadError> {
+        let map = File::open(source_path)
+            // SAFETY: the caller promises not to change the file while it's mapped.
+            .and_then(|file| unsafe { mmap::Mmap::map(&file) })
+            .map_err(|e| self.upload_error(pipeline::Error::Read(e)))?;
+        if !self.inner.tail.lock().unwrap().is_empty() {
+            return Err(self.upload_error(pipeline::Error::Read(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't follow an upload which held back part of a block",
+            ))));
+        }
+        let base = self.inner.complete_up_to() - self.inner.start_offset;
+        let data = map
+            .as_slice()
+            .get(self.inner.complete_up_to() as usize..)
+            .unwrap_or_default();
+
+        let mut opts = opts.resolve();
+        opts.total_bytes.get_or_insert(map.as_slice().len() as u64);
+
+        self.start_upload(&opts)?;
+        let closed = AtomicBool::new(false);
+        let start_time = Instant::now();
+        pipeline::process_slice(
+            data,
+            || BLOCK_SIZE * self.inner.blocks_per_request(&opts),
+            self.inner.parallelism(&opts),
+            |block_offset, data: &[u8]| {
+                Self::upload_chunk(
+                    self.client.as_ref(),
+                    self.inner.as_ref(),
+                    base + block_offset,
+                    data,
+                    &closed,
+                    start_time,
+                    &opts,
+                )
+            },
+        )
+        .map_err(|e| self.upload_error(pipeline::Error::Process(e)))?;
+
+        Ok(self.finish_upload(&closed, start_time, &opts))
+    }
+
+    /// Like [`UploadSession::upload`], but for a seekable source, such as a file, which is read
+    /// as each request is made instead of ahead of time.
+    ///
+    /// Each request's data is read from the source just before it is sent, and if the request
+    /// fails, its buffer is given up while waiting to retry, and the data is read again for the
+    /// next attempt. This way, only one request's worth of data per thread is held in memory at a
+    /// time, rather than the data being read ahead and kept for as long as its request takes,
+    /// which makes large [`blocks_per_request`](UploadOpts::blocks_per_request) values affordable.
+    /// [`max_buffered_bytes`](UploadOpts::max_buffered_bytes) doesn't apply.
+    ///
+    /// The source is uploaded from its current position to its end, and must not change length
+    /// while it is being uploaded.
+    pub fn upload_seek(
+        &self,
+        mut source: impl Read + Seek + Send,
+        opts: UploadOpts,
+    ) -> Result<u64, UploadError> {
+        let mut opts = opts.resolve();
+        let (start, len) = source
+            .stream_position()
+            .and_then(|start| Ok((start, source.seek(SeekFrom::End(0))? - start)))
+            .map_err(|e| self.upload_error(pipeline::Error::Read(e)))?;
+        if !self.inner.tail.lock().unwrap().is_empty() {
+            return Err(self.upload_error(pipeline::Error::Read(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't follow an upload which held back part of a block",
+            ))));
+        }
+        let base = self.inner.complete_up_to() - self.inner.start_offset;
+        opts.total_bytes
+            .get_or_insert(self.inner.start_offset + base + len);
+
+        self.start_upload(&opts)?;
+        let source = Mutex::new(source);
+        let pool = opts
+            .buffer_pool
+            .clone()
+            .unwrap_or_else(|| Arc::new(BufferPool::new(self.inner.parallelism(&opts))));
+        // Each attempt gives up after one error, so that it can be retried here after reading the
+        // data again.
+        let attempt_opts = UploadOpts {
+            retry: RetryOpts {
+                retry_count: 1,
+                ..opts.retry.clone()
+            },
+            ..opts.clone()
+        };
+        let closed = AtomicBool::new(false);
+        let start_time = Instant::now();
+        pipeline::process_ranges(
+            len,
+            || BLOCK_SIZE * self.inner.blocks_per_request(&opts),
+            self.inner.parallelism(&opts),
+            |range| {
+                let mut backoff =
+                    Backoff::new(&opts.retry).with_operation_id(&self.inner.operation_id);
+                loop {
+                    let mut buf = pool.get((range.end - range.start) as usize);
+                    {
+                        let mut source = source.lock().unwrap();
+                        source
+                            .seek(SeekFrom::Start(start + range.start))
+                            .and_then(|_| source.read_exact(&mut buf))
+                            .map_err(pipeline::Error::Read)?;
+                    }
+                    let result = Self::upload_chunk(
+                        self.client.as_ref(),
+                        self.inner.as_ref(),
+                        base + range.start,
+                        &buf,
+                        &closed,
+                        start_time,
+                        &attempt_opts,
+                    );
+                    pool.put(buf);
+                    match result {
+                        Ok(()) => return Ok(()),
+                        Err(e @ Error::Api(_)) => return Err(pipeline::Error::Process(e)),
+                        Err(e) if self.inner.shutdown.is_stopping() => {
+                            return Err(pipeline::Error::Process(e))
                         }
-                        result
-                    },
-                ),
-            )
+                        Err(e) => backoff
+                            .handle("uploading data read from the source", e)
+                            .map_err(pipeline::Error::Process)?,
+                    }
+                }
+            },
+        )
+        .map_err(|e| self.upload_error(e))?;
+
+        Ok(self.finish_upload(&closed, start_time, &opts))
+    }
+
+    /// Make an error for a failed upload, with where to resume it from.
+    fn upload_error(&self, error: pipeline::Error<BoxedError>) -> UploadError {
+        UploadError::new(error, self.get_resume(), self.inner.operation_id.clone())
+    }
+
+    /// Check the options and set up the session to use them for an upload.
+    fn start_upload(&self, opts: &UploadOpts) -> Result<(), UploadError> {
+        let limit_exceeded = |error| UploadError::LimitExceeded {
+            error,
+            resume: self.get_resume(),
+            operation_id: self.inner.operation_id.clone(),
         };
+        limits::check(
+            "request size",
+            (BLOCK_SIZE * opts.blocks_per_request) as u64,
+            limits::MAX_REQUEST_SIZE,
+        )
+        .map_err(limit_exceeded)?;
+        if let Some(total) = opts.total_bytes {
+            limits::check_upload_size(total).map_err(limit_exceeded)?;
+        }
+        info!(
+            "[{}] Uploading to session {} from offset {}",
+            self.inner.operation_id,
+            self.inner.session_id,
+            self.inner.complete_up_to()
+        );
+        *self.inner.retry.lock().unwrap() = opts.retry.clone();
 
-        result.map_err(|e| match e {
-            parallel_reader::Error::Read(e) => Error::HttpClient(e.into()),
-            parallel_reader::Error::Process {
-                chunk_offset: _,
-                error,
-            } => error.boxed(),
-        })?;
+        *self.inner.tuner.lock().unwrap() = if opts.auto_tune {
+            Some(BlockTuner::new(opts.blocks_per_request))
+        } else {
+            None
+        };
 
-        let final_len = self.inner.complete_up_to();
+        *self.inner.throttles.lock().unwrap() = Arc::new(
+            Throttles::new(opts.max_bytes_per_sec, opts.max_rpc_per_sec)
+                .with_bandwidth_schedule(opts.bandwidth_schedule.as_ref()),
+        );
+        self.inner
+            .scheduler
+            .lock()
+            .unwrap()
+            .clone_from(&opts.scheduler);
+        Ok(())
+    }
+
+    /// After all the data has been uploaded, close the session if that wasn't done along with the
+    /// last chunk, and return the total length.
+    fn finish_upload(&self, closed: &AtomicBool, start_time: Instant, opts: &UploadOpts) -> u64 {
         // If we didn't close it above, we need to upload an empty buffer now to mark the session as
         // closed.
-        if !closed.load(SeqCst) {
-            let append_arg = self.inner.append_arg(final_len).with_close(true);
-            if let Err(e) = Self::upload_block_with_retry(
+        if !closed.load(SeqCst) && !opts.keep_open {
+            if let Err(e) = self.close_session(start_time, opts) {
+                warn!("[{}] failed to close session: {e}", self.inner.operation_id);
+                // But don't error out; try committing anyway. It could be we're resuming a file
+                // where we already closed it out but failed to commit.
+            }
+        }
+        self.inner.complete_up_to()
+    }
+
+    /// Close the session with an empty request at the end of the data, unless it already is.
+    fn close_session(
+        &self,
+        start_time: Instant,
+        opts: &UploadOpts,
+    ) -> Result<(), Error<UploadSessionAppendError>> {
+        if self.inner.closed.load(SeqCst) {
+            return Ok(());
+        }
+        let append_arg = self
+            .inner
+            .append_arg(self.inner.complete_up_to() - self.inner.start_offset)
+            .with_close(true);
+        match Self::upload_block_with_retry(
+            self.client.as_ref(),
+            self.inner.as_ref(),
+            &append_arg,
+            &[],
+            start_time,
+            opts,
+        ) {
+            Ok(()) => Ok(()),
+            Err(Error::Api(UploadSessionAppendError::Closed)) => {
+                // Such as by the session object this one was resumed from.
+                debug!("[{}] session was already closed", self.inner.operation_id);
+                self.inner.closed.store(true, SeqCst);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Close the session so it can be committed, sending any part of a block held back from the
+    /// last upload, and return the total length.
+    ///
+    /// This is needed after uploading with [`keep_open`](UploadOpts::keep_open). It can also be
+    /// used to make sure a session is closed before committing it, since
+    /// [`UploadSession::upload`] doesn't fail if only closing the session does, or to close a
+    /// resumed session without uploading anything more. If the session is already closed, this
+    /// does nothing.
+    ///
+    /// Failed requests are retried using the [`RetryOpts`] given to the last call to
+    /// [`UploadSession::upload`].
+    pub fn close(&self) -> Result<u64, BoxedError> {
+        let opts = UploadOpts {
+            retry: self.inner.retry.lock().unwrap().clone(),
+            ..Default::default()
+        };
+        let tail = std::mem::take(&mut *self.inner.tail.lock().unwrap());
+        let closed = AtomicBool::new(false);
+        let start_time = Instant::now();
+        if !tail.is_empty() {
+            Self::upload_chunk(
                 self.client.as_ref(),
                 self.inner.as_ref(),
-                &append_arg,
-                &[],
+                self.inner.complete_up_to() - self.inner.start_offset,
+                &tail,
+                &closed,
                 start_time,
                 &opts,
-            ) {
-                warn!("failed to close session: {}", e);
-                // But don't error out; try committing anyway. It could be we're resuming a file
-                // where we already closed it out but failed to commit.
-            }
+            )
+            .inspect_err(|_| {
+                // Put it back so closing can be tried again.
+                *self.inner.tail.lock().unwrap() = tail;
+            })?;
         }
+        self.close_session(start_time, &opts)
+            .map_err(Error::boxed)?;
+        Ok(self.inner.complete_up_to())
+    }
 
-        Ok(final_len)
+    /// Whether the session has been closed, so no more data can be added to it and it can be
+    /// committed.
+    ///
+    /// A session which was [resumed](UploadSession::resume) isn't known to be closed until this
+    /// object closes it.
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(SeqCst)
     }
 
     /// After calling [`UploadSession::upload`], commit the data to a file.
+    ///
+    /// This takes either a [`CommitOptions`] or a [`CommitInfo`](files::CommitInfo).
+    ///
+    /// Failed requests are retried using the [`RetryOpts`] given to the last call to
+    /// [`UploadSession::upload`], or the defaults if it hasn't been called.
     pub fn commit(
         &self,
-        commit_info: files::CommitInfo,
+        commit_info: impl Into<files::CommitInfo>,
     ) -> Result<files::FileMetadata, Error<UploadSessionFinishError>> {
-        let finish = self.inner.commit_arg(commit_info);
+        let finish = self.inner.commit_arg(commit_info.into());
 
-        let mut errors = 0;
+        let retry = self.inner.retry.lock().unwrap().clone();
+        let scheduler = self.inner.scheduler.lock().unwrap().clone();
+        let mut backoff = Backoff::new(&retry)
+            .with_operation_id(&self.inner.operation_id)
+            .for_endpoint(EndpointKind::Content);
         loop {
-            match files::upload_session_finish(self.client.as_ref(), &finish, &[]) {
+            backoff.wait();
+            let result = {
+                let _permit = scheduler.as_deref().map(TransferScheduler::acquire);
+                files::upload_session_finish(self.client.as_ref(), &finish, &[])
+            };
+            match result {
                 Ok(file_metadata) => {
                     info!(
-                        "Upload succeeded: {}",
+                        "[{}] Upload succeeded: {}",
+                        self.inner.operation_id,
                         file_metadata.path_display.as_deref().unwrap_or("?")
                     );
+                    self.inner
+                        .emit(UploadEvent::Committed(Box::new(file_metadata.clone())));
                     return Ok(file_metadata);
                 }
-                Err(e) => {
-                    errors += 1;
-                    if errors == 3 {
-                        error!("Error committing upload: {e}, failing.");
-                        return Err(e);
-                    } else {
-                        warn!("Error committing upload: {e}, retrying.");
-                        sleep(Duration::from_secs(1));
-                    }
+                Err(e @ Error::Api(_)) => {
+                    // These won't go away by retrying.
+                    error!(
+                        "[{}] Error committing upload: {e}, failing.",
+                        self.inner.operation_id
+                    );
+                    return Err(e);
                 }
+                Err(e) => backoff.handle("committing upload", e)?,
             }
         }
     }
 
+    /// Like [`UploadSession::commit`], but if there's a conflict with something already at the
+    /// path, as determined by the mode and
+    /// [`with_strict_conflict`](CommitOptions::with_strict_conflict), return a [`Conflict`] error
+    /// with the metadata of what's there, so the caller can decide how to merge them.
+    pub fn commit_or_conflict(
+        &self,
+        commit_info: impl Into<files::CommitInfo>,
+    ) -> Result<files::FileMetadata, BoxedError> {
+        let commit_info = commit_info.into();
+        let path = commit_info.path.clone();
+        match self.commit(commit_info) {
+            Ok(file_metadata) => Ok(file_metadata),
+            Err(Error::Api(UploadSessionFinishError::Path(files::WriteError::Conflict(
+                reason,
+            )))) => {
+                warn!(
+                    "[{}] Conflict committing to {path}: {reason}",
+                    self.inner.operation_id
+                );
+                let throttles = self.inner.throttles.lock().unwrap().clone();
+                throttles.acquire(EndpointKind::Rpc, 0);
+                let existing = match files::get_metadata(
+                    self.client.as_ref(),
+                    &files::GetMetadataArg::new(path.clone()),
+                ) {
+                    Ok(metadata) => Some(metadata),
+                    Err(e) => {
+                        warn!(
+                            "[{}] failed to get metadata of conflicting {path}: {e}",
+                            self.inner.operation_id
+                        );
+                        None
+                    }
+                };
+                Err(Error::Api(Box::new(Conflict {
+                    path,
+                    reason,
+                    existing,
+                })))
+            }
+            Err(e) => Err(e.boxed()),
+        }
+    }
+
+    /// Like [`UploadSession::commit`], but only overwrite the existing file if it is still at the
+    /// given revision, as from the `rev` field of its
+    /// [`FileMetadata`](files::FileMetadata). If it has been changed or deleted since, nothing is
+    /// committed and an [`UpdateConflict`] error is returned.
+    ///
+    /// This overrides the mode, autorename, and strict conflict options given.
+    pub fn commit_update(
+        &self,
+        commit_info: impl Into<files::CommitInfo>,
+        rev: String,
+    ) -> Result<files::FileMetadata, BoxedError> {
+        let commit_info = commit_info.into();
+        let path = commit_info.path.clone();
+        let commit_info = commit_info
+            .with_mode(files::WriteMode::Update(rev.clone()))
+            .with_autorename(false)
+            .with_strict_conflict(true);
+        match self.commit(commit_info) {
+            Ok(file_metadata) => Ok(file_metadata),
+            Err(Error::Api(UploadSessionFinishError::Path(files::WriteError::Conflict(_)))) => {
+                warn!(
+                    "[{}] {path} has changed since revision {rev}",
+                    self.inner.operation_id
+                );
+                Err(Error::Api(Box::new(UpdateConflict { path, rev })))
+            }
+            Err(e) => Err(e.boxed()),
+        }
+    }
+
+    /// Like [`UploadSession::commit`], but also check that the Content Hash of the committed file
+    /// matches that of the data uploaded. If it doesn't, a [`HashMismatch`] error is returned.
+    ///
+    /// Note that in that case the file has still been committed, and it is up to the caller to
+    /// decide what to do about it.
+    ///
+    /// This can only be used if all the data was uploaded using this [`UploadSession`], because
+    /// the hash of any data uploaded before it was resumed is not known. If the session was created
+    /// using [`UploadSession::resume`] with a nonzero offset, an [`UnverifiableUpload`] error is
+    /// returned without committing anything.
+    pub fn commit_verified(
+        &self,
+        commit_info: impl Into<files::CommitInfo>,
+    ) -> Result<files::FileMetadata, BoxedError> {
+        let Some(expected) = self.inner.content_hash() else {
+            return Err(Error::Api(Box::new(UnverifiableUpload {
+                start_offset: self.inner.start_offset,
+            })));
+        };
+        let file_metadata = self.commit(commit_info).map_err(Error::boxed)?;
+        if file_metadata.content_hash.as_ref() != Some(&expected) {
+            error!(
+                "[{}] Content hash mismatch for {}",
+                self.inner.operation_id,
+                file_metadata.path_display.as_deref().unwrap_or("?")
+            );
+            return Err(Error::Api(Box::new(HashMismatch {
+                expected,
+                actual: file_metadata.content_hash,
+            })));
+        }
+        Ok(file_metadata)
+    }
+
+    /// Summarize what this session has done, given the metadata of the file it was committed to.
+    ///
+    /// The elapsed time is counted from when the session object was made.
+    pub fn outcome(&self, metadata: files::FileMetadata) -> UploadOutcome {
+        UploadOutcome {
+            action: UploadAction::Uploaded,
+            metadata,
+            content_hash: self.inner.content_hash(),
+            bytes_uploaded: self.inner.bytes_transferred.load(SeqCst),
+            resumed_bytes: self.inner.start_offset,
+            elapsed: self.inner.created.elapsed(),
+            retries: self.inner.retries.load(SeqCst),
+        }
+    }
+
+    /// Get the ranges of the source, as byte offsets, which have been completely uploaded so far,
+    /// in increasing order. Blocks currently being uploaded are not included.
+    ///
+    /// Because blocks are uploaded in parallel, there can be gaps between the ranges. If the
+    /// session was resumed, everything before the resume offset is considered uploaded.
+    pub fn uploaded_ranges(&self) -> Vec<Range<u64>> {
+        self.inner.completion.lock().unwrap().ranges()
+    }
+
+    /// Get a receiver for events about the progress of this session from now on, as an
+    /// alternative to [`ProgressHandler`]. Events are sent on the threads doing the upload, and
+    /// never block waiting for the receiver.
+    ///
+    /// This can be called multiple times, and each receiver gets all the events.
+    pub fn events(&self) -> mpsc::Receiver<UploadEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.inner.events.lock().unwrap().push(tx);
+        rx
+    }
+
     /// Get the session ID and offset to resume a partially-completed upload. Pass the result to
     /// [`UploadSession::resume`] to create a new session and resume the upload from the
     /// `start_offset` in the return value.
@@ -248,6 +1609,110 @@ impl<C: UserAuthClient + Send + Sync + 'static> UploadSession<C> {
         }
     }
 
+    /// Get a handle which can stop an upload to this session from another thread, such as when the
+    /// user presses Ctrl-C, without losing the data already being sent.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// A unique ID for this session object, which is included in its log messages, so that they
+    /// can be picked out from those of other uploads, such as when reporting a problem.
+    ///
+    /// This is made up by the client, so unlike the upload session ID, it isn't known to Dropbox,
+    /// and resuming a session gets a new one.
+    pub fn operation_id(&self) -> &str {
+        &self.inner.operation_id
+    }
+
+    /// Upload one chunk of the source, as read by [`UploadSession::upload`].
+    fn upload_chunk(
+        client: &C,
+        inner: &SessionInner,
+        block_offset: u64,
+        data: &[u8],
+        closed: &AtomicBool,
+        start_time: Instant,
+        opts: &UploadOpts,
+    ) -> Result<(), BoxedError> {
+        let Some(_request) = inner.shutdown.start_request() else {
+            debug!(
+                "[{}] Shutting down; not uploading data at {block_offset}",
+                inner.operation_id
+            );
+            return Err(Error::Api(Box::new(UploadStopped)));
+        };
+        if let Err(e) =
+            limits::check_upload_size(inner.start_offset + block_offset + data.len() as u64)
+        {
+            error!("[{}] {e}, failing.", inner.operation_id);
+            return Err(Error::Api(Box::new(e)));
+        }
+        let mut data = data;
+        if opts.keep_open && !inner.sequential && data.len() % BLOCK_SIZE != 0 {
+            // More data may follow, so this can't be the last request. Hold back the partial block
+            // for the next one.
+            let (whole, partial) = data.split_at(data.len() - data.len() % BLOCK_SIZE);
+            *inner.tail.lock().unwrap() = partial.to_vec();
+            data = whole;
+            if data.is_empty() {
+                return Ok(());
+            }
+        }
+        let block_hashes = data
+            .chunks(BLOCK_SIZE)
+            .map(content_hash::block_hash)
+            .collect::<Vec<_>>();
+        let mut append_arg = inner
+            .append_arg(block_offset)
+            .with_content_hash(content_hash::hex(&content_hash::combine_block_hashes(
+                &block_hashes,
+            )));
+        if data.len() % BLOCK_SIZE != 0 && !opts.keep_open {
+            // This must be the last block. Only the last one is allowed to be not 4 MiB exactly.
+            // If the last one happens to be a multiple of 4 MiB, the session is closed afterwards
+            // with an empty request instead.
+            append_arg.close = true;
+            closed.store(true, SeqCst);
+        }
+        match Self::upload_block_with_retry(client, inner, &append_arg, data, start_time, opts) {
+            Ok(()) => (),
+            Err(e)
+                if data.len() > BLOCK_SIZE && should_split(&e) && !inner.shutdown.is_stopping() =>
+            {
+                // The request might be too big for the connection; try smaller ones.
+                warn!(
+                    "[{}] Uploading {} blocks at {block_offset} failed ({e}); retrying them one \
+                    at a time",
+                    inner.operation_id,
+                    block_hashes.len()
+                );
+                let end = block_offset + data.len() as u64;
+                let mut offset = block_offset;
+                for (block, hash) in data.chunks(BLOCK_SIZE).zip(&block_hashes) {
+                    let mut arg = inner
+                        .append_arg(offset)
+                        .with_content_hash(content_hash::hex(&content_hash::combine_block_hashes(
+                            [hash],
+                        )));
+                    offset += block.len() as u64;
+                    arg.close = append_arg.close && offset == end;
+                    Self::upload_block_with_retry(client, inner, &arg, block, start_time, opts)
+                        .map_err(|e| append_error(e, inner, &arg, block.len(), opts))?;
+                }
+            }
+            Err(e) => return Err(append_error(e, inner, &append_arg, data.len(), opts)),
+        }
+        inner.save_block_hashes(block_offset, block_hashes);
+        inner.mark_block_uploaded(
+            block_offset,
+            data.len() as u64,
+            opts.checkpoint_handler.as_deref().map(AsRef::as_ref),
+        );
+        Ok(())
+    }
+
     fn upload_block_with_retry(
         client: &C,
         inner: &SessionInner,
@@ -257,47 +1722,184 @@ impl<C: UserAuthClient + Send + Sync + 'static> UploadSession<C> {
         opts: &UploadOpts,
     ) -> Result<(), Error<UploadSessionAppendError>> {
         let block_start_time = Instant::now();
-        let mut errors = 0;
-        let mut backoff = opts.initial_backoff_time;
+        let mut backoff = Backoff::new(&opts.retry)
+            .with_operation_id(&inner.operation_id)
+            .for_endpoint(EndpointKind::Content);
+        let mut hash_mismatches = 0;
+        let mut arg = Cow::Borrowed(arg);
+        let mut data = buf;
+        let throttles = inner.throttles.lock().unwrap().clone();
+        let scheduler = inner.scheduler.lock().unwrap().clone();
+        let offset = arg.cursor.offset;
+        let len = buf.len() as u64;
+        if !buf.is_empty() {
+            inner.emit(UploadEvent::BlockStarted { offset, len });
+        }
         loop {
-            match files::upload_session_append_v2(client, arg, buf) {
+            throttles.acquire(EndpointKind::Content, data.len() as u64);
+            let attempt_start_time = Instant::now();
+            let result = {
+                let _permit = scheduler.as_deref().map(TransferScheduler::acquire);
+                files::upload_session_append_v2(client, &arg, data)
+            };
+            match result {
                 Ok(()) => {
+                    if let Some(tuner) = inner.tuner.lock().unwrap().as_mut() {
+                        tuner.success(
+                            buf.len() / BLOCK_SIZE,
+                            data.len() as u64,
+                            attempt_start_time.elapsed(),
+                        );
+                    }
                     break;
                 }
-                Err(Error::RateLimited {
-                    reason,
-                    retry_after_seconds,
-                }) => {
+                Err(Error::Api(UploadSessionAppendError::IncorrectOffset(e))) => {
+                    // Dropbox already has data past the start of this block. This happens if a
+                    // previous attempt succeeded but we didn't get the response, or if an upload
+                    // is resumed from an old offset.
+                    let offset = arg.cursor.offset;
+                    let end = offset + data.len() as u64;
+                    if arg.close && e.correct_offset > end {
+                        // Nothing can be added after the end of the file.
+                        error!(
+                            "[{}] Dropbox has data up to {}, past the end of the file at {end}, \
+                            failing.",
+                            inner.operation_id, e.correct_offset
+                        );
+                        return Err(Error::Api(UploadSessionAppendError::IncorrectOffset(e)));
+                    } else if arg.close && e.correct_offset == end && !data.is_empty() {
+                        // The session still needs closing.
+                        info!(
+                            "[{}] Dropbox already has data up to {end}, only closing the session",
+                            inner.operation_id
+                        );
+                        data = &[];
+                        let mut new_arg = arg.into_owned();
+                        new_arg.cursor.offset = end;
+                        new_arg.content_hash = None;
+                        arg = Cow::Owned(new_arg);
+                    } else if !arg.close && e.correct_offset >= end {
+                        info!(
+                            "[{}] Dropbox already has data up to {}, skipping block at {offset}",
+                            inner.operation_id, e.correct_offset
+                        );
+                        data = &[];
+                        break;
+                    } else if e.correct_offset > offset {
+                        info!(
+                            "[{}] Dropbox already has data up to {}, sending only the rest of \
+                            the block at {offset}",
+                            inner.operation_id, e.correct_offset
+                        );
+                        data = &data[(e.correct_offset - offset) as usize..];
+                        let mut new_arg = arg.into_owned();
+                        new_arg.cursor.offset = e.correct_offset;
+                        new_arg.content_hash = Some(ContentHash::from(data).finish_hex());
+                        arg = Cow::Owned(new_arg);
+                    } else {
+                        error!(
+                            "[{}] Dropbox expected offset {} for block at {offset}, failing.",
+                            inner.operation_id, e.correct_offset
+                        );
+                        return Err(Error::Api(UploadSessionAppendError::IncorrectOffset(e)));
+                    }
+                }
+                Err(
+                    e @ (Error::Api(UploadSessionAppendError::PayloadTooLarge)
+                    | Error::UnexpectedHttpError { code: 413, .. }),
+                ) => {
+                    // Sending the same amount of data again won't help.
                     warn!(
-                        "rate-limited ({}), waiting {} seconds",
-                        reason, retry_after_seconds
+                        "[{}] Request at {offset} was too large: {e}",
+                        inner.operation_id
                     );
-                    if retry_after_seconds > 0 {
-                        sleep(Duration::from_secs(u64::from(retry_after_seconds)));
+                    return Err(e);
+                }
+                Err(e @ Error::Api(UploadSessionAppendError::ContentHashMismatch)) => {
+                    // The data was corrupted on the way; send it again right away.
+                    if hash_mismatches >= opts.hash_mismatch_retries {
+                        error!(
+                            "[{}] Data for block at {offset} was corrupted {} times, failing.",
+                            inner.operation_id,
+                            hash_mismatches + 1
+                        );
+                        return Err(e);
                     }
+                    hash_mismatches += 1;
+                    warn!(
+                        "[{}] Data for block at {offset} was corrupted, sending it again.",
+                        inner.operation_id
+                    );
+                    inner.retries.fetch_add(1, SeqCst);
+                    inner.emit(UploadEvent::BlockRetried {
+                        offset,
+                        error: e.to_string(),
+                    });
+                }
+                Err(e @ Error::Api(UploadSessionAppendError::NotFound)) => {
+                    // This won't go away by retrying.
+                    error!(
+                        "[{}] Upload session not found, failing.",
+                        inner.operation_id
+                    );
+                    return Err(e);
+                }
+                Err(e @ Error::Api(UploadSessionAppendError::Closed)) => {
+                    // Nor will this.
+                    warn!("[{}] Upload session is already closed.", inner.operation_id);
+                    return Err(e);
+                }
+                Err(e) if inner.shutdown.is_stopping() => {
+                    // Give up on it instead of making the shutdown wait for the retries.
+                    warn!(
+                        "[{}] Request at {offset} failed while shutting down: {e}",
+                        inner.operation_id
+                    );
+                    return Err(e);
                 }
                 Err(e) => {
-                    errors += 1;
-                    if errors == opts.retry_count {
-                        error!("Error calling upload_session_append: {e}, failing.");
-                        return Err(e);
+                    let event = if let Error::RateLimited {
+                        retry_after_seconds,
+                        ..
+                    } = e
+                    {
+                        UploadEvent::RateLimited {
+                            offset,
+                            retry_after_seconds,
+                        }
                     } else {
-                        warn!("Error calling upload_session_append: {e}, retrying.");
-                    }
-                    sleep(jitter(backoff));
-                    if backoff < opts.max_backoff_time {
-                        backoff *= 2;
-                    }
+                        inner.retries.fetch_add(1, SeqCst);
+                        if let Some(tuner) = inner.tuner.lock().unwrap().as_mut() {
+                            tuner.failure();
+                        }
+                        UploadEvent::BlockRetried {
+                            offset,
+                            error: e.to_string(),
+                        }
+                    };
+                    backoff.handle("calling upload_session_append", e)?;
+                    inner.emit(event);
                 }
             }
         }
 
+        if !buf.is_empty() {
+            inner.emit(UploadEvent::BlockCompleted { offset, len });
+        }
+        if arg.close {
+            inner.closed.store(true, SeqCst);
+            inner.emit(UploadEvent::SessionClosed { len: offset + len });
+        }
+
         let now = Instant::now();
         let block_dur = now.duration_since(block_start_time);
         let overall_dur = now.duration_since(start_time);
 
-        let block_bytes = buf.len() as u64;
+        // Only what was sent this time, not what Dropbox already had.
+        let block_bytes = data.len() as u64;
         let bytes_sofar = inner.bytes_transferred.fetch_add(block_bytes, SeqCst) + block_bytes;
+        let blocks = len.div_ceil(BLOCK_SIZE as u64);
+        let blocks_sofar = inner.blocks_transferred.fetch_add(blocks, SeqCst) + blocks;
 
         // This assumes that we have `PARALLELISM` uploads going at the same time and at roughly the
         // same upload speed:
@@ -306,14 +1908,399 @@ impl<C: UserAuthClient + Send + Sync + 'static> UploadSession<C> {
         let overall_rate = bytes_sofar as f64 / overall_dur.as_secs_f64();
 
         if let Some(handler) = &opts.progress_handler {
-            handler.update(bytes_sofar, block_rate, overall_rate);
+            handler.progress(&Progress {
+                operation_id: inner.operation_id.clone(),
+                start_offset: inner.start_offset,
+                bytes_uploaded: bytes_sofar,
+                total_bytes: opts.total_bytes,
+                blocks_completed: inner.start_offset.div_ceil(BLOCK_SIZE as u64) + blocks_sofar,
+                total_blocks: opts
+                    .total_bytes
+                    .map(|total| total.div_ceil(BLOCK_SIZE as u64)),
+                retries: inner.retries.load(SeqCst),
+                instant_rate: block_rate,
+                overall_rate,
+            });
         }
 
         Ok(())
     }
 }
 
+/// A handle for stopping uploads to an [`UploadSession`] early, from
+/// [`UploadSession::shutdown_handle`].
+///
+/// Once it is stopped, no more requests are started, and the upload fails with [`UploadStopped`],
+/// while requests which are already in progress are left to finish, so their data isn't wasted.
+/// Any further uploads to the session fail the same way.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<SessionInner>,
+}
+
+impl ShutdownHandle {
+    /// Stop uploading, without waiting. This only sets a flag, so it can be called from anywhere,
+    /// including a signal handler.
+    pub fn stop(&self) {
+        self.inner.shutdown.stopping.store(true, SeqCst);
+    }
+
+    /// Whether the upload has been told to stop.
+    pub fn is_stopped(&self) -> bool {
+        self.inner.shutdown.is_stopping()
+    }
+
+    /// Stop uploading, wait for the requests in progress to finish, and return the parameters to
+    /// resume the upload from where it got to.
+    ///
+    /// Because requests are made in parallel, some data past the resume offset may have been
+    /// uploaded already; resuming sends it again.
+    pub fn shutdown(&self) -> UploadResume {
+        self.stop();
+        let state = &self.inner.shutdown;
+        drop(
+            state
+                .drained
+                .wait_while(state.in_flight.lock().unwrap(), |n| *n > 0)
+                .unwrap(),
+        );
+        info!(
+            "[{}] Shut down at offset {}",
+            self.inner.operation_id,
+            self.inner.complete_up_to()
+        );
+        UploadResume {
+            session_id: self.inner.session_id.clone(),
+            start_offset: self.inner.complete_up_to(),
+        }
+    }
+}
+
+/// Whether a session is being shut down, and how many requests are in progress.
+#[derive(Default)]
+struct ShutdownState {
+    stopping: AtomicBool,
+    in_flight: Mutex<usize>,
+    drained: Condvar,
+}
+
+impl ShutdownState {
+    fn is_stopping(&self) -> bool {
+        self.stopping.load(SeqCst)
+    }
+
+    /// Count a request as in progress until the returned guard is dropped, or return `None` if
+    /// the session is shutting down.
+    fn start_request(&self) -> Option<InFlight<'_>> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if self.is_stopping() {
+            return None;
+        }
+        *in_flight += 1;
+        Some(InFlight { state: self })
+    }
+}
+
+struct InFlight<'a> {
+    state: &'a ShutdownState,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        *self.state.in_flight.lock().unwrap() -= 1;
+        self.state.drained.notify_all();
+    }
+}
+
+/// Upload a local file to the given path in Dropbox.
+///
+/// This blocks the current thread until the whole file has been uploaded and committed, or an
+/// error occurs.
+pub fn upload_file<C: UserAuthClient + Send + Sync + 'static>(
+    client: Arc<C>,
+    source_path: &Path,
+    dest_path: &str,
+    opts: &UploadFileOpts,
+) -> Result<UploadOutcome, BoxedError> {
+    let rpc_throttle = Throttles::new(None, opts.upload.max_rpc_per_sec);
+    upload_file_throttled(client, source_path, dest_path, opts, &rpc_throttle)
+}
+
+/// Like [`upload_file`], but with the throttle for its RPC requests given by the caller, so that
+/// it can be shared by uploads of many files.
+fn upload_file_throttled<C: UserAuthClient + Send + Sync + 'static>(
+    client: Arc<C>,
+    source_path: &Path,
+    dest_path: &str,
+    opts: &UploadFileOpts,
+    rpc_throttle: &Throttles,
+) -> Result<UploadOutcome, BoxedError> {
+    let start_time = Instant::now();
+    let mut source = File::open(source_path).map_err(|e| Error::HttpClient(e.into()))?;
+
+    if opts.check_space {
+        let len = source
+            .metadata()
+            .map_err(|e| Error::HttpClient(e.into()))?
+            .len();
+        rpc_throttle.acquire(EndpointKind::Rpc, 0);
+        check_space(client.as_ref(), len)?;
+    }
+
+    if opts.if_exists == IfExists::SkipIfIdentical {
+        if let Some((existing, hash)) =
+            identical_remote_file(client.as_ref(), &mut source, dest_path, rpc_throttle)?
+        {
+            info!("Skipping upload of identical file: {dest_path}");
+            return Ok(UploadOutcome::without_upload(
+                UploadAction::Skipped,
+                existing,
+                Some(hash),
+                start_time.elapsed(),
+            ));
+        }
+        source
+            .seek(SeekFrom::Start(0))
+            .map_err(|e| Error::HttpClient(e.into()))?;
+    }
+
+    let mtime = source
+        .metadata()
+        .and_then(|meta| meta.modified())
+        .map_err(|e| Error::HttpClient(e.into()))?;
+    let client_modified = match &opts.client_modified_policy {
+        Some(policy) => policy.client_modified(source_path, mtime),
+        None => Some(mtime),
+    };
+
+    let mut commit = CommitOptions::new(dest_path).with_mode(files::WriteMode::Overwrite);
+    if let Some(time) = client_modified {
+        commit = commit.with_client_modified(time);
+    }
+    let mut outcome = upload_seekable(client, source, commit, &opts.upload)?;
+    outcome.elapsed = start_time.elapsed();
+    Ok(outcome)
+}
+
+/// Upload a seekable source, such as a file, from its current position to the end, and commit it,
+/// checking its Content Hash like [`UploadSession::commit_verified`].
+///
+/// If the upload session is lost partway through, and
+/// [`restart_on_session_loss`](UploadOpts::restart_on_session_loss) allows it, the source is
+/// rewound and uploaded again in a new session. The outcome then describes the last session, but
+/// its elapsed time includes the earlier ones.
+///
+/// The source is read as the data is sent, like with [`UploadSession::upload_seek`]. This is why
+/// it has to be [`Send`]: it's read from the upload threads. Its length is found up front by
+/// seeking to the end, so it must not change length while it's being uploaded.
+///
+/// A destination path longer than [`MAX_PATH_LENGTH`](limits::MAX_PATH_LENGTH) fails with a
+/// [`LimitExceeded`](limits::LimitExceeded) error before anything is uploaded.
+///
+/// This blocks the current thread until the whole source has been uploaded and committed, or an
+/// error occurs.
+pub fn upload_seekable<C: UserAuthClient + Send + Sync + 'static>(
+    client: Arc<C>,
+    mut source: impl Read + Seek + Send,
+    commit_info: impl Into<files::CommitInfo>,
+    opts: &UploadOpts,
+) -> Result<UploadOutcome, BoxedError> {
+    let start_time = Instant::now();
+    let commit_info = commit_info.into();
+    limits::check_path_length(&commit_info.path).map_err(|e| Error::Api(Box::new(e) as _))?;
+    let start = source
+        .stream_position()
+        .map_err(|e| Error::HttpClient(e.into()))?;
+    let mut opts = opts.clone();
+    if opts.total_bytes.is_none() {
+        let len = source
+            .seek(SeekFrom::End(0))
+            .and_then(|end| source.seek(SeekFrom::Start(start)).map(|_| end - start))
+            .map_err(|e| Error::HttpClient(e.into()))?;
+        opts.total_bytes = Some(len);
+    }
+    let mut restarts = 0;
+    loop {
+        let session = UploadSession::new(client.clone()).map_err(Error::boxed)?;
+        let result = session
+            .upload_seek(&mut source, opts.clone())
+            .map_err(BoxedError::from)
+            .and_then(|_| session.commit_verified(commit_info.clone()));
+        match result {
+            Err(e) if restarts < opts.restart_on_session_loss && is_session_lost(&e) => {
+                restarts += 1;
+                warn!(
+                    "[{}] Upload session for {} was lost; starting over (restart {restarts} of {})",
+                    session.operation_id(),
+                    commit_info.path,
+                    opts.restart_on_session_loss
+                );
+                source
+                    .seek(SeekFrom::Start(start))
+                    .map_err(|e| Error::HttpClient(e.into()))?;
+            }
+            result => {
+                let mut outcome = session.outcome(result?);
+                outcome.elapsed = start_time.elapsed();
+                return Ok(outcome);
+            }
+        }
+    }
+}
+
+/// Convert an error from uploading the given data into one for returning from the upload.
+fn append_error(
+    error: Error<UploadSessionAppendError>,
+    inner: &SessionInner,
+    arg: &files::UploadSessionAppendArg,
+    len: usize,
+    opts: &UploadOpts,
+) -> BoxedError {
+    if inner.shutdown.is_stopping() {
+        // The request was given up on rather than retried, because of the shutdown.
+        return Error::Api(Box::new(UploadStopped));
+    }
+    match error {
+        Error::Api(UploadSessionAppendError::ContentHashMismatch) => {
+            Error::Api(Box::new(BlockHashMismatch {
+                offset: arg.cursor.offset,
+                len: len as u64,
+                attempts: opts.hash_mismatch_retries + 1,
+            }))
+        }
+        e => e.boxed(),
+    }
+}
+
+/// Whether a failed request with more than one block should be retried as single-block requests:
+/// if it was too large, or failed repeatedly without a response, such as by timing out.
+fn should_split(error: &Error<UploadSessionAppendError>) -> bool {
+    matches!(
+        error,
+        Error::Api(UploadSessionAppendError::PayloadTooLarge)
+            | Error::UnexpectedHttpError { code: 413, .. }
+            | Error::HttpClient(_)
+    )
+}
+
+/// Whether an error means the upload session no longer exists, such as because it expired.
+fn is_session_lost(error: &BoxedError) -> bool {
+    let Error::Api(e) = error else {
+        return false;
+    };
+    matches!(e.downcast_ref(), Some(UploadSessionAppendError::NotFound))
+        || matches!(
+            e.downcast_ref(),
+            Some(UploadSessionFinishError::LookupFailed(
+                files::UploadSessionLookupError::NotFound
+            ))
+        )
+}
+
+/// Make a random ID for an upload, for [`UploadSession::operation_id`].
+fn new_operation_id() -> String {
+    use ring::rand::{generate, SystemRandom};
+    let bytes: [u8; 8] = generate(&SystemRandom::new()).unwrap().expose();
+    content_hash::hex(&bytes)
+}
+
+/// Check that there's at least the given amount of free space in the account.
+fn check_space(client: &impl UserAuthClient, needed: u64) -> Result<(), BoxedError> {
+    let usage = users::get_space_usage(client).map_err(Error::boxed)?;
+    let available = match usage.allocation {
+        users::SpaceAllocation::Individual(individual) => {
+            individual.allocated.saturating_sub(usage.used)
+        }
+        users::SpaceAllocation::Team(team) => {
+            let team_available = team.allocated.saturating_sub(team.used);
+            if team.user_within_team_space_allocated == 0 {
+                // No limit for the user within the team's space.
+                team_available
+            } else {
+                team.user_within_team_space_allocated
+                    .saturating_sub(team.user_within_team_space_used_cached)
+                    .min(team_available)
+            }
+        }
+        _ => {
+            warn!("unknown space allocation type; not checking space");
+            return Ok(());
+        }
+    };
+    if needed > available {
+        error!("upload needs {needed} bytes, but only {available} are available");
+        return Err(Error::Api(Box::new(InsufficientSpace {
+            needed,
+            available,
+        })));
+    }
+    Ok(())
+}
+
+/// If there is a file at the given Dropbox path with the same size and Content Hash as the given
+/// local file, return its metadata and the Content Hash.
+fn identical_remote_file(
+    client: &impl UserAuthClient,
+    source: &mut File,
+    dest_path: &str,
+    rpc_throttle: &Throttles,
+) -> Result<Option<(files::FileMetadata, String)>, BoxedError> {
+    rpc_throttle.acquire(EndpointKind::Rpc, 0);
+    let existing =
+        match files::get_metadata(client, &files::GetMetadataArg::new(dest_path.to_owned())) {
+            Ok(files::Metadata::File(file)) => file,
+            Ok(_) => return Ok(None),
+            Err(Error::Api(files::GetMetadataError::Path(files::LookupError::NotFound))) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(path_root::boxed(e)),
+        };
+
+    let len = source
+        .metadata()
+        .map_err(|e| Error::HttpClient(e.into()))?
+        .len();
+    if existing.size != len {
+        return Ok(None);
+    }
+
+    let mut hash = ContentHash::new();
+    hash.read_stream(source)
+        .map_err(|e| Error::HttpClient(e.into()))?;
+    let hash = hash.finish_hex();
+    if existing.content_hash.as_ref() == Some(&hash) {
+        Ok(Some((existing, hash)))
+    } else {
+        Ok(None)
+    }
+}
+
 impl SessionInner {
+    /// Send an event to all the receivers, dropping any which have gone away.
+    fn emit(&self, event: UploadEvent) {
+        self.events
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// How many requests to make at once. Sequential sessions can only take one at a time.
+    fn parallelism(&self, opts: &UploadOpts) -> usize {
+        if self.sequential {
+            1
+        } else {
+            opts.parallelism
+        }
+    }
+
+    /// How many blocks to read for the next request.
+    fn blocks_per_request(&self, opts: &UploadOpts) -> usize {
+        match self.tuner.lock().unwrap().as_ref() {
+            Some(tuner) => tuner.blocks(),
+            None => opts.blocks_per_request,
+        }
+    }
+
     /// Generate the argument to append a block at the given offset.
     fn append_arg(&self, block_offset: u64) -> files::UploadSessionAppendArg {
         files::UploadSessionAppendArg::new(files::UploadSessionCursor::new(
@@ -326,18 +2313,47 @@ impl SessionInner {
     /// time.
     fn commit_arg(&self, commit_info: files::CommitInfo) -> files::UploadSessionFinishArg {
         files::UploadSessionFinishArg::new(
-            files::UploadSessionCursor::new(
-                self.session_id.clone(),
-                self.bytes_transferred.load(SeqCst),
-            ),
+            files::UploadSessionCursor::new(self.session_id.clone(), self.complete_up_to()),
             commit_info,
         )
     }
 
-    /// Mark a block as uploaded.
-    fn mark_block_uploaded(&self, block_offset: u64, block_len: u64) {
+    /// Save the hashes of the blocks in an uploaded chunk, for verifying the whole file later.
+    fn save_block_hashes(&self, chunk_offset: u64, block_hashes: Vec<[u8; OUTPUT_SIZE]>) {
+        let mut saved = self.block_hashes.lock().unwrap();
+        for (i, hash) in block_hashes.into_iter().enumerate() {
+            saved.insert(chunk_offset + (i * BLOCK_SIZE) as u64, hash);
+        }
+    }
+
+    /// Mark a block as uploaded, and notify the checkpoint handler if this advanced the resume
+    /// point.
+    fn mark_block_uploaded(
+        &self,
+        block_offset: u64,
+        block_len: u64,
+        checkpoint_handler: Option<&dyn CheckpointHandler>,
+    ) {
         let mut completion = self.completion.lock().unwrap();
-        completion.complete_block(self.start_offset + block_offset, block_len);
+        if completion.complete_block(self.start_offset + block_offset, block_len) {
+            if let Some(handler) = checkpoint_handler {
+                handler.checkpoint(&UploadResume {
+                    session_id: self.session_id.clone(),
+                    start_offset: completion.complete_up_to,
+                });
+            }
+        }
+    }
+
+    /// The Content Hash of the data uploaded, or `None` if the session was resumed, since the
+    /// hash of the data before that isn't known.
+    fn content_hash(&self) -> Option<String> {
+        if self.start_offset != 0 {
+            return None;
+        }
+        Some(content_hash::hex(&content_hash::combine_block_hashes(
+            self.block_hashes.lock().unwrap().values(),
+        )))
     }
 
     /// Return the offset up to which the file is completely uploaded. It can be resumed from this
@@ -371,8 +2387,8 @@ impl CompletionTracker {
         }
     }
 
-    /// Mark a block as completely uploaded.
-    pub fn complete_block(&mut self, block_offset: u64, block_len: u64) {
+    /// Mark a block as completely uploaded. Returns whether this advanced `complete_up_to`.
+    pub fn complete_block(&mut self, block_offset: u64, block_len: u64) -> bool {
         if block_offset == self.complete_up_to {
             // Advance the cursor.
             self.complete_up_to += block_len;
@@ -381,24 +2397,817 @@ impl CompletionTracker {
             while let Some(len) = self.uploaded_blocks.remove(&self.complete_up_to) {
                 self.complete_up_to += len;
             }
+            true
         } else {
             // This block isn't at the low-water mark; there's a gap behind it. Save it for later.
             self.uploaded_blocks.insert(block_offset, block_len);
+            false
         }
     }
+
+    /// Get the ranges that have been completely uploaded, in order, with adjacent ranges merged.
+    pub fn ranges(&self) -> Vec<Range<u64>> {
+        let mut blocks = self.uploaded_blocks.iter().collect::<Vec<_>>();
+        blocks.sort_unstable();
+        let mut ranges: Vec<Range<u64>> = vec![];
+        if self.complete_up_to != 0 {
+            ranges.push(0..self.complete_up_to);
+        }
+        for (&offset, &len) in blocks {
+            match ranges.last_mut() {
+                Some(last) if last.end == offset => last.end += len,
+                _ => ranges.push(offset..offset + len),
+            }
+        }
+        ranges
+    }
 }
 
-// Add a random duration in the range [-duration/4, duration/4].
-fn jitter(duration: Duration) -> Duration {
-    use ring::rand::{generate, SystemRandom};
-    let rng = SystemRandom::new();
-    let bytes: [u8; 4] = generate(&rng).unwrap().expose();
-    let u = u32::from_ne_bytes(bytes);
-    let max = f64::from(u32::MAX);
-    let f = f64::from(u) / max / 4.;
-    if u % 2 == 0 {
-        duration + duration.mul_f64(f)
-    } else {
-        duration - duration.mul_f64(f)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+    use std::io;
+
+    const EMPTY_FILE_METADATA: &str = r#"{
+        "name": "empty",
+        "id": "id:empty",
+        "client_modified": "2024-01-01T00:00:00Z",
+        "server_modified": "2024-01-01T00:00:00Z",
+        "rev": "0123456789abcdef",
+        "size": 0,
+        "path_display": "/empty",
+        "content_hash": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    }"#;
+
+    #[test]
+    fn completion_in_order() {
+        let mut tracker = CompletionTracker::default();
+        assert!(tracker.complete_block(0, 10));
+        assert!(tracker.complete_block(10, 10));
+        assert_eq!(20, tracker.complete_up_to);
+    }
+
+    #[test]
+    fn completion_out_of_order() {
+        let mut tracker = CompletionTracker::resume_from(100);
+        assert!(!tracker.complete_block(120, 10));
+        assert!(!tracker.complete_block(110, 10));
+        assert_eq!(100, tracker.complete_up_to);
+        assert!(tracker.complete_block(100, 10));
+        assert_eq!(130, tracker.complete_up_to);
+        assert!(tracker.uploaded_blocks.is_empty());
+    }
+
+    #[test]
+    fn rate_without_upload() {
+        let metadata = serde_json::from_str(EMPTY_FILE_METADATA).unwrap();
+        let outcome =
+            UploadOutcome::without_upload(UploadAction::Skipped, metadata, None, Duration::ZERO);
+        assert_eq!(0.0, outcome.rate());
+    }
+
+    #[test]
+    fn incorrect_offset_skips_uploaded_block() {
+        let client = Arc::new(MockClient::new("null").then(
+            409,
+            r#"{"error": {".tag": "incorrect_offset", "correct_offset": 4194304}}"#,
+        ));
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let opts = UploadOpts {
+            parallelism: 1,
+            blocks_per_request: 1,
+            ..Default::default()
+        };
+        let len = session
+            .upload(&vec![0u8; 2 * BLOCK_SIZE][..], opts)
+            .unwrap();
+        assert_eq!(2 * BLOCK_SIZE as u64, len);
+        assert_eq!(2 * BLOCK_SIZE as u64, session.get_resume().start_offset);
+        // Two blocks, and then closing the session.
+        assert_eq!(3, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn incorrect_offset_still_closes() {
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(
+                    409,
+                    r#"{"error": {".tag": "incorrect_offset", "correct_offset": 5}}"#,
+                )
+                .then(200, "null"),
+        );
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let events = session.events();
+        assert_eq!(
+            5,
+            session
+                .upload(&b"hello"[..], UploadOpts::default())
+                .unwrap()
+        );
+        assert!(session.is_closed());
+        assert!(events
+            .try_iter()
+            .any(|event| event == UploadEvent::SessionClosed { len: 5 }));
+        // The block, and then only closing the session.
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn incorrect_offset_past_end_fails() {
+        let client = Arc::new(MockClient::new("null").then(
+            409,
+            r#"{"error": {".tag": "incorrect_offset", "correct_offset": 6}}"#,
+        ));
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let e = session
+            .upload(&b"hello"[..], UploadOpts::default())
+            .unwrap_err();
+        assert!(matches!(e, UploadError::Append { .. }), "{e:?}");
+        assert!(!session.is_closed());
+    }
+
+    #[test]
+    fn too_large() {
+        let client = Arc::new(MockClient::new("null"));
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: limits::MAX_UPLOAD_SESSION_SIZE - 1,
+            },
+        );
+        let Err(UploadError::LimitExceeded { error, resume, .. }) =
+            session.upload(&[0u8; 2][..], UploadOpts::default())
+        else {
+            panic!("wrong result");
+        };
+        assert_eq!(
+            limits::LimitExceeded {
+                what: "file size",
+                value: limits::MAX_UPLOAD_SESSION_SIZE + 1,
+                max: limits::MAX_UPLOAD_SESSION_SIZE,
+            },
+            error
+        );
+        assert_eq!(limits::MAX_UPLOAD_SESSION_SIZE - 1, resume.start_offset);
+
+        let opts = UploadOpts {
+            total_bytes: Some(limits::MAX_UPLOAD_SESSION_SIZE + 1),
+            ..Default::default()
+        };
+        let e = session.upload(&[0u8; 2][..], opts).unwrap_err();
+        assert!(matches!(e, UploadError::LimitExceeded { .. }));
+        // Passed on as it was.
+        let Error::Api(e) = BoxedError::from(e) else {
+            panic!("wrong error");
+        };
+        assert!(e.downcast_ref::<limits::LimitExceeded>().is_some());
+        assert!(client.urls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn path_too_long() {
+        let client = Arc::new(MockClient::new("null"));
+        let path = format!("/{}", "x".repeat(limits::MAX_PATH_LENGTH));
+        let Err(Error::Api(e)) = upload_seekable(
+            client.clone(),
+            io::Cursor::new(vec![0; 10]),
+            CommitOptions::new(path),
+            &UploadOpts::default(),
+        ) else {
+            panic!("expected an API error");
+        };
+        let e = e.downcast_ref::<limits::LimitExceeded>().unwrap();
+        assert_eq!(limits::MAX_PATH_LENGTH as u64 + 1, e.value);
+        assert!(client.urls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_error_includes_resume() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken"))
+            }
+        }
+
+        let client = Arc::new(MockClient::new("null"));
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let opts = UploadOpts {
+            parallelism: 1,
+            blocks_per_request: 1,
+            ..Default::default()
+        };
+        let source = io::Cursor::new(vec![0u8; BLOCK_SIZE]).chain(Broken);
+        let Err(UploadError::Read {
+            error,
+            resume,
+            operation_id,
+        }) = session.upload(source, opts)
+        else {
+            panic!("wrong result");
+        };
+        assert_eq!("broken", error.to_string());
+        assert_eq!(session.operation_id(), operation_id);
+        // The block read before the error was still uploaded.
+        assert_eq!(BLOCK_SIZE as u64, resume.start_offset);
+        assert_eq!(1, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn restart_on_session_loss() {
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(200, r#"{"session_id": "lost"}"#)
+                .then(409, r#"{"error": {".tag": "not_found"}}"#)
+                .then(
+                    409,
+                    r#"{"error": {".tag": "lookup_failed", "lookup_failed": {".tag": "not_found"}}}"#,
+                )
+                .then(200, r#"{"session_id": "new"}"#)
+                .then(200, "null")
+                .then(200, EMPTY_FILE_METADATA),
+        );
+        let opts = UploadOpts {
+            restart_on_session_loss: 1,
+            ..Default::default()
+        };
+        let outcome = upload_seekable(
+            client.clone(),
+            io::Cursor::new(vec![]),
+            CommitOptions::new("/empty"),
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(UploadAction::Uploaded, outcome.action);
+        assert_eq!("/empty", outcome.metadata.path_display.as_deref().unwrap());
+        assert_eq!(0, outcome.bytes_uploaded);
+        // Start, close, and finish, twice.
+        assert_eq!(6, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn upload_seek_rereads_failed_requests() {
+        struct CountSeeks(io::Cursor<Vec<u8>>, Arc<AtomicU32>);
+        impl Read for CountSeeks {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+        impl Seek for CountSeeks {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+                if let SeekFrom::Start(_) = pos {
+                    self.1.fetch_add(1, SeqCst);
+                }
+                self.0.seek(pos)
+            }
+        }
+
+        let client = Arc::new(MockClient::new("null").then(503, "unavailable"));
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let seeks = Arc::new(AtomicU32::new(0));
+        let source = CountSeeks(io::Cursor::new(b"hello".to_vec()), seeks.clone());
+        let opts = UploadOpts {
+            retry: RetryOpts {
+                initial_backoff_time: Duration::from_millis(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(5, session.upload_seek(source, opts).unwrap());
+        // Read once for each attempt.
+        assert_eq!(2, seeks.load(SeqCst));
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn close_resumed_session() {
+        let client = Arc::new(MockClient::new("null").then(
+            409,
+            r#"{"error": {".tag": "closed"}, "error_summary": "closed/"}"#,
+        ));
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 10,
+            },
+        );
+        assert!(!session.is_closed());
+        assert_eq!(10, session.close().unwrap());
+        assert!(session.is_closed());
+        // Already closed, so this doesn't make a request.
+        assert_eq!(10, session.close().unwrap());
+        assert_eq!(1, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn commit_verified_resumed_session() {
+        let client = Arc::new(MockClient::new("null"));
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 10,
+            },
+        );
+        let Err(Error::Api(err)) = session.commit_verified(CommitOptions::new("/x")) else {
+            panic!("expected an API error");
+        };
+        assert_eq!(
+            Some(&UnverifiableUpload { start_offset: 10 }),
+            err.downcast_ref::<UnverifiableUpload>()
+        );
+        // Nothing was committed.
+        assert!(client.urls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn shutdown_keeps_in_flight_data() {
+        struct StopAfterFirst(ShutdownHandle);
+        impl ProgressHandler for StopAfterFirst {
+            fn progress(&self, _: &Progress) {
+                self.0.stop();
+            }
+        }
+
+        let client = Arc::new(MockClient::new("null").then(200, r#"{"session_id": "session"}"#));
+        let session = UploadSession::new(client.clone()).unwrap();
+        let handle = session.shutdown_handle();
+        let opts = UploadOpts {
+            parallelism: 1,
+            blocks_per_request: 1,
+            progress_handler: Some(Arc::new(Box::new(StopAfterFirst(handle.clone())))),
+            ..Default::default()
+        };
+        let e = session
+            .upload(&vec![0u8; 3 * BLOCK_SIZE][..], opts)
+            .unwrap_err();
+        assert!(matches!(e, UploadError::Stopped { .. }));
+        assert!(handle.is_stopped());
+        let resume = handle.shutdown();
+        assert_eq!("session", resume.session_id);
+        assert_eq!(BLOCK_SIZE as u64, resume.start_offset);
+        // Start, and only the block which was in progress.
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn failure_while_stopping_is_stopped() {
+        use dropbox_sdk::client_trait::{HttpClient, HttpRequestResultRaw};
+        use std::sync::OnceLock;
+
+        /// Stops the upload as each request is made, and then fails it.
+        struct StopOnRequest(MockClient, OnceLock<ShutdownHandle>);
+        impl HttpClient for StopOnRequest {
+            type Request = <MockClient as HttpClient>::Request;
+            fn execute(
+                &self,
+                request: Self::Request,
+                body: &[u8],
+            ) -> Result<HttpRequestResultRaw, Error> {
+                self.1.get().unwrap().stop();
+                self.0.execute(request, body)
+            }
+            fn new_request(&self, url: &str) -> Self::Request {
+                self.0.new_request(url)
+            }
+            fn token(&self) -> Option<Arc<String>> {
+                self.0.token()
+            }
+        }
+        impl UserAuthClient for StopOnRequest {}
+
+        let client = Arc::new(StopOnRequest(
+            MockClient::new("null").then(503, "unavailable"),
+            OnceLock::new(),
+        ));
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let _ = client.1.set(session.shutdown_handle());
+        let e = session
+            .upload(&b"hello"[..], UploadOpts::default())
+            .unwrap_err();
+        assert!(matches!(e, UploadError::Stopped { .. }), "{e:?}");
+        // Not retried.
+        assert_eq!(1, client.0.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn payload_too_large_is_split() {
+        let client = Arc::new(
+            MockClient::new("null").then(409, r#"{"error": {".tag": "payload_too_large"}}"#),
+        );
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let opts = UploadOpts {
+            blocks_per_request: 2,
+            deterministic: true,
+            ..Default::default()
+        };
+        let len = session
+            .upload(&vec![0u8; 2 * BLOCK_SIZE][..], opts)
+            .unwrap();
+        assert_eq!(2 * BLOCK_SIZE as u64, len);
+        assert_eq!(vec![0..2 * BLOCK_SIZE as u64], session.uploaded_ranges());
+        // The rejected request, each block on its own, and then closing the session.
+        assert_eq!(4, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn hash_mismatch() {
+        const MISMATCH: &str = r#"{"error": {".tag": "content_hash_mismatch"}}"#;
+        let upload = |retries| {
+            let client = Arc::new(
+                MockClient::new("null")
+                    .then(409, MISMATCH)
+                    .then(409, MISMATCH),
+            );
+            let session = UploadSession::resume(
+                client.clone(),
+                UploadResume {
+                    session_id: "session".to_owned(),
+                    start_offset: 0,
+                },
+            );
+            let opts = UploadOpts {
+                hash_mismatch_retries: retries,
+                ..Default::default()
+            };
+            session.upload(&b"data"[..], opts)
+        };
+        assert_eq!(4, upload(2).unwrap());
+        let Err(UploadError::Integrity { error, resume, .. }) = upload(1) else {
+            panic!("wrong result");
+        };
+        assert_eq!(
+            BlockHashMismatch {
+                offset: 0,
+                len: 4,
+                attempts: 2
+            },
+            error
+        );
+        assert_eq!(0, resume.start_offset);
+    }
+
+    #[test]
+    fn deterministic_overrides_concurrency() {
+        let opts = UploadOpts {
+            parallelism: 8,
+            auto_tune: true,
+            deterministic: true,
+            ..Default::default()
+        }
+        .resolve();
+        assert_eq!(1, opts.parallelism);
+        assert!(!opts.auto_tune);
+        assert!(!opts.retry.jitter);
+        assert_eq!(
+            UploadOpts::default().blocks_per_request,
+            opts.blocks_per_request
+        );
+    }
+
+    #[test]
+    fn progress_eta() {
+        let mut progress = Progress {
+            operation_id: "op".to_owned(),
+            start_offset: 100,
+            bytes_uploaded: 200,
+            total_bytes: Some(400),
+            blocks_completed: 0,
+            total_blocks: Some(1),
+            retries: 0,
+            instant_rate: 0.,
+            overall_rate: 10.,
+        };
+        assert_eq!(Some(0.75), progress.fraction());
+        assert_eq!(Some(Duration::from_secs(10)), progress.eta());
+        progress.overall_rate = 0.;
+        assert_eq!(None, progress.eta());
+        progress.total_bytes = None;
+        assert_eq!(None, progress.fraction());
+    }
+
+    #[test]
+    fn commit_conflict() {
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(
+                    409,
+                    r#"{"error": {".tag": "path", "path": {".tag": "conflict", "conflict": {".tag": "file"}}}}"#,
+                )
+                .then(200, r#"{".tag": "file", "name": "x", "id": "id:x", "size": 1,
+                    "client_modified": "2024-01-01T00:00:00Z",
+                    "server_modified": "2024-01-01T00:00:00Z", "rev": "aaaaaaaaa"}"#),
+        );
+        let session = UploadSession::resume(
+            client,
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let err = session
+            .commit_or_conflict(CommitOptions::new("/x").with_strict_conflict(true))
+            .unwrap_err();
+        let Error::Api(err) = err else {
+            panic!("wrong error: {err}");
+        };
+        let conflict = err.downcast_ref::<Conflict>().unwrap();
+        assert_eq!("/x", conflict.path);
+        assert_eq!(files::WriteConflictError::File, conflict.reason);
+        assert!(matches!(
+            &conflict.existing,
+            Some(files::Metadata::File(f)) if f.rev == "aaaaaaaaa"
+        ));
+    }
+
+    #[test]
+    fn events() {
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(200, "null")
+                .then(200, EMPTY_FILE_METADATA),
+        );
+        let session = UploadSession::resume(
+            client,
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let events = session.events();
+        session.upload(&b"x"[..], UploadOpts::default()).unwrap();
+        let metadata = session.commit(CommitOptions::new("/empty")).unwrap();
+        assert_eq!(
+            vec![
+                UploadEvent::BlockStarted { offset: 0, len: 1 },
+                UploadEvent::BlockCompleted { offset: 0, len: 1 },
+                UploadEvent::SessionClosed { len: 1 },
+                UploadEvent::Committed(Box::new(metadata)),
+            ],
+            events.try_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sequential() {
+        let client = Arc::new(MockClient::new("null").then(200, r#"{"session_id": "seq"}"#));
+        let session = UploadSession::new_sequential(client).unwrap();
+        let events = session.events();
+        let opts = UploadOpts {
+            parallelism: 8,
+            blocks_per_request: 1,
+            ..Default::default()
+        };
+        session
+            .upload(&vec![0u8; 3 * BLOCK_SIZE + 1][..], opts)
+            .unwrap();
+        // One request at a time, in order.
+        let expected = (0..4u64)
+            .flat_map(|i| {
+                let offset = i * BLOCK_SIZE as u64;
+                let len = if i == 3 { 1 } else { BLOCK_SIZE as u64 };
+                [
+                    UploadEvent::BlockStarted { offset, len },
+                    UploadEvent::BlockCompleted { offset, len },
+                ]
+            })
+            .chain([UploadEvent::SessionClosed {
+                len: 3 * BLOCK_SIZE as u64 + 1,
+            }])
+            .collect::<Vec<_>>();
+        assert_eq!(expected, events.try_iter().collect::<Vec<_>>());
+    }
+
+    #[cfg(all(unix, feature = "mmap"))]
+    #[test]
+    fn upload_mmap_follows_earlier_uploads() {
+        let client = Arc::new(MockClient::new("null"));
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let path = std::env::temp_dir().join(format!("upload_mmap_test_{}", std::process::id()));
+        std::fs::write(&path, vec![0u8; BLOCK_SIZE + 5]).unwrap();
+        let opts = UploadOpts {
+            keep_open: true,
+            ..Default::default()
+        };
+
+        // A held-back partial block would be sent after the mapped data, out of order.
+        session.upload(&b"abc"[..], opts.clone()).unwrap();
+        // SAFETY: nothing else changes the file.
+        let result = unsafe { session.upload_mmap(&path, opts.clone()) };
+        assert!(matches!(result, Err(UploadError::Read { .. })));
+
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        session
+            .upload(&vec![0u8; BLOCK_SIZE][..], opts.clone())
+            .unwrap();
+        // SAFETY: as above.
+        let len = unsafe { session.upload_mmap(&path, UploadOpts::default()) };
+        std::fs::remove_file(&path).unwrap();
+        // Only the rest of the file is sent.
+        assert_eq!(BLOCK_SIZE as u64 + 5, len.unwrap());
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn keep_open() {
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(200, "null")
+                .then(200, "null")
+                .then(200, "null")
+                .then(200, EMPTY_FILE_METADATA),
+        );
+        let session = UploadSession::resume(
+            client.clone(),
+            UploadResume {
+                session_id: "session".to_owned(),
+                start_offset: 0,
+            },
+        );
+        let events = session.events();
+        let opts = UploadOpts {
+            parallelism: 1,
+            blocks_per_request: 1,
+            keep_open: true,
+            ..Default::default()
+        };
+        let half = BLOCK_SIZE / 2;
+        let block = BLOCK_SIZE as u64;
+
+        // The half block at the end of each is held back until the next one.
+        let len = session
+            .upload(&vec![0u8; BLOCK_SIZE + half][..], opts.clone())
+            .unwrap();
+        assert_eq!(block, len);
+        let len = session.upload(&vec![0u8; BLOCK_SIZE][..], opts).unwrap();
+        assert_eq!(2 * block, len);
+        assert_eq!(2 * block + half as u64, session.close().unwrap());
+        session.commit(CommitOptions::new("/empty")).unwrap();
+
+        let events = events.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                UploadEvent::BlockStarted {
+                    offset: 0,
+                    len: block
+                },
+                UploadEvent::BlockCompleted {
+                    offset: 0,
+                    len: block
+                },
+                UploadEvent::BlockStarted {
+                    offset: block,
+                    len: block
+                },
+                UploadEvent::BlockCompleted {
+                    offset: block,
+                    len: block
+                },
+                UploadEvent::BlockStarted {
+                    offset: 2 * block,
+                    len: half as u64
+                },
+                UploadEvent::BlockCompleted {
+                    offset: 2 * block,
+                    len: half as u64
+                },
+                UploadEvent::SessionClosed {
+                    len: 2 * block + half as u64
+                },
+            ],
+            events[..events.len() - 1]
+        );
+        assert_eq!(4, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn insufficient_space() {
+        let client = Arc::new(MockClient::new("null").then(
+            200,
+            r#"{"used": 100, "allocation": {".tag": "individual", "allocated": 110}}"#,
+        ));
+        let source_path = std::env::temp_dir().join(format!("space_test_{}", std::process::id()));
+        std::fs::write(&source_path, [0u8; 20]).unwrap();
+        let opts = UploadFileOpts {
+            check_space: true,
+            ..Default::default()
+        };
+        let result = upload_file(client.clone(), &source_path, "/f", &opts);
+        std::fs::remove_file(&source_path).unwrap();
+        let Err(Error::Api(e)) = result else {
+            panic!("expected an API error");
+        };
+        assert_eq!(
+            Some(&InsufficientSpace {
+                needed: 20,
+                available: 10
+            }),
+            e.downcast_ref()
+        );
+        assert_eq!(1, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn operation_ids() {
+        let client = Arc::new(MockClient::new("null"));
+        let resume = UploadResume {
+            session_id: "session".to_owned(),
+            start_offset: 0,
+        };
+        let a = UploadSession::resume(client.clone(), resume.clone());
+        let b = UploadSession::resume(client, resume);
+        assert_eq!(16, a.operation_id().len());
+        assert_ne!(a.operation_id(), b.operation_id());
+    }
+
+    #[test]
+    fn clock_skew_clamp() {
+        let policy = ClockSkewPolicy::default();
+        let path = Path::new("x");
+        let ok = SystemTime::now() - Duration::from_secs(60);
+        assert_eq!(Some(ok), policy.client_modified(path, ok));
+        assert_eq!(
+            Some(policy.earliest),
+            policy.client_modified(path, SystemTime::UNIX_EPOCH)
+        );
+        let future = SystemTime::now() + Duration::from_secs(7 * 24 * 60 * 60);
+        assert!(policy.client_modified(path, future).unwrap() < future);
+
+        let policy = ClockSkewPolicy {
+            clamp: false,
+            ..Default::default()
+        };
+        assert_eq!(Some(future), policy.client_modified(path, future));
+    }
+
+    #[test]
+    fn completion_ranges() {
+        let mut tracker = CompletionTracker::default();
+        assert!(tracker.ranges().is_empty());
+        tracker.complete_block(30, 10);
+        tracker.complete_block(50, 10);
+        tracker.complete_block(40, 10);
+        tracker.complete_block(0, 10);
+        assert_eq!(vec![0..10, 30..60], tracker.ranges());
+        tracker.complete_block(10, 20);
+        assert_eq!(vec![0..60], tracker.ranges());
     }
 }
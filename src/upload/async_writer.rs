@@ -0,0 +1,319 @@
+//! Uploading data written through [`futures_io::AsyncWrite`].
+
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io::{self, Read};
+use std::mem;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use dropbox_sdk::files;
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+use futures_io::AsyncWrite;
+
+use super::{UploadOpts, UploadOutcome, UploadSession};
+use crate::BLOCK_SIZE;
+
+/// How many full blocks can be waiting for the background thread before writes have to wait.
+const MAX_QUEUED_BLOCKS: usize = 1;
+
+/// Like [`UploadWriter`](super::UploadWriter), but for async code: it implements
+/// [`AsyncWrite`], so that data from an async source, such as an HTTP request body, can be sent
+/// straight to Dropbox without writing it to disk first.
+///
+/// The Dropbox client is blocking, so the requests are made by a background thread, and writes
+/// return [`Poll::Pending`] while it is behind instead of blocking the executor.
+///
+/// Call [`AsyncUploadWriter::finish`] after writing everything to commit it. If the writer is
+/// dropped instead, everything written to it, including the data still buffered, is uploaded by
+/// the background thread but not committed. Dropping it doesn't wait for that.
+///
+/// If the upload fails, writes fail with [`io::ErrorKind::BrokenPipe`], and the error from the
+/// upload is returned by [`AsyncUploadWriter::finish`].
+pub struct AsyncUploadWriter<C: UserAuthClient + Send + Sync + 'static> {
+    session: Arc<UploadSession<C>>,
+    shared: Arc<Shared>,
+    buf: Vec<u8>,
+    commit: Sender<files::CommitInfo>,
+}
+
+/// State shared between the writer and the background thread.
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// Blocks written, waiting to be uploaded.
+    blocks: VecDeque<Vec<u8>>,
+
+    /// Whether the writer has been closed, so no more blocks will be added.
+    closed: bool,
+
+    /// The result of the upload and commit, or of the upload if it failed.
+    result: Option<Result<UploadOutcome, BoxedError>>,
+
+    /// The task waiting for room in `blocks`, or for `result`.
+    waker: Option<Waker>,
+}
+
+impl State {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<C: UserAuthClient + Send + Sync + 'static> AsyncUploadWriter<C> {
+    /// Write to the given upload session.
+    ///
+    /// The session has to be started beforehand, with [`UploadSession::new`], because that makes a
+    /// blocking request.
+    pub fn new(session: UploadSession<C>, opts: UploadOpts) -> Self {
+        let session = Arc::new(session);
+        let shared = Arc::new(Shared::default());
+        let (commit_tx, commit_rx) = mpsc::channel();
+        {
+            let session = Arc::clone(&session);
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                let source = BlockReader {
+                    shared: &shared,
+                    current: vec![],
+                    pos: 0,
+                };
+                let result = match session.upload(source, opts) {
+                    // If the writer is dropped without finishing, there's nothing to commit.
+                    Ok(_) => match commit_rx.recv() {
+                        Ok(commit_info) => session
+                            .commit(commit_info)
+                            .map(|metadata| session.outcome(metadata))
+                            .map_err(Error::boxed),
+                        Err(mpsc::RecvError) => return,
+                    },
+                    Err(e) => Err(e.into()),
+                };
+                let mut state = shared.state.lock().unwrap();
+                state.result = Some(result);
+                state.wake();
+            });
+        }
+        Self {
+            session,
+            shared,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            commit: commit_tx,
+        }
+    }
+
+    /// The upload session being written to, such as for getting its
+    /// [resume parameters](UploadSession::get_resume) if the upload fails.
+    pub fn session(&self) -> &UploadSession<C> {
+        &self.session
+    }
+
+    /// Finish uploading everything written so far, close the session, and commit it.
+    pub async fn finish(
+        mut self,
+        commit_info: impl Into<files::CommitInfo>,
+    ) -> Result<UploadOutcome, BoxedError> {
+        // This only fails if the upload has, in which case its error is returned below.
+        let _ = poll_fn(|cx| Pin::new(&mut self).poll_close(cx)).await;
+        // If the upload failed, the background thread is no longer listening.
+        let _ = self.commit.send(commit_info.into());
+        poll_fn(|cx| {
+            let mut state = self.shared.state.lock().unwrap();
+            match state.result.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Hand the buffered data to the background thread, if there's room for it.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.result.is_some() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "upload failed",
+            )));
+        }
+        if self.buf.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        if state.blocks.len() >= MAX_QUEUED_BLOCKS {
+            state.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        state
+            .blocks
+            .push_back(mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE)));
+        self.shared.cond.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: UserAuthClient + Send + Sync + 'static> AsyncWrite for AsyncUploadWriter<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buf.len() == BLOCK_SIZE {
+            if let Err(e) = std::task::ready!(this.poll_send(cx)) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        let n = buf.len().min(BLOCK_SIZE - this.buf.len());
+        this.buf.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    /// Hand the buffered data to the background thread. This doesn't wait for it to be uploaded.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send(cx)
+    }
+
+    /// Hand the buffered data to the background thread, and let it finish uploading. This doesn't
+    /// wait for it to be uploaded, or commit it; use [`AsyncUploadWriter::finish`] for that.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        std::task::ready!(this.poll_send(cx))?;
+        let mut state = this.shared.state.lock().unwrap();
+        state.closed = true;
+        this.shared.cond.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: UserAuthClient + Send + Sync + 'static> Drop for AsyncUploadWriter<C> {
+    fn drop(&mut self) {
+        // Let the upload finish instead of waiting for more data forever. The buffered data is
+        // queued even if that's more than MAX_QUEUED_BLOCKS, since there's no waiting for room.
+        let mut state = self.shared.state.lock().unwrap();
+        if !self.buf.is_empty() && state.result.is_none() {
+            state.blocks.push_back(mem::take(&mut self.buf));
+        }
+        state.closed = true;
+        self.shared.cond.notify_one();
+    }
+}
+
+/// The upload source for the background thread, which reads the blocks given to it by the writer.
+struct BlockReader<'a> {
+    shared: &'a Shared,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for BlockReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.current.len() {
+            let mut state = self.shared.state.lock().unwrap();
+            loop {
+                if let Some(block) = state.blocks.pop_front() {
+                    state.wake();
+                    self.current = block;
+                    self.pos = 0;
+                    break;
+                }
+                if state.closed {
+                    return Ok(0);
+                }
+                state = self.shared.cond.wait(state).unwrap();
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::{block_on, MockClient};
+    use crate::upload::CommitOptions;
+    use std::time::{Duration, Instant};
+
+    async fn write_all(writer: &mut AsyncUploadWriter<MockClient>, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = poll_fn(|cx| Pin::new(&mut *writer).poll_write(cx, data))
+                .await
+                .unwrap();
+            data = &data[n..];
+        }
+    }
+
+    #[test]
+    fn write_and_finish() {
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(200, r#"{"session_id": "session"}"#)
+                .then(200, "null")
+                .then(200, "null")
+                .then(
+                    200,
+                    r#"{
+                        "name": "out",
+                        "id": "id:out",
+                        "client_modified": "2024-01-01T00:00:00Z",
+                        "server_modified": "2024-01-01T00:00:00Z",
+                        "rev": "0123456789abcdef",
+                        "size": 4194309,
+                        "path_display": "/out"
+                    }"#,
+                ),
+        );
+        let session = UploadSession::new(client.clone()).unwrap();
+        let opts = UploadOpts {
+            parallelism: 1,
+            blocks_per_request: 1,
+            ..Default::default()
+        };
+        let outcome = block_on(async {
+            let mut writer = AsyncUploadWriter::new(session, opts);
+            write_all(&mut writer, &vec![0; BLOCK_SIZE]).await;
+            write_all(&mut writer, b"hello").await;
+            writer.finish(CommitOptions::new("/out")).await
+        })
+        .unwrap();
+        assert_eq!(BLOCK_SIZE as u64 + 5, outcome.metadata.size);
+        // Start, a whole block, the rest which closes the session, and finish.
+        let urls = client.urls.lock().unwrap();
+        assert_eq!(4, urls.len());
+        assert!(urls[3].ends_with("upload_session/finish"));
+    }
+
+    #[test]
+    fn drop_uploads_buffer() {
+        let client = Arc::new(MockClient::new("null").then(200, r#"{"session_id": "session"}"#));
+        let session = UploadSession::new(client.clone()).unwrap();
+        block_on(async {
+            let mut writer = AsyncUploadWriter::new(session, UploadOpts::default());
+            write_all(&mut writer, b"hello").await;
+        });
+        // The background thread sends the buffered data, but doesn't commit it.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while client.urls.lock().unwrap().len() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let urls = client.urls.lock().unwrap();
+        assert_eq!(2, urls.len());
+        assert!(urls[1].ends_with("upload_session/append_v2"));
+    }
+}
@@ -0,0 +1,249 @@
+//! Building [`UploadOpts`] with validation.
+
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{BufferPool, CheckpointHandler, ProgressHandler, UploadOpts};
+use crate::limits::{self, LimitExceeded};
+use crate::retry::RetryOpts;
+use crate::scheduler::{BandwidthSchedule, TransferScheduler};
+use crate::BLOCK_SIZE;
+
+/// An invalid combination of [`UploadOpts`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidUploadOpts {
+    /// [`parallelism`](UploadOpts::parallelism) was zero, so nothing would be uploaded.
+    ZeroParallelism,
+
+    /// [`blocks_per_request`](UploadOpts::blocks_per_request) was zero, so nothing would be
+    /// uploaded.
+    ZeroBlocksPerRequest,
+
+    /// [`blocks_per_request`](UploadOpts::blocks_per_request) makes requests larger than Dropbox
+    /// allows.
+    RequestTooLarge(LimitExceeded),
+
+    /// [`max_bytes_per_sec`](UploadOpts::max_bytes_per_sec) or
+    /// [`max_rpc_per_sec`](UploadOpts::max_rpc_per_sec) was zero, so nothing would be uploaded.
+    ZeroRate,
+
+    /// The retry options' initial backoff time is longer than their maximum backoff time.
+    BackoffOrder {
+        /// The initial backoff time given.
+        initial: Duration,
+        /// The maximum backoff time given.
+        max: Duration,
+    },
+}
+
+impl Display for InvalidUploadOpts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ZeroParallelism => f.write_str("parallelism must be at least 1"),
+            Self::ZeroBlocksPerRequest => f.write_str("blocks per request must be at least 1"),
+            Self::RequestTooLarge(e) => e.fmt(f),
+            Self::ZeroRate => f.write_str("rate limits must be at least 1 per second"),
+            Self::BackoffOrder { initial, max } => write!(
+                f,
+                "initial backoff time {initial:?} is longer than the maximum backoff time {max:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidUploadOpts {}
+
+impl UploadOpts {
+    /// Start building upload options, starting from the defaults, which are checked for invalid
+    /// combinations when [`build`](UploadOptsBuilder::build) is called.
+    pub fn builder() -> UploadOptsBuilder {
+        UploadOptsBuilder {
+            opts: Self::default(),
+        }
+    }
+
+    /// Check the options for invalid combinations.
+    pub fn validate(&self) -> Result<(), InvalidUploadOpts> {
+        if self.parallelism == 0 {
+            return Err(InvalidUploadOpts::ZeroParallelism);
+        }
+        if self.blocks_per_request == 0 {
+            return Err(InvalidUploadOpts::ZeroBlocksPerRequest);
+        }
+        limits::check(
+            "request size",
+            (BLOCK_SIZE * self.blocks_per_request) as u64,
+            limits::MAX_REQUEST_SIZE,
+        )
+        .map_err(InvalidUploadOpts::RequestTooLarge)?;
+        if self.max_bytes_per_sec == Some(0) || self.max_rpc_per_sec == Some(0) {
+            return Err(InvalidUploadOpts::ZeroRate);
+        }
+        if self.retry.initial_backoff_time > self.retry.max_backoff_time {
+            return Err(InvalidUploadOpts::BackoffOrder {
+                initial: self.retry.initial_backoff_time,
+                max: self.retry.max_backoff_time,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A builder for [`UploadOpts`], made with [`UploadOpts::builder`].
+///
+/// Each method sets the field of the same name; see [`UploadOpts`] for what they do.
+#[derive(Clone)]
+pub struct UploadOptsBuilder {
+    opts: UploadOpts,
+}
+
+impl UploadOptsBuilder {
+    /// Set [`UploadOpts::parallelism`].
+    pub fn parallelism(mut self, parallelism: usize) -> Self {
+        self.opts.parallelism = parallelism;
+        self
+    }
+
+    /// Set [`UploadOpts::blocks_per_request`].
+    pub fn blocks_per_request(mut self, blocks_per_request: usize) -> Self {
+        self.opts.blocks_per_request = blocks_per_request;
+        self
+    }
+
+    /// Set [`UploadOpts::auto_tune`].
+    pub fn auto_tune(mut self, auto_tune: bool) -> Self {
+        self.opts.auto_tune = auto_tune;
+        self
+    }
+
+    /// Set [`UploadOpts::restart_on_session_loss`].
+    pub fn restart_on_session_loss(mut self, restarts: u32) -> Self {
+        self.opts.restart_on_session_loss = restarts;
+        self
+    }
+
+    /// Set [`UploadOpts::hash_mismatch_retries`].
+    pub fn hash_mismatch_retries(mut self, retries: u32) -> Self {
+        self.opts.hash_mismatch_retries = retries;
+        self
+    }
+
+    /// Set [`UploadOpts::max_bytes_per_sec`].
+    pub fn max_bytes_per_sec(mut self, max_bytes_per_sec: u64) -> Self {
+        self.opts.max_bytes_per_sec = Some(max_bytes_per_sec);
+        self
+    }
+
+    /// Set [`UploadOpts::max_rpc_per_sec`].
+    pub fn max_rpc_per_sec(mut self, max_rpc_per_sec: u64) -> Self {
+        self.opts.max_rpc_per_sec = Some(max_rpc_per_sec);
+        self
+    }
+
+    /// Set [`UploadOpts::bandwidth_schedule`].
+    pub fn bandwidth_schedule(mut self, schedule: Arc<BandwidthSchedule>) -> Self {
+        self.opts.bandwidth_schedule = Some(schedule);
+        self
+    }
+
+    /// Set [`UploadOpts::max_buffered_bytes`].
+    pub fn max_buffered_bytes(mut self, max_buffered_bytes: usize) -> Self {
+        self.opts.max_buffered_bytes = Some(max_buffered_bytes);
+        self
+    }
+
+    /// Set [`UploadOpts::buffer_pool`].
+    pub fn buffer_pool(mut self, pool: Arc<BufferPool>) -> Self {
+        self.opts.buffer_pool = Some(pool);
+        self
+    }
+
+    /// Set [`UploadOpts::retry`].
+    pub fn retry(mut self, retry: RetryOpts) -> Self {
+        self.opts.retry = retry;
+        self
+    }
+
+    /// Set [`UploadOpts::total_bytes`].
+    pub fn total_bytes(mut self, total_bytes: u64) -> Self {
+        self.opts.total_bytes = Some(total_bytes);
+        self
+    }
+
+    /// Set [`UploadOpts::progress_handler`].
+    pub fn progress_handler(mut self, handler: impl ProgressHandler + 'static) -> Self {
+        self.opts.progress_handler = Some(Arc::new(Box::new(handler)));
+        self
+    }
+
+    /// Set [`UploadOpts::checkpoint_handler`].
+    pub fn checkpoint_handler(mut self, handler: impl CheckpointHandler + 'static) -> Self {
+        self.opts.checkpoint_handler = Some(Arc::new(Box::new(handler)));
+        self
+    }
+
+    /// Set [`UploadOpts::keep_open`].
+    pub fn keep_open(mut self, keep_open: bool) -> Self {
+        self.opts.keep_open = keep_open;
+        self
+    }
+
+    /// Set [`UploadOpts::deterministic`].
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.opts.deterministic = deterministic;
+        self
+    }
+
+    /// Set [`UploadOpts::scheduler`].
+    pub fn scheduler(mut self, scheduler: Arc<TransferScheduler>) -> Self {
+        self.opts.scheduler = Some(scheduler);
+        self
+    }
+
+    /// Check the options, and return them if they are valid.
+    pub fn build(self) -> Result<UploadOpts, InvalidUploadOpts> {
+        self.opts.validate()?;
+        Ok(self.opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        assert!(UploadOpts::builder().build().is_ok());
+        assert_eq!(
+            Some(InvalidUploadOpts::ZeroParallelism),
+            UploadOpts::builder().parallelism(0).build().err()
+        );
+        assert_eq!(
+            Some(InvalidUploadOpts::ZeroBlocksPerRequest),
+            UploadOpts::builder().blocks_per_request(0).build().err()
+        );
+        assert!(UploadOpts::builder().blocks_per_request(37).build().is_ok());
+        assert!(matches!(
+            UploadOpts::builder().blocks_per_request(38).build(),
+            Err(InvalidUploadOpts::RequestTooLarge(_))
+        ));
+        assert_eq!(
+            Some(InvalidUploadOpts::ZeroRate),
+            UploadOpts::builder().max_bytes_per_sec(0).build().err()
+        );
+        assert_eq!(
+            Some(InvalidUploadOpts::ZeroRate),
+            UploadOpts::builder().max_rpc_per_sec(0).build().err()
+        );
+        let retry = RetryOpts {
+            initial_backoff_time: Duration::from_secs(3),
+            max_backoff_time: Duration::from_secs(2),
+            ..Default::default()
+        };
+        assert!(matches!(
+            UploadOpts::builder().retry(retry).build(),
+            Err(InvalidUploadOpts::BackoffOrder { .. })
+        ));
+    }
+}
@@ -0,0 +1,123 @@
+//! Uploading the output of another program.
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+
+use dropbox_sdk::files;
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use super::{UploadOpts, UploadOutcome, UploadSession};
+
+/// The program whose output was being uploaded by [`upload_command`] or [`upload_child`] exited
+/// unsuccessfully, so its output was not committed.
+#[derive(Debug, Clone)]
+pub struct CommandFailed {
+    /// The exit status of the program.
+    pub status: ExitStatus,
+}
+
+impl Display for CommandFailed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "command failed: {}", self.status)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// Run a command, upload everything it writes to its standard output, and commit it once the
+/// command exits successfully, such as to back up a database dump without writing it to a local
+/// file first.
+///
+/// The command's standard output is replaced by a pipe; its standard input and error are left as
+/// configured.
+///
+/// If the command exits unsuccessfully, this returns a [`CommandFailed`] error, and the data is not
+/// committed.
+///
+/// This blocks the current thread until the command has exited and its output has been committed,
+/// or an error occurs.
+pub fn upload_command<C: UserAuthClient + Send + Sync + 'static>(
+    client: Arc<C>,
+    command: &mut Command,
+    commit_info: impl Into<files::CommitInfo>,
+    opts: &UploadOpts,
+) -> Result<UploadOutcome, BoxedError> {
+    let child = command
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::HttpClient(e.into()))?;
+    upload_child(client, child, commit_info, opts)
+}
+
+/// Like [`upload_command`], but for a program which has already been started, with its standard
+/// output [piped](Stdio::piped).
+///
+/// If the upload fails, the program is killed.
+pub fn upload_child<C: UserAuthClient + Send + Sync + 'static>(
+    client: Arc<C>,
+    mut child: Child,
+    commit_info: impl Into<files::CommitInfo>,
+    opts: &UploadOpts,
+) -> Result<UploadOutcome, BoxedError> {
+    let Some(stdout) = child.stdout.take() else {
+        return Err(Error::HttpClient(
+            io::Error::new(io::ErrorKind::InvalidInput, "child's stdout is not piped").into(),
+        ));
+    };
+
+    let result = UploadSession::new(client)
+        .map_err(Error::boxed)
+        .and_then(|session| {
+            session
+                .upload(stdout, opts.clone())
+                .map(|_| session)
+                .map_err(BoxedError::from)
+        });
+    let session = match result {
+        Ok(session) => session,
+        Err(e) => {
+            if let Err(kill_err) = child.kill().and_then(|()| child.wait()) {
+                warn!("failed to kill child process {}: {kill_err}", child.id());
+            }
+            return Err(e);
+        }
+    };
+
+    let status = child.wait().map_err(|e| Error::HttpClient(e.into()))?;
+    if !status.success() {
+        error!(
+            "[{}] command failed ({status}); not committing",
+            session.operation_id()
+        );
+        return Err(Error::Api(Box::new(CommandFailed { status })));
+    }
+    let metadata = session.commit_verified(commit_info)?;
+    Ok(session.outcome(metadata))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+    use crate::upload::CommitOptions;
+
+    #[test]
+    fn failed_command_is_not_committed() {
+        let client = Arc::new(MockClient::new("null").then(200, r#"{"session_id": "session"}"#));
+        let result = upload_command(
+            client.clone(),
+            &mut Command::new("false"),
+            CommitOptions::new("/out"),
+            &UploadOpts::default(),
+        );
+        let Err(Error::Api(e)) = result else {
+            panic!("wrong result");
+        };
+        assert!(!e.downcast_ref::<CommandFailed>().unwrap().status.success());
+        // Start and close, but no finish.
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,519 @@
+//! Uploading whole directories.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use dropbox_sdk::files::{self, RelocationError, WriteError};
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use super::ignore::IgnorePatterns;
+#[cfg(feature = "list")]
+use super::IfExists;
+use super::{
+    check_space, upload_file_throttled, BufferPool, UploadAction, UploadFileOpts, UploadOutcome,
+};
+use crate::content_hash::ContentHash;
+use crate::limits::EndpointKind;
+use crate::throttle::Throttles;
+
+/// Options for uploading a local directory with [`upload_dir`].
+#[derive(Clone, Default)]
+pub struct UploadDirOpts {
+    /// Options for uploading each file.
+    pub file: UploadFileOpts,
+
+    /// Look for files with identical contents, and only upload one of them; the others are then
+    /// copied from it on the server.
+    ///
+    /// This saves bandwidth when there are many duplicated files, at the cost of having to read
+    /// each file an extra time to calculate its Content Hash before uploading anything.
+    pub dedup: bool,
+
+    /// Patterns for files and directories to skip, relative to the directory being uploaded.
+    pub ignore: IgnorePatterns,
+
+    /// The name of files, such as `.dropboxignore`, to read more ignore patterns from. Patterns in
+    /// one of these apply to the directory it is in and its subdirectories, relative to that
+    /// directory, and take precedence over those from [`ignore`](Self::ignore) and from ignore
+    /// files in parent directories. The ignore files themselves are uploaded unless they match a
+    /// pattern.
+    pub ignore_file: Option<String>,
+}
+
+/// A file found by [`upload_dir`] and what happened to it.
+#[derive(Debug, Clone)]
+pub struct UploadDirEntry {
+    /// The path of the local file.
+    pub source_path: PathBuf,
+
+    /// The Dropbox path it was uploaded to.
+    pub dest_path: String,
+
+    /// What happened to it.
+    pub outcome: UploadOutcome,
+}
+
+/// Upload all the files in a local directory and its subdirectories to the given path in Dropbox.
+///
+/// Files are uploaded one at a time, each using the parallelism configured in the options. Symbolic
+/// links, and anything matching the [ignore patterns](UploadDirOpts::ignore), are skipped. This
+/// stops at the first error.
+///
+/// If [`check_space`](UploadFileOpts::check_space) is set, the space needed for all the files is
+/// checked before uploading any of them.
+///
+/// The files share a [`BufferPool`](super::BufferPool), unless one is given in the options, and
+/// the limit on RPC requests given by [`max_rpc_per_sec`](super::UploadOpts::max_rpc_per_sec).
+///
+/// To see what this would do without uploading anything, use `plan_upload_dir`.
+pub fn upload_dir<C: UserAuthClient + Send + Sync + 'static>(
+    client: Arc<C>,
+    source_dir: &Path,
+    dest_dir: &str,
+    opts: &UploadDirOpts,
+) -> Result<Vec<UploadDirEntry>, BoxedError> {
+    let sources = local_files(
+        source_dir,
+        dest_dir,
+        &opts.ignore,
+        opts.ignore_file.as_deref(),
+    )
+    .map_err(|e| Error::HttpClient(e.into()))?;
+
+    let mut file_opts = opts.file.clone();
+    let upload_opts = &mut file_opts.upload;
+    upload_opts
+        .buffer_pool
+        .get_or_insert_with(|| Arc::new(BufferPool::new(upload_opts.parallelism * 2 + 1)));
+    let rpc_throttle = Throttles::new(None, upload_opts.max_rpc_per_sec);
+
+    if file_opts.check_space {
+        // Check for all the files at once, instead of each one.
+        let total = sources
+            .iter()
+            .map(|(path, _)| fs::metadata(path).map(|meta| meta.len()))
+            .sum::<io::Result<u64>>()
+            .map_err(|e| Error::HttpClient(e.into()))?;
+        rpc_throttle.acquire(EndpointKind::Rpc, 0);
+        check_space(client.as_ref(), total)?;
+        file_opts.check_space = false;
+    }
+
+    // Content Hash -> Dropbox path it was uploaded to.
+    let mut uploaded = HashMap::<String, String>::new();
+
+    let mut results = vec![];
+    for (source_path, dest_path) in sources {
+        let start_time = Instant::now();
+        let hash = if opts.dedup {
+            let mut hash = ContentHash::new();
+            File::open(&source_path)
+                .and_then(|f| hash.read_stream(f))
+                .map_err(|e| Error::HttpClient(e.into()))?;
+            Some(hash.finish_hex())
+        } else {
+            None
+        };
+
+        let copied = match hash.as_ref().and_then(|h| uploaded.get(h)) {
+            Some(from_path) => copy_file(client.as_ref(), from_path, &dest_path, &rpc_throttle)?,
+            None => None,
+        };
+
+        let outcome = match copied {
+            Some(metadata) => UploadOutcome::without_upload(
+                UploadAction::Copied,
+                metadata,
+                hash.clone(),
+                start_time.elapsed(),
+            ),
+            None => upload_file_throttled(
+                client.clone(),
+                &source_path,
+                &dest_path,
+                &file_opts,
+                &rpc_throttle,
+            )?,
+        };
+
+        if let Some(hash) = hash {
+            uploaded.entry(hash).or_insert_with(|| dest_path.clone());
+        }
+
+        results.push(UploadDirEntry {
+            source_path,
+            dest_path,
+            outcome,
+        });
+    }
+    Ok(results)
+}
+
+/// What [`upload_dir`] would do with a file, from [`plan_upload_dir`].
+#[cfg(feature = "list")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PlanAction {
+    /// There is nothing at the destination path, so the file would be uploaded.
+    Create,
+
+    /// There is a different file at the destination path (or an identical one, unless
+    /// [`IfExists::SkipIfIdentical`](super::IfExists::SkipIfIdentical) is set), which would be
+    /// replaced.
+    Update,
+
+    /// There is an identical file at the destination path, so the file would be skipped.
+    Skip,
+}
+
+/// A file found by [`plan_upload_dir`] and what would be done with it.
+#[cfg(feature = "list")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedFile {
+    /// The path of the local file.
+    pub source_path: PathBuf,
+
+    /// The Dropbox path it would be uploaded to.
+    pub dest_path: String,
+
+    /// What would be done with it.
+    pub action: PlanAction,
+
+    /// The size of the local file.
+    pub bytes: u64,
+}
+
+/// What [`upload_dir`] would do, from [`plan_upload_dir`].
+#[cfg(feature = "list")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UploadPlan {
+    /// The files, in the order they would be uploaded.
+    pub files: Vec<PlannedFile>,
+}
+
+#[cfg(feature = "list")]
+impl UploadPlan {
+    /// How many files would have the given action.
+    pub fn count(&self, action: PlanAction) -> usize {
+        self.files.iter().filter(|f| f.action == action).count()
+    }
+
+    /// The total size of the files which would have the given action.
+    pub fn bytes(&self, action: PlanAction) -> u64 {
+        self.files
+            .iter()
+            .filter(|f| f.action == action)
+            .map(|f| f.bytes)
+            .sum()
+    }
+
+    /// The total size of the files which would be uploaded.
+    pub fn bytes_to_upload(&self) -> u64 {
+        self.bytes(PlanAction::Create) + self.bytes(PlanAction::Update)
+    }
+}
+
+/// Work out what [`upload_dir`] would do with the same arguments, without uploading anything.
+///
+/// This lists the destination folder recursively and compares it with the local files. Local
+/// files which are the same size as the remote file at their destination are read to compare
+/// their Content Hashes. Dropbox paths are compared case-insensitively.
+///
+/// Deduplication isn't planned for: files which [`dedup`](UploadDirOpts::dedup) would copy are
+/// reported as being uploaded.
+#[cfg(feature = "list")]
+pub fn plan_upload_dir(
+    client: &impl UserAuthClient,
+    source_dir: &Path,
+    dest_dir: &str,
+    opts: &UploadDirOpts,
+) -> Result<UploadPlan, BoxedError> {
+    let sources = local_files(
+        source_dir,
+        dest_dir,
+        &opts.ignore,
+        opts.ignore_file.as_deref(),
+    )
+    .map_err(|e| Error::HttpClient(e.into()))?;
+
+    // Lowercased path -> remote file.
+    let mut remote = HashMap::<String, files::FileMetadata>::new();
+    let list_path = match dest_dir.trim_end_matches('/') {
+        "" => "/",
+        path => path,
+    };
+    match crate::list::list_directory(client, list_path, true) {
+        Ok(iter) => {
+            for file in iter.files() {
+                let file = file.map_err(Error::boxed)?;
+                if let Some(path) = file.path_lower.clone() {
+                    remote.insert(path, file);
+                }
+            }
+        }
+        Err(Error::Api(files::ListFolderError::Path(files::LookupError::NotFound))) => {
+            debug!("{dest_dir} doesn't exist yet");
+        }
+        Err(e) => return Err(crate::path_root::boxed(e)),
+    }
+
+    let mut plan = UploadPlan::default();
+    for (source_path, dest_path) in sources {
+        let bytes = fs::metadata(&source_path)
+            .map_err(|e| Error::HttpClient(e.into()))?
+            .len();
+        let action = match remote.get(&dest_path.to_lowercase()) {
+            None => PlanAction::Create,
+            Some(existing)
+                if opts.file.if_exists == IfExists::SkipIfIdentical && existing.size == bytes =>
+            {
+                let mut hash = ContentHash::new();
+                File::open(&source_path)
+                    .and_then(|f| hash.read_stream(f))
+                    .map_err(|e| Error::HttpClient(e.into()))?;
+                if existing.content_hash.as_deref() == Some(hash.finish_hex().as_str()) {
+                    PlanAction::Skip
+                } else {
+                    PlanAction::Update
+                }
+            }
+            Some(_) => PlanAction::Update,
+        };
+        plan.files.push(PlannedFile {
+            source_path,
+            dest_path,
+            action,
+            bytes,
+        });
+    }
+    Ok(plan)
+}
+
+/// Copy a file on the server. If something is already present at the destination, return `None`
+/// and leave it up to the caller to upload it instead.
+fn copy_file(
+    client: &impl UserAuthClient,
+    from_path: &str,
+    to_path: &str,
+    rpc_throttle: &Throttles,
+) -> Result<Option<files::FileMetadata>, BoxedError> {
+    rpc_throttle.acquire(EndpointKind::Rpc, 0);
+    match files::copy_v2(
+        client,
+        &files::RelocationArg::new(from_path.to_owned(), to_path.to_owned()),
+    ) {
+        Ok(result) => match result.metadata {
+            files::Metadata::File(metadata) => {
+                info!("Copied {from_path} to {to_path}");
+                Ok(Some(metadata))
+            }
+            other => Err(Error::UnexpectedResponse(format!(
+                "copy produced something other than a file: {other:?}"
+            ))),
+        },
+        Err(Error::Api(RelocationError::To(WriteError::Conflict(_)))) => Ok(None),
+        Err(e) => Err(e.boxed()),
+    }
+}
+
+/// Find all the files under the given local directory which aren't ignored, and the Dropbox paths
+/// they should be uploaded to, in a consistent order.
+fn local_files(
+    source_dir: &Path,
+    dest_dir: &str,
+    ignore: &IgnorePatterns,
+    ignore_file: Option<&str>,
+) -> io::Result<Vec<(PathBuf, String)>> {
+    let mut files = vec![];
+    // Each directory has the pattern sets which apply to it, with the path relative to the source
+    // directory that each one's patterns are relative to, outermost first.
+    let mut dirs = vec![(
+        source_dir.to_owned(),
+        dest_dir.trim_end_matches('/').to_owned(),
+        String::new(),
+        Arc::new(vec![(String::new(), ignore.clone())]),
+    )];
+    while let Some((dir, dest, rel, mut patterns)) = dirs.pop() {
+        if let Some(name) = ignore_file {
+            match fs::read_to_string(dir.join(name)) {
+                Ok(text) => {
+                    debug!("Using ignore patterns from {:?}", dir.join(name));
+                    Arc::make_mut(&mut patterns).push((rel.clone(), IgnorePatterns::parse(&text)));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+        let mut entries = fs::read_dir(&dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().into_string().map_err(|name| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("file name {name:?} is not valid UTF-8"),
+                )
+            })?;
+            let dest_path = format!("{dest}/{name}");
+            let rel_path = if rel.is_empty() {
+                name
+            } else {
+                format!("{rel}/{name}")
+            };
+            let file_type = entry.file_type()?;
+            if is_ignored(&patterns, &rel_path, file_type.is_dir()) {
+                debug!("Skipping {:?}: ignored", entry.path());
+            } else if file_type.is_dir() {
+                dirs.push((entry.path(), dest_path, rel_path, patterns.clone()));
+            } else if file_type.is_file() {
+                files.push((entry.path(), dest_path));
+            } else {
+                warn!("Skipping {:?}: not a regular file", entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Whether the last pattern to match a path, from the innermost set with one, excludes it.
+fn is_ignored(patterns: &[(String, IgnorePatterns)], rel_path: &str, is_dir: bool) -> bool {
+    patterns
+        .iter()
+        .rev()
+        .find_map(|(base, patterns)| {
+            let path = if base.is_empty() {
+                rel_path
+            } else {
+                rel_path.strip_prefix(base.as_str())?.strip_prefix('/')?
+            };
+            patterns.matches(path, is_dir)
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_files_maps_paths() {
+        let root = std::env::temp_dir().join(format!("upload_dir_test_{}", std::process::id()));
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a.txt"), b"a").unwrap();
+        fs::write(root.join("sub/b.txt"), b"b").unwrap();
+
+        let files = local_files(&root, "/dest/", &IgnorePatterns::new(), None).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            vec![
+                (root.join("a.txt"), "/dest/a.txt".to_owned()),
+                (root.join("sub").join("b.txt"), "/dest/sub/b.txt".to_owned()),
+            ],
+            files
+        );
+    }
+
+    #[test]
+    fn local_files_ignores() {
+        let root = std::env::temp_dir().join(format!("upload_dir_ignore_{}", std::process::id()));
+        fs::create_dir_all(root.join("node_modules/pkg")).unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("node_modules/pkg/index.js"), b"x").unwrap();
+        fs::write(root.join("src/main.rs"), b"x").unwrap();
+        fs::write(root.join("src/main.o"), b"x").unwrap();
+        fs::write(root.join("src/keep.o"), b"x").unwrap();
+        fs::write(
+            root.join("src/.dropboxignore"),
+            b"!keep.o\n.dropboxignore\n",
+        )
+        .unwrap();
+
+        let ignore = IgnorePatterns::parse("node_modules/\n*.o\n");
+        let files = local_files(&root, "/dest", &ignore, Some(".dropboxignore")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            vec!["/dest/src/keep.o", "/dest/src/main.rs"],
+            files
+                .iter()
+                .map(|(_, dest)| dest.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(feature = "list")]
+    #[test]
+    fn plan() {
+        use crate::testing::tests::MockClient;
+
+        let root = std::env::temp_dir().join(format!("upload_dir_plan_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("new.txt"), b"new").unwrap();
+        fs::write(root.join("same.txt"), b"same").unwrap();
+        fs::write(root.join("changed.txt"), b"changed").unwrap();
+
+        let mut hash = ContentHash::new();
+        hash.update(b"same");
+        let listing = format!(
+            r#"{{"entries": [
+                {{".tag": "file", "name": "same.txt", "id": "id:1", "size": 4, "rev": "111111111",
+                 "client_modified": "2024-01-01T00:00:00Z", "server_modified": "2024-01-01T00:00:00Z",
+                 "path_lower": "/dest/same.txt", "content_hash": "{}"}},
+                {{".tag": "file", "name": "Changed.txt", "id": "id:2", "size": 7, "rev": "222222222",
+                 "client_modified": "2024-01-01T00:00:00Z", "server_modified": "2024-01-01T00:00:00Z",
+                 "path_lower": "/dest/changed.txt", "content_hash": "00"}}
+            ], "cursor": "cursor", "has_more": false}}"#,
+            hash.finish_hex()
+        );
+        let client = MockClient::new(String::leak(listing));
+        let opts = UploadDirOpts {
+            file: UploadFileOpts {
+                if_exists: IfExists::SkipIfIdentical,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let plan = plan_upload_dir(&client, &root, "/dest", &opts).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            vec![
+                ("/dest/changed.txt", PlanAction::Update),
+                ("/dest/new.txt", PlanAction::Create),
+                ("/dest/same.txt", PlanAction::Skip),
+            ],
+            plan.files
+                .iter()
+                .map(|f| (f.dest_path.as_str(), f.action))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(10, plan.bytes_to_upload());
+        assert_eq!(1, client.urls.lock().unwrap().len());
+    }
+
+    #[cfg(feature = "list")]
+    #[test]
+    fn plan_into_root() {
+        use crate::testing::tests::MockClient;
+
+        let root = std::env::temp_dir().join(format!("upload_dir_root_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.txt"), b"a").unwrap();
+
+        let client = MockClient::new(r#"{"entries": [], "cursor": "cursor", "has_more": false}"#);
+        let plan = plan_upload_dir(&client, &root, "/", &UploadDirOpts::default()).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            vec![("/a.txt", PlanAction::Create)],
+            plan.files
+                .iter()
+                .map(|f| (f.dest_path.as_str(), f.action))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Gitignore-style patterns for skipping files when uploading directories.
+
+use crate::glob;
+
+/// A list of patterns for files to leave out of a directory upload, in the style of a
+/// `.gitignore` file.
+///
+/// Each pattern is matched against paths relative to the directory being uploaded (or the one
+/// containing the ignore file), using `/` as the separator:
+///
+/// - `*` matches anything except `/`, and `?` matches any one character except `/`.
+/// - `**` as a whole path component matches any number of components, including none.
+/// - A pattern with no `/` except at the end matches a name at any depth, like `node_modules` or
+///   `*.log`. Otherwise, it matches from the top, like `build/output` or `/target`.
+/// - A pattern ending in `/` only matches directories.
+/// - A pattern starting with `!` includes files which an earlier pattern excluded. The last
+///   matching pattern wins. Files in an excluded directory can't be included again, because the
+///   directory isn't looked in.
+/// - Blank lines and lines starting with `#` are ignored.
+///
+/// ```
+/// # use dropbox_toolbox::upload::IgnorePatterns;
+/// let patterns = IgnorePatterns::parse("node_modules/\n*.log\n!keep.log\n");
+/// assert!(patterns.is_ignored("web/node_modules", true));
+/// assert!(patterns.is_ignored("logs/today.log", false));
+/// assert!(!patterns.is_ignored("logs/keep.log", false));
+/// assert!(!patterns.is_ignored("src/main.rs", false));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IgnorePatterns {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    /// The path components of the pattern. If the pattern isn't anchored, this starts with `**`.
+    components: Vec<String>,
+    include: bool,
+    dir_only: bool,
+}
+
+impl IgnorePatterns {
+    /// Make an empty list, which ignores nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the contents of an ignore file, with one pattern per line.
+    pub fn parse(text: &str) -> Self {
+        let mut patterns = Self::new();
+        for line in text.lines() {
+            patterns.add(line);
+        }
+        patterns
+    }
+
+    /// Add a pattern, which takes precedence over those added before it.
+    pub fn add(&mut self, pattern: &str) {
+        let pattern = pattern.trim_end();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return;
+        }
+        let (include, pattern) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let anchored = pattern.contains('/');
+        let mut components = vec![];
+        if !anchored {
+            components.push("**".to_owned());
+        }
+        components.extend(glob::components(pattern).into_iter().map(str::to_owned));
+        self.rules.push(Rule {
+            components,
+            include,
+            dir_only,
+        });
+    }
+
+    /// Whether there are no patterns.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether the given relative path should be left out.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        self.matches(path, is_dir).unwrap_or(false)
+    }
+
+    /// Whether the last pattern which matches the path excludes it (`Some(true)`) or includes it
+    /// (`Some(false)`), or `None` if none match.
+    pub(crate) fn matches(&self, path: &str, is_dir: bool) -> Option<bool> {
+        let components = glob::components(path);
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                (is_dir || !rule.dir_only) && glob::match_path(&rule.components, &components)
+            })
+            .map(|rule| !rule.include)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anchoring() {
+        let patterns = IgnorePatterns::parse("/target\nbuild/out\n");
+        assert!(patterns.is_ignored("target", true));
+        assert!(!patterns.is_ignored("sub/target", true));
+        assert!(patterns.is_ignored("build/out", false));
+        assert!(!patterns.is_ignored("sub/build/out", false));
+    }
+
+    #[test]
+    fn wildcards() {
+        let patterns = IgnorePatterns::parse("# comment\n\ncache/**/*.tmp\nfile?.txt\n");
+        assert!(patterns.is_ignored("cache/a.tmp", false));
+        assert!(patterns.is_ignored("cache/x/y/a.tmp", false));
+        assert!(!patterns.is_ignored("other/a.tmp", false));
+        assert!(patterns.is_ignored("deep/file1.txt", false));
+        assert!(!patterns.is_ignored("deep/file10.txt", false));
+    }
+
+    #[test]
+    fn dir_only() {
+        let patterns = IgnorePatterns::parse("logs/\n");
+        assert!(patterns.is_ignored("logs", true));
+        assert!(!patterns.is_ignored("logs", false));
+    }
+}
@@ -0,0 +1,218 @@
+//! Uploading through temporary upload links, so that a process which has no Dropbox credentials
+//! can upload a file.
+
+use std::io::Read;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use dropbox_sdk::client_trait::{HttpClient, HttpRequest};
+use dropbox_sdk::files;
+use dropbox_sdk::{BoxedError, Error, UserAuthClient};
+
+use super::{new_operation_id, Progress, ProgressHandler};
+use crate::limits::{self, EndpointKind};
+use crate::retry::{Backoff, RetryOpts};
+use crate::BLOCK_SIZE;
+
+/// A link which one file can be uploaded to without authentication, from
+/// [`temporary_upload_link`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadLink {
+    /// The path the file will be committed to.
+    pub path: String,
+
+    /// The URL to upload the file to, with [`upload_to_link`].
+    pub url: String,
+
+    /// When the link stops working. This is estimated from when it was requested.
+    pub expires: SystemTime,
+}
+
+/// Get a link which a file can be uploaded to once, without authentication, so that a worker
+/// process can be given it instead of credentials.
+///
+/// The file is committed as described by the commit info when it is uploaded. The link works for
+/// the given length of time, which must be between
+/// [`MIN_UPLOAD_LINK_LIFETIME`](limits::MIN_UPLOAD_LINK_LIFETIME) and
+/// [`MAX_UPLOAD_LINK_LIFETIME`](limits::MAX_UPLOAD_LINK_LIFETIME); otherwise this fails with
+/// [`Error::BadRequest`], like Dropbox would, without making a request.
+pub fn temporary_upload_link(
+    client: &impl UserAuthClient,
+    commit_info: impl Into<files::CommitInfo>,
+    lifetime: Duration,
+    retry: &RetryOpts,
+) -> Result<UploadLink, Error> {
+    if !(limits::MIN_UPLOAD_LINK_LIFETIME..=limits::MAX_UPLOAD_LINK_LIFETIME).contains(&lifetime) {
+        return Err(Error::BadRequest(format!(
+            "upload link lifetime of {lifetime:?} is outside the allowed range of {:?} to {:?}",
+            limits::MIN_UPLOAD_LINK_LIFETIME,
+            limits::MAX_UPLOAD_LINK_LIFETIME,
+        )));
+    }
+    let arg = files::GetTemporaryUploadLinkArg::new(commit_info.into())
+        .with_duration(lifetime.as_secs_f64());
+    let mut backoff = Backoff::new(retry).for_endpoint(EndpointKind::Rpc);
+    loop {
+        backoff.wait();
+        let requested = SystemTime::now();
+        match files::get_temporary_upload_link(client, &arg) {
+            Ok(result) => {
+                return Ok(UploadLink {
+                    path: arg.commit_info.path,
+                    url: result.link,
+                    expires: requested + lifetime,
+                })
+            }
+            Err(e @ Error::Api(_)) => return Err(e),
+            Err(e) => backoff.handle("getting temporary upload link", e)?,
+        }
+    }
+}
+
+/// Options for [`upload_to_link`].
+#[derive(Clone, Default)]
+pub struct UploadLinkOpts {
+    /// How to retry failed requests.
+    pub retry: RetryOpts,
+
+    /// An optional callback to receive progress once the data has been uploaded.
+    pub progress_handler: Option<Arc<Box<dyn ProgressHandler>>>,
+}
+
+/// Upload data to a link from [`temporary_upload_link`], which commits it as a file.
+///
+/// This takes a client with no authentication, such as
+/// [`NoauthDefaultClient`](dropbox_sdk::default_client::NoauthDefaultClient), since the link
+/// itself grants access.
+///
+/// The data is sent in a single request, so it can be at most
+/// [`MAX_REQUEST_SIZE`](limits::MAX_REQUEST_SIZE) bytes; larger data fails with a
+/// [`LimitExceeded`](limits::LimitExceeded) error before sending anything.
+///
+/// Requests which fail without a response from Dropbox, or with a server error, are retried.
+/// Other errors, such as the link having expired or already been used, are returned as
+/// [`Error::UnexpectedHttpError`].
+pub fn upload_to_link(
+    client: &impl HttpClient,
+    url: &str,
+    data: &[u8],
+    opts: &UploadLinkOpts,
+) -> Result<(), BoxedError> {
+    limits::check("request size", data.len() as u64, limits::MAX_REQUEST_SIZE)
+        .map_err(|e| Error::Api(Box::new(e) as _))?;
+    let operation_id = new_operation_id();
+    let start_time = Instant::now();
+    let mut backoff = Backoff::new(&opts.retry)
+        .with_operation_id(&operation_id)
+        .for_endpoint(EndpointKind::Content);
+    let mut retries = 0;
+    loop {
+        backoff.wait();
+        let request = client
+            .new_request(url)
+            .set_header("Content-Type", "application/octet-stream");
+        match client.execute(request, data).and_then(check_status) {
+            Ok(()) => break,
+            Err(e @ Error::UnexpectedHttpError { .. }) => {
+                error!("[{operation_id}] Error uploading to temporary link: {e}, failing.");
+                return Err(e.boxed());
+            }
+            Err(e) => {
+                retries += 1;
+                backoff
+                    .handle("uploading to temporary link", e)
+                    .map_err(Error::boxed)?;
+            }
+        }
+    }
+
+    if let Some(handler) = &opts.progress_handler {
+        let len = data.len() as u64;
+        let rate = len as f64 / start_time.elapsed().as_secs_f64();
+        handler.progress(&Progress {
+            operation_id,
+            start_offset: 0,
+            bytes_uploaded: len,
+            total_bytes: Some(len),
+            blocks_completed: len.div_ceil(BLOCK_SIZE as u64),
+            total_blocks: Some(len.div_ceil(BLOCK_SIZE as u64)),
+            retries,
+            instant_rate: rate,
+            overall_rate: rate,
+        });
+    }
+    Ok(())
+}
+
+/// Turn the response to an upload into an error if it wasn't successful.
+fn check_status(response: dropbox_sdk::client_trait::HttpRequestResultRaw) -> Result<(), Error> {
+    let status = response.status;
+    if status == 200 {
+        return Ok(());
+    }
+    let mut body = String::new();
+    if let Err(e) = response.body.take(64 * 1024).read_to_string(&mut body) {
+        return Err(Error::HttpClient(Box::new(e)));
+    }
+    if status >= 500 || status == 429 {
+        // These are worth retrying.
+        Err(Error::ServerError(format!("HTTP {status}: {body}")))
+    } else {
+        Err(Error::UnexpectedHttpError {
+            code: status,
+            response: body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+
+    fn opts() -> UploadLinkOpts {
+        UploadLinkOpts {
+            retry: RetryOpts {
+                initial_backoff_time: Duration::from_millis(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let client = MockClient::new("").then(503, "unavailable");
+        upload_to_link(&client, "https://link", b"data", &opts()).unwrap();
+        assert_eq!(2, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn used_link_fails() {
+        let client = MockClient::new("").then(409, "already used");
+        let result = upload_to_link(&client, "https://link", b"data", &opts());
+        assert!(matches!(
+            result,
+            Err(Error::UnexpectedHttpError { code: 409, .. })
+        ));
+        assert_eq!(1, client.urls.lock().unwrap().len());
+    }
+
+    #[test]
+    fn lifetime_out_of_range() {
+        let client = MockClient::new("null");
+        for lifetime in [
+            limits::MIN_UPLOAD_LINK_LIFETIME - Duration::from_secs(1),
+            limits::MAX_UPLOAD_LINK_LIFETIME + Duration::from_secs(1),
+        ] {
+            let result = temporary_upload_link(
+                &client,
+                crate::upload::CommitOptions::new("/f"),
+                lifetime,
+                &RetryOpts::default(),
+            );
+            assert!(matches!(result, Err(Error::BadRequest(_))));
+        }
+        assert!(client.urls.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+//! Mapping files into memory.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+/// A read-only memory mapping of a whole file.
+pub struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only, so it can be read from any thread.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map the given file.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be changed until the mapping is dropped, since its contents are
+    /// borrowed as a `&[u8]` by [`Mmap::as_slice`].
+    pub unsafe fn map(file: &File) -> io::Result<Self> {
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large to map"))?;
+        if len == 0 {
+            // Zero-length mappings aren't allowed.
+            return Ok(Self {
+                ptr: ptr::null_mut(),
+                len,
+            });
+        }
+        // SAFETY: the arguments are valid, and the result is checked.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    /// The contents of the file.
+    pub fn as_slice(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: the mapping is valid for `len` bytes until it is dropped.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            // SAFETY: this is the mapping made in `map`, and it is no longer borrowed.
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn map_file() {
+        let path = std::env::temp_dir().join(format!("mmap_test_{}", std::process::id()));
+        fs::write(&path, b"hello").unwrap();
+        // SAFETY: nothing else changes the file while it's mapped.
+        let map = unsafe { Mmap::map(&File::open(&path).unwrap()) }.unwrap();
+        assert_eq!(b"hello", map.as_slice());
+        drop(map);
+
+        fs::write(&path, b"").unwrap();
+        // SAFETY: nothing else changes the file while it's mapped.
+        let map = unsafe { Mmap::map(&File::open(&path).unwrap()) }.unwrap();
+        assert_eq!(b"", map.as_slice());
+        fs::remove_file(&path).unwrap();
+    }
+}
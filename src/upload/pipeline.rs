@@ -0,0 +1,377 @@
+//! Reading a stream in chunks and processing the chunks in parallel.
+
+use std::io::{self, Read};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+
+/// An error from [`read_and_process`].
+pub enum Error<E> {
+    /// Reading from the source failed.
+    Read(io::Error),
+
+    /// Processing a chunk failed, with the error returned by the processing function.
+    Process(E),
+}
+
+/// Read from a stream and process it by chunks in parallel.
+///
+/// Reads are done sequentially on the current thread, and `chunk_size` is called before each one
+/// to decide how much to read, so the chunk size can change as the stream is read. Every chunk is
+/// full-size except the last one. The chunks are then passed to `process` on the given number of
+/// threads.
+///
+/// Chunk buffers are taken from the given pool, and returned to it once they've been processed.
+///
+/// No more than `num_threads` chunks are read ahead of the ones being processed. If
+/// `max_buffered_bytes` is given, reading also waits while the chunks read and not yet processed
+/// would add up to more than that, unless there are none, so that a chunk bigger than the limit
+/// can still be processed on its own.
+///
+/// If processing any chunk returns an error, or a read fails, reading stops and the first error is
+/// returned once all the chunks already being processed are done. Chunks which have been read but
+/// not yet started are dropped without being processed.
+pub fn read_and_process<E: Send>(
+    mut source: impl Read,
+    mut chunk_size: impl FnMut() -> usize,
+    num_threads: usize,
+    max_buffered_bytes: Option<usize>,
+    pool: &BufferPool,
+    process: impl Fn(u64, &[u8]) -> Result<(), E> + Sync,
+) -> Result<(), Error<E>> {
+    assert!(num_threads > 0, "non-zero number of threads required");
+    let budget = max_buffered_bytes.map(Budget::new);
+
+    // Work is sent as (offset, data) pairs. This is bounded by the number of workers, to ensure we
+    // don't read too far ahead of the work.
+    let (work_tx, work_rx) = mpsc::sync_channel::<(u64, Vec<u8>)>(num_threads);
+    let work_rx = Mutex::new(work_rx);
+
+    // Errors from processing go here. Once one has been sent, the workers keep receiving work, but
+    // discard it, so that the reading loop doesn't block forever trying to send more.
+    let (error_tx, error_rx) = mpsc::channel::<E>();
+    let failed = AtomicBool::new(false);
+
+    let read_result = thread::scope(|scope| {
+        for _ in 0..num_threads {
+            let error_tx = error_tx.clone();
+            let (work_rx, failed, process, budget) = (&work_rx, &failed, &process, &budget);
+            scope.spawn(move || loop {
+                let Ok((offset, data)) = work_rx.lock().unwrap().recv() else {
+                    // Channel closed: no more work.
+                    break;
+                };
+                if !failed.load(SeqCst) {
+                    if let Err(error) = process(offset, &data) {
+                        failed.store(true, SeqCst);
+                        error_tx.send(error).unwrap();
+                    }
+                }
+                if let Some(budget) = budget {
+                    budget.release(data.len());
+                }
+                pool.put(data);
+            });
+        }
+
+        let mut offset = 0u64;
+        let result = loop {
+            if failed.load(SeqCst) {
+                break Ok(());
+            }
+            let size = chunk_size();
+            assert!(size > 0, "non-zero chunk size required");
+            if let Some(budget) = &budget {
+                budget.acquire(size);
+            }
+            let mut buf = pool.get(size);
+            match large_read(&mut source, &mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => {
+                    if let Some(budget) = &budget {
+                        budget.release(size - n);
+                    }
+                    buf.truncate(n);
+                    work_tx.send((offset, buf)).expect("worker threads exited");
+                    offset += n as u64;
+                }
+                Err(e) => break Err(Error::Read(e)),
+            }
+        };
+
+        // Close the work channel, which makes the workers exit once they've finished.
+        drop(work_tx);
+        result
+    });
+
+    // A processing error takes precedence over a read error, because a failed read doesn't need
+    // to be reported if the upload was already going to fail.
+    drop(error_tx);
+    match error_rx.recv() {
+        Ok(error) => Err(Error::Process(error)),
+        Err(mpsc::RecvError) => read_result,
+    }
+}
+
+/// Like [`read_and_process`], but for data which is already in memory, so the chunks are passed to
+/// `process` without copying them.
+#[cfg_attr(not(all(unix, feature = "mmap")), allow(dead_code))]
+pub fn process_slice<E: Send>(
+    data: &[u8],
+    chunk_size: impl FnMut() -> usize,
+    num_threads: usize,
+    process: impl Fn(u64, &[u8]) -> Result<(), E> + Sync,
+) -> Result<(), E> {
+    process_ranges(data.len() as u64, chunk_size, num_threads, |range| {
+        process(range.start, &data[range.start as usize..range.end as usize])
+    })
+}
+
+/// Like [`read_and_process`], but instead of reading anything, pass the ranges of the chunks of
+/// data of the given length to `process`, which is responsible for getting the data itself, so
+/// nothing is read ahead.
+pub fn process_ranges<E: Send>(
+    len: u64,
+    mut chunk_size: impl FnMut() -> usize,
+    num_threads: usize,
+    process: impl Fn(Range<u64>) -> Result<(), E> + Sync,
+) -> Result<(), E> {
+    assert!(num_threads > 0, "non-zero number of threads required");
+
+    let (work_tx, work_rx) = mpsc::sync_channel::<Range<u64>>(num_threads);
+    let work_rx = Mutex::new(work_rx);
+    let (error_tx, error_rx) = mpsc::channel::<E>();
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        for _ in 0..num_threads {
+            let error_tx = error_tx.clone();
+            let (work_rx, failed, process) = (&work_rx, &failed, &process);
+            scope.spawn(move || loop {
+                let Ok(range) = work_rx.lock().unwrap().recv() else {
+                    break;
+                };
+                if failed.load(SeqCst) {
+                    continue;
+                }
+                if let Err(error) = process(range) {
+                    failed.store(true, SeqCst);
+                    error_tx.send(error).unwrap();
+                }
+            });
+        }
+
+        let mut offset = 0;
+        while offset < len && !failed.load(SeqCst) {
+            let size = chunk_size();
+            assert!(size > 0, "non-zero chunk size required");
+            let end = len.min(offset + size as u64);
+            work_tx.send(offset..end).expect("worker threads exited");
+            offset = end;
+        }
+        drop(work_tx);
+    });
+
+    drop(error_tx);
+    match error_rx.recv() {
+        Ok(error) => Err(error),
+        Err(mpsc::RecvError) => Ok(()),
+    }
+}
+
+/// A pool of buffers for reading chunks into, which can be shared by multiple uploads so that
+/// buffers are reused from one to the next instead of being allocated for every chunk.
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Make a new pool which keeps up to the given number of unused buffers.
+    ///
+    /// An upload uses about twice its [`parallelism`](super::UploadOpts::parallelism) buffers at
+    /// once, so keeping more than that is only useful when the pool is shared by uploads running at
+    /// the same time.
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: Mutex::new(vec![]),
+            max_buffers,
+        }
+    }
+
+    /// Get a buffer of the given size, reusing one from the pool if there are any. Its contents
+    /// are unspecified.
+    pub(super) fn get(&self, size: usize) -> Vec<u8> {
+        match self.buffers.lock().unwrap().pop() {
+            Some(mut buf) => {
+                buf.resize(size, 0);
+                buf
+            }
+            None => vec![0u8; size],
+        }
+    }
+
+    /// Return a buffer to the pool, if it isn't full.
+    pub(super) fn put(&self, buf: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+}
+
+/// Keeps track of how many bytes of chunks are buffered.
+struct Budget {
+    max: usize,
+    used: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Budget {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self, bytes: usize) {
+        let mut used = self.used.lock().unwrap();
+        while *used > 0 && *used + bytes > self.max {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += bytes;
+    }
+
+    fn release(&self, bytes: usize) {
+        *self.used.lock().unwrap() -= bytes;
+        self.released.notify_one();
+    }
+}
+
+// std::io::Read::read() is not required to fill the buffer, such as when reading from a pipe, but
+// we want full chunks until we hit EOF, so do multiple reads in a loop if necessary.
+fn large_read(mut source: impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match source.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+
+    #[test]
+    fn variable_chunk_sizes() {
+        let source = [0u8; 100];
+        let mut sizes = [10, 20, 30].into_iter().cycle();
+        let chunks = Mutex::new(vec![]);
+        let result = read_and_process(
+            &source[..],
+            || sizes.next().unwrap(),
+            3,
+            None,
+            &BufferPool::new(0),
+            |offset, data: &[u8]| -> Result<(), ()> {
+                chunks.lock().unwrap().push((offset, data.len()));
+                Ok(())
+            },
+        );
+        assert!(result.is_ok());
+        let mut chunks = chunks.into_inner().unwrap();
+        chunks.sort();
+        assert_eq!(
+            vec![(0, 10), (10, 20), (30, 30), (60, 10), (70, 20), (90, 10)],
+            chunks
+        );
+    }
+
+    #[test]
+    fn slice() {
+        let source = [0u8; 100];
+        let total = AtomicU64::new(0);
+        let result = process_slice(
+            &source,
+            || 30,
+            2,
+            |offset, data: &[u8]| -> Result<(), ()> {
+                assert_eq!(if offset == 90 { 10 } else { 30 }, data.len());
+                total.fetch_add(data.len() as u64, SeqCst);
+                Ok(())
+            },
+        );
+        assert!(result.is_ok());
+        assert_eq!(100, total.load(SeqCst));
+    }
+
+    #[test]
+    fn stops_on_error() {
+        let source = [0u8; 1000];
+        let processed = AtomicU64::new(0);
+        let result = read_and_process(
+            &source[..],
+            || 10,
+            2,
+            None,
+            &BufferPool::new(0),
+            |offset, _data: &[u8]| {
+                if offset == 50 {
+                    return Err("oops");
+                }
+                processed.fetch_add(1, SeqCst);
+                Ok(())
+            },
+        );
+        match result {
+            Err(Error::Process(error)) => assert_eq!("oops", error),
+            _ => panic!("expected a processing error"),
+        }
+        assert!(processed.load(SeqCst) < 99);
+    }
+
+    #[test]
+    fn max_buffered_bytes() {
+        let source = [0u8; 1000];
+        let buffered = AtomicU64::new(0);
+        let max_seen = AtomicU64::new(0);
+        let result = read_and_process(
+            &source[..],
+            || 10,
+            10,
+            Some(25),
+            &BufferPool::new(0),
+            |_offset, data: &[u8]| -> Result<(), ()> {
+                let now = buffered.fetch_add(data.len() as u64, SeqCst) + data.len() as u64;
+                max_seen.fetch_max(now, SeqCst);
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                buffered.fetch_sub(data.len() as u64, SeqCst);
+                Ok(())
+            },
+        );
+        assert!(result.is_ok());
+        assert!(max_seen.load(SeqCst) <= 20);
+    }
+
+    #[test]
+    fn buffer_pool() {
+        let pool = BufferPool::new(1);
+        let buf = pool.get(100);
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        pool.put(vec![0u8; 10]); // Pool is full; this is dropped.
+        let buf = pool.get(50);
+        assert_eq!(ptr, buf.as_ptr());
+        assert_eq!(50, buf.len());
+        assert_eq!(10, pool.get(10).len());
+    }
+}
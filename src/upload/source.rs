@@ -0,0 +1,98 @@
+//! Adapters for uploading data which is generated on the fly, rather than read from a stream.
+
+use std::io::{self, Read};
+
+/// Make an upload source which calls the given function to fill buffers, like [`Read::read`]: it
+/// should write some data into the buffer and return how much it wrote, or 0 when there is no more
+/// data.
+///
+/// ```
+/// # use dropbox_toolbox::upload::source;
+/// # use std::io::Read;
+/// let mut remaining = 10_000;
+/// let mut source = source::from_fn(|buf: &mut [u8]| {
+///     let n = buf.len().min(remaining);
+///     buf[..n].fill(b'x');
+///     remaining -= n;
+///     Ok(n)
+/// });
+/// let mut all = vec![];
+/// source.read_to_end(&mut all).unwrap();
+/// assert_eq!(10_000, all.len());
+/// ```
+pub fn from_fn<F: FnMut(&mut [u8]) -> io::Result<usize>>(f: F) -> FromFn<F> {
+    FromFn(f)
+}
+
+/// An upload source made with [`from_fn`].
+pub struct FromFn<F>(F);
+
+impl<F: FnMut(&mut [u8]) -> io::Result<usize>> Read for FromFn<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (self.0)(buf)
+    }
+}
+
+/// Make an upload source from an iterator of chunks of data, of any size.
+pub fn from_chunks<I>(chunks: I) -> FromChunks<I::IntoIter>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    FromChunks {
+        chunks: chunks.into_iter(),
+        current: None,
+        pos: 0,
+    }
+}
+
+/// An upload source made with [`from_chunks`].
+pub struct FromChunks<I: Iterator> {
+    chunks: I,
+    current: Option<I::Item>,
+    pos: usize,
+}
+
+impl<I> Read for FromChunks<I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(chunk) = &self.current {
+                let rest = &chunk.as_ref()[self.pos..];
+                if !rest.is_empty() {
+                    let n = rest.len().min(buf.len());
+                    buf[..n].copy_from_slice(&rest[..n]);
+                    self.pos += n;
+                    return Ok(n);
+                }
+            }
+            match self.chunks.next() {
+                Some(chunk) => {
+                    self.current = Some(chunk);
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks() {
+        let mut source = from_chunks(vec![b"ab".to_vec(), vec![], b"cde".to_vec()]);
+        let mut buf = [0u8; 2];
+        assert_eq!(2, source.read(&mut buf).unwrap());
+        assert_eq!(b"ab", &buf);
+        let mut rest = vec![];
+        source.read_to_end(&mut rest).unwrap();
+        assert_eq!(b"cde", &rest[..]);
+        assert_eq!(0, source.read(&mut buf).unwrap());
+    }
+}
@@ -0,0 +1,151 @@
+//! Choosing how many blocks to upload in each request, based on observed throughput.
+
+use std::time::Duration;
+
+/// How many requests to time at each size before deciding whether to change it.
+const SAMPLES: u32 = 4;
+
+/// How much faster (per request) requests need to get to be worth making them bigger.
+const MIN_IMPROVEMENT: f64 = 1.1;
+
+/// Adjusts the number of blocks per request as an upload goes.
+///
+/// Each request has a fixed overhead, so when the overhead dominates, sending more data per request
+/// makes each request transfer faster. This starts at 1 block per request, and adds a block each
+/// time the throughput of a request improves by enough, until it stops improving or the maximum is
+/// reached. When a request fails, the size is halved, because larger requests are more likely to
+/// time out and cost more to retry.
+#[derive(Debug)]
+pub struct BlockTuner {
+    blocks: usize,
+    max: usize,
+    settled: bool,
+    prev_rate: Option<f64>,
+    sample_bytes: u64,
+    sample_time: Duration,
+    samples: u32,
+}
+
+impl BlockTuner {
+    pub fn new(max: usize) -> Self {
+        Self {
+            blocks: 1,
+            max: max.max(1),
+            settled: false,
+            prev_rate: None,
+            sample_bytes: 0,
+            sample_time: Duration::ZERO,
+            samples: 0,
+        }
+    }
+
+    /// How many blocks the next request should have.
+    pub fn blocks(&self) -> usize {
+        self.blocks
+    }
+
+    /// Record a successful request of the given number of blocks and bytes, which took the given
+    /// time.
+    pub fn success(&mut self, blocks: usize, bytes: u64, time: Duration) {
+        if blocks != self.blocks || self.settled {
+            // Either a request from before the last change, or there's nothing left to decide.
+            return;
+        }
+        self.sample_bytes += bytes;
+        self.sample_time += time;
+        self.samples += 1;
+        if self.samples < SAMPLES {
+            return;
+        }
+
+        let rate = self.sample_bytes as f64 / self.sample_time.as_secs_f64().max(f64::EPSILON);
+        match self.prev_rate {
+            Some(prev) if rate < prev * MIN_IMPROVEMENT => {
+                debug!("{} blocks per request is not faster; settling", self.blocks);
+                self.settled = true;
+            }
+            _ if self.blocks < self.max => {
+                self.blocks += 1;
+                debug!("increasing to {} blocks per request", self.blocks);
+            }
+            _ => self.settled = true,
+        }
+        self.prev_rate = Some(rate);
+        self.reset_samples();
+    }
+
+    /// Record a failed request.
+    pub fn failure(&mut self) {
+        let blocks = (self.blocks / 2).max(1);
+        if blocks != self.blocks {
+            debug!("request failed; decreasing to {blocks} blocks per request");
+            self.blocks = blocks;
+            self.settled = true;
+        }
+        self.prev_rate = None;
+        self.reset_samples();
+    }
+
+    fn reset_samples(&mut self) {
+        self.sample_bytes = 0;
+        self.sample_time = Duration::ZERO;
+        self.samples = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(tuner: &mut BlockTuner, secs_per_request: f64) {
+        for _ in 0..SAMPLES {
+            let blocks = tuner.blocks();
+            tuner.success(
+                blocks,
+                blocks as u64,
+                Duration::from_secs_f64(secs_per_request),
+            );
+        }
+    }
+
+    #[test]
+    fn grows_while_faster() {
+        let mut tuner = BlockTuner::new(4);
+        assert_eq!(1, tuner.blocks());
+        // Overhead-dominated: requests take the same time regardless of size.
+        run(&mut tuner, 1.);
+        assert_eq!(2, tuner.blocks());
+        run(&mut tuner, 1.);
+        assert_eq!(3, tuner.blocks());
+        // Now time scales with size: no improvement.
+        run(&mut tuner, 1.5);
+        assert_eq!(3, tuner.blocks());
+        run(&mut tuner, 0.1);
+        assert_eq!(3, tuner.blocks());
+    }
+
+    #[test]
+    fn stops_at_max() {
+        let mut tuner = BlockTuner::new(2);
+        for _ in 0..5 {
+            run(&mut tuner, 1.);
+        }
+        assert_eq!(2, tuner.blocks());
+    }
+
+    #[test]
+    fn shrinks_on_failure() {
+        let mut tuner = BlockTuner::new(8);
+        for _ in 0..3 {
+            run(&mut tuner, 1.);
+        }
+        assert_eq!(4, tuner.blocks());
+        tuner.failure();
+        assert_eq!(2, tuner.blocks());
+        run(&mut tuner, 0.1);
+        assert_eq!(2, tuner.blocks());
+        tuner.failure();
+        tuner.failure();
+        assert_eq!(1, tuner.blocks());
+    }
+}
@@ -0,0 +1,196 @@
+//! Uploading data written through [`std::io::Write`].
+
+use std::io::{self, Write};
+use std::mem;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use dropbox_sdk::files;
+use dropbox_sdk::UserAuthClient;
+use dropbox_sdk::{BoxedError, Error};
+
+use super::{source, UploadError, UploadOpts, UploadOutcome, UploadSession};
+use crate::BLOCK_SIZE;
+
+/// Uploads the data written to it to an upload session, so that code which writes to a
+/// [`Write`], such as an encoder or serializer, or [`io::copy`], can send it straight to Dropbox.
+///
+/// Data is collected into blocks, which are uploaded by a background thread, in parallel as
+/// configured by the [`UploadOpts`]. Writes block when the upload falls behind.
+///
+/// Call [`UploadWriter::finish`] after writing everything to commit it. If the writer is dropped
+/// instead, everything written to it, including the data still buffered, is uploaded but not
+/// committed. Like a [`BufWriter`](io::BufWriter), dropping it waits for that, and ignores any
+/// errors.
+///
+/// If the upload fails, writes fail with [`io::ErrorKind::BrokenPipe`], and the error from the
+/// upload is returned by [`UploadWriter::finish`].
+pub struct UploadWriter<C: UserAuthClient + Send + Sync + 'static> {
+    session: Arc<UploadSession<C>>,
+    buf: Vec<u8>,
+    chunks: Option<SyncSender<Vec<u8>>>,
+    uploader: Option<JoinHandle<Result<u64, UploadError>>>,
+}
+
+impl<C: UserAuthClient + Send + Sync + 'static> UploadWriter<C> {
+    /// Start a new upload session to write to.
+    pub fn new(
+        client: Arc<C>,
+        opts: UploadOpts,
+    ) -> Result<Self, Error<files::UploadSessionStartError>> {
+        Ok(Self::from_session(UploadSession::new(client)?, opts))
+    }
+
+    /// Write to the given upload session, such as one which was
+    /// [resumed](UploadSession::resume).
+    pub fn from_session(session: UploadSession<C>, opts: UploadOpts) -> Self {
+        let session = Arc::new(session);
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(1);
+        let uploader = {
+            let session = Arc::clone(&session);
+            thread::spawn(move || session.upload(source::from_chunks(rx), opts))
+        };
+        Self {
+            session,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            chunks: Some(tx),
+            uploader: Some(uploader),
+        }
+    }
+
+    /// The upload session being written to, such as for getting its
+    /// [resume parameters](UploadSession::get_resume) if the upload fails.
+    pub fn session(&self) -> &UploadSession<C> {
+        &self.session
+    }
+
+    /// Finish uploading everything written so far, close the session, and commit it.
+    pub fn finish(
+        mut self,
+        commit_info: impl Into<files::CommitInfo>,
+    ) -> Result<UploadOutcome, BoxedError> {
+        let sent = self.send();
+        self.chunks = None;
+        let uploaded = self
+            .uploader
+            .take()
+            .expect("uploader already joined")
+            .join()
+            .expect("uploader thread panicked")?;
+        sent.map_err(|e| Error::HttpClient(e.into()))?;
+        info!(
+            "[{}] Wrote {uploaded} bytes; committing",
+            self.session.operation_id()
+        );
+        let metadata = self.session.commit(commit_info).map_err(Error::boxed)?;
+        Ok(self.session.outcome(metadata))
+    }
+
+    /// Send the buffered data to the uploader.
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE));
+        self.chunks
+            .as_ref()
+            .and_then(|tx| tx.send(chunk).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "upload failed"))
+    }
+}
+
+impl<C: UserAuthClient + Send + Sync + 'static> Write for UploadWriter<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == BLOCK_SIZE {
+            self.send()?;
+        }
+        Ok(n)
+    }
+
+    /// Hand the buffered data to the background thread. This doesn't wait for it to be uploaded.
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+impl<C: UserAuthClient + Send + Sync + 'static> Drop for UploadWriter<C> {
+    fn drop(&mut self) {
+        let Some(uploader) = self.uploader.take() else {
+            // Already finished.
+            return;
+        };
+        let _ = self.send();
+        self.chunks = None;
+        match uploader.join() {
+            Ok(Ok(uploaded)) => debug!(
+                "[{}] Writer dropped after uploading {uploaded} bytes; not committing",
+                self.session.operation_id()
+            ),
+            Ok(Err(e)) => warn!(
+                "[{}] Writer dropped after its upload failed: {e}",
+                self.session.operation_id()
+            ),
+            Err(_) => warn!(
+                "[{}] Writer dropped after its upload panicked",
+                self.session.operation_id()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tests::MockClient;
+    use crate::upload::CommitOptions;
+
+    #[test]
+    fn write_and_finish() {
+        let client = Arc::new(
+            MockClient::new("null")
+                .then(200, r#"{"session_id": "session"}"#)
+                .then(200, "null")
+                .then(
+                    200,
+                    r#"{
+                        "name": "out",
+                        "id": "id:out",
+                        "client_modified": "2024-01-01T00:00:00Z",
+                        "server_modified": "2024-01-01T00:00:00Z",
+                        "rev": "0123456789abcdef",
+                        "size": 11,
+                        "path_display": "/out"
+                    }"#,
+                ),
+        );
+        let mut writer = UploadWriter::new(client.clone(), UploadOpts::default()).unwrap();
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"world").unwrap();
+        let outcome = writer.finish(CommitOptions::new("/out")).unwrap();
+        assert_eq!(11, outcome.metadata.size);
+        assert_eq!(11, outcome.bytes_uploaded);
+        assert_eq!(0, outcome.resumed_bytes);
+        let mut hash = crate::content_hash::ContentHash::new();
+        hash.update(b"hello world");
+        assert_eq!(Some(hash.finish_hex()), outcome.content_hash);
+        // Start, one append with all the data which closes the session, and finish.
+        let urls = client.urls.lock().unwrap();
+        assert_eq!(3, urls.len());
+        assert!(urls[2].ends_with("upload_session/finish"));
+    }
+
+    #[test]
+    fn drop_uploads_buffer() {
+        let client = Arc::new(MockClient::new("null").then(200, r#"{"session_id": "session"}"#));
+        let mut writer = UploadWriter::new(client.clone(), UploadOpts::default()).unwrap();
+        writer.write_all(b"hello").unwrap();
+        drop(writer);
+        // Start, and an append with the buffered data, but no finish.
+        let urls = client.urls.lock().unwrap();
+        assert_eq!(2, urls.len());
+        assert!(urls[1].ends_with("upload_session/append_v2"));
+    }
+}